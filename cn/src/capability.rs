@@ -0,0 +1,11 @@
+use sha2::{Digest, Sha256};
+
+/// The read-only key that goes with a region's read-write key, for handing
+/// out read access to it. Data nodes derive it the same way.
+pub fn read_only(key: u64) -> u64 {
+    let digest = Sha256::new()
+        .chain_update(b"read-only")
+        .chain_update(key.to_le_bytes())
+        .finalize();
+    u64::from_le_bytes(digest[..8].try_into().unwrap())
+}
@@ -1,34 +1,489 @@
+use crate::credentials::{Authed, Credentials};
 use crate::proto::memory::{
-    memory_client::MemoryClient as GrpcMemoryClient, AllocateRequest, AllocateResponse,
-    AllocationError, DeallocationError, FreeRequest, FreeResponse, GetMemorySizeRequest,
-    GetMemorySizeResponse, MemoryAccessError, ReadRequest, ReadResponse, WriteRequest,
-    WriteResponse,
+    memory_client::MemoryClient as GrpcMemoryClient, AccessMode, AllocateRequest, AllocateResponse,
+    AllocationError, CompareAndSwapRequest, CompareAndSwapResponse, DeallocationError,
+    DrainProgress, DrainRequest, FreeRequest, FreeResponse, GetMemorySizeRequest,
+    GetMemorySizeResponse, GrantRequest, MakeTailRequest, MemoryAccessError, MigrateRequest,
+    Pressure, PressureRequest, PressureUpdate, PromoteRequest, ReadRequest, ReadResponse,
+    RegionMoved, RevokeRequest, TenantUsage, TierUsage, TransferRequest, UsageRequest,
+    WriteRequest, WriteResponse, XorRequest, XorResponse,
 };
-use tonic::{transport::Channel, Response, Status};
+use prost::Message;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tonic::{Code, Response, Status, Streaming};
 
+const MAX_LEARNED_KEYS: usize = 1 << 16; // before the oldest are forgotten
+const MAX_THROTTLED_RETRIES: usize = 16; // of a request, before giving up on it
+
+/// Remote memory as seen by the structures built on top of it, either a
+/// single data node or a cluster of them. Ids are opaque handles issued by
+/// `allocate_memory`.
+#[tonic::async_trait]
+pub trait RemoteMemory: Clone + Send + Sync + 'static {
+    /// Allocates a region, also returning the generation of the data node
+    /// it was placed on.
+    async fn allocate_fenced(&mut self, size: u64) -> Result<(u64, u32), AllocationError>;
+
+    async fn allocate_memory(&mut self, size: u64) -> Result<u64, AllocationError> {
+        Ok(self.allocate_fenced(size).await?.0)
+    }
+
+    /// Allocates a region its data node may evict to make room once it is
+    /// full, lower priorities first. Accesses to it fail with
+    /// `RegionEvicted` from then on.
+    async fn allocate_evictable(
+        &mut self,
+        size: u64,
+        priority: u32,
+    ) -> Result<u64, AllocationError>;
+
+    async fn free(&mut self, id: u64) -> Result<(), DeallocationError>;
+
+    /// Reads from the region, failing with `StaleGeneration` if its data
+    /// node has restarted since `generation`. Generation 0 skips the check.
+    async fn read_fenced(
+        &mut self,
+        id: u64,
+        generation: u32,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, MemoryAccessError>;
+
+    async fn read(
+        &mut self,
+        id: u64,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, MemoryAccessError> {
+        self.read_fenced(id, 0, offset, length).await
+    }
+
+    /// Like `read_fenced`, for writes.
+    async fn write_fenced(
+        &mut self,
+        id: u64,
+        generation: u32,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<(), MemoryAccessError>;
+
+    async fn write(
+        &mut self,
+        id: u64,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<(), MemoryAccessError> {
+        self.write_fenced(id, 0, offset, data).await
+    }
+
+    async fn get_memory_size(&mut self, id: u64) -> Result<u64, MemoryAccessError>;
+
+    /// Like `read_fenced`, for compare-and-swaps.
+    async fn compare_and_swap_fenced(
+        &mut self,
+        id: u64,
+        generation: u32,
+        offset: u64,
+        expected: u64,
+        desired: u64,
+    ) -> Result<Result<u64, u64>, MemoryAccessError>;
+
+    async fn compare_and_swap(
+        &mut self,
+        id: u64,
+        offset: u64,
+        expected: u64,
+        desired: u64,
+    ) -> Result<Result<u64, u64>, MemoryAccessError> {
+        self.compare_and_swap_fenced(id, 0, offset, expected, desired)
+            .await
+    }
+
+    /// Like `compare_and_swap`, but also writes `tail` right after the word
+    /// when the swap happens, in the same step. Not supported for
+    /// erasure-coded regions.
+    async fn compare_and_swap_with_tail(
+        &mut self,
+        id: u64,
+        offset: u64,
+        expected: u64,
+        desired: u64,
+        tail: Vec<u8>,
+    ) -> Result<Result<u64, u64>, MemoryAccessError>;
+
+    /// The key this client presents for region `id`, 0 if it has none.
+    /// Structures shared between clients store it next to the id, so that
+    /// nodes requiring keys let the others use the region too.
+    fn region_key(&self, id: u64) -> u64;
+
+    /// Presents `key` for region `id` while working on a structure it was
+    /// read out of. Unlike the keys of regions this client allocated, those
+    /// learned this way are forgotten once enough others have been learned.
+    fn learn_key(&self, id: u64, key: u64);
+
+    /// How close the memory is to full, as last reported. Callers can ease
+    /// off before allocations start failing.
+    fn pressure(&self) -> Pressure;
+}
+
+/// How a data node's replicas pass changes along, matching the node's
+/// `--replication` setting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplicaMode {
+    /// Everything goes to the primary.
+    PrimaryBackup,
+    /// Changes go to the head of the chain and reads to its tail.
+    Chain,
+}
+
+/// Client for a data node, optionally replicated. Clones share the
+/// connections, and fail over together.
+#[derive(Clone)]
 pub struct MemoryClient {
-    client: GrpcMemoryClient<Channel>,
+    replicas: Arc<Replicas>,
+}
+
+struct Replicas {
+    addrs: Vec<String>, // the primary or head, then the rest in promotion order
+    mode: ReplicaMode,
+    routes: std::sync::Mutex<Routes>,
+    failing_over: tokio::sync::Mutex<()>,
+    moved: std::sync::Mutex<HashMap<u64, RegionMoved>>, // regions migrated away
+    keys: std::sync::Mutex<HashMap<u64, u64>>,          // of the regions this client may use
+    learned: std::sync::Mutex<[HashMap<u64, u64>; 2]>,  // with `learn_key`, newest first
+    pressure: AtomicI32,                                // as last reported by the head, if followed
+    credentials: Credentials,
+}
+
+/// The replicas currently taking changes and serving reads, as indexes into
+/// `addrs` with their connections. Both are the primary unless in a chain.
+#[derive(Clone)]
+struct Routes {
+    head: (usize, GrpcMemoryClient<Authed>),
+    tail: (usize, GrpcMemoryClient<Authed>),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Head,
+    Tail,
 }
 
 impl MemoryClient {
-    pub async fn new(addr: String) -> Result<Self, Box<dyn std::error::Error>> {
-        let channel = Channel::from_shared(addr)?.connect().await?;
-        let client = GrpcMemoryClient::new(channel);
-        Ok(Self { client })
+    /// Connects to a primary data node with the given backups. When the
+    /// current primary becomes unavailable the client promotes the next
+    /// backup in the list and retries there, so every client of the node
+    /// must list its backups in the same order. Clients never go back to an
+    /// earlier replica: a restarted primary has lost its memory.
+    ///
+    /// An operation in flight during a failover may already have reached the
+    /// backup before it is retried. Retrying writes is harmless, but a
+    /// retried allocation leaks the first region and a retried free fails.
+    pub async fn with_backups(
+        primary: String,
+        backups: Vec<String>,
+        credentials: Credentials,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::connect(primary, backups, ReplicaMode::PrimaryBackup, credentials).await
+    }
+
+    /// Connects to a chain of data nodes, from `head` through `rest`. Changes
+    /// go to the head and reads to the tail. If the head fails the next node
+    /// is promoted, as with backups; if the tail fails, the node before it
+    /// is made the tail once it agrees its successors are gone.
+    pub async fn chain(
+        head: String,
+        rest: Vec<String>,
+        credentials: Credentials,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::connect(head, rest, ReplicaMode::Chain, credentials).await
     }
 
-    pub async fn allocate_memory(&mut self, size: u64) -> Result<u64, AllocationError> {
-        let request = AllocateRequest { size };
+    async fn connect(
+        first: String,
+        rest: Vec<String>,
+        mode: ReplicaMode,
+        credentials: Credentials,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut addrs = vec![first];
+        addrs.extend(rest);
+        let head = (0, Self::open(&credentials, &addrs[0]).await?);
+        let tail = match mode {
+            ReplicaMode::Chain if addrs.len() > 1 => {
+                let last = addrs.len() - 1;
+                (last, Self::open(&credentials, &addrs[last]).await?)
+            }
+            _ => head.clone(),
+        };
+        Ok(Self {
+            replicas: Arc::new(Replicas {
+                addrs,
+                mode,
+                routes: std::sync::Mutex::new(Routes { head, tail }),
+                failing_over: tokio::sync::Mutex::new(()),
+                moved: std::sync::Mutex::new(HashMap::new()),
+                keys: std::sync::Mutex::new(HashMap::new()),
+                learned: std::sync::Mutex::new(Default::default()),
+                pressure: AtomicI32::new(Pressure::None as i32),
+                credentials,
+            }),
+        })
+    }
+
+    async fn open(
+        credentials: &Credentials,
+        addr: &str,
+    ) -> Result<GrpcMemoryClient<Authed>, Box<dyn std::error::Error>> {
+        let channel = credentials.endpoint(addr.to_string())?.connect().await?;
+        Ok(GrpcMemoryClient::new(credentials.authed(channel)))
+    }
+
+    /// Connects to the replica at `addr`, returning a client for the calls
+    /// that fail over to it, with the credentials for those, and one for
+    /// everything else.
+    async fn open_to_fail_over(
+        &self,
+        addr: &str,
+    ) -> Result<(GrpcMemoryClient<Authed>, GrpcMemoryClient<Authed>), Box<dyn std::error::Error>>
+    {
+        let credentials = &self.replicas.credentials;
+        let channel = credentials.endpoint(addr.to_string())?.connect().await?;
+        let failover = credentials.failover().authed(channel.clone());
+        Ok((
+            GrpcMemoryClient::new(failover),
+            GrpcMemoryClient::new(credentials.authed(channel)),
+        ))
+    }
+
+    fn route(&self, target: Target) -> (usize, GrpcMemoryClient<Authed>) {
+        let routes = self.replicas.routes.lock().unwrap();
+        match target {
+            Target::Head => routes.head.clone(),
+            Target::Tail => routes.tail.clone(),
+        }
+    }
+
+    /// Runs `rpc` against the head or tail, failing over and retrying for as
+    /// long as that replica is lost and there are others left, and retrying
+    /// requests the node throttled once it says to, up to
+    /// `MAX_THROTTLED_RETRIES` times.
+    async fn call<T, F, Fut>(&self, target: Target, rpc: F) -> Result<Response<T>, Status>
+    where
+        F: Fn(GrpcMemoryClient<Authed>) -> Fut,
+        Fut: Future<Output = Result<Response<T>, Status>>,
+    {
+        let target = match self.replicas.mode {
+            ReplicaMode::PrimaryBackup => Target::Head,
+            ReplicaMode::Chain => target,
+        };
+        let mut throttled = 0;
+        loop {
+            let (index, client) = self.route(target);
+            match rpc(client).await {
+                Err(status) if primary_lost(&status) && self.fail_over(target, index).await => {}
+                Err(status) => match retry_after(&status) {
+                    Some(wait) if throttled < MAX_THROTTLED_RETRIES => {
+                        throttled += 1;
+                        tokio::time::sleep(wait).await
+                    }
+                    _ => return Err(status),
+                },
+                result => return result,
+            }
+        }
+    }
+
+    /// Records where region `id` went if `status` says it has migrated.
+    fn note_moved(&self, id: u64, status: &Status) -> bool {
+        if status.code() != Code::NotFound || status.details().is_empty() {
+            return false;
+        }
+        match RegionMoved::decode(status.details()) {
+            Ok(moved) => {
+                self.replicas.moved.lock().unwrap().insert(id, moved);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// The key this client presents for region `id`: the read-write key
+    /// for regions it allocated, or whichever key it was given with
+    /// `add_key` or `learn_key`.
+    pub fn key(&self, id: u64) -> Option<u64> {
+        if let Some(&key) = self.replicas.keys.lock().unwrap().get(&id) {
+            return Some(key);
+        }
+        let learned = self.replicas.learned.lock().unwrap();
+        learned.iter().find_map(|keys| keys.get(&id).copied())
+    }
+
+    /// Presents `key` for region `id` from now on, such as a read-only key
+    /// handed over by the client that allocated it.
+    pub fn add_key(&self, id: u64, key: u64) {
+        self.replicas.keys.lock().unwrap().insert(id, key);
+    }
+
+    /// Presents `key` for region `id` until `MAX_LEARNED_KEYS` more have
+    /// been learned, keeping clients that read shared structures from
+    /// holding the key of every region they ever came across.
+    pub fn learn_key(&self, id: u64, key: u64) {
+        if key == 0 || self.replicas.keys.lock().unwrap().contains_key(&id) {
+            return;
+        }
+        let mut learned = self.replicas.learned.lock().unwrap();
+        if learned[0].len() >= MAX_LEARNED_KEYS {
+            learned[1] = std::mem::take(&mut learned[0]);
+        }
+        learned[0].insert(id, key);
+    }
+
+    /// Where region `id` has migrated to, as the address of its new data
+    /// node and its id, generation and key there, if this client has been
+    /// told. Accesses to it fail with `RegionMoved`.
+    pub fn forwarded(&self, id: u64) -> Option<RegionMoved> {
+        self.replicas.moved.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Replaces the `failed` head or tail, unless another clone already has.
+    /// Returns false once no replica can take over.
+    async fn fail_over(&self, target: Target, failed: usize) -> bool {
+        let _guard = self.replicas.failing_over.lock().await;
+        if self.route(target).0 != failed {
+            return true;
+        }
+        let (head, tail) = {
+            let routes = self.replicas.routes.lock().unwrap();
+            (routes.head.0, routes.tail.0)
+        };
+        let addrs = &self.replicas.addrs;
+
+        // a head can't go past the tail, nor the tail before the head
+        let candidates: Vec<usize> = match (target, self.replicas.mode) {
+            (Target::Head, ReplicaMode::Chain) => (failed + 1..=tail).collect(),
+            (Target::Head, ReplicaMode::PrimaryBackup) => (failed + 1..addrs.len()).collect(),
+            (Target::Tail, _) => (head..failed).rev().collect(),
+        };
+        for index in candidates {
+            let result = match target {
+                Target::Head => self.promote(index, tail).await,
+                Target::Tail => self.make_tail(index).await,
+            };
+            match result {
+                Ok(client) => {
+                    log::warn!("Failed over to {}", addrs[index]);
+                    let mut routes = self.replicas.routes.lock().unwrap();
+                    match (target, self.replicas.mode) {
+                        (Target::Head, ReplicaMode::PrimaryBackup) => {
+                            routes.head = (index, client.clone());
+                            routes.tail = (index, client);
+                        }
+                        (Target::Head, ReplicaMode::Chain) => routes.head = (index, client),
+                        (Target::Tail, _) => routes.tail = (index, client),
+                    }
+                    return true;
+                }
+                Err(e) => log::warn!("Couldn't fail over to {}: {}", addrs[index], e),
+            }
+        }
+        false
+    }
+
+    /// Promotes the replica at `index`, passing it the replicas after it up
+    /// to `last`.
+    async fn promote(
+        &self,
+        index: usize,
+        last: usize,
+    ) -> Result<GrpcMemoryClient<Authed>, Box<dyn std::error::Error>> {
+        let addrs = &self.replicas.addrs;
+        let last = match self.replicas.mode {
+            ReplicaMode::PrimaryBackup => addrs.len() - 1,
+            ReplicaMode::Chain => last,
+        };
+        let (mut failover, client) = self.open_to_fail_over(&addrs[index]).await?;
+        let request = PromoteRequest {
+            backups: addrs[index + 1..=last].to_vec(),
+        };
+        failover.promote(request).await?;
+        Ok(client)
+    }
+
+    async fn make_tail(
+        &self,
+        index: usize,
+    ) -> Result<GrpcMemoryClient<Authed>, Box<dyn std::error::Error>> {
+        let (mut failover, client) = self.open_to_fail_over(&self.replicas.addrs[index]).await?;
+        failover.make_tail(MakeTailRequest {}).await?;
+        Ok(client)
+    }
+
+    /// Allocates a region, also returning the data node's generation.
+    pub async fn allocate_fenced(&mut self, size: u64) -> Result<(u64, u32), AllocationError> {
+        let request = AllocateRequest {
+            size,
+            ..AllocateRequest::default()
+        };
+        self.allocate(request).await
+    }
+
+    /// Allocates a region as a shard of `stripe`, a token every shard of the
+    /// same coded region shares, also returning the data node's generation.
+    pub async fn allocate_shard(
+        &mut self,
+        size: u64,
+        stripe: u64,
+    ) -> Result<(u64, u32), AllocationError> {
+        let request = AllocateRequest {
+            size,
+            stripe,
+            ..AllocateRequest::default()
+        };
+        self.allocate(request).await
+    }
+
+    /// Allocates a region the data node may evict, also returning its
+    /// generation.
+    pub async fn allocate_evictable(
+        &mut self,
+        size: u64,
+        priority: u32,
+    ) -> Result<(u64, u32), AllocationError> {
+        let request = AllocateRequest {
+            size,
+            evictable: true,
+            priority,
+            ..AllocateRequest::default()
+        };
+        self.allocate(request).await
+    }
+
+    async fn allocate(&mut self, request: AllocateRequest) -> Result<(u64, u32), AllocationError> {
         let response: Response<AllocateResponse> = self
-            .client
-            .allocate_memory(request)
+            .call(Target::Head, |mut client| {
+                let request = request.clone();
+                async move { client.allocate_memory(request).await }
+            })
             .await
             .map_err(|e: Status| match e.code() {
                 tonic::Code::InvalidArgument => AllocationError::AllocationTooLarge,
+                tonic::Code::ResourceExhausted => AllocationError::InsufficientMemory,
+                tonic::Code::PermissionDenied | tonic::Code::Unauthenticated => {
+                    AllocationError::AllocationPermissionDenied
+                }
                 _ => AllocationError::Unspecified,
             })?;
-        match response.into_inner().result {
-            Some(crate::proto::memory::allocate_response::Result::Size(size)) => Ok(size),
+        let response = response.into_inner();
+        match response.result {
+            Some(crate::proto::memory::allocate_response::Result::Size(id)) => {
+                self.add_key(id, response.key);
+                Ok((id, response.generation))
+            }
             Some(crate::proto::memory::allocate_response::Result::Error(error)) => {
                 // convert i32 to AllocationError
                 match AllocationError::from_i32(error) {
@@ -41,18 +496,31 @@ impl MemoryClient {
     }
 
     pub async fn free(&mut self, id: u64) -> Result<(), DeallocationError> {
-        let request = FreeRequest { id };
-        let response: Response<FreeResponse> =
-            self.client
-                .free_memory(request)
-                .await
-                .map_err(|e: Status| match e.code() {
-                    tonic::Code::OutOfRange => DeallocationError::DeallocationInvalidMemoryAddress,
-                    _ => DeallocationError::Unspecified,
-                })?;
+        let request = FreeRequest {
+            id,
+            key: self.key(id).unwrap_or_default(),
+        };
+        let response: Response<FreeResponse> = self
+            .call(Target::Head, |mut client| {
+                let request = request.clone();
+                async move { client.free_memory(request).await }
+            })
+            .await
+            .map_err(|e: Status| match e.code() {
+                _ if self.note_moved(id, &e) => DeallocationError::DeallocationRegionMoved,
+                tonic::Code::OutOfRange => DeallocationError::DeallocationInvalidMemoryAddress,
+                tonic::Code::DataLoss => DeallocationError::DeallocationRegionEvicted,
+                tonic::Code::PermissionDenied | tonic::Code::Unauthenticated => {
+                    DeallocationError::DeallocationPermissionDenied
+                }
+                _ => DeallocationError::Unspecified,
+            })?;
 
         match response.into_inner().result {
-            Some(crate::proto::memory::free_response::Result::Ok(true)) => Ok(()),
+            Some(crate::proto::memory::free_response::Result::Ok(true)) => {
+                self.replicas.keys.lock().unwrap().remove(&id);
+                Ok(())
+            }
             Some(crate::proto::memory::free_response::Result::Error(error)) => {
                 // convert i32 to deallocation error
                 match DeallocationError::from_i32(error) {
@@ -64,22 +532,39 @@ impl MemoryClient {
         }
     }
 
-    pub async fn read(
+    /// Reads from the region, failing with `StaleGeneration` if the data node
+    /// has restarted since `generation`. Generation 0 skips the check.
+    pub async fn read_fenced(
         &mut self,
         id: u64,
+        generation: u32,
         offset: u64,
         length: u64,
     ) -> Result<Vec<u8>, MemoryAccessError> {
-        let request = ReadRequest { id, offset, length };
-        let response: Response<ReadResponse> =
-            self.client
-                .read_memory(request)
-                .await
-                .map_err(|e: Status| match e.code() {
-                    tonic::Code::NotFound => MemoryAccessError::AccessInvalidMemoryAddress,
-                    tonic::Code::OutOfRange => MemoryAccessError::OutOfBoundsAccess,
-                    _ => MemoryAccessError::Unspecified,
-                })?;
+        let request = ReadRequest {
+            id,
+            offset,
+            length,
+            generation,
+            key: self.key(id).unwrap_or_default(),
+        };
+        let response: Response<ReadResponse> = self
+            .call(Target::Tail, |mut client| {
+                let request = request.clone();
+                async move { client.read_memory(request).await }
+            })
+            .await
+            .map_err(|e: Status| match e.code() {
+                _ if self.note_moved(id, &e) => MemoryAccessError::RegionMoved,
+                tonic::Code::NotFound => MemoryAccessError::AccessInvalidMemoryAddress,
+                tonic::Code::OutOfRange => MemoryAccessError::OutOfBoundsAccess,
+                tonic::Code::FailedPrecondition => MemoryAccessError::StaleGeneration,
+                tonic::Code::DataLoss => MemoryAccessError::RegionEvicted,
+                tonic::Code::PermissionDenied | tonic::Code::Unauthenticated => {
+                    MemoryAccessError::AccessPermissionDenied
+                }
+                _ => MemoryAccessError::Unspecified,
+            })?;
         match response.into_inner().result {
             Some(crate::proto::memory::read_response::Result::Memory(mem)) => Ok(mem),
             Some(crate::proto::memory::read_response::Result::Error(error)) => {
@@ -93,22 +578,38 @@ impl MemoryClient {
         }
     }
 
-    pub async fn write(
+    /// Like `read_fenced`, for writes.
+    pub async fn write_fenced(
         &mut self,
         id: u64,
+        generation: u32,
         offset: u64,
         data: Vec<u8>,
     ) -> Result<(), MemoryAccessError> {
-        let request = WriteRequest { id, offset, data };
-        let response: Response<WriteResponse> =
-            self.client
-                .write_memory(request)
-                .await
-                .map_err(|e: Status| match e.code() {
-                    tonic::Code::NotFound => MemoryAccessError::AccessInvalidMemoryAddress,
-                    tonic::Code::OutOfRange => MemoryAccessError::OutOfBoundsAccess,
-                    _ => MemoryAccessError::Unspecified,
-                })?;
+        let request = WriteRequest {
+            id,
+            offset,
+            data,
+            generation,
+            key: self.key(id).unwrap_or_default(),
+        };
+        let response: Response<WriteResponse> = self
+            .call(Target::Head, |mut client| {
+                let request = request.clone();
+                async move { client.write_memory(request).await }
+            })
+            .await
+            .map_err(|e: Status| match e.code() {
+                _ if self.note_moved(id, &e) => MemoryAccessError::RegionMoved,
+                tonic::Code::NotFound => MemoryAccessError::AccessInvalidMemoryAddress,
+                tonic::Code::OutOfRange => MemoryAccessError::OutOfBoundsAccess,
+                tonic::Code::FailedPrecondition => MemoryAccessError::StaleGeneration,
+                tonic::Code::DataLoss => MemoryAccessError::RegionEvicted,
+                tonic::Code::PermissionDenied | tonic::Code::Unauthenticated => {
+                    MemoryAccessError::AccessPermissionDenied
+                }
+                _ => MemoryAccessError::Unspecified,
+            })?;
         match response.into_inner().result {
             Some(crate::proto::memory::write_response::Result::Ok(true)) => Ok(()),
             Some(crate::proto::memory::write_response::Result::Error(error)) => {
@@ -122,14 +623,69 @@ impl MemoryClient {
         }
     }
 
+    /// Atomically XORs `data` into the region at `offset`. Fenced like
+    /// `write_fenced`.
+    pub async fn xor_fenced(
+        &mut self,
+        id: u64,
+        generation: u32,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<(), MemoryAccessError> {
+        let request = XorRequest {
+            id,
+            offset,
+            data,
+            generation,
+            key: self.key(id).unwrap_or_default(),
+        };
+        let response: Response<XorResponse> = self
+            .call(Target::Head, |mut client| {
+                let request = request.clone();
+                async move { client.xor_memory(request).await }
+            })
+            .await
+            .map_err(|e: Status| match e.code() {
+                _ if self.note_moved(id, &e) => MemoryAccessError::RegionMoved,
+                tonic::Code::NotFound => MemoryAccessError::AccessInvalidMemoryAddress,
+                tonic::Code::OutOfRange => MemoryAccessError::OutOfBoundsAccess,
+                tonic::Code::FailedPrecondition => MemoryAccessError::StaleGeneration,
+                tonic::Code::DataLoss => MemoryAccessError::RegionEvicted,
+                tonic::Code::PermissionDenied | tonic::Code::Unauthenticated => {
+                    MemoryAccessError::AccessPermissionDenied
+                }
+                _ => MemoryAccessError::Unspecified,
+            })?;
+        match response.into_inner().result {
+            Some(crate::proto::memory::xor_response::Result::Ok(true)) => Ok(()),
+            Some(crate::proto::memory::xor_response::Result::Error(error)) => {
+                match MemoryAccessError::from_i32(error) {
+                    Some(xor_error) => Err(xor_error),
+                    None => Err(MemoryAccessError::Unspecified),
+                }
+            }
+            _ => Err(MemoryAccessError::Unspecified),
+        }
+    }
+
     pub async fn get_memory_size(&mut self, id: u64) -> Result<u64, MemoryAccessError> {
-        let request = GetMemorySizeRequest { id };
+        let request = GetMemorySizeRequest {
+            id,
+            key: self.key(id).unwrap_or_default(),
+        };
         let response: Response<GetMemorySizeResponse> = self
-            .client
-            .get_memory_size(request)
+            .call(Target::Tail, |mut client| {
+                let request = request.clone();
+                async move { client.get_memory_size(request).await }
+            })
             .await
             .map_err(|e: Status| match e.code() {
+                _ if self.note_moved(id, &e) => MemoryAccessError::RegionMoved,
                 tonic::Code::NotFound => MemoryAccessError::AccessInvalidMemoryAddress,
+                tonic::Code::DataLoss => MemoryAccessError::RegionEvicted,
+                tonic::Code::PermissionDenied | tonic::Code::Unauthenticated => {
+                    MemoryAccessError::AccessPermissionDenied
+                }
                 _ => MemoryAccessError::Unspecified,
             })?;
 
@@ -144,4 +700,391 @@ impl MemoryClient {
             _ => Err(MemoryAccessError::Unspecified),
         }
     }
+
+    /// Atomically replaces the u64 at `offset` with `desired` if it equals
+    /// `expected`. Like `AtomicU64::compare_exchange`, returns `Ok(previous)`
+    /// on success and `Err(actual)` otherwise. Fenced like `write_fenced`.
+    pub async fn compare_and_swap_fenced(
+        &mut self,
+        id: u64,
+        generation: u32,
+        offset: u64,
+        expected: u64,
+        desired: u64,
+    ) -> Result<Result<u64, u64>, MemoryAccessError> {
+        let request = CompareAndSwapRequest {
+            id,
+            offset,
+            expected,
+            desired,
+            key: self.key(id).unwrap_or_default(),
+            generation,
+            tail: Vec::new(),
+            request_id: 0,
+        };
+        self.swap(request).await
+    }
+
+    /// Like `compare_and_swap_fenced`, but also writes `tail` right after
+    /// the word when the swap happens, in the same step.
+    pub async fn compare_and_swap_with_tail(
+        &mut self,
+        id: u64,
+        offset: u64,
+        expected: u64,
+        desired: u64,
+        tail: Vec<u8>,
+    ) -> Result<Result<u64, u64>, MemoryAccessError> {
+        let request = CompareAndSwapRequest {
+            id,
+            offset,
+            expected,
+            desired,
+            key: self.key(id).unwrap_or_default(),
+            generation: 0,
+            tail,
+            request_id: 0,
+        };
+        self.swap(request).await
+    }
+
+    /// Sends a swap under a fresh request id, which its retries keep, so
+    /// that one that went through before a failover isn't done again.
+    async fn swap(
+        &mut self,
+        mut request: CompareAndSwapRequest,
+    ) -> Result<Result<u64, u64>, MemoryAccessError> {
+        request.request_id = rand::random::<u64>().max(1);
+        let (id, expected) = (request.id, request.expected);
+        let response: Response<CompareAndSwapResponse> = self
+            .call(Target::Head, |mut client| {
+                let request = request.clone();
+                async move { client.compare_and_swap(request).await }
+            })
+            .await
+            .map_err(|e: Status| match e.code() {
+                _ if self.note_moved(id, &e) => MemoryAccessError::RegionMoved,
+                tonic::Code::NotFound => MemoryAccessError::AccessInvalidMemoryAddress,
+                tonic::Code::OutOfRange => MemoryAccessError::OutOfBoundsAccess,
+                tonic::Code::FailedPrecondition => MemoryAccessError::StaleGeneration,
+                tonic::Code::DataLoss => MemoryAccessError::RegionEvicted,
+                tonic::Code::PermissionDenied | tonic::Code::Unauthenticated => {
+                    MemoryAccessError::AccessPermissionDenied
+                }
+                _ => MemoryAccessError::Unspecified,
+            })?;
+
+        match response.into_inner().result {
+            Some(crate::proto::memory::compare_and_swap_response::Result::Previous(previous)) => {
+                if previous == expected {
+                    Ok(Ok(previous))
+                } else {
+                    Ok(Err(previous))
+                }
+            }
+            Some(crate::proto::memory::compare_and_swap_response::Result::Error(error)) => {
+                match MemoryAccessError::from_i32(error) {
+                    Some(cas_error) => Err(cas_error),
+                    None => Err(MemoryAccessError::Unspecified),
+                }
+            }
+            None => Err(MemoryAccessError::Unspecified),
+        }
+    }
+
+    /// Moves region `id` to the data node at `target`, which has to be a
+    /// primary or chain head, while it stays in use. Returns the region's id,
+    /// generation and read-write key there. The old id keeps working for
+    /// clients that follow forwarding addresses, like `ClusterClient`.
+    pub async fn migrate(
+        &mut self,
+        id: u64,
+        target: String,
+    ) -> Result<(u64, u32, u64), MemoryAccessError> {
+        let request = MigrateRequest { id, target };
+        let response = self
+            .call(Target::Head, |mut client| {
+                let request = request.clone();
+                async move { client.migrate_region(request).await }
+            })
+            .await
+            .map_err(|e: Status| match e.code() {
+                _ if self.note_moved(id, &e) => MemoryAccessError::RegionMoved,
+                tonic::Code::NotFound => MemoryAccessError::AccessInvalidMemoryAddress,
+                tonic::Code::PermissionDenied | tonic::Code::Unauthenticated => {
+                    MemoryAccessError::AccessPermissionDenied
+                }
+                _ => MemoryAccessError::Unspecified,
+            })?
+            .into_inner();
+        Ok((response.id, response.generation, response.key))
+    }
+
+    /// Changes who other than its owner may use region `id`, which only its
+    /// owner or an admin may do.
+    pub async fn share(
+        &mut self,
+        id: u64,
+        principal: &str,
+        sharing: Sharing,
+    ) -> Result<(), MemoryAccessError> {
+        let principal = principal.to_string();
+        let result = match sharing {
+            Sharing::Grant(mode) => {
+                let request = GrantRequest {
+                    id,
+                    principal,
+                    mode: mode as i32,
+                };
+                self.call(Target::Head, |mut client| {
+                    let request = request.clone();
+                    async move { client.grant_access(request).await }
+                })
+                .await
+                .map(|_| ())
+            }
+            Sharing::Revoke => {
+                let request = RevokeRequest { id, principal };
+                self.call(Target::Head, |mut client| {
+                    let request = request.clone();
+                    async move { client.revoke_access(request).await }
+                })
+                .await
+                .map(|_| ())
+            }
+            Sharing::Transfer => {
+                let request = TransferRequest { id, principal };
+                self.call(Target::Head, |mut client| {
+                    let request = request.clone();
+                    async move { client.transfer_ownership(request).await }
+                })
+                .await
+                .map(|_| ())
+            }
+        };
+        result.map_err(|e: Status| match e.code() {
+            _ if self.note_moved(id, &e) => MemoryAccessError::RegionMoved,
+            tonic::Code::NotFound => MemoryAccessError::AccessInvalidMemoryAddress,
+            tonic::Code::DataLoss => MemoryAccessError::RegionEvicted,
+            tonic::Code::PermissionDenied | tonic::Code::Unauthenticated => {
+                MemoryAccessError::AccessPermissionDenied
+            }
+            _ => MemoryAccessError::Unspecified,
+        })
+    }
+
+    pub async fn usage(&mut self) -> Result<Usage, MemoryAccessError> {
+        let response = self
+            .call(Target::Tail, |mut client| async move {
+                client.get_usage(UsageRequest {}).await
+            })
+            .await
+            .map_err(|e: Status| match e.code() {
+                tonic::Code::PermissionDenied | tonic::Code::Unauthenticated => {
+                    MemoryAccessError::AccessPermissionDenied
+                }
+                _ => MemoryAccessError::Unspecified,
+            })?
+            .into_inner();
+        let stripes = response
+            .regions
+            .iter()
+            .filter(|region| region.stripe != 0)
+            .map(|region| (region.id, region.stripe))
+            .collect();
+        Ok(Usage {
+            regions: response
+                .regions
+                .into_iter()
+                .map(|region| (region.id, region.size))
+                .collect(),
+            stripes,
+            draining: response.draining,
+            tenants: response.tenants,
+            tiers: response.tiers,
+        })
+    }
+
+    /// Streams the node's pressure, the current one first.
+    pub async fn watch_pressure(&self) -> Result<Streaming<PressureUpdate>, Status> {
+        let response = self
+            .call(Target::Head, |mut client| async move {
+                client.watch_pressure(PressureRequest {}).await
+            })
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    /// Keeps `pressure` up to date with what the node reports, watching
+    /// again whenever the stream breaks, until the returned task is
+    /// aborted.
+    pub fn follow_pressure(&self) -> JoinHandle<()> {
+        let client = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match client.watch_pressure().await {
+                    Ok(mut updates) => {
+                        while let Ok(Some(update)) = updates.message().await {
+                            let pressure = &client.replicas.pressure;
+                            if pressure.swap(update.pressure, Ordering::Relaxed) != update.pressure
+                            {
+                                log::info!(
+                                    "Node {} is under {:?} pressure ({} of {} bytes)",
+                                    client.replicas.addrs[0],
+                                    update.pressure(),
+                                    update.used_bytes,
+                                    update.capacity_bytes
+                                );
+                            }
+                        }
+                    }
+                    Err(e) => log::warn!("Lost pressure updates: {}", e.message()),
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        })
+    }
+
+    pub fn pressure(&self) -> Pressure {
+        Pressure::from_i32(self.replicas.pressure.load(Ordering::Relaxed)).unwrap_or(Pressure::None)
+    }
+
+    /// Starts emptying the node onto `targets`, returning its progress. The
+    /// node refuses new allocations from now on.
+    pub async fn drain(
+        &mut self,
+        targets: Vec<String>,
+    ) -> Result<Streaming<DrainProgress>, Status> {
+        let request = DrainRequest { targets };
+        let response = self
+            .call(Target::Head, |mut client| {
+                let request = request.clone();
+                async move { client.drain(request).await }
+            })
+            .await?;
+        Ok(response.into_inner())
+    }
+}
+
+/// A change to who may use a region.
+#[derive(Clone, Copy, Debug)]
+pub enum Sharing {
+    /// Lets the principal read, or read and write, the region.
+    Grant(AccessMode),
+    /// Takes back whatever the principal was granted.
+    Revoke,
+    /// Makes the principal the region's owner. The old owner keeps no
+    /// access unless granted some.
+    Transfer,
+}
+
+/// What a data node holds.
+pub struct Usage {
+    /// The id and size of each region.
+    pub regions: Vec<(u64, u64)>,
+    /// The stripe of each region that is a shard of a coded one.
+    pub stripes: HashMap<u64, u64>,
+    /// Whether the node is being emptied, and takes no new regions.
+    pub draining: bool,
+    /// What each tenant holds and may hold, the default tenant first.
+    pub tenants: Vec<TenantUsage>,
+    /// What each tier holds and served, RAM first, if the node spills to
+    /// disk.
+    pub tiers: Vec<TierUsage>,
+}
+
+/// Whether the request failed because the primary is down, dropped the
+/// connection while it was in flight, or has been replaced.
+fn primary_lost(status: &Status) -> bool {
+    status.code() == Code::Unavailable
+        || std::error::Error::source(status).is_some_and(|e| e.is::<tonic::transport::Error>())
+}
+
+/// How long to wait before retrying a request the node throttled, or
+/// `None` if it wasn't.
+fn retry_after(status: &Status) -> Option<Duration> {
+    if status.code() != Code::ResourceExhausted {
+        return None;
+    }
+    let millis = status.metadata().get("retry-after-ms")?.to_str().ok()?;
+    millis.parse().ok().map(Duration::from_millis)
+}
+
+#[tonic::async_trait]
+impl RemoteMemory for MemoryClient {
+    async fn allocate_fenced(&mut self, size: u64) -> Result<(u64, u32), AllocationError> {
+        MemoryClient::allocate_fenced(self, size).await
+    }
+
+    async fn allocate_evictable(
+        &mut self,
+        size: u64,
+        priority: u32,
+    ) -> Result<u64, AllocationError> {
+        Ok(MemoryClient::allocate_evictable(self, size, priority)
+            .await?
+            .0)
+    }
+
+    async fn free(&mut self, id: u64) -> Result<(), DeallocationError> {
+        MemoryClient::free(self, id).await
+    }
+
+    async fn read_fenced(
+        &mut self,
+        id: u64,
+        generation: u32,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, MemoryAccessError> {
+        MemoryClient::read_fenced(self, id, generation, offset, length).await
+    }
+
+    async fn write_fenced(
+        &mut self,
+        id: u64,
+        generation: u32,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<(), MemoryAccessError> {
+        MemoryClient::write_fenced(self, id, generation, offset, data).await
+    }
+
+    async fn get_memory_size(&mut self, id: u64) -> Result<u64, MemoryAccessError> {
+        MemoryClient::get_memory_size(self, id).await
+    }
+
+    async fn compare_and_swap_fenced(
+        &mut self,
+        id: u64,
+        generation: u32,
+        offset: u64,
+        expected: u64,
+        desired: u64,
+    ) -> Result<Result<u64, u64>, MemoryAccessError> {
+        MemoryClient::compare_and_swap_fenced(self, id, generation, offset, expected, desired).await
+    }
+
+    async fn compare_and_swap_with_tail(
+        &mut self,
+        id: u64,
+        offset: u64,
+        expected: u64,
+        desired: u64,
+        tail: Vec<u8>,
+    ) -> Result<Result<u64, u64>, MemoryAccessError> {
+        MemoryClient::compare_and_swap_with_tail(self, id, offset, expected, desired, tail).await
+    }
+
+    fn region_key(&self, id: u64) -> u64 {
+        self.key(id).unwrap_or_default()
+    }
+
+    fn learn_key(&self, id: u64, key: u64) {
+        MemoryClient::learn_key(self, id, key)
+    }
+
+    fn pressure(&self) -> Pressure {
+        MemoryClient::pressure(self)
+    }
 }
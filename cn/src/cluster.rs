@@ -0,0 +1,761 @@
+use crate::client::{MemoryClient, RemoteMemory, ReplicaMode, Sharing, Usage};
+use crate::credentials::Credentials;
+use crate::proto::memory::membership_client::MembershipClient;
+use crate::proto::memory::{
+    AllocationError, DeallocationError, DrainProgress, MemoryAccessError, Pressure, WatchRequest,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+use tonic::transport::Endpoint;
+use tonic::{Status, Streaming};
+
+const VNODES_PER_WEIGHT: u32 = 64; // ring points per unit of node weight
+const REGION_BITS: u32 = 48; // handles are [coded u1][node u15][region id u48]
+const REGION_MASK: u64 = (1 << REGION_BITS) - 1;
+const CODED_BIT: u64 = 1 << 63; // the region is the descriptor of a coded one
+const MAX_NODES: usize = 1 << (63 - REGION_BITS);
+const MAX_FORWARDS: usize = 64; // followed for one region, more means a loop
+
+/// A data node in a cluster. `weight` is its share of new allocations
+/// relative to the other nodes, typically proportional to its capacity. A
+/// node with weight 0 still serves its existing regions but gets no new
+/// ones. `backups` are the node's replicas, in the order they take over
+/// from it, or the rest of its chain.
+#[derive(Clone, Debug)]
+pub struct NodeConfig {
+    pub addr: String,
+    pub weight: u32,
+    pub backups: Vec<String>,
+    pub replication: ReplicaMode,
+    pub credentials: Credentials,
+}
+
+impl NodeConfig {
+    pub async fn connect(&self) -> Result<MemoryClient, Box<dyn std::error::Error>> {
+        match self.replication {
+            ReplicaMode::PrimaryBackup => {
+                let backups = self.backups.clone();
+                MemoryClient::with_backups(self.addr.clone(), backups, self.credentials.clone())
+                    .await
+            }
+            ReplicaMode::Chain => {
+                MemoryClient::chain(
+                    self.addr.clone(),
+                    self.backups.clone(),
+                    self.credentials.clone(),
+                )
+                .await
+            }
+        }
+    }
+}
+
+/// Client for a pool of data nodes. Allocations are placed on a
+/// consistent-hash ring where each node owns a number of virtual nodes
+/// proportional to its weight, so adding or removing a node only shifts
+/// its own share of placements. Ids handed out are global handles encoding
+/// (node, region id), with nodes numbered by their position in the list
+/// passed to `connect`, so every client of a cluster must list the nodes in
+/// the same order. Regions can also be erasure-coded across several nodes
+/// with `allocate_coded`.
+///
+/// With `follow_membership`, placement tries nodes the coordinator reports
+/// as down only after all the others.
+#[derive(Clone)]
+pub struct ClusterClient {
+    nodes: Vec<MemoryClient>,
+    configs: Arc<Vec<NodeConfig>>,
+    alive: Arc<Vec<AtomicBool>>, // as last reported by the coordinator
+    ring: Arc<Vec<(u64, u16)>>,  // (point, node), sorted by point
+    seed: u64,
+    next_token: u64,
+}
+
+impl ClusterClient {
+    pub async fn connect(nodes: Vec<NodeConfig>) -> Result<Self, Box<dyn std::error::Error>> {
+        if nodes.is_empty() || nodes.len() > MAX_NODES {
+            return Err(format!("A cluster needs between 1 and {} nodes", MAX_NODES).into());
+        }
+        if nodes.iter().all(|node| node.weight == 0) {
+            return Err("At least one node needs a non-zero weight".into());
+        }
+
+        let mut clients = Vec::with_capacity(nodes.len());
+        let mut ring = Vec::new();
+        for (i, node) in nodes.iter().enumerate() {
+            clients.push(node.connect().await?);
+            // points depend only on the node's address, so membership changes
+            // leave the other nodes' arcs where they were
+            for vnode in 0..node.weight * VNODES_PER_WEIGHT {
+                let point = hash(format!("{}#{}", node.addr, vnode).as_bytes());
+                ring.push((point, i as u16));
+            }
+        }
+        ring.sort_unstable();
+
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        Ok(Self {
+            nodes: clients,
+            alive: Arc::new(nodes.iter().map(|_| AtomicBool::new(true)).collect()),
+            configs: Arc::new(nodes),
+            ring: Arc::new(ring),
+            seed: mix(nanos ^ std::process::id() as u64),
+            next_token: 0,
+        })
+    }
+
+    /// Combines a node and a region id on it into a handle, if the id fits.
+    pub fn handle(node: u16, region: u64) -> Option<u64> {
+        (region <= REGION_MASK).then_some(((node as u64) << REGION_BITS) | region)
+    }
+
+    /// Splits a handle into its node and the region id on that node.
+    pub fn locate(handle: u64) -> (u16, u64) {
+        ((handle >> REGION_BITS) as u16, handle & REGION_MASK)
+    }
+
+    /// The handle of an erasure-coded region with the given descriptor.
+    pub(crate) fn coded(descriptor: u64) -> u64 {
+        descriptor | CODED_BIT
+    }
+
+    /// The descriptor of a coded region's handle, or `None` for a plain one.
+    fn coded_descriptor(handle: u64) -> Option<u64> {
+        (handle & CODED_BIT != 0).then_some(handle & !CODED_BIT)
+    }
+
+    /// Subscribes to membership changes from the coordinator at `url`,
+    /// resubscribing whenever the stream breaks, until the returned task is
+    /// aborted. Nodes are matched to members by URL, and a replicated node
+    /// counts as up while any of its replicas is. Nodes the coordinator
+    /// hasn't heard of are left as they were.
+    pub fn follow_membership(
+        &self,
+        url: String,
+        credentials: &Credentials,
+    ) -> Result<JoinHandle<()>, Box<dyn std::error::Error>> {
+        let endpoint = credentials.endpoint(url)?;
+        let credentials = credentials.clone();
+        let configs = self.configs.clone();
+        let alive = self.alive.clone();
+        Ok(tokio::spawn(async move {
+            loop {
+                if let Err(e) = watch_membership(&endpoint, &credentials, &configs, &alive).await {
+                    log::warn!("Lost membership updates: {}", e);
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }))
+    }
+
+    /// Follows the pressure of every node, for as long as the returned tasks
+    /// run. Placement then tries nodes under critical pressure after the
+    /// others, and the cluster's pressure is that of the least pressed node
+    /// that takes new regions.
+    pub fn follow_pressure(&self) -> Vec<JoinHandle<()>> {
+        self.nodes
+            .iter()
+            .map(|node| node.follow_pressure())
+            .collect()
+    }
+
+    /// Nodes in the order placement tries them for `token`: the owner of
+    /// the first ring point at or after it, then each further distinct node
+    /// clockwise, with nodes under critical pressure and then nodes marked
+    /// down moved to the end.
+    fn candidates(&self, token: u64) -> Vec<u16> {
+        let start = self.ring.partition_point(|&(point, _)| point < token);
+        let mut order = Vec::new();
+        for i in 0..self.ring.len() {
+            let node = self.ring[(start + i) % self.ring.len()].1;
+            if !order.contains(&node) {
+                order.push(node);
+                if order.len() == self.nodes.len() {
+                    break;
+                }
+            }
+        }
+        // still tried last, in case the membership or pressure is behind
+        order.sort_by_key(|&node| {
+            let critical = self.nodes[node as usize].pressure() == Pressure::Critical;
+            (!self.alive[node as usize].load(Ordering::Relaxed), critical)
+        });
+        order
+    }
+
+    /// Allocates `count` regions of `size` bytes as the shards of a new
+    /// stripe, each on a different node, returning their handles and
+    /// generations. Fails without leaving any allocated if there aren't
+    /// enough nodes that can take one.
+    pub(crate) async fn allocate_spread(
+        &mut self,
+        size: u64,
+        count: usize,
+    ) -> Result<Vec<(u64, u32)>, AllocationError> {
+        self.next_token += 1;
+        let token = mix(self.seed ^ self.next_token);
+
+        let stripe = rand::random::<u64>().max(1);
+        let mut placed = Vec::with_capacity(count);
+        let mut error = AllocationError::InsufficientMemory;
+        for node in self.candidates(token) {
+            if placed.len() == count {
+                break;
+            }
+            match self.nodes[node as usize].allocate_shard(size, stripe).await {
+                Ok((id, generation)) => match Self::handle(node, id) {
+                    Some(handle) => placed.push((handle, generation)),
+                    None => {
+                        let _ = self.nodes[node as usize].free(id).await;
+                    }
+                },
+                Err(AllocationError::AllocationTooLarge) => {
+                    error = AllocationError::AllocationTooLarge;
+                    break;
+                }
+                Err(_) => {}
+            }
+        }
+        if placed.len() == count {
+            return Ok(placed);
+        }
+        for &(handle, _) in &placed {
+            let _ = self.free(handle).await;
+        }
+        Err(error)
+    }
+
+    /// Places a region, evictable with `priority` if given, at a
+    /// pseudo-random point on the ring, falling back to the next node if the
+    /// chosen one is full or unreachable.
+    async fn place(
+        &mut self,
+        size: u64,
+        priority: Option<u32>,
+    ) -> Result<(u64, u32), AllocationError> {
+        self.next_token += 1;
+        let token = mix(self.seed ^ self.next_token);
+
+        let mut last_error = AllocationError::Unspecified;
+        for node in self.candidates(token) {
+            let client = &mut self.nodes[node as usize];
+            let placed = match priority {
+                Some(priority) => client.allocate_evictable(size, priority).await,
+                None => client.allocate_fenced(size).await,
+            };
+            match placed {
+                Ok((id, generation)) => match Self::handle(node, id) {
+                    Some(handle) => return Ok((handle, generation)),
+                    None => {
+                        // no room in the handle for this id
+                        let _ = self.nodes[node as usize].free(id).await;
+                    }
+                },
+                Err(AllocationError::AllocationTooLarge) => {
+                    return Err(AllocationError::AllocationTooLarge)
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Atomically XORs `data` into a plain region.
+    pub(crate) async fn xor_fenced(
+        &mut self,
+        id: u64,
+        generation: u32,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<(), MemoryAccessError> {
+        loop {
+            let (node, region, generation) = self.resolve(id, generation)?;
+            let client = &mut self.nodes[node as usize];
+            match client
+                .xor_fenced(region, generation, offset, data.clone())
+                .await
+            {
+                Err(MemoryAccessError::RegionMoved) => {} // now knows where to
+                result => return result,
+            }
+        }
+    }
+
+    /// Where the region with handle `id` is now, as its node, region id and
+    /// generation there, following the forwarding addresses of regions that
+    /// have migrated, up to `MAX_FORWARDS` of them. A generation of 0 stays
+    /// 0, unchecked.
+    fn resolve(&self, id: u64, generation: u32) -> Result<(u16, u64, u32), MemoryAccessError> {
+        let (mut node, mut region) = Self::locate(id);
+        let mut generation = generation;
+        for _ in 0..=MAX_FORWARDS {
+            let client = self
+                .nodes
+                .get(node as usize)
+                .ok_or(MemoryAccessError::AccessInvalidMemoryAddress)?;
+            let Some(to) = client.forwarded(region) else {
+                return Ok((node, region, generation));
+            };
+            node = self
+                .node_at(&to.addr)
+                .ok_or(MemoryAccessError::AccessInvalidMemoryAddress)?;
+            region = to.id;
+            if generation != 0 {
+                generation = to.generation;
+            }
+            if to.key != 0 {
+                self.nodes[node as usize].add_key(region, to.key);
+            }
+        }
+        Err(MemoryAccessError::AccessInvalidMemoryAddress)
+    }
+
+    /// The key presented for the plain region with handle `id`, which is
+    /// its read-write key if this client allocated it.
+    pub fn key(&self, id: u64) -> Result<u64, MemoryAccessError> {
+        let (node, region, _) = self.resolve(id, 0)?;
+        self.nodes[node as usize]
+            .key(region)
+            .ok_or(MemoryAccessError::AccessPermissionDenied)
+    }
+
+    /// Presents `key` for the plain region with handle `id` from now on,
+    /// such as a read-only key derived with `capability::read_only` by the
+    /// client that allocated it.
+    pub fn add_key(&self, id: u64, key: u64) -> Result<(), MemoryAccessError> {
+        let (node, region, _) = self.resolve(id, 0)?;
+        self.nodes[node as usize].add_key(region, key);
+        Ok(())
+    }
+
+    /// Changes who other than its owner may use the region with handle
+    /// `id`. For coded regions that is every shard and the descriptor, the
+    /// descriptor last so the region stays readable to its owner if a shard
+    /// can't be changed.
+    pub async fn share(
+        &mut self,
+        id: u64,
+        principal: &str,
+        sharing: Sharing,
+    ) -> Result<(), MemoryAccessError> {
+        let regions = match Self::coded_descriptor(id) {
+            Some(descriptor) => self.coded_regions(descriptor).await?,
+            None => vec![id],
+        };
+        for handle in regions {
+            loop {
+                let (node, region, _) = self.resolve(handle, 0)?;
+                match self.nodes[node as usize]
+                    .share(region, principal, sharing)
+                    .await
+                {
+                    Err(MemoryAccessError::RegionMoved) => {}
+                    result => {
+                        result?;
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The node with a replica at `addr`.
+    fn node_at(&self, addr: &str) -> Option<u16> {
+        self.configs
+            .iter()
+            .position(|node| node.addr == addr || node.backups.iter().any(|b| b == addr))
+            .map(|i| i as u16)
+    }
+
+    /// What each node holds, in the order the nodes were given, or why it
+    /// couldn't say.
+    pub async fn usage(&mut self) -> Vec<Result<Usage, MemoryAccessError>> {
+        let mut usage = Vec::with_capacity(self.nodes.len());
+        for client in &mut self.nodes {
+            usage.push(client.usage().await);
+        }
+        usage
+    }
+
+    /// Moves the region with handle `id` to node `to` while it stays in use,
+    /// returning its new handle. The old handle keeps working, through the
+    /// forwarding address the region leaves behind.
+    pub async fn migrate(&mut self, id: u64, to: u16) -> Result<u64, MemoryAccessError> {
+        let target = self
+            .configs
+            .get(to as usize)
+            .ok_or(MemoryAccessError::AccessInvalidMemoryAddress)?
+            .addr
+            .clone();
+        loop {
+            let (node, region, _) = self.resolve(id, 0)?;
+            if node == to {
+                return Self::handle(node, region).ok_or(MemoryAccessError::Unspecified);
+            }
+            match self.nodes[node as usize]
+                .migrate(region, target.clone())
+                .await
+            {
+                Ok((moved, _, key)) => {
+                    self.nodes[to as usize].add_key(moved, key);
+                    return Self::handle(to, moved).ok_or(MemoryAccessError::Unspecified);
+                }
+                Err(MemoryAccessError::RegionMoved) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Empties node `node` onto the other nodes with a non-zero weight,
+    /// returning the progress it streams. The last message says the node
+    /// is done and safe to stop.
+    pub async fn drain(&mut self, node: u16) -> Result<Streaming<DrainProgress>, Status> {
+        let targets = self
+            .configs
+            .iter()
+            .enumerate()
+            .filter(|&(i, config)| i != node as usize && config.weight > 0)
+            .map(|(_, config)| config.addr.clone())
+            .collect();
+        match self.nodes.get_mut(node as usize) {
+            Some(client) => client.drain(targets).await,
+            None => Err(Status::new(tonic::Code::NotFound, "No such node")),
+        }
+    }
+
+    /// Migrates regions from nodes holding more than their weight's share
+    /// of the cluster's bytes to nodes holding less, for as long as a move
+    /// brings the nodes closer to their shares, returning how many regions
+    /// moved. Nodes that can't report their usage are left out, draining
+    /// nodes get no share, and a region that fails to move is skipped.
+    /// Shards of coded regions only go to nodes holding no other shard of
+    /// the same stripe, so a node going down still loses at most one.
+    pub async fn rebalance(&mut self) -> Result<usize, MemoryAccessError> {
+        let mut regions: Vec<Option<Vec<(u64, u64)>>> = Vec::with_capacity(self.nodes.len());
+        let mut stripes: Vec<HashMap<u64, u64>> = Vec::with_capacity(self.nodes.len());
+        let mut weights: Vec<i64> = Vec::with_capacity(self.nodes.len());
+        for (client, node) in self.nodes.iter_mut().zip(self.configs.iter()) {
+            match client.usage().await {
+                Ok(usage) => {
+                    weights.push(if usage.draining {
+                        0
+                    } else {
+                        node.weight as i64
+                    });
+                    regions.push(Some(usage.regions));
+                    stripes.push(usage.stripes);
+                }
+                // not for this client to do, rather than a node being down
+                Err(MemoryAccessError::AccessPermissionDenied) => {
+                    return Err(MemoryAccessError::AccessPermissionDenied)
+                }
+                Err(_) => {
+                    weights.push(0);
+                    regions.push(None);
+                    stripes.push(HashMap::new());
+                }
+            }
+        }
+        let used: Vec<i64> = regions
+            .iter()
+            .map(|r| {
+                r.as_ref()
+                    .map_or(0, |r| r.iter().map(|&(_, size)| size as i64).sum())
+            })
+            .collect();
+        let total: i64 = used.iter().sum();
+        let total_weight: i64 = weights.iter().sum();
+        if total_weight == 0 {
+            return Err(MemoryAccessError::Unspecified);
+        }
+        // bytes over each node's share, negative if under
+        let mut excess: Vec<i64> = used
+            .iter()
+            .zip(&weights)
+            .map(|(&used, &weight)| used - total * weight / total_weight)
+            .collect();
+        let reachable: Vec<usize> = (0..regions.len())
+            .filter(|&i| regions[i].is_some())
+            .collect();
+        // the stripes each node holds a shard of
+        let mut held: Vec<HashSet<u64>> = stripes
+            .iter()
+            .map(|stripes| stripes.values().copied().collect())
+            .collect();
+
+        let mut moved = 0;
+        loop {
+            let from = *reachable.iter().max_by_key(|&&i| excess[i]).unwrap();
+            let to = *reachable
+                .iter()
+                .filter(|&&i| weights[i] > 0)
+                .min_by_key(|&&i| excess[i])
+                .unwrap();
+            let (over, under) = (excess[from], -excess[to]);
+            // a move of `size` bytes brings the two closer to their shares
+            // only if it is less than the gap between them; prefer the
+            // largest that doesn't overshoot either, then the smallest
+            let candidates = regions[from].as_mut().unwrap();
+            let stripe = |region: u64| stripes[from].get(&region).copied();
+            let allowed = |region: u64| stripe(region).is_none_or(|s| !held[to].contains(&s));
+            let fits = |region: u64, size: i64| size > 0 && size < over + under && allowed(region);
+            let pick = candidates
+                .iter()
+                .enumerate()
+                .filter(|(_, &(region, size))| {
+                    fits(region, size as i64) && size as i64 <= over.min(under)
+                })
+                .max_by_key(|(_, &(_, size))| size)
+                .or_else(|| {
+                    candidates
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, &(region, size))| fits(region, size as i64))
+                        .min_by_key(|(_, &(_, size))| size)
+                })
+                .map(|(i, _)| i);
+            let Some(pick) = pick else {
+                return Ok(moved);
+            };
+            let (region, size) = candidates.swap_remove(pick);
+            let Some(handle) = Self::handle(from as u16, region) else {
+                continue;
+            };
+            match self.migrate(handle, to as u16).await {
+                Ok(_) => {
+                    moved += 1;
+                    excess[from] -= size as i64;
+                    excess[to] += size as i64;
+                    if let Some(stripe) = stripes[from].remove(&region) {
+                        held[from].remove(&stripe);
+                        held[to].insert(stripe);
+                    }
+                }
+                Err(e) => log::warn!("Couldn't move region {}: {:?}", handle, e),
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl RemoteMemory for ClusterClient {
+    async fn allocate_fenced(&mut self, size: u64) -> Result<(u64, u32), AllocationError> {
+        self.place(size, None).await
+    }
+
+    async fn allocate_evictable(
+        &mut self,
+        size: u64,
+        priority: u32,
+    ) -> Result<u64, AllocationError> {
+        Ok(self.place(size, Some(priority)).await?.0)
+    }
+
+    async fn free(&mut self, id: u64) -> Result<(), DeallocationError> {
+        if let Some(descriptor) = Self::coded_descriptor(id) {
+            return self.free_coded(descriptor).await;
+        }
+        loop {
+            let (node, region, _) = self
+                .resolve(id, 0)
+                .map_err(|_| DeallocationError::DeallocationInvalidMemoryAddress)?;
+            match self.nodes[node as usize].free(region).await {
+                Err(DeallocationError::DeallocationRegionMoved) => {}
+                result => return result,
+            }
+        }
+    }
+
+    async fn read_fenced(
+        &mut self,
+        id: u64,
+        generation: u32,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, MemoryAccessError> {
+        if let Some(descriptor) = Self::coded_descriptor(id) {
+            return self
+                .read_coded(descriptor, generation, offset, length)
+                .await;
+        }
+        loop {
+            let (node, region, generation) = self.resolve(id, generation)?;
+            let client = &mut self.nodes[node as usize];
+            match client.read_fenced(region, generation, offset, length).await {
+                Err(MemoryAccessError::RegionMoved) => {}
+                result => return result,
+            }
+        }
+    }
+
+    async fn write_fenced(
+        &mut self,
+        id: u64,
+        generation: u32,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<(), MemoryAccessError> {
+        if let Some(descriptor) = Self::coded_descriptor(id) {
+            return self.write_coded(descriptor, generation, offset, data).await;
+        }
+        loop {
+            let (node, region, generation) = self.resolve(id, generation)?;
+            let client = &mut self.nodes[node as usize];
+            match client
+                .write_fenced(region, generation, offset, data.clone())
+                .await
+            {
+                Err(MemoryAccessError::RegionMoved) => {}
+                result => return result,
+            }
+        }
+    }
+
+    async fn get_memory_size(&mut self, id: u64) -> Result<u64, MemoryAccessError> {
+        if let Some(descriptor) = Self::coded_descriptor(id) {
+            return self.get_coded_size(descriptor).await;
+        }
+        loop {
+            let (node, region, _) = self.resolve(id, 0)?;
+            match self.nodes[node as usize].get_memory_size(region).await {
+                Err(MemoryAccessError::RegionMoved) => {}
+                result => return result,
+            }
+        }
+    }
+
+    async fn compare_and_swap_fenced(
+        &mut self,
+        id: u64,
+        generation: u32,
+        offset: u64,
+        expected: u64,
+        desired: u64,
+    ) -> Result<Result<u64, u64>, MemoryAccessError> {
+        if let Some(descriptor) = Self::coded_descriptor(id) {
+            return self
+                .compare_and_swap_coded(descriptor, generation, offset, expected, desired)
+                .await;
+        }
+        loop {
+            let (node, region, generation) = self.resolve(id, generation)?;
+            let client = &mut self.nodes[node as usize];
+            match client
+                .compare_and_swap_fenced(region, generation, offset, expected, desired)
+                .await
+            {
+                Err(MemoryAccessError::RegionMoved) => {}
+                result => return result,
+            }
+        }
+    }
+
+    async fn compare_and_swap_with_tail(
+        &mut self,
+        id: u64,
+        offset: u64,
+        expected: u64,
+        desired: u64,
+        tail: Vec<u8>,
+    ) -> Result<Result<u64, u64>, MemoryAccessError> {
+        if Self::coded_descriptor(id).is_some() {
+            return Err(MemoryAccessError::Unspecified);
+        }
+        loop {
+            let (node, region, _) = self.resolve(id, 0)?;
+            let client = &mut self.nodes[node as usize];
+            match client
+                .compare_and_swap_with_tail(region, offset, expected, desired, tail.clone())
+                .await
+            {
+                Err(MemoryAccessError::RegionMoved) => {}
+                result => return result,
+            }
+        }
+    }
+
+    // the keys of the regions the handles were issued for, which nodes the
+    // regions migrated away from still check before forwarding
+    fn region_key(&self, id: u64) -> u64 {
+        let (node, region) = Self::locate(id);
+        self.nodes
+            .get(node as usize)
+            .and_then(|client| client.key(region))
+            .unwrap_or_default()
+    }
+
+    fn learn_key(&self, id: u64, key: u64) {
+        let (node, region) = Self::locate(id);
+        if let Some(client) = self.nodes.get(node as usize) {
+            client.learn_key(region, key);
+        }
+    }
+
+    /// That of the least pressed node new regions can go to.
+    fn pressure(&self) -> Pressure {
+        (0..self.nodes.len())
+            .filter(|&node| {
+                self.configs[node].weight > 0 && self.alive[node].load(Ordering::Relaxed)
+            })
+            .map(|node| self.nodes[node].pressure())
+            .min()
+            .unwrap_or(Pressure::None)
+    }
+}
+
+async fn watch_membership(
+    endpoint: &Endpoint,
+    credentials: &Credentials,
+    configs: &[NodeConfig],
+    alive: &[AtomicBool],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut client = MembershipClient::new(credentials.authed(endpoint.connect().await?));
+    let mut updates = client.watch(WatchRequest {}).await?.into_inner();
+    while let Some(update) = updates.message().await? {
+        let members: HashMap<&str, bool> = update
+            .members
+            .iter()
+            .map(|member| (member.addr.as_str(), member.alive))
+            .collect();
+        for (i, node) in configs.iter().enumerate() {
+            let reported: Vec<bool> = std::iter::once(&node.addr)
+                .chain(&node.backups)
+                .filter_map(|addr| members.get(addr.as_str()).copied())
+                .collect();
+            if reported.is_empty() {
+                continue;
+            }
+            let up = reported.contains(&true);
+            if alive[i].swap(up, Ordering::Relaxed) != up {
+                log::info!("Node {} is {}", node.addr, if up { "up" } else { "down" });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// FNV-1a, finished with `mix` since FNV alone spreads similar inputs
+/// poorly. Stable across builds, unlike `DefaultHasher`, so every client
+/// computes the same ring.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in bytes {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    mix(h)
+}
+
+/// The splitmix64 finalizer.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
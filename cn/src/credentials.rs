@@ -0,0 +1,108 @@
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+use tonic::{Request, Status};
+
+/// A channel that presents the client's token on every call.
+pub type Authed = InterceptedService<Channel, Token>;
+
+/// How to connect to data nodes. TLS is used for `https` URLs only.
+#[derive(Clone, Default, Debug)]
+pub struct Credentials {
+    client: ClientTlsConfig,
+    token: Token,
+    failover: Option<Token>, // for promoting replicas, if not `token`
+}
+
+impl Credentials {
+    /// Trusts data nodes whose certificates are signed by the CA in `ca`,
+    /// presents the certificate and key in `identity`, all PEM files, to
+    /// those that require client certificates, and authenticates with
+    /// `token` to those that require one.
+    pub fn new(
+        ca: Option<&str>,
+        identity: Option<(&str, &str)>,
+        token: Option<&str>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut config = ClientTlsConfig::new();
+        if let Some(ca) = ca {
+            config = config.ca_certificate(Certificate::from_pem(std::fs::read(ca)?));
+        }
+        if let Some((cert, key)) = identity {
+            config = config.identity(Identity::from_pem(
+                std::fs::read(cert)?,
+                std::fs::read(key)?,
+            ));
+        }
+        let token = match token {
+            Some(token) => Token(Some(format!("Bearer {}", token).parse()?)),
+            None => Token(None),
+        };
+        Ok(Self {
+            client: config,
+            token,
+            failover: None,
+        })
+    }
+
+    /// Authenticates with `token` instead when failing over, which nodes
+    /// only let replicators and admins do.
+    pub fn with_failover_token(mut self, token: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        self.failover = Some(Token(Some(format!("Bearer {}", token).parse()?)));
+        Ok(self)
+    }
+
+    /// These credentials as used to fail over.
+    pub fn failover(&self) -> Self {
+        let mut credentials = self.clone();
+        if let Some(token) = self.failover.clone() {
+            credentials.token = token;
+        }
+        credentials
+    }
+
+    pub fn endpoint(&self, url: String) -> Result<Endpoint, tonic::transport::Error> {
+        let endpoint = Endpoint::from_shared(url)?;
+        if endpoint.uri().scheme_str() != Some("https") {
+            return Ok(endpoint);
+        }
+        // verified against the host in the URL, which for IPv6 addresses
+        // has to lose its brackets
+        let host = endpoint.uri().host().unwrap_or_default();
+        let host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        endpoint.tls_config(self.client.clone().domain_name(host))
+    }
+
+    pub fn authed(&self, channel: Channel) -> Authed {
+        InterceptedService::new(channel, self.token.clone())
+    }
+}
+
+/// Adds the bearer token, if there is one, to outgoing requests.
+#[derive(Clone, Default)]
+pub struct Token(Option<MetadataValue<Ascii>>);
+
+impl std::fmt::Debug for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // never the token itself, which would end up in logs
+        match self.0 {
+            Some(_) => write!(f, "Token(..)"),
+            None => write!(f, "Token(None)"),
+        }
+    }
+}
+
+impl Interceptor for Token {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(token) = &self.0 {
+            request
+                .metadata_mut()
+                .insert("authorization", token.clone());
+        }
+        Ok(request)
+    }
+}
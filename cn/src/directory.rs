@@ -0,0 +1,141 @@
+use crate::credentials::{Authed, Credentials};
+use crate::errors::MemoryError;
+use crate::proto::memory::{
+    directory_client::DirectoryClient as GrpcDirectoryClient, CreateNameRequest, DeleteNameRequest,
+    ListNamesRequest, LookupNameRequest, NameEntry, RenameNameRequest,
+};
+use tonic::{Code, Status};
+
+/// A name listed in a directory.
+#[derive(Debug, Clone)]
+pub struct Listing {
+    /// The last component of the name.
+    pub name: String,
+    /// The handle, generation and key it names, if it isn't only a
+    /// directory.
+    pub entry: Option<(u64, u32, u64)>,
+    pub has_children: bool,
+}
+
+/// Client for the directory of a data node, which maps names like
+/// `/stores/sessions` to handles so that compute nodes can find shared
+/// structures by name instead of passing ids around. Handles are stored as
+/// given, so they should be ones every client of the directory can use,
+/// such as `ClusterClient` handles for the same list of nodes, along with
+/// the region's read-write key for data nodes that require keys. Only the
+/// principal that created a name gets that key back, and only it may remove
+/// or move the name; other principals get the read-only key.
+#[derive(Clone)]
+pub struct DirectoryClient {
+    client: GrpcDirectoryClient<Authed>,
+}
+
+impl DirectoryClient {
+    pub async fn connect(
+        url: String,
+        credentials: &Credentials,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let channel = credentials.endpoint(url)?.connect().await?;
+        Ok(Self {
+            client: GrpcDirectoryClient::new(credentials.authed(channel)),
+        })
+    }
+
+    /// Names a region, failing with `NameExists` if the name is taken. Of
+    /// several clients racing to create a name, exactly one succeeds.
+    pub async fn create(
+        &mut self,
+        name: &str,
+        handle: u64,
+        generation: u32,
+        key: u64,
+    ) -> Result<(), MemoryError> {
+        let request = CreateNameRequest {
+            name: name.to_string(),
+            entry: Some(NameEntry {
+                handle,
+                generation,
+                key,
+            }),
+        };
+        self.client
+            .create_name(request)
+            .await
+            .map_err(|status| directory_error(name, status))?;
+        Ok(())
+    }
+
+    pub async fn lookup(&mut self, name: &str) -> Result<Option<(u64, u32, u64)>, MemoryError> {
+        let request = LookupNameRequest {
+            name: name.to_string(),
+        };
+        match self.client.lookup_name(request).await {
+            Ok(response) => Ok(response.into_inner().entry.map(unpack)),
+            Err(status) if status.code() == Code::NotFound => Ok(None),
+            Err(status) => Err(directory_error(name, status)),
+        }
+    }
+
+    /// The names directly under `directory`, in order. `/` lists the top
+    /// level.
+    pub async fn list(&mut self, directory: &str) -> Result<Vec<Listing>, MemoryError> {
+        let request = ListNamesRequest {
+            directory: directory.to_string(),
+        };
+        let response = self
+            .client
+            .list_names(request)
+            .await
+            .map_err(|status| directory_error(directory, status))?;
+        Ok(response
+            .into_inner()
+            .names
+            .into_iter()
+            .map(|listed| Listing {
+                name: listed.name,
+                entry: listed.entry.map(unpack),
+                has_children: listed.has_children,
+            })
+            .collect())
+    }
+
+    /// Removes a name, leaving any names under it, and returns what it
+    /// named so the caller can free it. `None` if there was no such name.
+    pub async fn delete(&mut self, name: &str) -> Result<Option<(u64, u32, u64)>, MemoryError> {
+        let request = DeleteNameRequest {
+            name: name.to_string(),
+        };
+        match self.client.delete_name(request).await {
+            Ok(response) => Ok(response.into_inner().entry.map(unpack)),
+            Err(status) if status.code() == Code::NotFound => Ok(None),
+            Err(status) => Err(directory_error(name, status)),
+        }
+    }
+
+    /// Moves a name and every name under it, without overwriting anything.
+    pub async fn rename(&mut self, from: &str, to: &str) -> Result<(), MemoryError> {
+        let request = RenameNameRequest {
+            from: from.to_string(),
+            to: to.to_string(),
+        };
+        match self.client.rename_name(request).await {
+            Ok(_) => Ok(()),
+            Err(status) if status.code() == Code::AlreadyExists => {
+                Err(MemoryError::NameExists(to.to_string()))
+            }
+            Err(status) => Err(directory_error(from, status)),
+        }
+    }
+}
+
+fn unpack(entry: NameEntry) -> (u64, u32, u64) {
+    (entry.handle, entry.generation, entry.key)
+}
+
+fn directory_error(name: &str, status: Status) -> MemoryError {
+    match status.code() {
+        Code::NotFound => MemoryError::NameNotFound(name.to_string()),
+        Code::AlreadyExists => MemoryError::NameExists(name.to_string()),
+        _ => MemoryError::Directory(status.message().to_string()),
+    }
+}
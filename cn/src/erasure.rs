@@ -0,0 +1,517 @@
+use crate::client::RemoteMemory;
+use crate::cluster::ClusterClient;
+use crate::proto::memory::{AllocationError, DeallocationError, MemoryAccessError};
+
+/// Most data plus parity shards a coded region can have.
+pub const MAX_SHARDS: usize = 16;
+
+// Descriptor layout:
+// [k u8][m u8][pad 6][lost shards u64][len u64]
+// then for each shard: [handle u64][generation u32][pad 4]
+const HEADER_LEN: usize = 24;
+const SHARD_ENTRY_LEN: usize = 16;
+const DESCRIPTOR_LEN: usize = HEADER_LEN + MAX_SHARDS * SHARD_ENTRY_LEN;
+const LOST_OFFSET: u64 = 8;
+
+/// Reed-Solomon over GF(256), systematic: the data shards are stored as they
+/// are, and parity shard j holds the sum over data shards i of
+/// `coefficient(j, i) * shard i`. The coefficients form a Cauchy matrix, so
+/// any k of the k + m shards determine the rest.
+#[derive(Clone, Copy, Debug)]
+pub struct Codec {
+    k: usize,
+    m: usize,
+}
+
+impl Codec {
+    pub fn new(k: usize, m: usize) -> Option<Self> {
+        (k > 0 && k + m <= MAX_SHARDS).then_some(Self { k, m })
+    }
+
+    /// 1 / (x_j + y_i) with x_j = k + j and y_i = i, all distinct.
+    pub fn coefficient(&self, parity: usize, data: usize) -> u8 {
+        gf_inv((self.k + parity) as u8 ^ data as u8)
+    }
+
+    /// Rebuilds data shard `want` from exactly k other shards, given as
+    /// (shard index, bytes) with parity shards numbered from k.
+    pub fn reconstruct(&self, want: usize, shards: &[(usize, Vec<u8>)]) -> Option<Vec<u8>> {
+        if shards.len() != self.k {
+            return None;
+        }
+        // row r expresses shard r of `shards` in terms of the data shards
+        let matrix = shards
+            .iter()
+            .map(|&(index, _)| {
+                (0..self.k)
+                    .map(|i| match index < self.k {
+                        true => (index == i) as u8,
+                        false => self.coefficient(index - self.k, i),
+                    })
+                    .collect()
+            })
+            .collect();
+        let row = invert(matrix)?.swap_remove(want);
+
+        let mut out = vec![0u8; shards[0].1.len()];
+        for (&factor, (_, bytes)) in row.iter().zip(shards) {
+            mul_add(&mut out, factor, bytes);
+        }
+        Some(out)
+    }
+}
+
+/// `dst += factor * src`
+fn mul_add(dst: &mut [u8], factor: u8, src: &[u8]) {
+    for (d, &s) in dst.iter_mut().zip(src) {
+        *d ^= gf_mul(factor, s);
+    }
+}
+
+/// Gauss-Jordan elimination. `None` if the matrix is singular, which a
+/// Cauchy matrix with identity rows mixed in never is.
+fn invert(mut a: Vec<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+    let n = a.len();
+    let mut inv: Vec<Vec<u8>> = (0..n)
+        .map(|r| (0..n).map(|c| (r == c) as u8).collect())
+        .collect();
+    for col in 0..n {
+        let pivot = (col..n).find(|&r| a[r][col] != 0)?;
+        a.swap(col, pivot);
+        inv.swap(col, pivot);
+        let scale = gf_inv(a[col][col]);
+        for c in 0..n {
+            a[col][c] = gf_mul(a[col][c], scale);
+            inv[col][c] = gf_mul(inv[col][c], scale);
+        }
+        for r in 0..n {
+            let factor = a[r][col];
+            if r == col || factor == 0 {
+                continue;
+            }
+            for c in 0..n {
+                let (x, y) = (a[col][c], inv[col][c]);
+                a[r][c] ^= gf_mul(factor, x);
+                inv[r][c] ^= gf_mul(factor, y);
+            }
+        }
+    }
+    Some(inv)
+}
+
+// exp and log tables for GF(256) with the polynomial x^8 + x^4 + x^3 + x^2 + 1
+static GF: ([u8; 512], [u8; 256]) = gf_tables();
+
+const fn gf_tables() -> ([u8; 512], [u8; 256]) {
+    let mut exp = [0u8; 512];
+    let mut log = [0u8; 256];
+    let mut x: u32 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11d;
+        }
+        i += 1;
+    }
+    while i < 512 {
+        exp[i] = exp[i - 255];
+        i += 1;
+    }
+    (exp, log)
+}
+
+fn gf_mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    GF.0[GF.1[a as usize] as usize + GF.1[b as usize] as usize]
+}
+
+fn gf_inv(a: u8) -> u8 {
+    GF.0[255 - GF.1[a as usize] as usize]
+}
+
+/// A coded region's descriptor. Data shard i holds bytes
+/// [i * shard_len, (i + 1) * shard_len) of the region; the tail of the last
+/// ones is zero padding, which parity covers like any other bytes.
+struct Stripe {
+    descriptor: u64,
+    codec: Codec,
+    lost: u64, // bit per shard that missed a write and must not be read
+    len: u64,
+    shards: Vec<(u64, u32)>, // (handle, generation)
+}
+
+/// A piece of an access that falls within one data shard.
+struct Segment {
+    shard: usize,
+    shard_offset: u64,
+    start: usize, // into the access
+    len: usize,
+}
+
+impl Stripe {
+    fn shard_len(&self) -> u64 {
+        shard_len(self.len, self.codec.k)
+    }
+
+    fn is_lost(&self, shard: usize) -> bool {
+        self.lost & (1 << shard) != 0
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![0u8; DESCRIPTOR_LEN];
+        out[0] = self.codec.k as u8;
+        out[1] = self.codec.m as u8;
+        out[8..16].copy_from_slice(&self.lost.to_le_bytes());
+        out[16..24].copy_from_slice(&self.len.to_le_bytes());
+        for (i, (handle, generation)) in self.shards.iter().enumerate() {
+            let entry = HEADER_LEN + i * SHARD_ENTRY_LEN;
+            out[entry..entry + 8].copy_from_slice(&handle.to_le_bytes());
+            out[entry + 8..entry + 12].copy_from_slice(&generation.to_le_bytes());
+        }
+        out
+    }
+
+    fn decode(descriptor: u64, bytes: &[u8]) -> Option<Self> {
+        let codec = Codec::new(bytes[0] as usize, bytes[1] as usize)?;
+        let shards = (0..codec.k + codec.m)
+            .map(|i| {
+                let entry = HEADER_LEN + i * SHARD_ENTRY_LEN;
+                (
+                    u64::from_le_bytes(bytes[entry..entry + 8].try_into().unwrap()),
+                    u32::from_le_bytes(bytes[entry + 8..entry + 12].try_into().unwrap()),
+                )
+            })
+            .collect();
+        Some(Self {
+            descriptor,
+            codec,
+            lost: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            len: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+            shards,
+        })
+    }
+
+    fn segments(&self, offset: u64, len: u64) -> Vec<Segment> {
+        let shard_len = self.shard_len();
+        let mut segments = Vec::new();
+        let mut pos = offset;
+        while pos < offset + len {
+            let shard_offset = pos % shard_len;
+            let n = (shard_len - shard_offset).min(offset + len - pos);
+            segments.push(Segment {
+                shard: (pos / shard_len) as usize,
+                shard_offset,
+                start: (pos - offset) as usize,
+                len: n as usize,
+            });
+            pos += n;
+        }
+        segments
+    }
+}
+
+/// Data shards are rounded up to whole words so a compare-and-swap of an
+/// aligned word never spans two of them.
+fn shard_len(len: u64, k: usize) -> u64 {
+    len.div_ceil(k as u64).div_ceil(8) * 8
+}
+
+impl ClusterClient {
+    /// Allocates a region of `size` bytes striped over `k` data and `m`
+    /// parity shards, each on a different node. Reads go to the data shards
+    /// and are rebuilt from any k shards when one can't be reached, so the
+    /// region survives losing any m of its nodes, at (k + m) / k times its
+    /// size rather than the m + 1 times of keeping copies.
+    ///
+    /// The region's layout lives in a small descriptor on one more node,
+    /// which every access reads first; that node must stay up, so it is
+    /// worth replicating. Writes read the old bytes to update parity, so
+    /// concurrent writes to the same bytes of a coded region must be
+    /// serialized by the caller, and neither writes nor compare-and-swaps
+    /// update data and parity atomically. Shards marked lost are never
+    /// rebuilt, so each one uses up one of the m losses the region can
+    /// take for the rest of its life.
+    pub async fn allocate_coded(
+        &mut self,
+        size: u64,
+        k: usize,
+        m: usize,
+    ) -> Result<(u64, u32), AllocationError> {
+        let codec = Codec::new(k, m).ok_or(AllocationError::Unspecified)?;
+        let shards = self.allocate_spread(shard_len(size, k), k + m).await?;
+        let stripe = Stripe {
+            descriptor: 0,
+            codec,
+            lost: 0,
+            len: size,
+            shards,
+        };
+
+        let placed = match self.allocate_fenced(DESCRIPTOR_LEN as u64).await {
+            Ok((descriptor, generation)) => self
+                .write_fenced(descriptor, generation, 0, stripe.encode())
+                .await
+                .map(|_| (descriptor, generation))
+                .map_err(|_| AllocationError::Unspecified),
+            Err(e) => Err(e),
+        };
+        match placed {
+            Ok((descriptor, generation)) => Ok((Self::coded(descriptor), generation)),
+            Err(e) => {
+                for (handle, _) in stripe.shards {
+                    let _ = self.free(handle).await;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn load_stripe(
+        &mut self,
+        descriptor: u64,
+        generation: u32,
+    ) -> Result<Stripe, MemoryAccessError> {
+        let bytes = self
+            .read_fenced(descriptor, generation, 0, DESCRIPTOR_LEN as u64)
+            .await?;
+        Stripe::decode(descriptor, &bytes).ok_or(MemoryAccessError::Unspecified)
+    }
+
+    /// Reads part of a data shard, rebuilding it from other shards if it
+    /// can't be read.
+    async fn read_shard(
+        &mut self,
+        stripe: &Stripe,
+        shard: usize,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, MemoryAccessError> {
+        if !stripe.is_lost(shard) {
+            let (handle, generation) = stripe.shards[shard];
+            if let Ok(bytes) = self.read_fenced(handle, generation, offset, len).await {
+                return Ok(bytes);
+            }
+        }
+
+        let mut available = Vec::with_capacity(stripe.codec.k);
+        for (index, &(handle, generation)) in stripe.shards.iter().enumerate() {
+            if available.len() == stripe.codec.k {
+                break;
+            }
+            if index == shard || stripe.is_lost(index) {
+                continue;
+            }
+            if let Ok(bytes) = self.read_fenced(handle, generation, offset, len).await {
+                available.push((index, bytes));
+            }
+        }
+        stripe
+            .codec
+            .reconstruct(shard, &available)
+            .ok_or(MemoryAccessError::Unspecified)
+    }
+
+    /// Records that a shard missed a write, so nobody reads it again.
+    async fn mark_lost(
+        &mut self,
+        stripe: &mut Stripe,
+        shard: usize,
+    ) -> Result<(), MemoryAccessError> {
+        while !stripe.is_lost(shard) {
+            let desired = stripe.lost | 1 << shard;
+            match self
+                .compare_and_swap(stripe.descriptor, LOST_OFFSET, stripe.lost, desired)
+                .await?
+            {
+                Ok(_) => stripe.lost = desired,
+                Err(actual) => stripe.lost = actual,
+            }
+        }
+        if stripe.lost.count_ones() as usize > stripe.codec.m {
+            // more shards gone than parity can make up for
+            return Err(MemoryAccessError::Unspecified);
+        }
+        Ok(())
+    }
+
+    /// Adds `factor(parity) * delta` into every parity shard, where `delta`
+    /// is the change to data shard `shard` at `offset`.
+    async fn update_parity(
+        &mut self,
+        stripe: &mut Stripe,
+        shard: usize,
+        offset: u64,
+        delta: &[u8],
+    ) -> Result<(), MemoryAccessError> {
+        for parity in 0..stripe.codec.m {
+            let index = stripe.codec.k + parity;
+            if stripe.is_lost(index) {
+                continue;
+            }
+            let mut scaled = vec![0u8; delta.len()];
+            mul_add(&mut scaled, stripe.codec.coefficient(parity, shard), delta);
+            let (handle, generation) = stripe.shards[index];
+            if self
+                .xor_fenced(handle, generation, offset, scaled)
+                .await
+                .is_err()
+            {
+                self.mark_lost(stripe, index).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn read_coded(
+        &mut self,
+        descriptor: u64,
+        generation: u32,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, MemoryAccessError> {
+        let stripe = self.load_stripe(descriptor, generation).await?;
+        if offset
+            .checked_add(length)
+            .is_none_or(|end| end > stripe.len)
+        {
+            return Err(MemoryAccessError::OutOfBoundsAccess);
+        }
+
+        let mut out = Vec::with_capacity(length as usize);
+        for segment in stripe.segments(offset, length) {
+            let bytes = self
+                .read_shard(
+                    &stripe,
+                    segment.shard,
+                    segment.shard_offset,
+                    segment.len as u64,
+                )
+                .await?;
+            out.extend_from_slice(&bytes);
+        }
+        Ok(out)
+    }
+
+    /// Writes each data shard and adds the change into parity. A shard that
+    /// can't be reached is marked lost rather than failing the write, as
+    /// long as no more than m are.
+    pub(crate) async fn write_coded(
+        &mut self,
+        descriptor: u64,
+        generation: u32,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<(), MemoryAccessError> {
+        let mut stripe = self.load_stripe(descriptor, generation).await?;
+        if offset
+            .checked_add(data.len() as u64)
+            .is_none_or(|end| end > stripe.len)
+        {
+            return Err(MemoryAccessError::OutOfBoundsAccess);
+        }
+
+        for segment in stripe.segments(offset, data.len() as u64) {
+            let new = &data[segment.start..segment.start + segment.len];
+            let old = self
+                .read_shard(
+                    &stripe,
+                    segment.shard,
+                    segment.shard_offset,
+                    segment.len as u64,
+                )
+                .await?;
+            let delta: Vec<u8> = old.iter().zip(new).map(|(a, b)| a ^ b).collect();
+            if delta.iter().all(|&b| b == 0) {
+                continue;
+            }
+
+            if !stripe.is_lost(segment.shard) {
+                let (handle, shard_generation) = stripe.shards[segment.shard];
+                if self
+                    .write_fenced(handle, shard_generation, segment.shard_offset, new.to_vec())
+                    .await
+                    .is_err()
+                {
+                    self.mark_lost(&mut stripe, segment.shard).await?;
+                }
+            }
+            self.update_parity(&mut stripe, segment.shard, segment.shard_offset, &delta)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Swaps a word within one data shard, which must be reachable, then
+    /// brings parity up to date. The two are separate steps: until the
+    /// second is done, or for good if the compute node fails in between,
+    /// parity doesn't cover the new word, and rebuilding that shard from
+    /// parity would bring back garbage.
+    pub(crate) async fn compare_and_swap_coded(
+        &mut self,
+        descriptor: u64,
+        generation: u32,
+        offset: u64,
+        expected: u64,
+        desired: u64,
+    ) -> Result<Result<u64, u64>, MemoryAccessError> {
+        let mut stripe = self.load_stripe(descriptor, generation).await?;
+        // also what keeps empty regions, with empty shards, from dividing by 0
+        if offset.checked_add(8).is_none_or(|end| end > stripe.len) {
+            return Err(MemoryAccessError::OutOfBoundsAccess);
+        }
+        let shard_len = stripe.shard_len();
+        let (shard, shard_offset) = ((offset / shard_len) as usize, offset % shard_len);
+        if shard_offset + 8 > shard_len {
+            return Err(MemoryAccessError::OutOfBoundsAccess);
+        }
+        if stripe.is_lost(shard) {
+            return Err(MemoryAccessError::Unspecified);
+        }
+
+        let (handle, shard_generation) = stripe.shards[shard];
+        let result = self
+            .compare_and_swap_fenced(handle, shard_generation, shard_offset, expected, desired)
+            .await?;
+        if result.is_ok() {
+            let delta = (expected ^ desired).to_le_bytes();
+            self.update_parity(&mut stripe, shard, shard_offset, &delta)
+                .await?;
+        }
+        Ok(result)
+    }
+
+    pub(crate) async fn get_coded_size(
+        &mut self,
+        descriptor: u64,
+    ) -> Result<u64, MemoryAccessError> {
+        Ok(self.load_stripe(descriptor, 0).await?.len)
+    }
+
+    /// The handles of a coded region's shards and its descriptor, last.
+    pub(crate) async fn coded_regions(
+        &mut self,
+        descriptor: u64,
+    ) -> Result<Vec<u64>, MemoryAccessError> {
+        let stripe = self.load_stripe(descriptor, 0).await?;
+        let mut regions: Vec<u64> = stripe.shards.iter().map(|&(handle, _)| handle).collect();
+        regions.push(descriptor);
+        Ok(regions)
+    }
+
+    pub(crate) async fn free_coded(&mut self, descriptor: u64) -> Result<(), DeallocationError> {
+        let stripe = self
+            .load_stripe(descriptor, 0)
+            .await
+            .map_err(|_| DeallocationError::DeallocationInvalidMemoryAddress)?;
+        for (handle, _) in stripe.shards {
+            // lost shards may be gone already
+            let _ = self.free(handle).await;
+        }
+        self.free(descriptor).await
+    }
+}
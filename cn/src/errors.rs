@@ -5,16 +5,76 @@ pub enum MemoryError {
     AllocationError(AllocationError),
     DeallocationError(DeallocationError),
     MemoryAccessError(MemoryAccessError),
+    KeyTooLarge { size: usize, limit: usize },
+    ValueTooLarge { size: usize, limit: usize },
+    CorruptManifest(u64),
+    CorruptIndex(u64),
+    CorruptPointer,
+    TransactionConflict,
+    CommitUnknown(MemoryAccessError),
+    NameNotFound(String),
+    NameExists(String),
+    Directory(String),
+    Io(std::io::Error),
 }
 
 impl std::error::Error for MemoryError {}
 
+impl MemoryError {
+    /// Whether the error came from touching a region that has since been
+    /// freed, which for shared structures means a concurrent writer replaced
+    /// it and the operation should be retried.
+    pub fn is_stale(&self) -> bool {
+        matches!(
+            self,
+            MemoryError::MemoryAccessError(
+                MemoryAccessError::AccessInvalidMemoryAddress | MemoryAccessError::StaleGeneration
+            )
+        )
+    }
+
+    /// Whether the error came from touching a region its data node evicted
+    /// to make room.
+    pub fn is_evicted(&self) -> bool {
+        matches!(
+            self,
+            MemoryError::MemoryAccessError(MemoryAccessError::RegionEvicted)
+                | MemoryError::DeallocationError(DeallocationError::DeallocationRegionEvicted)
+        )
+    }
+}
+
 impl std::fmt::Display for MemoryError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             MemoryError::AllocationError(e) => write!(f, "Allocation error: {:?}", e),
             MemoryError::DeallocationError(e) => write!(f, "Deallocation error: {:?}", e),
             MemoryError::MemoryAccessError(e) => write!(f, "Memory access error: {:?}", e),
+            MemoryError::KeyTooLarge { size, limit } => {
+                write!(f, "Key of {} bytes exceeds limit of {} bytes", size, limit)
+            }
+            MemoryError::ValueTooLarge { size, limit } => {
+                write!(
+                    f,
+                    "Value of {} bytes exceeds limit of {} bytes",
+                    size, limit
+                )
+            }
+            MemoryError::CorruptManifest(id) => {
+                write!(f, "Corrupt value manifest in region {}", id)
+            }
+            MemoryError::CorruptIndex(id) => write!(f, "Corrupt index node in region {}", id),
+            MemoryError::CorruptPointer => write!(f, "Corrupt far pointer"),
+            MemoryError::TransactionConflict => {
+                write!(f, "Transaction conflicted with a concurrent commit")
+            }
+            MemoryError::CommitUnknown(e) => {
+                write!(f, "Commit may or may not have been published: {:?}", e)
+            }
+            MemoryError::NameNotFound(name) => write!(f, "No such name {}", name),
+            MemoryError::NameExists(name) => write!(f, "{} already exists", name),
+            MemoryError::Directory(message) => write!(f, "Directory error: {}", message),
+            MemoryError::Io(e) => write!(f, "I/O error: {}", e),
         }
     }
 }
@@ -36,3 +96,9 @@ impl From<MemoryAccessError> for MemoryError {
         MemoryError::MemoryAccessError(error)
     }
 }
+
+impl From<std::io::Error> for MemoryError {
+    fn from(error: std::io::Error) -> Self {
+        MemoryError::Io(error)
+    }
+}
@@ -0,0 +1,170 @@
+use crate::client::RemoteMemory;
+use crate::cluster::ClusterClient;
+use crate::errors::MemoryError;
+use crate::proto::memory::MemoryAccessError;
+use std::fmt;
+
+/// A global address: a byte offset into a region on a particular data node,
+/// together with the node's generation when the region was allocated and
+/// the region's length. Accesses through a pointer are fenced on the
+/// generation, so a pointer outliving a data node restart fails with
+/// `StaleGeneration` rather than reaching whatever reuses its region id.
+///
+/// Node and region are packed the same way as `ClusterClient` handles, so
+/// pointers work through a `ClusterClient` and, for node 0, through a plain
+/// `MemoryClient`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FarPtr {
+    handle: u64,
+    generation: u32,
+    offset: u32,
+    len: u32,
+}
+
+impl FarPtr {
+    /// Size of the serialized form:
+    /// [node u16 | region u48][generation u32][offset u32][len u32]
+    pub const ENCODED_LEN: usize = 20;
+
+    /// Data nodes never issue generation 0, so the all-zero pointer can mark
+    /// the absence of one.
+    pub const NULL: FarPtr = FarPtr {
+        handle: 0,
+        generation: 0,
+        offset: 0,
+        len: 0,
+    };
+
+    /// Allocates a region of `len` bytes and points at its start.
+    pub async fn allocate<C: RemoteMemory>(client: &mut C, len: u32) -> Result<Self, MemoryError> {
+        let (handle, generation) = client.allocate_fenced(len as u64).await?;
+        Ok(Self {
+            handle,
+            generation,
+            offset: 0,
+            len,
+        })
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.generation == 0
+    }
+
+    pub fn node(&self) -> u16 {
+        ClusterClient::locate(self.handle).0
+    }
+
+    pub fn region(&self) -> u64 {
+        ClusterClient::locate(self.handle).1
+    }
+
+    /// Bytes between the pointer and the end of its region.
+    pub fn remaining(&self) -> u32 {
+        self.len - self.offset
+    }
+
+    /// Moves the pointer `n` bytes forward. `None` if that would go past the
+    /// end of the region; pointing exactly at the end is allowed.
+    pub fn checked_add(self, n: u32) -> Option<Self> {
+        let offset = self.offset.checked_add(n).filter(|&o| o <= self.len)?;
+        Some(Self { offset, ..self })
+    }
+
+    pub fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut out = [0u8; Self::ENCODED_LEN];
+        out[0..8].copy_from_slice(&self.handle.to_le_bytes());
+        out[8..12].copy_from_slice(&self.generation.to_le_bytes());
+        out[12..16].copy_from_slice(&self.offset.to_le_bytes());
+        out[16..20].copy_from_slice(&self.len.to_le_bytes());
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..Self::ENCODED_LEN)?;
+        let ptr = Self {
+            handle: u64::from_le_bytes(bytes[0..8].try_into().ok()?),
+            generation: u32::from_le_bytes(bytes[8..12].try_into().ok()?),
+            offset: u32::from_le_bytes(bytes[12..16].try_into().ok()?),
+            len: u32::from_le_bytes(bytes[16..20].try_into().ok()?),
+        };
+        (ptr.offset <= ptr.len).then_some(ptr)
+    }
+
+    /// Reads `length` bytes starting at the pointer.
+    pub async fn read<C: RemoteMemory>(
+        &self,
+        client: &mut C,
+        length: u32,
+    ) -> Result<Vec<u8>, MemoryError> {
+        self.check_access(length as usize)?;
+        let bytes = client
+            .read_fenced(
+                self.handle,
+                self.generation,
+                self.offset as u64,
+                length as u64,
+            )
+            .await?;
+        Ok(bytes)
+    }
+
+    /// Writes `data` starting at the pointer.
+    pub async fn write<C: RemoteMemory>(
+        &self,
+        client: &mut C,
+        data: &[u8],
+    ) -> Result<(), MemoryError> {
+        self.check_access(data.len())?;
+        client
+            .write_fenced(
+                self.handle,
+                self.generation,
+                self.offset as u64,
+                data.to_vec(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Reads the pointer stored at this address.
+    pub async fn load<C: RemoteMemory>(&self, client: &mut C) -> Result<Self, MemoryError> {
+        let bytes = self.read(client, Self::ENCODED_LEN as u32).await?;
+        Self::decode(&bytes).ok_or(MemoryError::CorruptPointer)
+    }
+
+    /// Stores `ptr` at this address.
+    pub async fn store<C: RemoteMemory>(
+        &self,
+        client: &mut C,
+        ptr: &Self,
+    ) -> Result<(), MemoryError> {
+        self.write(client, &ptr.encode()).await
+    }
+
+    /// Frees the region the pointer points into.
+    pub async fn free<C: RemoteMemory>(self, client: &mut C) -> Result<(), MemoryError> {
+        client.free(self.handle).await?;
+        Ok(())
+    }
+
+    fn check_access(&self, length: usize) -> Result<(), MemoryError> {
+        if length > self.remaining() as usize {
+            return Err(MemoryAccessError::OutOfBoundsAccess.into());
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FarPtr {
+    /// node:region@generation+offset
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}@{}+{}",
+            self.node(),
+            self.region(),
+            self.generation,
+            self.offset
+        )
+    }
+}
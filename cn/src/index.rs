@@ -0,0 +1,735 @@
+use crate::client::RemoteMemory;
+use crate::errors::MemoryError;
+use rand::Rng;
+use std::collections::HashSet;
+use std::ops::Bound;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const HEADER_SIZE: u64 = 1024; //metadata header: root version, commit counter, then root slots
+const SLOTS_START: u64 = 16;
+const SLOT_SIZE: u64 = 24; // version, root node id and its key
+const SLOTS: u64 = (HEADER_SIZE - SLOTS_START) / SLOT_SIZE;
+const MAX_COMMIT_ATTEMPTS: usize = 64;
+const MAX_COMMIT_BACKOFF_MS: u64 = 32; // before retrying a commit that lost the root
+const MAX_STALE_RETRIES: usize = 64; // of reads that found nodes freed underneath them
+const MAX_ENTRIES: usize = 64; // entries per leaf, children per internal node
+const LEAF: u8 = 0;
+const INTERNAL: u8 = 1;
+
+/// What the index stores for each key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub manifest: u64,
+    pub manifest_key: u64, // for nodes that require keys, 0 if there is none
+    pub version: u64,
+    pub expires_at: u64, // unix time in milliseconds, 0 if the key never expires
+    pub flags: u32,      // opaque to the store, kept for protocol frontends
+}
+
+impl Entry {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
+}
+
+/// New contents for a key. The version is assigned at commit.
+#[derive(Debug, Clone, Copy)]
+pub struct Put {
+    pub manifest: u64,
+    pub manifest_key: u64,
+    pub expires_at: u64,
+    pub flags: u32,
+}
+
+/// Outcome of a successful `BTreeIndex::apply`.
+#[derive(Debug)]
+pub struct Applied {
+    pub version: u64, // given to every written entry, 0 if nothing was written
+    pub replaced: Vec<Entry>,
+}
+
+/// A node's region id and the key to present for it. Keys are stored next to
+/// ids throughout, so that clients other than the one that wrote a node can
+/// use it on data nodes that require keys.
+type Ref = (u64, u64);
+
+enum Node {
+    Leaf(Vec<(String, Entry)>),
+    // keys[i] is the smallest key reachable through children[i + 1]
+    Internal {
+        keys: Vec<String>,
+        children: Vec<Ref>,
+    },
+}
+
+/// Result of rewriting the path to one key. Nothing is visible to readers
+/// until `root` is published in the header.
+struct CowUpdate {
+    root: Ref,
+    old: Option<Entry>,
+    created: Vec<u64>,
+    obsolete: Vec<Ref>,
+}
+
+/// Copy-on-write B+tree kept entirely in data node memory. Nodes are never
+/// modified in place: an update writes a new path from leaf to root, puts the
+/// new root in a free slot of the header region tagged with the commit's
+/// version, and then swaps the version the header publishes with a remote
+/// CAS, so any number of clients can share one index. Readers that trip over
+/// a node freed by a concurrent writer restart from the new root.
+#[derive(Clone)]
+pub struct BTreeIndex {
+    header_id: u64,
+    header_key: u64,
+}
+
+impl BTreeIndex {
+    pub async fn create<C: RemoteMemory>(client: &mut C) -> Result<Self, MemoryError> {
+        let header_id = client.allocate_memory(HEADER_SIZE).await?;
+        let (root, root_key) = store_node(client, &Node::Leaf(Vec::new())).await?;
+        // published as version 1 from the first slot
+        let header: Vec<u8> = [1, 1, 1, root, root_key]
+            .iter()
+            .flat_map(|word: &u64| word.to_le_bytes())
+            .collect();
+        client.write(header_id, 0, header).await?;
+        let header_key = client.region_key(header_id);
+        Ok(Self {
+            header_id,
+            header_key,
+        })
+    }
+
+    /// Attaches to an index created by another client, given the id and key
+    /// of its header region.
+    pub fn open(header_id: u64, header_key: u64) -> Self {
+        Self {
+            header_id,
+            header_key,
+        }
+    }
+
+    pub fn header_id(&self) -> u64 {
+        self.header_id
+    }
+
+    pub fn header_key(&self) -> u64 {
+        self.header_key
+    }
+
+    pub async fn get<C: RemoteMemory>(
+        &self,
+        client: &mut C,
+        key: &str,
+    ) -> Result<Option<Entry>, MemoryError> {
+        let mut retries = 0;
+        loop {
+            let (_, root) = self.root(client).await?;
+            match get_at(client, root, key).await {
+                Err(e) if e.is_stale() && retries < MAX_STALE_RETRIES => retries += 1,
+                result => return result,
+            }
+        }
+    }
+
+    /// Atomically applies `writes`, setting or removing each key, provided
+    /// every key in `reads` still has the version that was observed (`None`
+    /// meaning absent, which an expired entry also satisfies). Returns the
+    /// new version and the entries that were replaced or removed, or
+    /// `CommitUnknown` if publishing failed in a way that leaves open
+    /// whether it happened, in which case nothing the commit built is freed.
+    pub async fn apply<C: RemoteMemory>(
+        &self,
+        client: &mut C,
+        reads: &[(String, Option<u64>)],
+        writes: &[(String, Option<Put>)],
+    ) -> Result<Applied, MemoryError> {
+        for attempt in 0..MAX_COMMIT_ATTEMPTS as u64 {
+            let (header, root) = self.root(client).await?;
+            match self.try_apply(client, &header, root, reads, writes).await {
+                Ok(Some(applied)) => return Ok(applied),
+                Ok(None) => {
+                    // another writer moved the root first; wait a random
+                    // while, longer each time, so writers that keep losing
+                    // to each other spread out
+                    let limit = (attempt + 1).min(MAX_COMMIT_BACKOFF_MS);
+                    let wait = rand::thread_rng().gen_range(0..=limit);
+                    tokio::time::sleep(Duration::from_millis(wait)).await;
+                }
+                Err(e) if e.is_stale() => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(MemoryError::TransactionConflict)
+    }
+
+    /// Returns up to `limit` entries within the bounds, in key order or in
+    /// reverse key order.
+    pub async fn range<C: RemoteMemory>(
+        &self,
+        client: &mut C,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        reverse: bool,
+        limit: usize,
+    ) -> Result<Vec<(String, Entry)>, MemoryError> {
+        let mut retries = 0;
+        loop {
+            let (_, root) = self.root(client).await?;
+            match range_at(client, root, start, end, reverse, limit).await {
+                Err(e) if e.is_stale() && retries < MAX_STALE_RETRIES => retries += 1,
+                result => return result,
+            }
+        }
+    }
+
+    /// Reads the header, returning it with the root it publishes.
+    async fn root<C: RemoteMemory>(&self, client: &mut C) -> Result<(Vec<u8>, Ref), MemoryError> {
+        client.learn_key(self.header_id, self.header_key);
+        let header = client.read(self.header_id, 0, HEADER_SIZE).await?;
+        let version = word(&header, 0);
+        let slot = slots()
+            .find(|&at| word(&header, at) == version)
+            .ok_or(MemoryError::CorruptIndex(self.header_id))?;
+        let root = (word(&header, slot + 8), word(&header, slot + 16));
+        client.learn_key(root.0, root.1);
+        Ok((header, root))
+    }
+
+    /// Claims a slot of `header` for `root`, the root of commit `version`,
+    /// taking one holding a root older than the published one, which no
+    /// reader looks for any more. The root goes in with the same swap that
+    /// claims the slot, so a commit that stalls can't write it over a slot
+    /// someone else has claimed since. Returns its offset, or `None` if
+    /// every slot is taken by a commit still in flight.
+    async fn claim_slot<C: RemoteMemory>(
+        &self,
+        client: &mut C,
+        header: &[u8],
+        version: u64,
+        root: Ref,
+    ) -> Result<Option<u64>, MemoryError> {
+        client.learn_key(self.header_id, self.header_key);
+        let published = word(header, 0);
+        let root = [root.0.to_le_bytes(), root.1.to_le_bytes()].concat();
+        for at in slots() {
+            let current = word(header, at);
+            if current >= published {
+                continue;
+            }
+            if client
+                .compare_and_swap_with_tail(self.header_id, at, current, version, root.clone())
+                .await?
+                .is_ok()
+            {
+                return Ok(Some(at));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Takes the next value of the commit counter, starting from the one in
+    /// `header`.
+    async fn next_version<C: RemoteMemory>(
+        &self,
+        client: &mut C,
+        header: &[u8],
+    ) -> Result<u64, MemoryError> {
+        client.learn_key(self.header_id, self.header_key);
+        let mut current = word(header, 8);
+        loop {
+            match client
+                .compare_and_swap(self.header_id, 8, current, current + 1)
+                .await?
+            {
+                Ok(_) => return Ok(current + 1),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Hands back the slot claimed for commit `version` once it can't be
+    /// published, so that commits losing the root to each other don't use
+    /// up the slots.
+    async fn release_slot<C: RemoteMemory>(&self, client: &mut C, slot: u64, version: u64) {
+        let _ = client
+            .compare_and_swap(self.header_id, slot, version, 0)
+            .await;
+    }
+
+    /// Validates `reads` against `root`, as published in `header`, and
+    /// builds a new tree with `writes` applied, then tries to publish it.
+    /// Returns `None` if the root changed underneath us.
+    async fn try_apply<C: RemoteMemory>(
+        &self,
+        client: &mut C,
+        header: &[u8],
+        root: Ref,
+        reads: &[(String, Option<u64>)],
+        writes: &[(String, Option<Put>)],
+    ) -> Result<Option<Applied>, MemoryError> {
+        let now = now_millis();
+        for (key, version) in reads {
+            let unchanged = match (version, get_at(client, root, key).await?) {
+                (Some(version), Some(current)) => *version == current.version,
+                (None, Some(current)) => current.is_expired(now),
+                (None, None) => true,
+                (Some(_), None) => false,
+            };
+            if !unchanged {
+                return Err(MemoryError::TransactionConflict);
+            }
+        }
+        if writes.is_empty() {
+            return Ok(Some(Applied {
+                version: 0,
+                replaced: Vec::new(),
+            }));
+        }
+
+        let version = self.next_version(client, header).await?;
+        let mut new_root = root;
+        let mut created = HashSet::new();
+        let mut obsolete = Vec::new();
+        let mut replaced = Vec::new();
+        for (key, put) in writes {
+            let entry = put.map(|put| Entry {
+                manifest: put.manifest,
+                manifest_key: put.manifest_key,
+                version,
+                expires_at: put.expires_at,
+                flags: put.flags,
+            });
+            let update = match cow_update(client, new_root, key, entry).await {
+                Ok(update) => update,
+                Err(e) => {
+                    free_all(client, created).await;
+                    return Err(e);
+                }
+            };
+            new_root = update.root;
+            replaced.extend(update.old);
+            for (id, key) in update.obsolete {
+                // nodes from an earlier write in this batch were never published
+                if created.remove(&id) {
+                    let _ = client.free(id).await;
+                } else {
+                    obsolete.push((id, key));
+                }
+            }
+            created.extend(update.created);
+        }
+        let applied = Applied { version, replaced };
+        if new_root == root {
+            return Ok(Some(applied));
+        }
+
+        let slot = match self.claim_slot(client, header, version, new_root).await {
+            Ok(Some(slot)) => slot,
+            result => {
+                free_all(client, created).await;
+                return result.map(|_| None);
+            }
+        };
+        match client
+            .compare_and_swap(self.header_id, 0, word(header, 0), version)
+            .await
+        {
+            Ok(Ok(_)) => {
+                for &(id, key) in &obsolete {
+                    client.learn_key(id, key);
+                }
+                free_all(client, obsolete.into_iter().map(|(id, _)| id)).await;
+                Ok(Some(applied))
+            }
+            Ok(Err(_)) => {
+                self.release_slot(client, slot, version).await;
+                free_all(client, created).await;
+                Ok(None)
+            }
+            // the new root may be published, so it and the nodes it refers
+            // to have to stay
+            Err(e) => Err(MemoryError::CommitUnknown(e)),
+        }
+    }
+}
+
+async fn get_at<C: RemoteMemory>(
+    client: &mut C,
+    root: Ref,
+    key: &str,
+) -> Result<Option<Entry>, MemoryError> {
+    let mut node = root;
+    loop {
+        match load_node(client, node).await? {
+            Node::Leaf(entries) => {
+                return Ok(entries
+                    .binary_search_by(|(k, _)| k.as_str().cmp(key))
+                    .ok()
+                    .map(|i| entries[i].1));
+            }
+            Node::Internal { keys, children } => {
+                node = children[upper_bound(&keys, key)];
+            }
+        }
+    }
+}
+
+async fn range_at<C: RemoteMemory>(
+    client: &mut C,
+    root: Ref,
+    start: Bound<&str>,
+    end: Bound<&str>,
+    reverse: bool,
+    limit: usize,
+) -> Result<Vec<(String, Entry)>, MemoryError> {
+    let mut out = Vec::new();
+    let mut stack = vec![root];
+    while let Some(node) = stack.pop() {
+        match load_node(client, node).await? {
+            Node::Leaf(entries) => {
+                let in_range = entries
+                    .into_iter()
+                    .filter(|(k, _)| above(start, k) && below(end, k));
+                let take = limit - out.len();
+                if reverse {
+                    out.extend(in_range.rev().take(take));
+                } else {
+                    out.extend(in_range.take(take));
+                }
+                if out.len() == limit {
+                    break;
+                }
+            }
+            Node::Internal { keys, children } => {
+                let lo = match start {
+                    Bound::Included(s) | Bound::Excluded(s) => upper_bound(&keys, s),
+                    Bound::Unbounded => 0,
+                };
+                let hi = match end {
+                    Bound::Included(e) => upper_bound(&keys, e),
+                    Bound::Excluded(e) => keys.partition_point(|k| k.as_str() < e),
+                    Bound::Unbounded => keys.len(),
+                };
+                if lo > hi {
+                    continue;
+                }
+                // the stack pops last-in first, so push the far end first
+                if reverse {
+                    stack.extend(children[lo..=hi].iter().copied());
+                } else {
+                    stack.extend(children[lo..=hi].iter().rev().copied());
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+async fn free_all<C: RemoteMemory>(client: &mut C, ids: impl IntoIterator<Item = u64>) {
+    for id in ids {
+        let _ = client.free(id).await;
+    }
+}
+
+/// Rewrites the path from `root` down to the leaf holding `key`, setting or
+/// removing its entry. Returns the unchanged root if the update is a no-op.
+async fn cow_update<C: RemoteMemory>(
+    client: &mut C,
+    root: Ref,
+    key: &str,
+    entry: Option<Entry>,
+) -> Result<CowUpdate, MemoryError> {
+    // descend, remembering each internal node and the child taken
+    let mut path = Vec::new();
+    let mut node = root;
+    let mut entries = loop {
+        match load_node(client, node).await? {
+            Node::Leaf(entries) => break entries,
+            Node::Internal { keys, children } => {
+                let idx = upper_bound(&keys, key);
+                let child = children[idx];
+                path.push((node, keys, children, idx));
+                node = child;
+            }
+        }
+    };
+
+    let pos = entries.binary_search_by(|(k, _)| k.as_str().cmp(key));
+    let old = match (pos, entry) {
+        (Ok(i), Some(entry)) => Some(std::mem::replace(&mut entries[i].1, entry)),
+        (Ok(i), None) => Some(entries.remove(i).1),
+        (Err(i), Some(entry)) => {
+            entries.insert(i, (key.to_string(), entry));
+            None
+        }
+        (Err(_), None) => {
+            return Ok(CowUpdate {
+                root,
+                old: None,
+                created: Vec::new(),
+                obsolete: Vec::new(),
+            })
+        }
+    };
+
+    let mut update = CowUpdate {
+        root,
+        old,
+        created: Vec::new(),
+        obsolete: vec![node],
+    };
+
+    // replacement for the child slot: the new node, plus a separator and a
+    // right sibling if it split. Empty if the node disappeared.
+    let mut replacement: Vec<(String, Ref)> = Vec::new();
+    let is_root_leaf = path.is_empty();
+    if !entries.is_empty() || is_root_leaf {
+        let right = if entries.len() > MAX_ENTRIES {
+            Some(entries.split_off(entries.len() / 2))
+        } else {
+            None
+        };
+        let left = store_node(client, &Node::Leaf(entries)).await?;
+        update.created.push(left.0);
+        replacement.push((String::new(), left));
+        if let Some(right) = right {
+            let sep = right[0].0.clone();
+            let right = store_node(client, &Node::Leaf(right)).await?;
+            update.created.push(right.0);
+            replacement.push((sep, right));
+        }
+    }
+
+    while let Some((node, mut keys, mut children, idx)) = path.pop() {
+        update.obsolete.push(node);
+        children.remove(idx);
+        match replacement.len() {
+            0 => {
+                if idx > 0 {
+                    keys.remove(idx - 1);
+                } else if !keys.is_empty() {
+                    keys.remove(0);
+                }
+            }
+            _ => {
+                children.insert(idx, replacement[0].1);
+                if let Some((sep, right)) = replacement.get(1) {
+                    children.insert(idx + 1, *right);
+                    keys.insert(idx, sep.clone());
+                }
+            }
+        }
+
+        replacement.clear();
+        if children.is_empty() {
+            continue;
+        }
+        if path.is_empty() && children.len() == 1 {
+            // collapse a root with a single child
+            replacement.push((String::new(), children[0]));
+            break;
+        }
+
+        let split = if children.len() > MAX_ENTRIES {
+            let mid = children.len() / 2;
+            let right_children = children.split_off(mid);
+            let mut right_keys = keys.split_off(mid - 1);
+            let sep = right_keys.remove(0);
+            Some((sep, right_keys, right_children))
+        } else {
+            None
+        };
+        let left = store_node(client, &Node::Internal { keys, children }).await?;
+        update.created.push(left.0);
+        replacement.push((String::new(), left));
+        if let Some((sep, keys, children)) = split {
+            let right = store_node(client, &Node::Internal { keys, children }).await?;
+            update.created.push(right.0);
+            replacement.push((sep, right));
+        }
+    }
+
+    update.root = match replacement.len() {
+        0 => {
+            let root = store_node(client, &Node::Leaf(Vec::new())).await?;
+            update.created.push(root.0);
+            root
+        }
+        1 => replacement[0].1,
+        _ => {
+            let node = Node::Internal {
+                keys: vec![replacement[1].0.clone()],
+                children: vec![replacement[0].1, replacement[1].1],
+            };
+            let root = store_node(client, &node).await?;
+            update.created.push(root.0);
+            root
+        }
+    };
+    Ok(update)
+}
+
+/// Offsets of the root slots in the header.
+fn slots() -> impl Iterator<Item = u64> {
+    (0..SLOTS).map(|slot| SLOTS_START + slot * SLOT_SIZE)
+}
+
+fn word(bytes: &[u8], at: u64) -> u64 {
+    let at = at as usize;
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Number of separator keys less than or equal to `key`, i.e. the index of
+/// the child that may contain it.
+fn upper_bound(keys: &[String], key: &str) -> usize {
+    keys.partition_point(|k| k.as_str() <= key)
+}
+
+fn above(start: Bound<&str>, key: &str) -> bool {
+    match start {
+        Bound::Included(s) => key >= s,
+        Bound::Excluded(s) => key > s,
+        Bound::Unbounded => true,
+    }
+}
+
+fn below(end: Bound<&str>, key: &str) -> bool {
+    match end {
+        Bound::Included(e) => key <= e,
+        Bound::Excluded(e) => key < e,
+        Bound::Unbounded => true,
+    }
+}
+
+async fn store_node<C: RemoteMemory>(client: &mut C, node: &Node) -> Result<Ref, MemoryError> {
+    let bytes = node.encode();
+    let id = client.allocate_memory(bytes.len() as u64).await?;
+    if let Err(e) = client.write(id, 0, bytes).await {
+        let _ = client.free(id).await;
+        return Err(e.into());
+    }
+    Ok((id, client.region_key(id)))
+}
+
+async fn load_node<C: RemoteMemory>(client: &mut C, (id, key): Ref) -> Result<Node, MemoryError> {
+    client.learn_key(id, key);
+    let size = client.get_memory_size(id).await?;
+    let bytes = client.read(id, 0, size).await?;
+    Node::decode(&bytes).ok_or(MemoryError::CorruptIndex(id))
+}
+
+impl Node {
+    // leaf:     [LEAF][count u16] count x ([key len u16][key][manifest u64][manifest key u64][version u64][expires_at u64][flags u32])
+    // internal: [INTERNAL][count u16][child u64][child key u64] (count - 1) x ([key len u16][key][child u64][child key u64])
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            Node::Leaf(entries) => {
+                out.push(LEAF);
+                out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+                for (key, entry) in entries {
+                    put_key(&mut out, key);
+                    out.extend_from_slice(&entry.manifest.to_le_bytes());
+                    out.extend_from_slice(&entry.manifest_key.to_le_bytes());
+                    out.extend_from_slice(&entry.version.to_le_bytes());
+                    out.extend_from_slice(&entry.expires_at.to_le_bytes());
+                    out.extend_from_slice(&entry.flags.to_le_bytes());
+                }
+            }
+            Node::Internal { keys, children } => {
+                out.push(INTERNAL);
+                out.extend_from_slice(&(children.len() as u16).to_le_bytes());
+                put_ref(&mut out, children[0]);
+                for (key, &child) in keys.iter().zip(&children[1..]) {
+                    put_key(&mut out, key);
+                    put_ref(&mut out, child);
+                }
+            }
+        }
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Node> {
+        let mut reader = Reader { bytes, pos: 0 };
+        let kind = reader.take(1)?[0];
+        let count = u16::from_le_bytes(reader.take(2)?.try_into().ok()?) as usize;
+        match kind {
+            LEAF => {
+                let mut entries = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let key = reader.key()?;
+                    let manifest = reader.u64()?;
+                    let manifest_key = reader.u64()?;
+                    let version = reader.u64()?;
+                    let expires_at = reader.u64()?;
+                    let flags = reader.u32()?;
+                    entries.push((
+                        key,
+                        Entry {
+                            manifest,
+                            manifest_key,
+                            version,
+                            expires_at,
+                            flags,
+                        },
+                    ));
+                }
+                Some(Node::Leaf(entries))
+            }
+            INTERNAL if count > 0 => {
+                let mut keys = Vec::with_capacity(count - 1);
+                let mut children = Vec::with_capacity(count);
+                children.push((reader.u64()?, reader.u64()?));
+                for _ in 1..count {
+                    keys.push(reader.key()?);
+                    children.push((reader.u64()?, reader.u64()?));
+                }
+                Some(Node::Internal { keys, children })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn put_ref(out: &mut Vec<u8>, (id, key): Ref) {
+    out.extend_from_slice(&id.to_le_bytes());
+    out.extend_from_slice(&key.to_le_bytes());
+}
+
+fn put_key(out: &mut Vec<u8>, key: &str) {
+    out.extend_from_slice(&(key.len() as u16).to_le_bytes());
+    out.extend_from_slice(key.as_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let slice = self.bytes.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(slice)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn key(&mut self) -> Option<String> {
+        let len = u16::from_le_bytes(self.take(2)?.try_into().ok()?) as usize;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+}
@@ -1,76 +1,1044 @@
-use crate::client::MemoryClient;
+use crate::client::RemoteMemory;
 use crate::errors::MemoryError;
-use crate::proto::memory::AllocationError;
-use std::collections::HashMap;
+use crate::index::{now_millis, BTreeIndex, Entry, Put};
+use crate::proto::memory::{DeallocationError, Pressure};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, VecDeque};
+use std::ops::{Bound, RangeBounds};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::task::JoinHandle;
 
-const HEADER_SIZE: u64 = 1024; //metadata header
-const MAX_KEY_SIZE: usize = 256;
-const MAX_VALUE_SIZE: usize = 1024;
+const DEFAULT_PAGE_SIZE: usize = 100;
+const SWEEP_PAGE_SIZE: usize = 256;
+const MANIFEST_HEADER_SIZE: usize = 16; // value length (u64) + chunk size (u32) + chunk count (u32)
+const CHUNK_REF_SIZE: usize = 16; // chunk id (u64) + its key (u64)
+const HIGH_PRESSURE_DELAY: Duration = Duration::from_millis(10); // before writing each value
+const CRITICAL_PRESSURE_DELAY: Duration = Duration::from_millis(100);
+const HIGH_PRESSURE_EVICTIONS: usize = 16; // per sweep
+const CRITICAL_PRESSURE_EVICTIONS: usize = 256;
+const MAX_STALE_RETRIES: usize = 64; // of lookups whose manifest was freed underneath them
 
-pub struct KeyValueStore {
-    client: MemoryClient,
-    //header_id: u64,
-    data: HashMap<String, (u64, u64)>, // map keys to (memory_id, offset)
+/// Limits applied by `KeyValueStore`. Values are split into regions of at
+/// most `chunk_size` bytes, which must fit within the data node's allocation
+/// cap.
+///
+/// With `evictable` set the store is a cache: values are stored in regions
+/// the data nodes may evict with that priority once they are full, after
+/// which their keys read as absent. The index itself is never evictable.
+#[derive(Clone)]
+pub struct KvConfig {
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+    pub chunk_size: usize,
+    pub evictable: Option<u32>,
 }
 
-impl KeyValueStore {
-    pub async fn new(mut client: MemoryClient) -> Result<Self, MemoryError> {
-        let _header_id = client.allocate_memory(HEADER_SIZE).await?;
+impl Default for KvConfig {
+    fn default() -> Self {
+        Self {
+            max_key_size: 256,
+            max_value_size: 64 * 1024 * 1024, // 64mb
+            chunk_size: 512 * 1024,           // 512kb
+            evictable: None,
+        }
+    }
+}
+
+/// Remaining lifetime of a key, as reported by `KeyValueStore::ttl`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiry {
+    Never,
+    In(Duration),
+}
+
+/// A value together with the metadata stored alongside it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    pub value: Vec<u8>,
+    pub flags: u32,   // opaque to the store
+    pub version: u64, // changes on every write to the key
+}
+
+struct Manifest {
+    len: u64,
+    chunk_size: u64,
+    chunks: Vec<(u64, u64)>, // ids and keys
+}
+
+/// Handle to a store. Clones share the same data and can be used
+/// concurrently from different tasks.
+#[derive(Clone)]
+pub struct KeyValueStore<C> {
+    client: C,
+    config: KvConfig,
+    index: BTreeIndex, // ordered map of keys to manifest memory_id
+}
+
+impl<C: RemoteMemory> KeyValueStore<C> {
+    pub async fn new(client: C) -> Result<Self, MemoryError> {
+        Self::with_config(client, KvConfig::default()).await
+    }
+
+    pub async fn with_config(mut client: C, config: KvConfig) -> Result<Self, MemoryError> {
+        let index = BTreeIndex::create(&mut client).await?;
         Ok(Self {
             client,
-            //header_id,
-            data: HashMap::new(),
+            config,
+            index,
         })
     }
 
-    pub async fn set(&mut self, key: &str, value: &[u8]) -> Result<(), MemoryError> {
-        if key.len() > MAX_KEY_SIZE || value.len() > MAX_VALUE_SIZE {
-            return Err(MemoryError::AllocationError(
-                AllocationError::InsufficientMemory,
-            ));
+    /// Attaches to a store created by another client, identified by the id
+    /// and key of its header region.
+    pub fn open(client: C, header_id: u64, header_key: u64) -> Self {
+        Self {
+            client,
+            config: KvConfig::default(),
+            index: BTreeIndex::open(header_id, header_key),
         }
+    }
 
-        if key.len() > MAX_KEY_SIZE || value.len() > MAX_VALUE_SIZE {
-            return Err(MemoryError::AllocationError(
-                AllocationError::InsufficientMemory,
-            ));
-        }
-        let total_size = key.len() + value.len();
-        let memory_id = self.client.allocate_memory(total_size as u64).await?;
+    /// Makes this handle a cache, storing values it writes from now on in
+    /// regions evictable with `priority`, or with `None` stops it being one.
+    pub fn evictable(mut self, priority: Option<u32>) -> Self {
+        self.config.evictable = priority;
+        self
+    }
 
-        // Write key
-        self.client
-            .write(memory_id, 0, key.as_bytes().to_vec())
-            .await?;
-        // Write value
-        self.client
-            .write(memory_id, key.len() as u64, value.to_vec())
-            .await?;
+    pub fn header_id(&self) -> u64 {
+        self.index.header_id()
+    }
 
-        if let Some((old_id, _)) = self.data.insert(key.to_string(), (memory_id, 0)) {
-            self.client.free(old_id).await?;
-        }
+    /// The key of the header region, for handing to other clients along
+    /// with `header_id`, which data nodes that require keys check.
+    pub fn header_key(&self) -> u64 {
+        self.index.header_key()
+    }
+
+    pub fn config(&self) -> &KvConfig {
+        &self.config
+    }
+
+    pub async fn set(&mut self, key: &str, value: &[u8]) -> Result<(), MemoryError> {
+        self.put(key, value, 0, 0).await?;
+        Ok(())
+    }
+
+    /// Stores `value` under `key` until `ttl` has elapsed, after which the
+    /// key reads as absent.
+    pub async fn set_with_ttl(
+        &mut self,
+        key: &str,
+        value: &[u8],
+        ttl: Duration,
+    ) -> Result<(), MemoryError> {
+        self.put(key, value, expires_at(ttl), 0).await?;
+        Ok(())
+    }
+
+    /// Stores `value` under `key` with opaque `flags` and an optional `ttl`.
+    /// Returns the version the key was written at.
+    pub async fn set_item(
+        &mut self,
+        key: &str,
+        value: &[u8],
+        flags: u32,
+        ttl: Option<Duration>,
+    ) -> Result<u64, MemoryError> {
+        self.put(key, value, ttl.map_or(0, expires_at), flags).await
+    }
+
+    /// Stores everything read from `reader` under `key`, one chunk region at a
+    /// time, followed by a manifest region listing the chunks.
+    pub async fn set_stream<R: AsyncRead + Unpin>(
+        &mut self,
+        key: &str,
+        reader: &mut R,
+    ) -> Result<(), MemoryError> {
+        self.check_key(key)?;
+        let (manifest, manifest_key) = self.stage_value(reader).await?;
+        let put = Put {
+            manifest,
+            manifest_key,
+            expires_at: 0,
+            flags: 0,
+        };
+        self.commit(&[], vec![(key.to_string(), Some(put))]).await?;
         Ok(())
     }
 
     pub async fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, MemoryError> {
-        if let Some(&(memory_id, _offset)) = self.data.get(key) {
-            let key_size = key.len() as u64;
-            let total_size = self.client.get_memory_size(memory_id).await?;
-            let value_size = total_size - key_size;
-            let value = self.client.read(memory_id, key_size, value_size).await?;
+        let mut value = Vec::new();
+        if self.get_stream(key, &mut value).await? {
             Ok(Some(value))
         } else {
             Ok(None)
         }
     }
 
+    /// Like `get`, but also returns the flags and version of the key.
+    pub async fn get_item(&mut self, key: &str) -> Result<Option<Item>, MemoryError> {
+        match self.lookup(key).await? {
+            Some((entry, manifest)) => {
+                let mut value = Vec::new();
+                if !self.read_value(key, entry, &manifest, &mut value).await? {
+                    return Ok(None);
+                }
+                Ok(Some(Item {
+                    value,
+                    flags: entry.flags,
+                    version: entry.version,
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Writes the value stored under `key` to `writer` chunk by chunk.
+    /// Returns `false` if the key does not exist, or in a cache, if part of
+    /// its value turns out to have been evicted, in which case `writer` may
+    /// have been given the chunks before it.
+    pub async fn get_stream<W: AsyncWrite + Unpin>(
+        &mut self,
+        key: &str,
+        writer: &mut W,
+    ) -> Result<bool, MemoryError> {
+        match self.lookup(key).await? {
+            Some((entry, manifest)) => self.read_value(key, entry, &manifest, writer).await,
+            None => Ok(false),
+        }
+    }
+
+    pub async fn exists(&mut self, key: &str) -> Result<bool, MemoryError> {
+        Ok(self.live_entry(key).await?.is_some())
+    }
+
     pub async fn delete(&mut self, key: &str) -> Result<bool, MemoryError> {
-        if let Some((memory_id, _)) = self.data.remove(key) {
-            self.client.free(memory_id).await?;
-            Ok(true)
-        } else {
-            Ok(false)
+        let now = now_millis();
+        let replaced = self
+            .index
+            .apply(&mut self.client, &[], &[(key.to_string(), None)])
+            .await?
+            .replaced;
+        for entry in &replaced {
+            self.free_value(entry.manifest, entry.manifest_key).await?;
+        }
+        Ok(replaced.iter().any(|entry| !entry.is_expired(now)))
+    }
+
+    /// Sets `key` to expire after `ttl`. Returns `false` if the key does not
+    /// exist.
+    pub async fn expire(&mut self, key: &str, ttl: Duration) -> Result<bool, MemoryError> {
+        self.set_expiry(key, expires_at(ttl)).await
+    }
+
+    /// Removes any expiry from `key`. Returns `false` if the key does not
+    /// exist.
+    pub async fn persist(&mut self, key: &str) -> Result<bool, MemoryError> {
+        self.set_expiry(key, 0).await
+    }
+
+    async fn set_expiry(&mut self, key: &str, expires_at: u64) -> Result<bool, MemoryError> {
+        loop {
+            let entry = match self.live_entry(key).await? {
+                Some(entry) => entry,
+                None => return Ok(false),
+            };
+            let reads = [(key.to_string(), Some(entry.version))];
+            let put = Put {
+                manifest: entry.manifest,
+                manifest_key: entry.manifest_key,
+                expires_at,
+                flags: entry.flags,
+            };
+            let writes = [(key.to_string(), Some(put))];
+            match self.index.apply(&mut self.client, &reads, &writes).await {
+                Ok(_) => return Ok(true),
+                Err(MemoryError::TransactionConflict) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Returns how long `key` has left to live, or `None` if it does not
+    /// exist.
+    pub async fn ttl(&mut self, key: &str) -> Result<Option<Expiry>, MemoryError> {
+        Ok(self.live_entry(key).await?.map(|entry| {
+            if entry.expires_at == 0 {
+                Expiry::Never
+            } else {
+                let left = entry.expires_at.saturating_sub(now_millis());
+                Expiry::In(Duration::from_millis(left))
+            }
+        }))
+    }
+
+    /// Removes every expired key and frees its regions. Returns the number of
+    /// keys reclaimed.
+    pub async fn sweep_expired(&mut self) -> Result<usize, MemoryError> {
+        let mut start = Bound::Unbounded;
+        let mut swept = 0;
+        loop {
+            let entries = self
+                .index
+                .range(
+                    &mut self.client,
+                    as_str_bound(&start),
+                    Bound::Unbounded,
+                    false,
+                    SWEEP_PAGE_SIZE,
+                )
+                .await?;
+            let now = now_millis();
+            for (key, entry) in &entries {
+                if entry.is_expired(now) && self.reclaim(key, *entry).await? {
+                    swept += 1;
+                }
+            }
+            match entries.last() {
+                Some((last, _)) if entries.len() == SWEEP_PAGE_SIZE => {
+                    start = Bound::Excluded(last.clone())
+                }
+                _ => return Ok(swept),
+            }
+        }
+    }
+
+    /// Removes up to `count` of the keys with a TTL, soonest to expire
+    /// first, as if they had expired, and frees their regions. Keys without
+    /// one are never evicted. Returns the number of keys evicted.
+    pub async fn evict_volatile(&mut self, count: usize) -> Result<usize, MemoryError> {
+        // the `count` soonest to expire seen so far, latest on top
+        let mut soonest = BinaryHeap::with_capacity(count + 1);
+        let mut candidates = HashMap::new();
+        let mut start = Bound::Unbounded;
+        loop {
+            let entries = self
+                .index
+                .range(
+                    &mut self.client,
+                    as_str_bound(&start),
+                    Bound::Unbounded,
+                    false,
+                    SWEEP_PAGE_SIZE,
+                )
+                .await?;
+            for (key, entry) in &entries {
+                if entry.expires_at != 0 {
+                    soonest.push((entry.expires_at, key.clone()));
+                    candidates.insert(key.clone(), *entry);
+                    if soonest.len() > count {
+                        if let Some((_, latest)) = soonest.pop() {
+                            candidates.remove(&latest);
+                        }
+                    }
+                }
+            }
+            match entries.last() {
+                Some((last, _)) if entries.len() == SWEEP_PAGE_SIZE => {
+                    start = Bound::Excluded(last.clone())
+                }
+                _ => break,
+            }
+        }
+        let mut evicted = 0;
+        for (key, entry) in candidates {
+            if self.reclaim(&key, entry).await? {
+                evicted += 1;
+            }
+        }
+        Ok(evicted)
+    }
+
+    /// Runs `sweep_expired` every `interval` on a separate connection, and
+    /// under high or critical memory pressure also `evict_volatile`.
+    pub fn spawn_sweeper(&self, client: C, interval: Duration) -> JoinHandle<()> {
+        let mut sweeper = KeyValueStore {
+            client,
+            config: self.config.clone(),
+            index: BTreeIndex::open(self.header_id(), self.header_key()),
+        };
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = sweeper.sweep_expired().await {
+                    log::warn!("Expiry sweep failed: {}", e);
+                }
+                let count = match sweeper.client.pressure() {
+                    Pressure::High => HIGH_PRESSURE_EVICTIONS,
+                    Pressure::Critical => CRITICAL_PRESSURE_EVICTIONS,
+                    _ => continue,
+                };
+                match sweeper.evict_volatile(count).await {
+                    Ok(0) => {}
+                    Ok(evicted) => log::info!("Evicted {} keys under memory pressure", evicted),
+                    Err(e) => log::warn!("Eviction failed: {}", e),
+                }
+            }
+        })
+    }
+
+    /// Starts an optimistic transaction. Reads record the version they saw
+    /// and writes are buffered until `Transaction::commit`.
+    pub fn transaction(&mut self) -> Transaction<'_, C> {
+        Transaction {
+            store: self,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Iterates over the keys within `range` in order.
+    pub fn scan<K: AsRef<str>, R: RangeBounds<K>>(&mut self, range: R) -> KvIter<'_, C> {
+        let start = owned_bound(range.start_bound());
+        let end = owned_bound(range.end_bound());
+        KvIter::new(self, start, end)
+    }
+
+    /// Iterates over the keys starting with `prefix` in order.
+    pub fn prefix(&mut self, prefix: &str) -> KvIter<'_, C> {
+        let end = match prefix_end(prefix) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        KvIter::new(self, Bound::Included(prefix.to_string()), end)
+    }
+
+    fn check_key(&self, key: &str) -> Result<(), MemoryError> {
+        if key.len() > self.config.max_key_size {
+            return Err(MemoryError::KeyTooLarge {
+                size: key.len(),
+                limit: self.config.max_key_size,
+            });
+        }
+        Ok(())
+    }
+
+    async fn put(
+        &mut self,
+        key: &str,
+        value: &[u8],
+        expires_at: u64,
+        flags: u32,
+    ) -> Result<u64, MemoryError> {
+        self.check_key(key)?;
+        if value.len() > self.config.max_value_size {
+            return Err(MemoryError::ValueTooLarge {
+                size: value.len(),
+                limit: self.config.max_value_size,
+            });
+        }
+        let (manifest, manifest_key) = self.stage_value(&mut &value[..]).await?;
+        let put = Put {
+            manifest,
+            manifest_key,
+            expires_at,
+            flags,
+        };
+        self.commit(&[], vec![(key.to_string(), Some(put))]).await
+    }
+
+    /// Looks up `key`, treating an expired entry as absent and reclaiming it.
+    async fn live_entry(&mut self, key: &str) -> Result<Option<Entry>, MemoryError> {
+        match self.index.get(&mut self.client, key).await? {
+            Some(entry) if entry.is_expired(now_millis()) => {
+                self.reclaim(key, entry).await?;
+                Ok(None)
+            }
+            entry => Ok(entry),
+        }
+    }
+
+    /// Deletes an expired or evicted entry unless it was rewritten in the
+    /// meantime.
+    async fn reclaim(&mut self, key: &str, entry: Entry) -> Result<bool, MemoryError> {
+        let reads = [(key.to_string(), Some(entry.version))];
+        match self.commit(&reads, vec![(key.to_string(), None)]).await {
+            Ok(_) => Ok(true),
+            Err(MemoryError::TransactionConflict) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Finds the live entry for `key` and reads its manifest, retrying a
+    /// while if concurrent writers free the manifest in between. A key
+    /// whose manifest was evicted is reclaimed and reads as absent.
+    async fn lookup(&mut self, key: &str) -> Result<Option<(Entry, Manifest)>, MemoryError> {
+        let mut retries = 0;
+        loop {
+            let entry = match self.live_entry(key).await? {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+            match self.read_manifest(entry.manifest, entry.manifest_key).await {
+                Ok(manifest) => return Ok(Some((entry, manifest))),
+                Err(e) if e.is_stale() && retries < MAX_STALE_RETRIES => retries += 1,
+                Err(e) if e.is_evicted() => {
+                    self.reclaim(key, entry).await?;
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Reads the value of `key` into `writer`, or if one of its chunks was
+    /// evicted, reclaims the key and returns `false`.
+    async fn read_value<W: AsyncWrite + Unpin>(
+        &mut self,
+        key: &str,
+        entry: Entry,
+        manifest: &Manifest,
+        writer: &mut W,
+    ) -> Result<bool, MemoryError> {
+        match self.read_chunks(manifest, writer).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.is_evicted() => {
+                self.reclaim(key, entry).await?;
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn read_chunks<W: AsyncWrite + Unpin>(
+        &mut self,
+        manifest: &Manifest,
+        writer: &mut W,
+    ) -> Result<(), MemoryError> {
+        let mut remaining = manifest.len;
+        for &(id, key) in &manifest.chunks {
+            let chunk_len = remaining.min(manifest.chunk_size);
+            self.client.learn_key(id, key);
+            let chunk = self.client.read(id, 0, chunk_len).await?;
+            writer.write_all(&chunk).await?;
+            remaining -= chunk_len;
+        }
+        writer.flush().await?;
+        Ok(())
+    }
+
+    /// Writes a value into chunk and manifest regions that are not yet
+    /// reachable from the index. Returns the manifest's id and key. Under
+    /// high or critical memory pressure it waits a little first, to leave
+    /// the sweeper time to make room before allocations start failing.
+    async fn stage_value<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<(u64, u64), MemoryError> {
+        match self.client.pressure() {
+            Pressure::High => tokio::time::sleep(HIGH_PRESSURE_DELAY).await,
+            Pressure::Critical => tokio::time::sleep(CRITICAL_PRESSURE_DELAY).await,
+            _ => {}
+        }
+        let mut chunks = Vec::new();
+        let manifest = match self.write_chunks(reader, &mut chunks).await {
+            Ok(len) => self.write_manifest(len, &chunks).await,
+            Err(e) => Err(e),
+        };
+        if manifest.is_err() {
+            for &(id, _) in &chunks {
+                let _ = self.client.free(id).await;
+            }
+        }
+        manifest
+    }
+
+    /// Publishes staged manifests through the index, then frees the values
+    /// they replaced. On failure the staged values are freed instead. Returns
+    /// the version the writes were stored under.
+    async fn commit(
+        &mut self,
+        reads: &[(String, Option<u64>)],
+        writes: Vec<(String, Option<Put>)>,
+    ) -> Result<u64, MemoryError> {
+        let mut result = self.index.apply(&mut self.client, reads, &writes).await;
+        // writes that read nothing can't conflict, only keep losing the
+        // root to other writers, so they go on until they win
+        while reads.is_empty() && matches!(result, Err(MemoryError::TransactionConflict)) {
+            result = self.index.apply(&mut self.client, reads, &writes).await;
         }
+        match result {
+            Ok(applied) => {
+                for entry in applied.replaced {
+                    self.free_value(entry.manifest, entry.manifest_key).await?;
+                }
+                Ok(applied.version)
+            }
+            // the values may be published, so they have to stay
+            Err(e @ MemoryError::CommitUnknown(_)) => Err(e),
+            Err(e) => {
+                for put in writes.into_iter().filter_map(|(_, put)| put) {
+                    let _ = self.free_value(put.manifest, put.manifest_key).await;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Copies `reader` into freshly allocated chunk regions, recording their
+    /// ids and keys in `chunks`. Returns the total number of bytes written.
+    async fn write_chunks<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+        chunks: &mut Vec<(u64, u64)>,
+    ) -> Result<u64, MemoryError> {
+        let mut len = 0;
+        let mut buf = vec![0u8; self.config.chunk_size];
+        loop {
+            let mut filled = 0;
+            while filled < buf.len() {
+                let n = reader.read(&mut buf[filled..]).await?;
+                if n == 0 {
+                    break;
+                }
+                filled += n;
+            }
+            if filled == 0 {
+                return Ok(len as u64);
+            }
+
+            len += filled;
+            if len > self.config.max_value_size {
+                return Err(MemoryError::ValueTooLarge {
+                    size: len,
+                    limit: self.config.max_value_size,
+                });
+            }
+
+            let id = self.allocate_value(filled as u64).await?;
+            chunks.push((id, self.client.region_key(id)));
+            self.client.write(id, 0, buf[..filled].to_vec()).await?;
+
+            if filled < buf.len() {
+                return Ok(len as u64);
+            }
+        }
+    }
+
+    async fn write_manifest(
+        &mut self,
+        len: u64,
+        chunks: &[(u64, u64)],
+    ) -> Result<(u64, u64), MemoryError> {
+        let mut manifest = Vec::with_capacity(MANIFEST_HEADER_SIZE + chunks.len() * CHUNK_REF_SIZE);
+        manifest.extend_from_slice(&len.to_le_bytes());
+        manifest.extend_from_slice(&(self.config.chunk_size as u32).to_le_bytes());
+        manifest.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+        for (id, key) in chunks {
+            manifest.extend_from_slice(&id.to_le_bytes());
+            manifest.extend_from_slice(&key.to_le_bytes());
+        }
+
+        let id = self.allocate_value(manifest.len() as u64).await?;
+        if let Err(e) = self.client.write(id, 0, manifest).await {
+            let _ = self.client.free(id).await;
+            return Err(e.into());
+        }
+        Ok((id, self.client.region_key(id)))
+    }
+
+    async fn read_manifest(&mut self, id: u64, key: u64) -> Result<Manifest, MemoryError> {
+        self.client.learn_key(id, key);
+        let size = self.client.get_memory_size(id).await?;
+        let manifest = self.client.read(id, 0, size).await?;
+        if manifest.len() < MANIFEST_HEADER_SIZE {
+            return Err(MemoryError::CorruptManifest(id));
+        }
+
+        let len = u64::from_le_bytes(manifest[0..8].try_into().unwrap());
+        let chunk_size = u32::from_le_bytes(manifest[8..12].try_into().unwrap()) as u64;
+        let count = u32::from_le_bytes(manifest[12..16].try_into().unwrap()) as usize;
+        let refs = &manifest[MANIFEST_HEADER_SIZE..];
+        if refs.len() != count * CHUNK_REF_SIZE {
+            return Err(MemoryError::CorruptManifest(id));
+        }
+        let chunks = refs
+            .chunks_exact(CHUNK_REF_SIZE)
+            .map(|b| {
+                let id = u64::from_le_bytes(b[..8].try_into().unwrap());
+                (id, u64::from_le_bytes(b[8..].try_into().unwrap()))
+            })
+            .collect();
+        Ok(Manifest {
+            len,
+            chunk_size,
+            chunks,
+        })
+    }
+
+    /// Allocates a region for part of a value, evictable in a cache.
+    async fn allocate_value(&mut self, size: u64) -> Result<u64, MemoryError> {
+        let id = match self.config.evictable {
+            Some(priority) => self.client.allocate_evictable(size, priority).await?,
+            None => self.client.allocate_memory(size).await?,
+        };
+        Ok(id)
+    }
+
+    /// Frees a value's regions, skipping any that were evicted. A value
+    /// whose manifest was evicted leaves its chunks for the data nodes to
+    /// evict in turn.
+    async fn free_value(&mut self, manifest_id: u64, manifest_key: u64) -> Result<(), MemoryError> {
+        let manifest = match self.read_manifest(manifest_id, manifest_key).await {
+            Ok(manifest) => manifest,
+            Err(e) if e.is_evicted() => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let regions = manifest
+            .chunks
+            .into_iter()
+            .chain([(manifest_id, manifest_key)]);
+        for (id, key) in regions {
+            self.client.learn_key(id, key);
+            match self.client.free(id).await {
+                Ok(()) | Err(DeallocationError::DeallocationRegionEvicted) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Async iterator over a key range, fetching the index one page at a time.
+pub struct KvIter<'a, C> {
+    store: &'a mut KeyValueStore<C>,
+    start: Bound<String>,
+    end: Bound<String>,
+    reverse: bool,
+    page_size: usize,
+    page: VecDeque<(String, Entry)>,
+    exhausted: bool,
+}
+
+impl<'a, C: RemoteMemory> KvIter<'a, C> {
+    fn new(store: &'a mut KeyValueStore<C>, start: Bound<String>, end: Bound<String>) -> Self {
+        Self {
+            store,
+            start,
+            end,
+            reverse: false,
+            page_size: DEFAULT_PAGE_SIZE,
+            page: VecDeque::new(),
+            exhausted: false,
+        }
+    }
+
+    /// Iterate from the end of the range towards the start.
+    pub fn reverse(mut self) -> Self {
+        self.reverse = true;
+        self
+    }
+
+    /// Number of index entries fetched per round trip.
+    pub fn page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size.max(1);
+        self
+    }
+
+    pub async fn next(&mut self) -> Result<Option<(String, Vec<u8>)>, MemoryError> {
+        // skipping keys that turn out to be gone or evicted
+        loop {
+            if self.page.is_empty() {
+                self.fetch_page().await?;
+            }
+            let Some((key, entry)) = self.page.pop_front() else {
+                return Ok(None);
+            };
+            let mut value = Vec::new();
+            let read = match self
+                .store
+                .read_manifest(entry.manifest, entry.manifest_key)
+                .await
+            {
+                Ok(manifest) => {
+                    self.store
+                        .read_value(&key, entry, &manifest, &mut value)
+                        .await?
+                }
+                // overwritten since the page was fetched, read the new value
+                Err(e) if e.is_stale() => match self.store.get(&key).await? {
+                    Some(current) => {
+                        value = current;
+                        true
+                    }
+                    None => false,
+                },
+                Err(e) if e.is_evicted() => {
+                    self.store.reclaim(&key, entry).await?;
+                    false
+                }
+                Err(e) => return Err(e),
+            };
+            if read {
+                return Ok(Some((key, value)));
+            }
+        }
+    }
+
+    /// Like `next`, but skips reading the value.
+    pub async fn next_key(&mut self) -> Result<Option<String>, MemoryError> {
+        if self.page.is_empty() {
+            self.fetch_page().await?;
+        }
+        Ok(self.page.pop_front().map(|(key, _)| key))
+    }
+
+    /// Returns the next `page_size` keys and values, or an empty page once
+    /// the range is exhausted.
+    pub async fn next_page(&mut self) -> Result<Vec<(String, Vec<u8>)>, MemoryError> {
+        let mut out = Vec::new();
+        while out.len() < self.page_size {
+            match self.next().await? {
+                Some(item) => out.push(item),
+                None => break,
+            }
+        }
+        Ok(out)
+    }
+
+    async fn fetch_page(&mut self) -> Result<(), MemoryError> {
+        if self.exhausted {
+            return Ok(());
+        }
+        let entries = self
+            .store
+            .index
+            .range(
+                &mut self.store.client,
+                as_str_bound(&self.start),
+                as_str_bound(&self.end),
+                self.reverse,
+                self.page_size,
+            )
+            .await?;
+        if entries.len() < self.page_size {
+            self.exhausted = true;
+        }
+        // resume after the last key seen
+        if let Some((last, _)) = entries.last() {
+            if self.reverse {
+                self.end = Bound::Excluded(last.clone());
+            } else {
+                self.start = Bound::Excluded(last.clone());
+            }
+        }
+        let now = now_millis();
+        self.page.extend(
+            entries
+                .into_iter()
+                .filter(|(_, entry)| !entry.is_expired(now)),
+        );
+        Ok(())
+    }
+}
+
+fn expires_at(ttl: Duration) -> u64 {
+    let millis = ttl.as_millis().clamp(1, u64::MAX as u128) as u64;
+    now_millis().saturating_add(millis)
+}
+
+fn owned_bound<K: AsRef<str>>(bound: Bound<&K>) -> Bound<String> {
+    match bound {
+        Bound::Included(k) => Bound::Included(k.as_ref().to_string()),
+        Bound::Excluded(k) => Bound::Excluded(k.as_ref().to_string()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn as_str_bound(bound: &Bound<String>) -> Bound<&str> {
+    match bound {
+        Bound::Included(k) => Bound::Included(k.as_str()),
+        Bound::Excluded(k) => Bound::Excluded(k.as_str()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Smallest string greater than every string starting with `prefix`, or
+/// `None` if there is no such string.
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+/// Buffered multi-key update created by `KeyValueStore::transaction`. All
+/// writes become visible together at commit, which fails with
+/// `MemoryError::TransactionConflict` if any key read has changed since.
+pub struct Transaction<'a, C> {
+    store: &'a mut KeyValueStore<C>,
+    reads: HashMap<String, Option<Entry>>, // key -> entry seen, None if absent
+    writes: BTreeMap<String, Option<(Vec<u8>, WriteMeta)>>,
+}
+
+enum WriteMeta {
+    New { expires_at: u64, flags: u32 },
+    Keep, // whatever the key had when the transaction read it
+}
+
+impl<'a, C: RemoteMemory> Transaction<'a, C> {
+    pub async fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, MemoryError> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.as_ref().map(|(value, _)| value.clone()));
+        }
+
+        let found = self.store.lookup(key).await?;
+        self.record_read(key, found.as_ref().map(|(entry, _)| *entry))?;
+        match found {
+            Some((entry, manifest)) => {
+                let mut value = Vec::new();
+                // an evicted value makes the commit conflict, since the key
+                // is reclaimed after it was read
+                let read = self
+                    .store
+                    .read_value(key, entry, &manifest, &mut value)
+                    .await?;
+                Ok(read.then_some(value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Like `get`, but without reading the value.
+    pub async fn exists(&mut self, key: &str) -> Result<bool, MemoryError> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.is_some());
+        }
+
+        let entry = self.store.live_entry(key).await?;
+        self.record_read(key, entry)?;
+        Ok(entry.is_some())
+    }
+
+    /// Returns the version `key` had when this transaction first read it,
+    /// reading it now if it has not been. Writes buffered in this transaction
+    /// do not affect the result.
+    pub async fn version(&mut self, key: &str) -> Result<Option<u64>, MemoryError> {
+        let entry = match self.reads.get(key) {
+            Some(&entry) => entry,
+            None => {
+                let entry = self.store.live_entry(key).await?;
+                self.record_read(key, entry)?;
+                entry
+            }
+        };
+        Ok(entry.map(|entry| entry.version))
+    }
+
+    pub fn set(&mut self, key: &str, value: &[u8]) -> Result<(), MemoryError> {
+        self.set_item(key, value, 0, None)
+    }
+
+    pub fn set_with_ttl(
+        &mut self,
+        key: &str,
+        value: &[u8],
+        ttl: Duration,
+    ) -> Result<(), MemoryError> {
+        self.set_item(key, value, 0, Some(ttl))
+    }
+
+    /// Buffers a write of `value` with opaque `flags` and an optional `ttl`.
+    pub fn set_item(
+        &mut self,
+        key: &str,
+        value: &[u8],
+        flags: u32,
+        ttl: Option<Duration>,
+    ) -> Result<(), MemoryError> {
+        let meta = WriteMeta::New {
+            expires_at: ttl.map_or(0, expires_at),
+            flags,
+        };
+        self.buffer(key, value, meta)
+    }
+
+    /// Like `set`, but keeps the expiry and flags the key had when this
+    /// transaction read it. Keys that were not read get neither.
+    pub fn set_keep_ttl(&mut self, key: &str, value: &[u8]) -> Result<(), MemoryError> {
+        self.buffer(key, value, WriteMeta::Keep)
+    }
+
+    pub fn delete(&mut self, key: &str) {
+        self.writes.insert(key.to_string(), None);
+    }
+
+    /// Returns the version the writes were stored under, or 0 if there were
+    /// none.
+    pub async fn commit(self) -> Result<u64, MemoryError> {
+        let Transaction {
+            store,
+            reads,
+            writes,
+        } = self;
+
+        let mut staged: Vec<(String, Option<Put>)> = Vec::with_capacity(writes.len());
+        for (key, value) in writes {
+            let put = match value {
+                Some((value, meta)) => match store.stage_value(&mut value.as_slice()).await {
+                    Ok((manifest, manifest_key)) => {
+                        let (expires_at, flags) = match meta {
+                            WriteMeta::New { expires_at, flags } => (expires_at, flags),
+                            WriteMeta::Keep => reads
+                                .get(&key)
+                                .copied()
+                                .flatten()
+                                .map_or((0, 0), |entry| (entry.expires_at, entry.flags)),
+                        };
+                        Some(Put {
+                            manifest,
+                            manifest_key,
+                            expires_at,
+                            flags,
+                        })
+                    }
+                    Err(e) => {
+                        for put in staged.into_iter().filter_map(|(_, put)| put) {
+                            let _ = store.free_value(put.manifest, put.manifest_key).await;
+                        }
+                        return Err(e);
+                    }
+                },
+                None => None,
+            };
+            staged.push((key, put));
+        }
+
+        let reads: Vec<_> = reads
+            .into_iter()
+            .map(|(key, entry)| (key, entry.map(|entry| entry.version)))
+            .collect();
+        store.commit(&reads, staged).await
+    }
+
+    /// Remembers the first version seen for `key`, failing early if a later
+    /// read sees a different one.
+    fn record_read(&mut self, key: &str, entry: Option<Entry>) -> Result<(), MemoryError> {
+        let version = entry.map(|entry| entry.version);
+        let seen = self.reads.entry(key.to_string()).or_insert(entry);
+        if seen.map(|entry| entry.version) != version {
+            return Err(MemoryError::TransactionConflict);
+        }
+        Ok(())
+    }
+
+    fn buffer(&mut self, key: &str, value: &[u8], meta: WriteMeta) -> Result<(), MemoryError> {
+        self.store.check_key(key)?;
+        if value.len() > self.store.config.max_value_size {
+            return Err(MemoryError::ValueTooLarge {
+                size: value.len(),
+                limit: self.store.config.max_value_size,
+            });
+        }
+        self.writes
+            .insert(key.to_string(), Some((value.to_vec(), meta)));
+        Ok(())
     }
 }
@@ -0,0 +1,13 @@
+pub mod capability;
+pub mod client;
+pub mod cluster;
+pub mod credentials;
+pub mod directory;
+pub mod erasure;
+pub mod errors;
+pub mod far;
+pub mod index;
+pub mod kv;
+pub mod memcached;
+pub mod proto;
+pub mod resp;
@@ -1,14 +1,361 @@
-mod client;
-mod errors;
-mod kv;
-mod proto;
+use cn::client::{RemoteMemory, ReplicaMode, Sharing};
+use cn::cluster::{ClusterClient, NodeConfig};
+use cn::credentials::Credentials;
+use cn::directory::DirectoryClient;
+use cn::errors::MemoryError;
+use cn::far::FarPtr;
+use cn::kv::KeyValueStore;
+use cn::proto::memory::AccessMode;
+use cn::{capability, memcached, resp};
+use std::time::Duration;
 
-use crate::kv::KeyValueStore;
-use client::MemoryClient;
+const DEFAULT_DN_ADDR: &str = "http://[::1]:50051";
+const DEFAULT_RESP_ADDR: &str = "127.0.0.1:6379";
+const DEFAULT_MEMCACHED_ADDR: &str = "127.0.0.1:11211";
+
+/// Usage: cn [demo | resp | memcached | stats | rebalance | drain --node <url>
+///           | share --region <handle> --principal <name>
+///             --access read|read-write|none|owner]
+///           [--dn <url>[,<weight>] [--backup <url>... | --chain <url>...]]...
+///           [--listen <addr>] [--store <header id>[:<key>] | --store /<name>]
+///           [--cache <priority>]
+///           [--coordinator <url>] [--directory <url>]
+///           [--tls-ca <pem>] [--tls-cert <pem> --tls-key <pem>]
+///           [--token <token>] [--failover-token <token>]
+///
+/// Giving `--dn` more than once pools the data nodes into a cluster. Each
+/// `--backup` adds a replica to the `--dn` before it, to fail over to in
+/// order, and each `--chain` adds the next node of its chain instead.
+/// Servers given a `--coordinator` place new regions away from the nodes it
+/// reports as down. A store given by name is looked up in the directory,
+/// served by the first data node unless given with `--directory`, and
+/// created under that name if there is none yet. With `--cache` the store
+/// is a cache, its values evictable with that priority once the data nodes
+/// are full, lower priorities first, after which their keys read as absent.
+///
+/// Data nodes at `https` URLs are reached over TLS, trusting the CA in
+/// `--tls-ca`, and presenting the client certificate in `--tls-cert` and
+/// `--tls-key` to nodes that require one. Nodes that require a token are
+/// given the one in `--token`; regions then belong to it, and `rebalance`
+/// and `drain` need an admin's. Failing over needs a replicator's or an
+/// admin's, and uses the one in `--failover-token` if there is one.
+/// Compute nodes sharing a store through such nodes have to act as the
+/// same principal, with its token or another given to the same name. Nodes
+/// that require region keys also need the key of the store's header, which
+/// is printed after its id as `<id>:<key>` and kept with its name in the
+/// directory; the store keeps the keys of its other regions itself.
+/// Requests a node throttles for going over its rate limits are retried
+/// after the wait it asks for, a few times before giving up.
+///
+/// `stats` reports what each data node holds, overall, for each of its
+/// tenants and, for nodes that spill to disk, in each tier with its hit
+/// rate, and needs an admin's token. `rebalance` migrates regions between
+/// the data nodes until each holds its weight's share of the bytes, while
+/// they stay in use. `drain` empties the node given with `--node` onto the
+/// others, reporting progress until it is safe to stop. `share` changes who
+/// may use the region with the given handle besides its owner, or with
+/// `--access owner` hands it over, so another compute node can take on
+/// what this one built without copying it.
+/// Prints what the library logs, warnings and errors to stderr and the
+/// rest to stdout.
+struct Printer;
+
+impl log::Log for Printer {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() <= log::Level::Warn {
+            eprintln!("{}", record.args());
+        } else {
+            println!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let client = MemoryClient::new("http://[::1]:50051".to_string()).await?;
+    log::set_logger(&Printer).map_err(|e| e.to_string())?;
+    log::set_max_level(log::LevelFilter::Info);
+    let mut args = std::env::args().skip(1);
+    let mode = args.next().unwrap_or_else(|| "demo".to_string());
+    let mut nodes = Vec::new();
+    let mut listen = None;
+    let mut store_id = None;
+    let mut cache = None;
+    let mut coordinator = None;
+    let mut directory = None;
+    let mut drained = None;
+    let mut tls_ca = None;
+    let mut tls_cert = None;
+    let mut tls_key = None;
+    let mut token = None;
+    let mut failover_token = None;
+    let mut region = None;
+    let mut principal = None;
+    let mut access = None;
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or(format!("{} needs a value", arg))?;
+        match arg.as_str() {
+            "--dn" => nodes.push(parse_node(&value)?),
+            "--backup" | "--chain" => {
+                let node = nodes
+                    .last_mut()
+                    .ok_or(format!("{} needs a --dn before it", arg))?;
+                let replication = match arg.as_str() {
+                    "--chain" => ReplicaMode::Chain,
+                    _ => ReplicaMode::PrimaryBackup,
+                };
+                if !node.backups.is_empty() && node.replication != replication {
+                    return Err("A node can't have both backups and a chain".into());
+                }
+                node.replication = replication;
+                node.backups.push(value);
+            }
+            "--listen" => listen = Some(value),
+            "--store" => store_id = Some(value),
+            "--cache" => cache = Some(value.parse::<u32>()?),
+            "--coordinator" => coordinator = Some(value),
+            "--directory" => directory = Some(value),
+            "--node" => drained = Some(value),
+            "--tls-ca" => tls_ca = Some(value),
+            "--tls-cert" => tls_cert = Some(value),
+            "--tls-key" => tls_key = Some(value),
+            "--token" => token = Some(value),
+            "--failover-token" => failover_token = Some(value),
+            "--region" => region = Some(value.parse::<u64>()?),
+            "--principal" => principal = Some(value),
+            "--access" => access = Some(value),
+            _ => return Err(format!("Unknown option {}", arg).into()),
+        }
+    }
+    if nodes.is_empty() {
+        nodes.push(parse_node(DEFAULT_DN_ADDR)?);
+    }
+    let directory = directory.unwrap_or_else(|| nodes[0].addr.clone());
+    let identity = match (&tls_cert, &tls_key) {
+        (Some(cert), Some(key)) => Some((cert.as_str(), key.as_str())),
+        (None, None) => None,
+        _ => return Err("--tls-cert and --tls-key go together".into()),
+    };
+    let mut credentials = Credentials::new(tls_ca.as_deref(), identity, token.as_deref())?;
+    if let Some(token) = failover_token {
+        credentials = credentials.with_failover_token(&token)?;
+    }
+    for node in &mut nodes {
+        node.credentials = credentials.clone();
+    }
+
+    match mode.as_str() {
+        "demo" => demo(nodes, directory, &credentials).await,
+        "resp" => {
+            let store = open_store(nodes, store_id, coordinator, directory, &credentials)
+                .await?
+                .evictable(cache);
+            let addr = listen.unwrap_or_else(|| DEFAULT_RESP_ADDR.to_string());
+            resp::serve(&addr, store).await?;
+            Ok(())
+        }
+        "memcached" => {
+            let store = open_store(nodes, store_id, coordinator, directory, &credentials)
+                .await?
+                .evictable(cache);
+            let addr = listen.unwrap_or_else(|| DEFAULT_MEMCACHED_ADDR.to_string());
+            memcached::serve(&addr, store).await?;
+            Ok(())
+        }
+        "stats" => {
+            let mut cluster = ClusterClient::connect(nodes.clone()).await?;
+            for (node, usage) in nodes.iter().zip(cluster.usage().await) {
+                let usage = match usage {
+                    Ok(usage) => usage,
+                    Err(e) => {
+                        println!("{}: {:?}", node.addr, e);
+                        continue;
+                    }
+                };
+                let used: u64 = usage.regions.iter().map(|&(_, size)| size).sum();
+                let draining = if usage.draining { ", draining" } else { "" };
+                println!(
+                    "{}: {} bytes in {} regions{}",
+                    node.addr,
+                    used,
+                    usage.regions.len(),
+                    draining
+                );
+                for tenant in usage.tenants {
+                    println!(
+                        "  {}: {}/{} bytes, {}/{} regions",
+                        tenant.name,
+                        tenant.used_bytes,
+                        limit(tenant.max_bytes),
+                        tenant.regions,
+                        limit(tenant.max_regions)
+                    );
+                }
+                let hits: u64 = usage.tiers.iter().map(|tier| tier.hits).sum();
+                for tier in usage.tiers {
+                    println!(
+                        "  {}: {} bytes in {} regions, {} hits ({:.1}%), {} moved in",
+                        tier.name,
+                        tier.used_bytes,
+                        tier.regions,
+                        tier.hits,
+                        100.0 * tier.hits as f64 / hits.max(1) as f64,
+                        tier.moved_in
+                    );
+                }
+            }
+            Ok(())
+        }
+        "rebalance" => {
+            let mut cluster = ClusterClient::connect(nodes).await?;
+            let moved = cluster.rebalance().await.map_err(MemoryError::from)?;
+            println!("Moved {} regions", moved);
+            Ok(())
+        }
+        "drain" => {
+            let addr = drained.ok_or("drain needs a --node")?;
+            let node = nodes
+                .iter()
+                .position(|node| node.addr == addr)
+                .ok_or(format!("{} is not one of the --dn nodes", addr))?;
+            let mut cluster = ClusterClient::connect(nodes).await?;
+            let mut progress = cluster.drain(node as u16).await?;
+            while let Some(update) = progress.message().await? {
+                if update.done {
+                    println!("Moved {} regions, {} is safe to stop", update.moved, addr);
+                } else {
+                    println!(
+                        "Moved {} regions, {} left ({} bytes)",
+                        update.moved, update.regions_left, update.bytes_left
+                    );
+                }
+            }
+            Ok(())
+        }
+        "share" => {
+            let region = region.ok_or("share needs a --region")?;
+            let principal = principal.ok_or("share needs a --principal")?;
+            let sharing = match access.as_deref() {
+                Some("read") => Sharing::Grant(AccessMode::AccessRead),
+                Some("read-write") => Sharing::Grant(AccessMode::AccessReadWrite),
+                Some("none") => Sharing::Revoke,
+                Some("owner") => Sharing::Transfer,
+                _ => return Err("share needs --access read|read-write|none|owner".into()),
+            };
+            let mut cluster = ClusterClient::connect(nodes).await?;
+            cluster
+                .share(region, &principal, sharing)
+                .await
+                .map_err(MemoryError::from)?;
+            println!("Changed {}'s access to region {}", principal, region);
+            Ok(())
+        }
+        _ => Err(format!("Unknown mode {}", mode).into()),
+    }
+}
+
+/// A tenant's limit as reported by a data node, where 0 is none.
+fn limit(max: u64) -> String {
+    match max {
+        0 => "-".to_string(),
+        max => max.to_string(),
+    }
+}
+
+/// Parses `<url>[,<weight>]`, with a default weight of 1.
+fn parse_node(value: &str) -> Result<NodeConfig, Box<dyn std::error::Error>> {
+    let (addr, weight) = match value.split_once(',') {
+        Some((addr, weight)) => (addr, weight.parse()?),
+        None => (value, 1),
+    };
+    Ok(NodeConfig {
+        addr: addr.to_string(),
+        weight,
+        backups: Vec::new(),
+        replication: ReplicaMode::PrimaryBackup,
+        credentials: Credentials::default(),
+    })
+}
+
+/// Opens the store with the given header id or name on the cluster, or
+/// creates a new one, and starts sweeping its expired keys, following the
+/// nodes' pressure, and following the membership if there is a
+/// coordinator.
+async fn open_store(
+    nodes: Vec<NodeConfig>,
+    store_id: Option<String>,
+    coordinator: Option<String>,
+    directory: String,
+    credentials: &Credentials,
+) -> Result<KeyValueStore<ClusterClient>, Box<dyn std::error::Error>> {
+    let client = ClusterClient::connect(nodes).await?;
+    if let Some(url) = coordinator {
+        client.follow_membership(url, credentials)?;
+    }
+    let store = match store_id {
+        Some(name) if name.starts_with('/') => {
+            let mut directory = DirectoryClient::connect(directory, credentials).await?;
+            open_named_store(client.clone(), &mut directory, &name).await?
+        }
+        Some(handle) => {
+            let (header_id, header_key) = match handle.split_once(':') {
+                Some((id, key)) => (id.parse()?, key.parse()?),
+                None => (handle.parse()?, 0),
+            };
+            KeyValueStore::open(client.clone(), header_id, header_key)
+        }
+        None => KeyValueStore::new(client.clone()).await?,
+    };
+    println!("Using store {}:{}", store.header_id(), store.header_key());
+    client.follow_pressure();
+    store.spawn_sweeper(client, Duration::from_secs(1));
+    Ok(store)
+}
+
+/// Opens the store named `name`, creating and naming a new one if there is
+/// none. Of several compute nodes doing this at once, all end up with the
+/// same store.
+async fn open_named_store<C: RemoteMemory>(
+    client: C,
+    directory: &mut DirectoryClient,
+    name: &str,
+) -> Result<KeyValueStore<C>, MemoryError> {
+    if let Some((header_id, _, header_key)) = directory.lookup(name).await? {
+        return Ok(KeyValueStore::open(client, header_id, header_key));
+    }
+    let store = KeyValueStore::new(client.clone()).await?;
+    match directory
+        .create(name, store.header_id(), 0, store.header_key())
+        .await
+    {
+        Ok(()) => Ok(store),
+        Err(MemoryError::NameExists(_)) => {
+            // another node got there first, and our empty store is leaked
+            let (header_id, _, header_key) = directory
+                .lookup(name)
+                .await?
+                .ok_or_else(|| MemoryError::NameNotFound(name.to_string()))?;
+            Ok(KeyValueStore::open(client, header_id, header_key))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+async fn demo(
+    nodes: Vec<NodeConfig>,
+    directory: String,
+    credentials: &Credentials,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let first = nodes[0].clone();
+    let client = first.connect().await?;
     let mut kv_store = KeyValueStore::new(client).await?;
 
     kv_store.set("name", b"Alice").await?;
@@ -34,9 +381,231 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         println!("Deleted 'city' key");
     }
 
-    if let None = kv_store.get("city").await? {
+    if kv_store.get("city").await?.is_none() {
         println!("City key no longer exists");
     }
 
+    let blob: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    kv_store.set_stream("blob", &mut blob.as_slice()).await?;
+    let mut read_back = Vec::new();
+    if kv_store.get_stream("blob", &mut read_back).await? {
+        println!(
+            "Blob of {} bytes round-tripped: {}",
+            read_back.len(),
+            read_back == blob
+        );
+    }
+    kv_store.delete("blob").await?;
+
+    for i in 0..5 {
+        kv_store
+            .set(&format!("user:{}", i), format!("user {}", i).as_bytes())
+            .await?;
+    }
+    let mut users = kv_store.prefix("user:").reverse().page_size(2);
+    while let Some((key, value)) = users.next().await? {
+        println!("{} = {}", key, String::from_utf8_lossy(&value));
+    }
+    let mut range = kv_store.scan("user:1".."user:3");
+    let page = range.next_page().await?;
+    println!("Scanned {} keys from user:1 to user:3", page.len());
+
+    // a second compute node finding the same store by name
+    let mut directory = DirectoryClient::connect(directory, credentials).await?;
+    directory
+        .create(
+            "/demo/stores/main",
+            kv_store.header_id(),
+            0,
+            kv_store.header_key(),
+        )
+        .await?;
+    let other_client = first.connect().await?;
+    let mut other_store =
+        open_named_store(other_client, &mut directory, "/demo/stores/main").await?;
+    println!(
+        "Found the store by name: {}",
+        other_store.header_id() == kv_store.header_id()
+    );
+
+    let mut tx = kv_store.transaction();
+    let from = tx.get("user:0").await?.unwrap_or_default();
+    tx.set("user:1", &from)?;
+    tx.delete("user:0");
+    tx.commit().await?;
+    println!("Moved user:0 to user:1 atomically");
+
+    let mut tx = kv_store.transaction();
+    tx.get("age").await?;
+    tx.set("age", b"32")?;
+    other_store.set("age", b"40").await?;
+    match tx.commit().await {
+        Err(MemoryError::TransactionConflict) => println!("Concurrent update to age detected"),
+        other => {
+            other?;
+        }
+    }
+    if let Some(age) = kv_store.get("age").await? {
+        println!("Age after conflict: {}", String::from_utf8_lossy(&age));
+    }
+
+    let sweeper_client = first.connect().await?;
+    let sweeper = kv_store.spawn_sweeper(sweeper_client, Duration::from_millis(100));
+    kv_store
+        .set_with_ttl("session", b"token", Duration::from_secs(1))
+        .await?;
+    kv_store.expire("name", Duration::from_secs(60)).await?;
+    println!("TTL of session: {:?}", kv_store.ttl("session").await?);
+    println!("TTL of name: {:?}", kv_store.ttl("name").await?);
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    if kv_store.get("session").await?.is_none() {
+        println!("Session expired");
+    }
+    sweeper.abort();
+
+    directory.rename("/demo/stores", "/demo/archived").await?;
+    for listing in directory.list("/demo/archived").await? {
+        println!(
+            "Named store {} has header {:?}",
+            listing.name, listing.entry
+        );
+    }
+    directory.delete("/demo/archived/main").await?;
+    let left = directory.list("/demo").await?;
+    println!(
+        "Directories left under /demo: {}",
+        left.iter().filter(|listing| listing.has_children).count()
+    );
+
+    // the same store API over every node, with regions spread by weight
+    let mut cluster = ClusterClient::connect(nodes.clone()).await?;
+    let mut placed = vec![0; nodes.len()];
+    let mut regions = Vec::new();
+    for _ in 0..100 {
+        let id = cluster
+            .allocate_memory(64)
+            .await
+            .map_err(MemoryError::from)?;
+        placed[ClusterClient::locate(id).0 as usize] += 1;
+        regions.push(id);
+    }
+    for id in regions {
+        cluster.free(id).await.map_err(MemoryError::from)?;
+    }
+    println!("Placed 100 regions per node: {:?}", placed);
+
+    // read access handed to another client with the region's read-only key;
+    // writes with it are refused by nodes that require keys
+    let mut owner = ClusterClient::connect(nodes.clone()).await?;
+    let id = owner.allocate_memory(8).await.map_err(MemoryError::from)?;
+    owner
+        .write(id, 0, b"readonly".to_vec())
+        .await
+        .map_err(MemoryError::from)?;
+    let mut reader = ClusterClient::connect(nodes.clone()).await?;
+    let key = owner.key(id).map_err(MemoryError::from)?;
+    reader
+        .add_key(id, capability::read_only(key))
+        .map_err(MemoryError::from)?;
+    let shared = reader.read(id, 0, 8).await.map_err(MemoryError::from)?;
+    println!(
+        "Read with the read-only key: {}, write refused: {}",
+        String::from_utf8_lossy(&shared),
+        reader.write(id, 0, b"changed!".to_vec()).await.is_err()
+    );
+    owner.free(id).await.map_err(MemoryError::from)?;
+
+    let mut cluster_store = KeyValueStore::new(cluster).await?;
+    cluster_store
+        .set("greeting", b"hello from the cluster")
+        .await?;
+    if let Some(greeting) = cluster_store.get("greeting").await? {
+        println!("Cluster greeting: {}", String::from_utf8_lossy(&greeting));
+    }
+
+    // a region moved to another node while in use, still reachable by the
+    // handle it had before
+    if nodes.len() >= 2 {
+        let mut cluster = ClusterClient::connect(nodes.clone()).await?;
+        let (id, generation) = cluster
+            .allocate_fenced(64 * 1024)
+            .await
+            .map_err(MemoryError::from)?;
+        let pattern: Vec<u8> = (0..64 * 1024).map(|i| (i % 249) as u8).collect();
+        cluster
+            .write_fenced(id, generation, 0, pattern.clone())
+            .await
+            .map_err(MemoryError::from)?;
+        let from = ClusterClient::locate(id).0;
+        let to = (from + 1) % nodes.len() as u16;
+        let moved = cluster.migrate(id, to).await.map_err(MemoryError::from)?;
+        let read_back = cluster
+            .read_fenced(id, generation, 0, pattern.len() as u64)
+            .await
+            .map_err(MemoryError::from)?;
+        println!(
+            "Region moved from node {} to {}, old handle still reads it: {}",
+            from,
+            ClusterClient::locate(moved).0,
+            read_back == pattern
+        );
+        cluster.free(id).await.map_err(MemoryError::from)?;
+    }
+
+    // a region striped over every node, surviving the loss of any one of them
+    if nodes.len() >= 3 {
+        let (k, m) = (nodes.len() - 1, 1);
+        let mut cluster = ClusterClient::connect(nodes.clone()).await?;
+        let (id, generation) = cluster
+            .allocate_coded(4096, k, m)
+            .await
+            .map_err(MemoryError::from)?;
+        let pattern: Vec<u8> = (0..4096).map(|i| (i % 253) as u8).collect();
+        cluster
+            .write_fenced(id, generation, 100, pattern[100..].to_vec())
+            .await
+            .map_err(MemoryError::from)?;
+        let read_back = cluster
+            .read_fenced(id, generation, 100, 3996)
+            .await
+            .map_err(MemoryError::from)?;
+        println!(
+            "Coded {}+{} region round-tripped: {}",
+            k,
+            m,
+            read_back == pattern[100..]
+        );
+        cluster.free(id).await.map_err(MemoryError::from)?;
+    }
+
+    // a linked list of [next pointer][value] nodes, spread over the cluster
+    let mut cluster = ClusterClient::connect(nodes).await?;
+    let mut head = FarPtr::NULL;
+    for value in [3u32, 2, 1] {
+        let node = FarPtr::allocate(&mut cluster, FarPtr::ENCODED_LEN as u32 + 4).await?;
+        node.store(&mut cluster, &head).await?;
+        let value_ptr = node.checked_add(FarPtr::ENCODED_LEN as u32).unwrap();
+        value_ptr.write(&mut cluster, &value.to_le_bytes()).await?;
+        head = node;
+    }
+    println!(
+        "Past the end of {} is out of bounds: {}",
+        head,
+        head.checked_add(head.remaining() + 1).is_none()
+    );
+    let mut cursor = head;
+    while !cursor.is_null() {
+        let value_ptr = cursor.checked_add(FarPtr::ENCODED_LEN as u32).unwrap();
+        let value = value_ptr.read(&mut cluster, 4).await?;
+        println!(
+            "List value {} at {}",
+            u32::from_le_bytes(value[..4].try_into().unwrap()),
+            cursor
+        );
+        let next = cursor.load(&mut cluster).await?;
+        cursor.free(&mut cluster).await?;
+        cursor = next;
+    }
+
     Ok(())
 }
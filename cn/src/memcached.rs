@@ -0,0 +1,772 @@
+use crate::client::RemoteMemory;
+use crate::errors::MemoryError;
+use crate::index::now_millis;
+use crate::kv::{KeyValueStore, Transaction};
+use std::io;
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter,
+};
+use tokio::net::{TcpListener, TcpStream};
+
+const MAX_KEY_LEN: usize = 250; // same as memcached
+const MAX_LINE_LEN: u64 = 64 * 1024;
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30; // larger exptimes are unix timestamps
+const BINARY_REQUEST: u8 = 0x80;
+const BINARY_RESPONSE: u8 = 0x81;
+const BINARY_HEADER_LEN: usize = 24;
+const NO_AUTO_CREATE: u32 = 0xffff_ffff; // incr/decr exptime meaning a missing key is not created
+
+// binary protocol opcodes, quiet variants are these plus 0x10 (or GETQ/GETKQ)
+const OP_GET: u8 = 0x00;
+const OP_SET: u8 = 0x01;
+const OP_ADD: u8 = 0x02;
+const OP_REPLACE: u8 = 0x03;
+const OP_DELETE: u8 = 0x04;
+const OP_INCREMENT: u8 = 0x05;
+const OP_DECREMENT: u8 = 0x06;
+const OP_QUIT: u8 = 0x07;
+const OP_GETQ: u8 = 0x09;
+const OP_NOOP: u8 = 0x0a;
+const OP_VERSION: u8 = 0x0b;
+const OP_GETK: u8 = 0x0c;
+const OP_GETKQ: u8 = 0x0d;
+const OP_TOUCH: u8 = 0x1c;
+
+// binary protocol response statuses
+const STATUS_OK: u16 = 0x00;
+const STATUS_NOT_FOUND: u16 = 0x01;
+const STATUS_EXISTS: u16 = 0x02;
+const STATUS_TOO_LARGE: u16 = 0x03;
+const STATUS_INVALID_ARGUMENTS: u16 = 0x04;
+const STATUS_NOT_STORED: u16 = 0x05;
+const STATUS_NON_NUMERIC: u16 = 0x06;
+const STATUS_UNKNOWN_COMMAND: u16 = 0x81;
+const STATUS_INTERNAL_ERROR: u16 = 0x84;
+
+/// Serves the memcached text and binary protocols on `addr`, backed by
+/// `store`. CAS tokens are the versions the store assigns to each write.
+pub async fn serve<C: RemoteMemory>(addr: &str, store: KeyValueStore<C>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("Serving memcached on {}", addr);
+
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let connection = Connection {
+            store: store.clone(),
+        };
+        tokio::spawn(async move {
+            if let Err(e) = connection.run(socket).await {
+                log::warn!("Memcached connection error: {}", e);
+            }
+        });
+    }
+}
+
+/// How a storage command treats the value already under the key.
+#[derive(Clone, Copy)]
+enum StoreMode {
+    Set,
+    Add,      // only if absent
+    Replace,  // only if present
+    Cas(u64), // only if still at this version
+}
+
+enum Outcome {
+    Done(u64), // version of the write
+    NotStored,
+    Exists,
+    NotFound,
+}
+
+enum Counter {
+    Value(u64, u64), // new value, version of the write
+    NotFound,
+    NonNumeric,
+}
+
+#[derive(Clone, Copy)]
+enum Exptime {
+    Never,
+    In(Duration),
+    Past,
+}
+
+impl Exptime {
+    /// Seconds from now, or a unix timestamp beyond 30 days. Negative
+    /// values mean the item is already expired, and timestamps too far off
+    /// to count in milliseconds that it never does.
+    fn parse(raw: i64) -> Exptime {
+        if raw < 0 {
+            Exptime::Past
+        } else if raw == 0 {
+            Exptime::Never
+        } else if raw <= MAX_RELATIVE_EXPTIME {
+            Exptime::In(Duration::from_secs(raw as u64))
+        } else {
+            let Some(at) = (raw as u64).checked_mul(1000) else {
+                return Exptime::Never;
+            };
+            match at.checked_sub(now_millis()) {
+                Some(left) if left > 0 => Exptime::In(Duration::from_millis(left)),
+                _ => Exptime::Past,
+            }
+        }
+    }
+}
+
+struct Connection<C> {
+    store: KeyValueStore<C>,
+}
+
+impl<C: RemoteMemory> Connection<C> {
+    async fn run(mut self, socket: TcpStream) -> io::Result<()> {
+        let (reader, writer) = socket.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+        let mut out = Vec::new();
+
+        // like memcached, the first byte a client sends picks the protocol
+        let binary = reader.fill_buf().await?.first() == Some(&BINARY_REQUEST);
+        loop {
+            out.clear();
+            let open = if binary {
+                self.binary_request(&mut reader, &mut out).await?
+            } else {
+                self.text_command(&mut reader, &mut out).await?
+            };
+            writer.write_all(&out).await?;
+
+            if !open {
+                return writer.flush().await;
+            }
+            // only flush once a pipelined batch has been answered
+            if reader.buffer().is_empty() {
+                writer.flush().await?;
+            }
+        }
+    }
+
+    /// Handles one text protocol command. Returns `false` once the
+    /// connection should be closed.
+    async fn text_command<R: AsyncBufRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+        out: &mut Vec<u8>,
+    ) -> io::Result<bool> {
+        let line = match read_line(reader).await {
+            Ok(Some(line)) => line,
+            Ok(None) => return Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                out.extend_from_slice(b"CLIENT_ERROR line too long\r\n");
+                return Ok(false);
+            }
+            Err(e) => return Err(e),
+        };
+        let tokens: Vec<&[u8]> = line
+            .split(|&b| b == b' ')
+            .filter(|token| !token.is_empty())
+            .collect();
+        let (command, args) = match tokens.split_first() {
+            Some((command, args)) => (*command, args),
+            None => {
+                out.extend_from_slice(b"ERROR\r\n");
+                return Ok(true);
+            }
+        };
+
+        let reply = match command {
+            b"get" | b"gets" => self.text_get(args, command == b"gets").await,
+            b"quit" => return Ok(false),
+            b"version" => Ok(format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes()),
+            _ => {
+                let (noreply, args) = match args.split_last() {
+                    Some((last, rest)) if *last == b"noreply" => (true, rest),
+                    _ => (false, args),
+                };
+                let reply = match command {
+                    b"set" | b"add" | b"replace" | b"cas" => {
+                        self.text_store(command, args, reader).await?
+                    }
+                    b"delete" => self.text_delete(args).await,
+                    b"incr" | b"decr" => self.text_counter(args, command == b"incr").await,
+                    b"touch" => self.text_touch(args).await,
+                    b"verbosity" => Ok(b"OK\r\n".to_vec()),
+                    _ => Ok(b"ERROR\r\n".to_vec()),
+                };
+                if noreply {
+                    return Ok(true);
+                }
+                reply
+            }
+        };
+        match reply {
+            Ok(reply) => out.extend_from_slice(&reply),
+            Err(MemoryError::ValueTooLarge { .. }) => {
+                out.extend_from_slice(b"SERVER_ERROR object too large for cache\r\n")
+            }
+            Err(e) => out.extend_from_slice(format!("SERVER_ERROR {}\r\n", e).as_bytes()),
+        }
+        Ok(true)
+    }
+
+    /// get|gets <key>*
+    async fn text_get(&mut self, keys: &[&[u8]], with_cas: bool) -> Result<Vec<u8>, MemoryError> {
+        if keys.is_empty() {
+            return Ok(b"ERROR\r\n".to_vec());
+        }
+        let mut reply = Vec::new();
+        for key in keys {
+            let key = match as_key(key) {
+                Some(key) => key,
+                None => return Ok(bad_format()),
+            };
+            if let Some(item) = self.store.get_item(key).await? {
+                let header = if with_cas {
+                    format!(
+                        "VALUE {} {} {} {}\r\n",
+                        key,
+                        item.flags,
+                        item.value.len(),
+                        item.version
+                    )
+                } else {
+                    format!("VALUE {} {} {}\r\n", key, item.flags, item.value.len())
+                };
+                reply.extend_from_slice(header.as_bytes());
+                reply.extend_from_slice(&item.value);
+                reply.extend_from_slice(b"\r\n");
+            }
+        }
+        reply.extend_from_slice(b"END\r\n");
+        Ok(reply)
+    }
+
+    /// set|add|replace <key> <flags> <exptime> <bytes>, or
+    /// cas <key> <flags> <exptime> <bytes> <cas unique>, followed by a data
+    /// block. Only fails on I/O errors; store errors go in the reply.
+    async fn text_store<R: AsyncBufRead + Unpin>(
+        &mut self,
+        command: &[u8],
+        args: &[&[u8]],
+        reader: &mut R,
+    ) -> io::Result<Result<Vec<u8>, MemoryError>> {
+        let expected = if command == b"cas" { 5 } else { 4 };
+        if args.len() != expected {
+            return Ok(Ok(b"ERROR\r\n".to_vec()));
+        }
+        let parsed = (
+            as_key(args[0]),
+            parse::<u32>(args[1]),
+            parse::<i64>(args[2]),
+            parse::<usize>(args[3]),
+        );
+        let (key, flags, exptime, bytes) = match parsed {
+            (Some(key), Some(flags), Some(exptime), Some(bytes)) => (key, flags, exptime, bytes),
+            _ => return Ok(Ok(bad_format())),
+        };
+        let mode = match command {
+            b"set" => StoreMode::Set,
+            b"add" => StoreMode::Add,
+            b"replace" => StoreMode::Replace,
+            _ => match parse::<u64>(args[4]) {
+                Some(token) => StoreMode::Cas(token),
+                None => return Ok(Ok(bad_format())),
+            },
+        };
+
+        // the data block has to be consumed even if it is rejected
+        let limit = self.store.config().max_value_size;
+        if bytes > limit {
+            let mut block = reader.take(bytes as u64 + 2);
+            tokio::io::copy(&mut block, &mut tokio::io::sink()).await?;
+            return Ok(Err(MemoryError::ValueTooLarge { size: bytes, limit }));
+        }
+        let mut data = vec![0u8; bytes + 2];
+        reader.read_exact(&mut data).await?;
+        if !data.ends_with(b"\r\n") {
+            return Ok(Ok(b"CLIENT_ERROR bad data chunk\r\n".to_vec()));
+        }
+        data.truncate(bytes);
+
+        let outcome = self
+            .store_item(mode, key, &data, flags, Exptime::parse(exptime))
+            .await;
+        Ok(outcome.map(|outcome| {
+            let reply: &[u8] = match outcome {
+                Outcome::Done(_) => b"STORED\r\n",
+                Outcome::NotStored => b"NOT_STORED\r\n",
+                Outcome::Exists => b"EXISTS\r\n",
+                Outcome::NotFound => b"NOT_FOUND\r\n",
+            };
+            reply.to_vec()
+        }))
+    }
+
+    /// delete <key> [0]
+    async fn text_delete(&mut self, args: &[&[u8]]) -> Result<Vec<u8>, MemoryError> {
+        let key = match args {
+            [key] | [key, b"0"] => match as_key(key) {
+                Some(key) => key,
+                None => return Ok(bad_format()),
+            },
+            _ => return Ok(bad_format()),
+        };
+        match self.delete(key, None).await? {
+            Outcome::Done(_) => Ok(b"DELETED\r\n".to_vec()),
+            _ => Ok(b"NOT_FOUND\r\n".to_vec()),
+        }
+    }
+
+    /// incr|decr <key> <delta>
+    async fn text_counter(&mut self, args: &[&[u8]], incr: bool) -> Result<Vec<u8>, MemoryError> {
+        let (key, delta) = match args {
+            [key, delta] => match (as_key(key), parse::<u64>(delta)) {
+                (Some(key), Some(delta)) => (key, delta),
+                (Some(_), None) => {
+                    return Ok(b"CLIENT_ERROR invalid numeric delta argument\r\n".to_vec())
+                }
+                (None, _) => return Ok(bad_format()),
+            },
+            _ => return Ok(b"ERROR\r\n".to_vec()),
+        };
+        match self.counter(key, delta, incr, None).await? {
+            Counter::Value(value, _) => Ok(format!("{}\r\n", value).into_bytes()),
+            Counter::NotFound => Ok(b"NOT_FOUND\r\n".to_vec()),
+            Counter::NonNumeric => {
+                Ok(b"CLIENT_ERROR cannot increment or decrement non-numeric value\r\n".to_vec())
+            }
+        }
+    }
+
+    /// touch <key> <exptime>
+    async fn text_touch(&mut self, args: &[&[u8]]) -> Result<Vec<u8>, MemoryError> {
+        let (key, exptime) = match args {
+            [key, exptime] => match (as_key(key), parse::<i64>(exptime)) {
+                (Some(key), Some(exptime)) => (key, exptime),
+                _ => return Ok(bad_format()),
+            },
+            _ => return Ok(b"ERROR\r\n".to_vec()),
+        };
+        if self.touch(key, Exptime::parse(exptime)).await? {
+            Ok(b"TOUCHED\r\n".to_vec())
+        } else {
+            Ok(b"NOT_FOUND\r\n".to_vec())
+        }
+    }
+
+    /// Handles one binary protocol request. Returns `false` once the
+    /// connection should be closed.
+    async fn binary_request<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+        out: &mut Vec<u8>,
+    ) -> io::Result<bool> {
+        let mut header = [0u8; BINARY_HEADER_LEN];
+        match reader.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        if header[0] != BINARY_REQUEST {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bad binary request magic",
+            ));
+        }
+        let request = Request {
+            opcode: header[1],
+            opaque: u32::from_be_bytes(header[12..16].try_into().unwrap()),
+            cas: u64::from_be_bytes(header[16..24].try_into().unwrap()),
+        };
+        let key_len = u16::from_be_bytes(header[2..4].try_into().unwrap()) as usize;
+        let extras_len = header[4] as usize;
+        let body_len = u32::from_be_bytes(header[8..12].try_into().unwrap()) as usize;
+        if body_len < key_len + extras_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "binary request body shorter than its key and extras",
+            ));
+        }
+
+        // the body has to be consumed even if it is rejected
+        let limit = self.store.config().max_value_size;
+        if body_len > limit + MAX_KEY_LEN + u8::MAX as usize {
+            let mut body = reader.take(body_len as u64);
+            tokio::io::copy(&mut body, &mut tokio::io::sink()).await?;
+            request.respond(out, &Reply::status(STATUS_TOO_LARGE));
+            return Ok(true);
+        }
+        let mut body = vec![0u8; body_len];
+        reader.read_exact(&mut body).await?;
+        let (extras, rest) = body.split_at(extras_len);
+        let (key, value) = rest.split_at(key_len);
+
+        let (base, quiet) = match request.opcode {
+            OP_GETQ => (OP_GET, true),
+            OP_GETKQ => (OP_GETK, true),
+            op @ 0x11..=0x17 => (op - 0x10, true),
+            op => (op, false),
+        };
+        let reply = match self
+            .binary_dispatch(base, &request, extras, key, value)
+            .await
+        {
+            Ok(reply) => reply,
+            Err(MemoryError::ValueTooLarge { .. }) => Reply::status(STATUS_TOO_LARGE),
+            Err(e) => Reply::error(STATUS_INTERNAL_ERROR, &e.to_string()),
+        };
+
+        // quiet gets only report hits, other quiet commands only failures
+        let silent = quiet
+            && match base {
+                OP_GET | OP_GETK => reply.status == STATUS_NOT_FOUND,
+                _ => reply.status == STATUS_OK,
+            };
+        if !silent {
+            request.respond(out, &reply);
+        }
+        Ok(base != OP_QUIT)
+    }
+
+    async fn binary_dispatch(
+        &mut self,
+        opcode: u8,
+        request: &Request,
+        extras: &[u8],
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Reply, MemoryError> {
+        let key_required = matches!(
+            opcode,
+            OP_GET
+                | OP_GETK
+                | OP_SET
+                | OP_ADD
+                | OP_REPLACE
+                | OP_DELETE
+                | OP_INCREMENT
+                | OP_DECREMENT
+                | OP_TOUCH
+        );
+        let key = match as_key(key) {
+            Some(key) => key,
+            None if key_required => return Ok(Reply::status(STATUS_INVALID_ARGUMENTS)),
+            None => "",
+        };
+        let cas = (request.cas != 0).then_some(request.cas);
+
+        match (opcode, extras.len()) {
+            (OP_GET | OP_GETK, 0) if value.is_empty() => match self.store.get_item(key).await? {
+                Some(item) => Ok(Reply {
+                    status: STATUS_OK,
+                    cas: item.version,
+                    extras: item.flags.to_be_bytes().to_vec(),
+                    key: if opcode == OP_GETK {
+                        key.as_bytes().to_vec()
+                    } else {
+                        Vec::new()
+                    },
+                    value: item.value,
+                }),
+                None => Ok(Reply::status(STATUS_NOT_FOUND)),
+            },
+            (OP_SET | OP_ADD | OP_REPLACE, 8) => {
+                let flags = u32::from_be_bytes(extras[0..4].try_into().unwrap());
+                let exptime = u32::from_be_bytes(extras[4..8].try_into().unwrap());
+                let mode = match (opcode, cas) {
+                    (OP_ADD, Some(_)) => return Ok(Reply::status(STATUS_INVALID_ARGUMENTS)),
+                    (OP_ADD, None) => StoreMode::Add,
+                    (_, Some(token)) => StoreMode::Cas(token),
+                    (OP_SET, None) => StoreMode::Set,
+                    _ => StoreMode::Replace,
+                };
+                let outcome = self
+                    .store_item(mode, key, value, flags, Exptime::parse(exptime as i64))
+                    .await?;
+                Ok(Reply::outcome(outcome))
+            }
+            (OP_DELETE, 0) if value.is_empty() => Ok(Reply::outcome(self.delete(key, cas).await?)),
+            (OP_INCREMENT | OP_DECREMENT, 20) if value.is_empty() => {
+                let delta = u64::from_be_bytes(extras[0..8].try_into().unwrap());
+                let initial = u64::from_be_bytes(extras[8..16].try_into().unwrap());
+                let exptime = u32::from_be_bytes(extras[16..20].try_into().unwrap());
+                let initial = match exptime {
+                    NO_AUTO_CREATE => None,
+                    exptime => Some((initial, Exptime::parse(exptime as i64))),
+                };
+                let incr = opcode == OP_INCREMENT;
+                match self.counter(key, delta, incr, initial).await? {
+                    Counter::Value(value, version) => Ok(Reply {
+                        cas: version,
+                        value: value.to_be_bytes().to_vec(),
+                        ..Reply::status(STATUS_OK)
+                    }),
+                    Counter::NotFound => Ok(Reply::status(STATUS_NOT_FOUND)),
+                    Counter::NonNumeric => Ok(Reply::status(STATUS_NON_NUMERIC)),
+                }
+            }
+            (OP_TOUCH, 4) if value.is_empty() => {
+                let exptime = u32::from_be_bytes(extras[0..4].try_into().unwrap());
+                if self.touch(key, Exptime::parse(exptime as i64)).await? {
+                    Ok(Reply::status(STATUS_OK))
+                } else {
+                    Ok(Reply::status(STATUS_NOT_FOUND))
+                }
+            }
+            (OP_QUIT | OP_NOOP, 0) => Ok(Reply::status(STATUS_OK)),
+            (OP_VERSION, 0) => Ok(Reply {
+                value: env!("CARGO_PKG_VERSION").as_bytes().to_vec(),
+                ..Reply::status(STATUS_OK)
+            }),
+            (
+                OP_GET | OP_GETK | OP_SET | OP_ADD | OP_REPLACE | OP_DELETE | OP_INCREMENT
+                | OP_DECREMENT | OP_TOUCH | OP_QUIT | OP_NOOP | OP_VERSION,
+                _,
+            ) => Ok(Reply::status(STATUS_INVALID_ARGUMENTS)),
+            _ => Ok(Reply::status(STATUS_UNKNOWN_COMMAND)),
+        }
+    }
+
+    async fn store_item(
+        &mut self,
+        mode: StoreMode,
+        key: &str,
+        value: &[u8],
+        flags: u32,
+        exptime: Exptime,
+    ) -> Result<Outcome, MemoryError> {
+        match (mode, exptime) {
+            (StoreMode::Set, Exptime::Never) => {
+                let version = self.store.set_item(key, value, flags, None).await?;
+                return Ok(Outcome::Done(version));
+            }
+            (StoreMode::Set, Exptime::In(ttl)) => {
+                let version = self.store.set_item(key, value, flags, Some(ttl)).await?;
+                return Ok(Outcome::Done(version));
+            }
+            _ => {}
+        }
+
+        loop {
+            let mut tx = self.store.transaction();
+            let rejected = match mode {
+                StoreMode::Set => None,
+                StoreMode::Add => tx.exists(key).await?.then_some(Outcome::NotStored),
+                StoreMode::Replace => (!tx.exists(key).await?).then_some(Outcome::NotStored),
+                StoreMode::Cas(token) => match tx.version(key).await? {
+                    None => Some(Outcome::NotFound),
+                    Some(version) if version != token => Some(Outcome::Exists),
+                    Some(_) => None,
+                },
+            };
+            if let Some(outcome) = rejected {
+                return Ok(outcome);
+            }
+            write_item(&mut tx, key, value, flags, exptime)?;
+            match tx.commit().await {
+                Ok(version) => return Ok(Outcome::Done(version)),
+                Err(MemoryError::TransactionConflict) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Deletes `key`, or with a CAS token only if the key is still at that
+    /// version.
+    async fn delete(&mut self, key: &str, cas: Option<u64>) -> Result<Outcome, MemoryError> {
+        let token = match cas {
+            Some(token) => token,
+            None if self.store.delete(key).await? => return Ok(Outcome::Done(0)),
+            None => return Ok(Outcome::NotFound),
+        };
+        loop {
+            let mut tx = self.store.transaction();
+            match tx.version(key).await? {
+                None => return Ok(Outcome::NotFound),
+                Some(version) if version != token => return Ok(Outcome::Exists),
+                Some(_) => tx.delete(key),
+            }
+            match tx.commit().await {
+                Ok(_) => return Ok(Outcome::Done(0)),
+                Err(MemoryError::TransactionConflict) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Adds or subtracts `delta` from a decimal value, keeping its flags and
+    /// expiry. Increments wrap around and decrements stop at zero. A missing
+    /// key is created with `initial` if given.
+    async fn counter(
+        &mut self,
+        key: &str,
+        delta: u64,
+        incr: bool,
+        initial: Option<(u64, Exptime)>,
+    ) -> Result<Counter, MemoryError> {
+        loop {
+            let mut tx = self.store.transaction();
+            let next = match tx.get(key).await? {
+                Some(value) => {
+                    let next = match parse::<u64>(&value) {
+                        Some(current) if incr => current.wrapping_add(delta),
+                        Some(current) => current.saturating_sub(delta),
+                        None => return Ok(Counter::NonNumeric),
+                    };
+                    tx.set_keep_ttl(key, next.to_string().as_bytes())?;
+                    next
+                }
+                None => match initial {
+                    Some((initial, exptime)) => {
+                        write_item(&mut tx, key, initial.to_string().as_bytes(), 0, exptime)?;
+                        initial
+                    }
+                    None => return Ok(Counter::NotFound),
+                },
+            };
+            match tx.commit().await {
+                Ok(version) => return Ok(Counter::Value(next, version)),
+                Err(MemoryError::TransactionConflict) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Changes the expiry of `key`. Returns `false` if it does not exist.
+    async fn touch(&mut self, key: &str, exptime: Exptime) -> Result<bool, MemoryError> {
+        match exptime {
+            Exptime::Never => self.store.persist(key).await,
+            Exptime::In(ttl) => self.store.expire(key, ttl).await,
+            Exptime::Past => self.store.delete(key).await,
+        }
+    }
+}
+
+/// Header fields of a binary request that are echoed in its response.
+struct Request {
+    opcode: u8,
+    opaque: u32,
+    cas: u64,
+}
+
+impl Request {
+    fn respond(&self, out: &mut Vec<u8>, reply: &Reply) {
+        let body_len = reply.extras.len() + reply.key.len() + reply.value.len();
+        out.push(BINARY_RESPONSE);
+        out.push(self.opcode);
+        out.extend_from_slice(&(reply.key.len() as u16).to_be_bytes());
+        out.push(reply.extras.len() as u8);
+        out.push(0); // data type
+        out.extend_from_slice(&reply.status.to_be_bytes());
+        out.extend_from_slice(&(body_len as u32).to_be_bytes());
+        out.extend_from_slice(&self.opaque.to_be_bytes());
+        out.extend_from_slice(&reply.cas.to_be_bytes());
+        out.extend_from_slice(&reply.extras);
+        out.extend_from_slice(&reply.key);
+        out.extend_from_slice(&reply.value);
+    }
+}
+
+struct Reply {
+    status: u16,
+    cas: u64,
+    extras: Vec<u8>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl Reply {
+    /// A reply with no body, or with the standard message for an error
+    /// status.
+    fn status(status: u16) -> Reply {
+        let message = match status {
+            STATUS_OK => "",
+            STATUS_NOT_FOUND => "Not found",
+            STATUS_EXISTS => "Data exists for key.",
+            STATUS_TOO_LARGE => "Too large.",
+            STATUS_INVALID_ARGUMENTS => "Invalid arguments",
+            STATUS_NOT_STORED => "Not stored.",
+            STATUS_NON_NUMERIC => "Non-numeric server-side value for incr or decr",
+            STATUS_UNKNOWN_COMMAND => "Unknown command",
+            _ => "Internal error",
+        };
+        Reply::error(status, message)
+    }
+
+    fn error(status: u16, message: &str) -> Reply {
+        Reply {
+            status,
+            cas: 0,
+            extras: Vec::new(),
+            key: Vec::new(),
+            value: message.as_bytes().to_vec(),
+        }
+    }
+
+    fn outcome(outcome: Outcome) -> Reply {
+        match outcome {
+            Outcome::Done(version) => Reply {
+                cas: version,
+                ..Reply::status(STATUS_OK)
+            },
+            Outcome::NotStored => Reply::status(STATUS_NOT_STORED),
+            Outcome::Exists => Reply::status(STATUS_EXISTS),
+            Outcome::NotFound => Reply::status(STATUS_NOT_FOUND),
+        }
+    }
+}
+
+/// Buffers a write of `value`, or a delete if it would already be expired.
+fn write_item<C: RemoteMemory>(
+    tx: &mut Transaction<'_, C>,
+    key: &str,
+    value: &[u8],
+    flags: u32,
+    exptime: Exptime,
+) -> Result<(), MemoryError> {
+    match exptime {
+        Exptime::Never => tx.set_item(key, value, flags, None),
+        Exptime::In(ttl) => tx.set_item(key, value, flags, Some(ttl)),
+        Exptime::Past => {
+            tx.delete(key);
+            Ok(())
+        }
+    }
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let n = (&mut *reader)
+        .take(MAX_LINE_LEN)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// Keys are at most 250 bytes with no whitespace or control characters.
+fn as_key(arg: &[u8]) -> Option<&str> {
+    if arg.is_empty() || arg.len() > MAX_KEY_LEN || arg.iter().any(|b| b.is_ascii_control()) {
+        return None;
+    }
+    std::str::from_utf8(arg)
+        .ok()
+        .filter(|key| !key.contains(' '))
+}
+
+fn parse<T: std::str::FromStr>(arg: &[u8]) -> Option<T> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+fn bad_format() -> Vec<u8> {
+    b"CLIENT_ERROR bad command line format\r\n".to_vec()
+}
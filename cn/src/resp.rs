@@ -0,0 +1,628 @@
+use crate::client::RemoteMemory;
+use crate::errors::MemoryError;
+use crate::index::now_millis;
+use crate::kv::{Expiry, KeyValueStore};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024; // 512mb, same as redis
+const MAX_ARGS: usize = 1024 * 1024;
+const MAX_INLINE_LEN: u64 = 64 * 1024;
+const MAX_CURSORS: usize = 4096;
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Serves the Redis protocol (RESP2, or RESP3 after `HELLO 3`) on `addr`,
+/// backed by `store`. Each connection works on its own clone of the store.
+pub async fn serve<C: RemoteMemory>(addr: &str, store: KeyValueStore<C>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("Serving RESP on {}", addr);
+
+    let cursors = Arc::new(Mutex::new(Cursors::default()));
+    let next_id = AtomicU64::new(1);
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let connection = Connection {
+            id: next_id.fetch_add(1, Ordering::Relaxed),
+            store: store.clone(),
+            cursors: cursors.clone(),
+            protocol: 2,
+        };
+        tokio::spawn(async move {
+            if let Err(e) = connection.run(socket).await {
+                log::warn!("RESP connection error: {}", e);
+            }
+        });
+    }
+}
+
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Reply>),
+    Map(Vec<(Reply, Reply)>),
+}
+
+impl Reply {
+    fn encode(&self, protocol: u8, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Reply::Error(e) => out.extend_from_slice(format!("-{}\r\n", e).as_bytes()),
+            Reply::Integer(i) => out.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+            Reply::Bulk(bytes) => {
+                out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Null if protocol >= 3 => out.extend_from_slice(b"_\r\n"),
+            Reply::Null => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(protocol, out);
+                }
+            }
+            Reply::Map(pairs) => {
+                // RESP2 has no maps, so they go out as flat key/value arrays
+                if protocol >= 3 {
+                    out.extend_from_slice(format!("%{}\r\n", pairs.len()).as_bytes());
+                } else {
+                    out.extend_from_slice(format!("*{}\r\n", pairs.len() * 2).as_bytes());
+                }
+                for (key, value) in pairs {
+                    key.encode(protocol, out);
+                    value.encode(protocol, out);
+                }
+            }
+        }
+    }
+
+    fn bulk(s: &str) -> Reply {
+        Reply::Bulk(s.as_bytes().to_vec())
+    }
+}
+
+impl From<MemoryError> for Reply {
+    fn from(error: MemoryError) -> Self {
+        Reply::Error(format!("ERR {}", error))
+    }
+}
+
+/// SCAN cursors, shared by all connections so a scan can continue on any
+/// of them. A cursor remembers the last key returned.
+#[derive(Default)]
+struct Cursors {
+    next_id: u64,
+    last_keys: HashMap<u64, String>,
+    order: VecDeque<u64>,
+}
+
+impl Cursors {
+    fn insert(&mut self, last_key: String) -> u64 {
+        self.next_id += 1;
+        self.last_keys.insert(self.next_id, last_key);
+        self.order.push_back(self.next_id);
+        if self.order.len() > MAX_CURSORS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.last_keys.remove(&oldest);
+            }
+        }
+        self.next_id
+    }
+}
+
+struct Connection<C> {
+    id: u64,
+    store: KeyValueStore<C>,
+    cursors: Arc<Mutex<Cursors>>,
+    protocol: u8,
+}
+
+impl<C: RemoteMemory> Connection<C> {
+    async fn run(mut self, socket: TcpStream) -> io::Result<()> {
+        let (reader, writer) = socket.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+        let mut out = Vec::new();
+
+        loop {
+            let args = match read_command(&mut reader).await {
+                Ok(Some(args)) => args,
+                Ok(None) => return writer.flush().await,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    let reply = Reply::Error(format!("ERR Protocol error: {}", e));
+                    out.clear();
+                    reply.encode(self.protocol, &mut out);
+                    writer.write_all(&out).await?;
+                    return writer.flush().await;
+                }
+                Err(e) => return Err(e),
+            };
+            if args.is_empty() {
+                continue;
+            }
+
+            let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+            let reply = if quit {
+                Reply::Simple("OK")
+            } else {
+                match self.dispatch(&args).await {
+                    Ok(reply) | Err(reply) => reply,
+                }
+            };
+            out.clear();
+            reply.encode(self.protocol, &mut out);
+            writer.write_all(&out).await?;
+
+            if quit {
+                return writer.flush().await;
+            }
+            // only flush once a pipelined batch has been answered
+            if reader.buffer().is_empty() {
+                writer.flush().await?;
+            }
+        }
+    }
+
+    async fn dispatch(&mut self, args: &[Vec<u8>]) -> Result<Reply, Reply> {
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+        let args = &args[1..];
+        match name.as_str() {
+            "ping" => match args {
+                [] => Ok(Reply::Simple("PONG")),
+                [message] => Ok(Reply::Bulk(message.clone())),
+                _ => Err(wrong_arity(&name)),
+            },
+            "echo" => match args {
+                [message] => Ok(Reply::Bulk(message.clone())),
+                _ => Err(wrong_arity(&name)),
+            },
+            "hello" => self.hello(args),
+            "select" => match args {
+                [db] if db.as_slice() == b"0" => Ok(Reply::Simple("OK")),
+                [_] => Err(Reply::Error("ERR DB index is out of range".to_string())),
+                _ => Err(wrong_arity(&name)),
+            },
+            // enough for redis-cli and redis-benchmark to get started
+            "command" => Ok(Reply::Array(Vec::new())),
+            "config" => Ok(Reply::Map(Vec::new())),
+            "client" => Ok(Reply::Simple("OK")),
+            "get" => match args {
+                [key] => Ok(self
+                    .store
+                    .get(as_key(key)?)
+                    .await?
+                    .map_or(Reply::Null, Reply::Bulk)),
+                _ => Err(wrong_arity(&name)),
+            },
+            "set" if args.len() >= 2 => self.set(args).await,
+            "del" if !args.is_empty() => {
+                let mut deleted = 0;
+                for key in args {
+                    if self.store.delete(as_key(key)?).await? {
+                        deleted += 1;
+                    }
+                }
+                Ok(Reply::Integer(deleted))
+            }
+            "exists" if !args.is_empty() => {
+                let mut found = 0;
+                for key in args {
+                    if self.store.exists(as_key(key)?).await? {
+                        found += 1;
+                    }
+                }
+                Ok(Reply::Integer(found))
+            }
+            "mget" if !args.is_empty() => {
+                let mut values = Vec::with_capacity(args.len());
+                for key in args {
+                    let value = match std::str::from_utf8(key) {
+                        Ok(key) => self.store.get(key).await?,
+                        Err(_) => None,
+                    };
+                    values.push(value.map_or(Reply::Null, Reply::Bulk));
+                }
+                Ok(Reply::Array(values))
+            }
+            "mset" if !args.is_empty() && args.len().is_multiple_of(2) => {
+                let mut tx = self.store.transaction();
+                for pair in args.chunks(2) {
+                    tx.set(as_key(&pair[0])?, &pair[1])?;
+                }
+                tx.commit().await?;
+                Ok(Reply::Simple("OK"))
+            }
+            "expire" => match args {
+                [key, seconds] => {
+                    let key = as_key(key)?;
+                    let seconds = parse_int(seconds)?;
+                    let updated = if seconds <= 0 {
+                        self.store.delete(key).await?
+                    } else {
+                        let ttl = ttl(seconds, 1000, "expire")?;
+                        self.store.expire(key, ttl).await?
+                    };
+                    Ok(Reply::Integer(updated as i64))
+                }
+                _ => Err(wrong_arity(&name)),
+            },
+            "ttl" => match args {
+                [key] => Ok(Reply::Integer(match self.store.ttl(as_key(key)?).await? {
+                    None => -2,
+                    Some(Expiry::Never) => -1,
+                    Some(Expiry::In(left)) => ((left.as_millis() + 500) / 1000) as i64,
+                })),
+                _ => Err(wrong_arity(&name)),
+            },
+            "incr" => match args {
+                [key] => self.incr(as_key(key)?).await,
+                _ => Err(wrong_arity(&name)),
+            },
+            "keys" => match args {
+                [pattern] => {
+                    let mut keys = Vec::new();
+                    let mut iter = self.store.scan::<String, _>(..);
+                    while let Some(key) = iter.next_key().await? {
+                        if glob_match(pattern, key.as_bytes()) {
+                            keys.push(Reply::Bulk(key.into_bytes()));
+                        }
+                    }
+                    Ok(Reply::Array(keys))
+                }
+                _ => Err(wrong_arity(&name)),
+            },
+            "scan" if !args.is_empty() => self.scan(args).await,
+            "set" | "del" | "exists" | "mget" | "mset" | "scan" => Err(wrong_arity(&name)),
+            _ => Err(Reply::Error(format!("ERR unknown command '{}'", name))),
+        }
+    }
+
+    fn hello(&mut self, args: &[Vec<u8>]) -> Result<Reply, Reply> {
+        let mut protocol = self.protocol;
+        let mut options = args.iter();
+        if let Some(version) = options.next() {
+            protocol = match version.as_slice() {
+                b"2" => 2,
+                b"3" => 3,
+                _ => {
+                    return Err(Reply::Error(
+                        "NOPROTO unsupported protocol version".to_string(),
+                    ))
+                }
+            };
+        }
+        while let Some(option) = options.next() {
+            // credentials and client names are accepted but not used
+            let skip = match option.to_ascii_uppercase().as_slice() {
+                b"AUTH" => 2,
+                b"SETNAME" => 1,
+                _ => return Err(syntax_error()),
+            };
+            if options.by_ref().take(skip).count() != skip {
+                return Err(syntax_error());
+            }
+        }
+
+        self.protocol = protocol;
+        Ok(Reply::Map(vec![
+            (Reply::bulk("server"), Reply::bulk("cn")),
+            (
+                Reply::bulk("version"),
+                Reply::bulk(env!("CARGO_PKG_VERSION")),
+            ),
+            (Reply::bulk("proto"), Reply::Integer(protocol as i64)),
+            (Reply::bulk("id"), Reply::Integer(self.id as i64)),
+            (Reply::bulk("mode"), Reply::bulk("standalone")),
+            (Reply::bulk("role"), Reply::bulk("master")),
+            (Reply::bulk("modules"), Reply::Array(Vec::new())),
+        ]))
+    }
+
+    /// SET key value [NX | XX] [EX seconds | PX milliseconds | KEEPTTL]
+    async fn set(&mut self, args: &[Vec<u8>]) -> Result<Reply, Reply> {
+        let key = as_key(&args[0])?;
+        let value = &args[1];
+        let mut ttl = None;
+        let (mut nx, mut xx, mut keep_ttl) = (false, false, false);
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            match option.to_ascii_uppercase().as_slice() {
+                b"EX" | b"PX" if ttl.is_none() => {
+                    let amount = parse_int(options.next().ok_or_else(syntax_error)?)?;
+                    if amount <= 0 {
+                        return Err(Reply::Error(
+                            "ERR invalid expire time in 'set' command".to_string(),
+                        ));
+                    }
+                    let unit = if option.eq_ignore_ascii_case(b"EX") {
+                        1000
+                    } else {
+                        1
+                    };
+                    ttl = Some(self::ttl(amount, unit, "set")?);
+                }
+                b"NX" => nx = true,
+                b"XX" => xx = true,
+                b"KEEPTTL" => keep_ttl = true,
+                _ => return Err(syntax_error()),
+            }
+        }
+        if (nx && xx) || (keep_ttl && ttl.is_some()) {
+            return Err(syntax_error());
+        }
+
+        if !nx && !xx && !keep_ttl {
+            match ttl {
+                Some(ttl) => self.store.set_with_ttl(key, value, ttl).await?,
+                None => self.store.set(key, value).await?,
+            }
+            return Ok(Reply::Simple("OK"));
+        }
+
+        loop {
+            let mut tx = self.store.transaction();
+            let exists = tx.exists(key).await?;
+            if (nx && exists) || (xx && !exists) {
+                return Ok(Reply::Null);
+            }
+            match ttl {
+                Some(ttl) => tx.set_with_ttl(key, value, ttl)?,
+                None if keep_ttl => tx.set_keep_ttl(key, value)?,
+                None => tx.set(key, value)?,
+            }
+            match tx.commit().await {
+                Ok(_) => return Ok(Reply::Simple("OK")),
+                Err(MemoryError::TransactionConflict) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    async fn incr(&mut self, key: &str) -> Result<Reply, Reply> {
+        loop {
+            let mut tx = self.store.transaction();
+            let current = match tx.get(key).await? {
+                Some(value) => parse_int(&value)?,
+                None => 0,
+            };
+            let next = current.checked_add(1).ok_or_else(|| {
+                Reply::Error("ERR increment or decrement would overflow".to_string())
+            })?;
+            tx.set_keep_ttl(key, next.to_string().as_bytes())?;
+            match tx.commit().await {
+                Ok(_) => return Ok(Reply::Integer(next)),
+                Err(MemoryError::TransactionConflict) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]
+    async fn scan(&mut self, args: &[Vec<u8>]) -> Result<Reply, Reply> {
+        let cursor = parse_int(&args[0])?;
+        let mut pattern: &[u8] = b"*";
+        let mut count = DEFAULT_SCAN_COUNT;
+        let mut strings_only = true;
+        let mut options = args[1..].iter();
+        while let Some(option) = options.next() {
+            let argument = options.next().ok_or_else(syntax_error)?;
+            match option.to_ascii_uppercase().as_slice() {
+                b"MATCH" => pattern = argument,
+                b"COUNT" => match parse_int(argument)? {
+                    n if n > 0 => count = n as usize,
+                    _ => return Err(syntax_error()),
+                },
+                // every value is a string
+                b"TYPE" => strings_only = argument.eq_ignore_ascii_case(b"string"),
+                _ => return Err(syntax_error()),
+            }
+        }
+
+        let start = match cursor {
+            0 => Bound::Unbounded,
+            id => match self.cursors.lock().unwrap().last_keys.get(&(id as u64)) {
+                Some(last_key) => Bound::Excluded(last_key.clone()),
+                None => return Err(Reply::Error("ERR invalid cursor".to_string())),
+            },
+        };
+
+        let mut keys = Vec::new();
+        let mut last_key = None;
+        let mut iter = self
+            .store
+            .scan((start, Bound::<String>::Unbounded))
+            .page_size(count);
+        for _ in 0..count {
+            match iter.next_key().await? {
+                Some(key) => {
+                    if strings_only && glob_match(pattern, key.as_bytes()) {
+                        keys.push(Reply::Bulk(key.as_bytes().to_vec()));
+                    }
+                    last_key = Some(key);
+                }
+                None => {
+                    last_key = None;
+                    break;
+                }
+            }
+        }
+
+        let next = match last_key {
+            Some(key) => self.cursors.lock().unwrap().insert(key),
+            None => 0,
+        };
+        Ok(Reply::Array(vec![
+            Reply::bulk(&next.to_string()),
+            Reply::Array(keys),
+        ]))
+    }
+}
+
+/// Reads one command, either a RESP array of bulk strings or an inline
+/// command line. Returns `None` at end of stream.
+async fn read_command<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader).await? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        return Ok(Some(
+            line.split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect(),
+        ));
+    }
+
+    let count = parse_len(&line[1..], MAX_ARGS)?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let line = read_line(reader)
+            .await?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        if line.first() != Some(&b'$') {
+            return Err(protocol_error("expected '$'"));
+        }
+        let len = parse_len(&line[1..], MAX_BULK_LEN)?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string not terminated by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let n = (&mut *reader)
+        .take(MAX_INLINE_LEN)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(protocol_error("line too long"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], max: usize) -> io::Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .filter(|&len| len <= max)
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn parse_int(arg: &[u8]) -> Result<i64, Reply> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| Reply::Error("ERR value is not an integer or out of range".to_string()))
+}
+
+fn as_key(arg: &[u8]) -> Result<&str, Reply> {
+    std::str::from_utf8(arg).map_err(|_| Reply::Error("ERR keys must be valid UTF-8".to_string()))
+}
+
+fn wrong_arity(command: &str) -> Reply {
+    Reply::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        command
+    ))
+}
+
+/// A ttl of `amount` times `unit` milliseconds, or an error if the time it
+/// expires at is past what Redis can represent.
+fn ttl(amount: i64, unit: i64, command: &str) -> Result<Duration, Reply> {
+    amount
+        .checked_mul(unit)
+        .filter(|&millis| millis.checked_add(now_millis() as i64).is_some())
+        .map(|millis| Duration::from_millis(millis as u64))
+        .ok_or_else(|| Reply::Error(format!("ERR invalid expire time in '{}' command", command)))
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error("ERR syntax error".to_string())
+}
+
+/// Redis-style glob matching: `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // just past the last `*`, and where the text after what it took
+    // starts; a later `*` can take over for an earlier one, so only the
+    // last is ever returned to
+    let mut star = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, t));
+            continue;
+        }
+        match glob_one(&pattern[p..], text[t]) {
+            Some(len) => {
+                p += len;
+                t += 1;
+            }
+            None => match star {
+                Some((after, from)) => {
+                    // let the `*` take one more byte
+                    star = Some((after, from + 1));
+                    (p, t) = (after, from + 1);
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// How much of `pattern` its first element takes up, if it matches `c`.
+fn glob_one(pattern: &[u8], c: u8) -> Option<usize> {
+    match pattern.split_first()? {
+        (b'?', _) => Some(1),
+        (b'[', class) => {
+            let (negate, mut i) = match class.first() {
+                Some(b'^') => (true, 1),
+                _ => (false, 0),
+            };
+            let mut matched = false;
+            while i < class.len() && class[i] != b']' {
+                if class[i] == b'\\' && i + 1 < class.len() {
+                    matched |= class[i + 1] == c;
+                    i += 2;
+                } else if i + 2 < class.len() && class[i + 1] == b'-' && class[i + 2] != b']' {
+                    let (lo, hi) = (class[i].min(class[i + 2]), class[i].max(class[i + 2]));
+                    matched |= (lo..=hi).contains(&c);
+                    i += 3;
+                } else {
+                    matched |= class[i] == c;
+                    i += 1;
+                }
+            }
+            (i < class.len() && matched != negate).then_some(i + 2)
+        }
+        (b'\\', rest) if !rest.is_empty() => (rest[0] == c).then_some(2),
+        (&first, _) => (first == c).then_some(1),
+    }
+}
@@ -0,0 +1,47 @@
+mod common;
+
+use cn::client::RemoteMemory;
+use common::{connect, Node};
+
+const ARENA_SIZE: u64 = 256 * 1024;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn arena_reuses_and_merges_freed_blocks() {
+    let arena_size = ARENA_SIZE.to_string();
+    let node = Node::start(&["--arena", "memfd", "--arena-size", &arena_size]);
+    let mut client = connect(&node).await;
+
+    // regions of assorted sizes until the arena is full
+    let mut regions = Vec::new();
+    loop {
+        let size = 512 + (regions.len() as u64 % 7) * 1000;
+        let Ok(id) = client.allocate_memory(size).await else {
+            break;
+        };
+        let tag = (regions.len() as u64).to_le_bytes().to_vec();
+        client.write(id, 0, tag).await.unwrap();
+        regions.push((id, size));
+    }
+    assert!(regions.len() > 10, "only {} regions fit", regions.len());
+
+    // freeing every other one leaves holes that fit regions as large again
+    let mut live = Vec::new();
+    for (i, &(id, size)) in regions.iter().enumerate() {
+        if i % 2 == 0 {
+            client.free(id).await.unwrap();
+            live.push(client.allocate_memory(size).await.unwrap());
+        } else {
+            live.push(id);
+        }
+    }
+    for (i, &(id, _)) in regions.iter().enumerate().skip(1).step_by(2) {
+        let tag = client.read(id, 0, 8).await.unwrap();
+        assert_eq!(tag, (i as u64).to_le_bytes());
+    }
+
+    // once everything is freed the blocks merge back into one
+    for id in live {
+        client.free(id).await.unwrap();
+    }
+    client.allocate_memory(ARENA_SIZE / 2).await.unwrap();
+}
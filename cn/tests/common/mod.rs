@@ -0,0 +1,99 @@
+// Shared by the integration tests, each of which uses only some of it.
+#![allow(dead_code)]
+
+use cn::client::{MemoryClient, ReplicaMode};
+use cn::cluster::NodeConfig;
+use cn::credentials::Credentials;
+use tokio::runtime::{Builder, Runtime};
+
+/// A data node running in this process, on its own runtime so it can be
+/// killed without taking the test's down with it.
+pub struct Node {
+    pub url: String,
+    runtime: Option<Runtime>,
+}
+
+impl Node {
+    /// Starts a node on an ephemeral port with the given `dn` options.
+    pub fn start(args: &[&str]) -> Self {
+        let scheme = if args.contains(&"--tls-cert") {
+            "https"
+        } else {
+            "http"
+        };
+        let mut all = vec!["--listen".to_string(), "[::1]:0".to_string()];
+        all.extend(args.iter().map(|arg| arg.to_string()));
+        // a runtime can't be started from inside the test's, so on a thread
+        std::thread::spawn(move || {
+            let runtime = Builder::new_multi_thread()
+                .worker_threads(2)
+                .enable_all()
+                .build()
+                .unwrap();
+            let (addr, serving) = runtime.block_on(dn::start(all)).unwrap();
+            runtime.spawn(serving);
+            Self {
+                url: format!("{}://{}", scheme, addr),
+                runtime: Some(runtime),
+            }
+        })
+        .join()
+        .unwrap()
+    }
+
+    /// Stops the node at once, dropping its connections and everything it
+    /// holds, as a crash would.
+    pub fn kill(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+pub async fn connect(node: &Node) -> MemoryClient {
+    MemoryClient::with_backups(node.url.clone(), Vec::new(), Credentials::default())
+        .await
+        .unwrap()
+}
+
+/// How a cluster client reaches `node`, placing regions on it by `weight`.
+pub fn config(node: &Node, weight: u32, credentials: &Credentials) -> NodeConfig {
+    NodeConfig {
+        addr: node.url.clone(),
+        weight,
+        backups: Vec::new(),
+        replication: ReplicaMode::PrimaryBackup,
+        credentials: credentials.clone(),
+    }
+}
+
+/// A directory for the files a test's nodes read, removed with it.
+pub struct TempDir(pub std::path::PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("cn-test-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    /// Writes `contents` to the file `name` in the directory, returning its
+    /// path.
+    pub fn write(&self, name: &str, contents: &[u8]) -> String {
+        let path = self.0.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,135 @@
+mod common;
+
+use cn::cluster::ClusterClient;
+use cn::credentials::Credentials;
+use cn::directory::DirectoryClient;
+use cn::errors::MemoryError;
+use cn::kv::KeyValueStore;
+use common::{config, Node};
+
+async fn directory(node: &Node) -> DirectoryClient {
+    DirectoryClient::connect(node.url.clone(), &Credentials::default())
+        .await
+        .unwrap()
+}
+
+/// The names directly under `dir`, each with its handle if it has one and
+/// whether there are names under it.
+async fn list(client: &mut DirectoryClient, dir: &str) -> Vec<(String, Option<u64>, bool)> {
+    client
+        .list(dir)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|listing| {
+            let handle = listing.entry.map(|(handle, _, _)| handle);
+            (listing.name, handle, listing.has_children)
+        })
+        .collect()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn names_form_a_hierarchy_that_moves_as_a_whole() {
+    let node = Node::start(&[]);
+    let mut names = directory(&node).await;
+
+    names.create("/apps/web/sessions", 1, 7, 0).await.unwrap();
+    names.create("/apps/web", 2, 7, 0).await.unwrap();
+    names.create("/apps/batch/queue", 3, 7, 0).await.unwrap();
+    assert_eq!(
+        names.lookup("/apps/web/sessions").await.unwrap(),
+        Some((1, 7, 0))
+    );
+    assert_eq!(names.lookup("/apps/nothing").await.unwrap(), None);
+    assert!(matches!(
+        names.create("/apps/web", 4, 7, 0).await,
+        Err(MemoryError::NameExists(name)) if name == "/apps/web"
+    ));
+
+    // directories exist for as long as there are names under them
+    assert_eq!(
+        list(&mut names, "/").await,
+        [("apps".to_string(), None, true)]
+    );
+    assert_eq!(
+        list(&mut names, "/apps").await,
+        [
+            ("batch".to_string(), None, true),
+            ("web".to_string(), Some(2), true),
+        ]
+    );
+
+    // renaming takes everything under the name along, and never overwrites
+    assert!(matches!(
+        names.rename("/apps/batch", "/apps/web").await,
+        Err(MemoryError::NameExists(_))
+    ));
+    names.rename("/apps/web", "/archive/web").await.unwrap();
+    assert_eq!(names.lookup("/apps/web/sessions").await.unwrap(), None);
+    assert_eq!(
+        names.lookup("/archive/web/sessions").await.unwrap(),
+        Some((1, 7, 0))
+    );
+    assert_eq!(names.lookup("/archive/web").await.unwrap(), Some((2, 7, 0)));
+
+    // deleting a name leaves the ones under it
+    assert_eq!(names.delete("/archive/web").await.unwrap(), Some((2, 7, 0)));
+    assert_eq!(names.delete("/archive/web").await.unwrap(), None);
+    assert_eq!(
+        list(&mut names, "/archive").await,
+        [("web".to_string(), None, true)]
+    );
+    assert_eq!(
+        names.delete("/archive/web/sessions").await.unwrap(),
+        Some((1, 7, 0))
+    );
+    assert_eq!(
+        names.delete("/apps/batch/queue").await.unwrap(),
+        Some((3, 7, 0))
+    );
+    assert_eq!(list(&mut names, "/").await, []);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn compute_nodes_rendezvous_on_a_store_by_name() {
+    let nodes = [Node::start(&[]), Node::start(&[])];
+    let none = Credentials::default();
+    let cluster = || async {
+        ClusterClient::connect(vec![
+            config(&nodes[0], 1, &none),
+            config(&nodes[1], 1, &none),
+        ])
+        .await
+        .unwrap()
+    };
+
+    // of several compute nodes creating the name, exactly one wins
+    let mut racers = Vec::new();
+    for handle in 1..=8 {
+        let mut names = directory(&nodes[0]).await;
+        racers.push(tokio::spawn(async move {
+            names.create("/race", handle, 1, 0).await.is_ok()
+        }));
+    }
+    let mut won = 0;
+    for racer in racers {
+        won += racer.await.unwrap() as usize;
+    }
+    assert_eq!(won, 1);
+
+    let mut store = KeyValueStore::new(cluster().await).await.unwrap();
+    store.set("greeting", b"hello").await.unwrap();
+    let mut names = directory(&nodes[0]).await;
+    names
+        .create("/stores/main", store.header_id(), 0, store.header_key())
+        .await
+        .unwrap();
+
+    // another compute node, knowing only the name
+    let mut names = directory(&nodes[0]).await;
+    let (header, _, key) = names.lookup("/stores/main").await.unwrap().unwrap();
+    let mut found = KeyValueStore::open(cluster().await, header, key);
+    assert_eq!(found.get("greeting").await.unwrap().unwrap(), b"hello");
+    found.set("reply", b"hi").await.unwrap();
+    assert_eq!(store.get("reply").await.unwrap().unwrap(), b"hi");
+}
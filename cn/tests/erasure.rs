@@ -0,0 +1,63 @@
+mod common;
+
+use cn::client::RemoteMemory;
+use cn::cluster::ClusterClient;
+use cn::credentials::Credentials;
+use cn::proto::memory::MemoryAccessError;
+use common::{config, Node};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn coded_compare_and_swap_is_fenced_by_generation() {
+    let nodes: Vec<Node> = (0..3).map(|_| Node::start(&[])).collect();
+    let configs = nodes
+        .iter()
+        .map(|node| config(node, 1, &Credentials::default()))
+        .collect();
+    let mut cluster = ClusterClient::connect(configs).await.unwrap();
+    let (id, generation) = cluster.allocate_coded(64, 2, 1).await.unwrap();
+    let stale = if generation == u32::MAX {
+        1
+    } else {
+        generation + 1
+    };
+
+    // in the second data shard
+    assert_eq!(
+        cluster
+            .compare_and_swap_fenced(id, generation, 40, 0, 7)
+            .await
+            .unwrap(),
+        Ok(0)
+    );
+    assert_eq!(
+        cluster.compare_and_swap_fenced(id, stale, 40, 7, 9).await,
+        Err(MemoryAccessError::StaleGeneration)
+    );
+    let value = cluster.read_fenced(id, generation, 40, 8).await.unwrap();
+    assert_eq!(value, 7u64.to_le_bytes());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn coded_compare_and_swap_checks_bounds_first() {
+    let nodes: Vec<Node> = (0..3).map(|_| Node::start(&[])).collect();
+    let configs = nodes
+        .iter()
+        .map(|node| config(node, 1, &Credentials::default()))
+        .collect();
+    let mut cluster = ClusterClient::connect(configs).await.unwrap();
+
+    let (empty, generation) = cluster.allocate_coded(0, 2, 1).await.unwrap();
+    assert_eq!(
+        cluster
+            .compare_and_swap_fenced(empty, generation, 0, 0, 1)
+            .await,
+        Err(MemoryAccessError::OutOfBoundsAccess)
+    );
+    let (id, generation) = cluster.allocate_coded(64, 2, 1).await.unwrap();
+    assert_eq!(
+        cluster
+            .compare_and_swap_fenced(id, generation, u64::MAX - 3, 0, 1)
+            .await,
+        Err(MemoryAccessError::OutOfBoundsAccess)
+    );
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use cn::client::{MemoryClient, RemoteMemory};
+use cn::credentials::Credentials;
+use common::{Node, TempDir};
+
+const REGION: u64 = 1024;
+
+async fn connect(node: &Node, token: &str) -> MemoryClient {
+    let credentials = Credentials::new(None, None, Some(token)).unwrap();
+    MemoryClient::with_backups(node.url.clone(), Vec::new(), credentials)
+        .await
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn refused_allocations_evict_nothing() {
+    let dir = TempDir::new("eviction-refused");
+    let tokens = dir.write("tokens", b"cache-token cache\nsmall-token small\n");
+    let tenants = dir.write("tenants", b"cache - - cache\nsmall 1024 - small\n");
+    let capacity = (4 * REGION).to_string();
+    let node = Node::start(&[
+        "--tokens",
+        &tokens,
+        "--tenants",
+        &tenants,
+        "--capacity",
+        &capacity,
+    ]);
+
+    // fill the node with evictable regions
+    let mut cache = connect(&node, "cache-token").await;
+    let mut regions = Vec::new();
+    for i in 0..4u64 {
+        let (id, _) = cache.allocate_evictable(REGION, 1).await.unwrap();
+        cache.write(id, 0, i.to_le_bytes().to_vec()).await.unwrap();
+        regions.push(id);
+    }
+
+    // over the tenant's quota, so refused before anything is evicted
+    let mut small = connect(&node, "small-token").await;
+    assert!(small.allocate_memory(2 * REGION).await.is_err());
+    for (i, &id) in (0..4u64).zip(&regions) {
+        assert_eq!(cache.read(id, 0, 8).await.unwrap(), i.to_le_bytes());
+    }
+
+    // within it, so the least valuable region makes room
+    small.allocate_memory(REGION).await.unwrap();
+    let mut evicted = 0;
+    for &id in &regions {
+        if cache.read(id, 0, 8).await.is_err() {
+            evicted += 1;
+        }
+    }
+    assert_eq!(evicted, 1);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn nothing_is_evicted_for_regions_the_arena_cant_place() {
+    // room for exactly four regions, each a block with a header
+    let arena_size = (4096 + 4 * (REGION + 128)).to_string();
+    let capacity = (4 * REGION + REGION / 2).to_string();
+    let node = Node::start(&[
+        "--arena",
+        "memfd",
+        "--arena-size",
+        &arena_size,
+        "--capacity",
+        &capacity,
+    ]);
+    let mut client = common::connect(&node).await;
+    let mut regions = Vec::new();
+    for i in 0..4u64 {
+        let (id, _) = client.allocate_evictable(REGION, 1).await.unwrap();
+        client.write(id, 0, i.to_le_bytes().to_vec()).await.unwrap();
+        regions.push(id);
+    }
+
+    // evicting one region makes room under the capacity, but not a block
+    // large enough in the arena
+    assert!(client.allocate_memory(REGION + REGION / 2).await.is_err());
+    for (i, &id) in (0..4u64).zip(&regions) {
+        assert_eq!(client.read(id, 0, 8).await.unwrap(), i.to_le_bytes());
+    }
+
+    // while one of the same size takes the block of the one evicted
+    client.allocate_memory(REGION).await.unwrap();
+    let mut evicted = 0;
+    for &id in &regions {
+        if client.read(id, 0, 8).await.is_err() {
+            evicted += 1;
+        }
+    }
+    assert_eq!(evicted, 1);
+}
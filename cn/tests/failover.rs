@@ -0,0 +1,212 @@
+mod common;
+
+use cn::client::{MemoryClient, RemoteMemory};
+use cn::credentials::Credentials;
+use cn::proto::memory::compare_and_swap_response::Result as SwapResult;
+use cn::proto::memory::memory_client::MemoryClient as GrpcMemoryClient;
+use cn::proto::memory::{CompareAndSwapRequest, PromoteRequest};
+use common::{Node, TempDir};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+const WRITES: usize = 100;
+const KILL_AFTER: usize = 30; // writes acknowledged before a replica dies
+
+/// Allocates a region and writes its number into it `WRITES` times, while
+/// `victim` is killed once `KILL_AFTER` writes are acknowledged, then
+/// checks every acknowledged write reads back.
+async fn write_through_failure(mut client: MemoryClient, victim: Node) {
+    let acknowledged = Arc::new(AtomicUsize::new(0));
+    let killer = {
+        let acknowledged = acknowledged.clone();
+        let mut victim = victim;
+        tokio::spawn(async move {
+            while acknowledged.load(Ordering::SeqCst) < KILL_AFTER {
+                tokio::task::yield_now().await;
+            }
+            victim.kill();
+        })
+    };
+
+    let mut written = Vec::with_capacity(WRITES);
+    for i in 0..WRITES as u64 {
+        let id = client.allocate_memory(8).await.unwrap();
+        client.write(id, 0, i.to_le_bytes().to_vec()).await.unwrap();
+        written.push((id, i));
+        acknowledged.fetch_add(1, Ordering::SeqCst);
+    }
+    killer.await.unwrap();
+
+    for (id, i) in written {
+        let value = client.read(id, 0, 8).await.unwrap();
+        assert_eq!(value, i.to_le_bytes(), "write {} to region {} lost", i, id);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn backup_keeps_writes_acknowledged_before_the_primary_died() {
+    let backup = Node::start(&["--role", "backup"]);
+    let primary = Node::start(&["--backup", &backup.url]);
+    let client = MemoryClient::with_backups(
+        primary.url.clone(),
+        vec![backup.url.clone()],
+        Credentials::default(),
+    )
+    .await
+    .unwrap();
+    write_through_failure(client, primary).await;
+}
+
+/// Starts a chain of three nodes, returning them from the head.
+fn chain() -> (Node, Node, Node) {
+    let chain = ["--replication", "chain"];
+    let tail = Node::start(&[&chain[..], &["--role", "backup"]].concat());
+    let middle = Node::start(&[&chain[..], &["--role", "backup", "--backup", &tail.url]].concat());
+    let head = Node::start(
+        &[
+            &chain[..],
+            &["--backup", &middle.url, "--backup", &tail.url],
+        ]
+        .concat(),
+    );
+    (head, middle, tail)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn chain_keeps_writes_acknowledged_before_the_head_died() {
+    let (head, middle, tail) = chain();
+    let client = MemoryClient::chain(
+        head.url.clone(),
+        vec![middle.url.clone(), tail.url.clone()],
+        Credentials::default(),
+    )
+    .await
+    .unwrap();
+    write_through_failure(client, head).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn chain_keeps_writes_acknowledged_before_the_tail_died() {
+    let (head, middle, tail) = chain();
+    let client = MemoryClient::chain(
+        head.url.clone(),
+        vec![middle.url.clone(), tail.url.clone()],
+        Credentials::default(),
+    )
+    .await
+    .unwrap();
+    write_through_failure(client, tail).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn chain_keeps_writes_acknowledged_before_the_middle_died() {
+    let (head, middle, tail) = chain();
+    let client = MemoryClient::chain(
+        head.url.clone(),
+        vec![middle.url.clone(), tail.url.clone()],
+        Credentials::default(),
+    )
+    .await
+    .unwrap();
+    write_through_failure(client, middle).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn only_replicators_and_admins_fail_over() {
+    let dir = TempDir::new("failover-tokens");
+    let tokens = dir.write(
+        "tokens",
+        b"node-token dn admin\napp-token app\nfailover-token app-failover replicator\n",
+    );
+    let backup = Node::start(&["--role", "backup", "--tokens", &tokens]);
+    let mut primary = Node::start(&[
+        "--backup",
+        &backup.url,
+        "--tokens",
+        &tokens,
+        "--token",
+        "node-token",
+    ]);
+    let app = Credentials::new(None, None, Some("app-token")).unwrap();
+    let connect = |credentials: Credentials| {
+        MemoryClient::with_backups(primary.url.clone(), vec![backup.url.clone()], credentials)
+    };
+    let mut plain = connect(app.clone()).await.unwrap();
+    let mut replicator = connect(app.with_failover_token("failover-token").unwrap())
+        .await
+        .unwrap();
+
+    let id = plain.allocate_memory(8).await.unwrap();
+    plain.write(id, 0, b"replicas".to_vec()).await.unwrap();
+    primary.kill();
+    assert!(plain.write(id, 0, b"promoted".to_vec()).await.is_err());
+    assert_eq!(replicator.read(id, 0, 8).await.unwrap(), b"replicas");
+    replicator.write(id, 0, b"promoted".to_vec()).await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn retried_swaps_are_not_done_again_even_after_failover() {
+    let backup = Node::start(&["--role", "backup"]);
+    let mut primary = Node::start(&["--backup", &backup.url]);
+    let mut client =
+        MemoryClient::with_backups(primary.url.clone(), Vec::new(), Credentials::default())
+            .await
+            .unwrap();
+    let id = client.allocate_memory(8).await.unwrap();
+    let swap = CompareAndSwapRequest {
+        id,
+        offset: 0,
+        expected: 0,
+        desired: 5,
+        request_id: 42,
+        ..Default::default()
+    };
+    let swapped = |response: tonic::Response<_>| match response.into_inner() {
+        cn::proto::memory::CompareAndSwapResponse {
+            result: Some(SwapResult::Previous(previous)),
+        } => previous,
+        other => panic!("swap failed: {:?}", other),
+    };
+
+    let mut raw = GrpcMemoryClient::connect(primary.url.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        swapped(raw.compare_and_swap(swap.clone()).await.unwrap()),
+        0
+    );
+    // the word goes back, and a retry of the swap reports it done without
+    // doing it again
+    client
+        .write(id, 0, 0u64.to_le_bytes().to_vec())
+        .await
+        .unwrap();
+    assert_eq!(
+        swapped(raw.compare_and_swap(swap.clone()).await.unwrap()),
+        0
+    );
+    assert_eq!(client.read(id, 0, 8).await.unwrap(), 0u64.to_le_bytes());
+
+    // the backup remembers swaps forwarded to it for once it takes over
+    let second = CompareAndSwapRequest {
+        request_id: 43,
+        ..swap.clone()
+    };
+    assert_eq!(
+        swapped(raw.compare_and_swap(second.clone()).await.unwrap()),
+        0
+    );
+    primary.kill();
+    let mut raw = GrpcMemoryClient::connect(backup.url.clone()).await.unwrap();
+    raw.promote(PromoteRequest::default()).await.unwrap();
+    let mut client =
+        MemoryClient::with_backups(backup.url.clone(), Vec::new(), Credentials::default())
+            .await
+            .unwrap();
+    client
+        .write(id, 0, 0u64.to_le_bytes().to_vec())
+        .await
+        .unwrap();
+    assert_eq!(swapped(raw.compare_and_swap(second).await.unwrap()), 0);
+    assert_eq!(client.read(id, 0, 8).await.unwrap(), 0u64.to_le_bytes());
+}
@@ -0,0 +1,75 @@
+mod common;
+
+use cn::cluster::ClusterClient;
+use cn::credentials::Credentials;
+use cn::errors::MemoryError;
+use cn::far::FarPtr;
+use cn::proto::memory::MemoryAccessError;
+use common::{config, Node};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn far_pointers_link_regions_across_nodes() {
+    let nodes = [Node::start(&[]), Node::start(&[])];
+    let none = Credentials::default();
+    let mut client = ClusterClient::connect(vec![
+        config(&nodes[0], 1, &none),
+        config(&nodes[1], 1, &none),
+    ])
+    .await
+    .unwrap();
+
+    // a list with a node on each data node, each pointing at the next
+    let mut list = Vec::new();
+    while list.iter().all(|p: &FarPtr| p.node() == 0) || list.iter().all(|p| p.node() == 1) {
+        let ptr = FarPtr::allocate(&mut client, 64).await.unwrap();
+        assert_eq!((ptr.remaining(), ptr.is_null()), (64, false));
+        list.push(ptr);
+        assert!(list.len() < 64, "every region went to one node");
+    }
+    for (i, pair) in list.windows(2).enumerate() {
+        pair[0].store(&mut client, &pair[1]).await.unwrap();
+        let payload = pair[0].checked_add(FarPtr::ENCODED_LEN as u32).unwrap();
+        payload.write(&mut client, &[i as u8; 8]).await.unwrap();
+    }
+    let last = list.last().unwrap();
+    last.store(&mut client, &FarPtr::NULL).await.unwrap();
+
+    let mut ptr = list[0];
+    let mut followed = Vec::new();
+    while !ptr.is_null() {
+        followed.push(ptr);
+        ptr = ptr.load(&mut client).await.unwrap();
+    }
+    assert_eq!(followed, list);
+    let payload = list[1].checked_add(FarPtr::ENCODED_LEN as u32).unwrap();
+    assert_eq!(payload.read(&mut client, 8).await.unwrap(), [1; 8]);
+
+    // bounds are checked before anything goes over the wire
+    let end = list[0].checked_add(64).unwrap();
+    assert_eq!(end.remaining(), 0);
+    assert_eq!(list[0].checked_add(65), None);
+    assert!(matches!(
+        payload.read(&mut client, 64).await,
+        Err(MemoryError::MemoryAccessError(
+            MemoryAccessError::OutOfBoundsAccess
+        ))
+    ));
+    assert!(matches!(
+        end.write(&mut client, &[0]).await,
+        Err(MemoryError::MemoryAccessError(
+            MemoryAccessError::OutOfBoundsAccess
+        ))
+    ));
+
+    // and on decoding
+    let encoded = payload.encode();
+    assert_eq!(FarPtr::decode(&encoded), Some(payload));
+    assert_eq!(FarPtr::decode(&encoded[..FarPtr::ENCODED_LEN - 1]), None);
+    let mut past_end = encoded;
+    past_end[12..16].copy_from_slice(&65u32.to_le_bytes());
+    assert_eq!(FarPtr::decode(&past_end), None);
+
+    // freed regions can't be reached through old pointers
+    list[1].free(&mut client).await.unwrap();
+    assert!(payload.read(&mut client, 8).await.unwrap_err().is_stale());
+}
@@ -0,0 +1,183 @@
+mod common;
+
+use cn::client::RemoteMemory;
+use cn::errors::MemoryError;
+use cn::kv::{Expiry, KeyValueStore, KvConfig, KvIter};
+use common::{connect, Node};
+use std::ops::Bound;
+use std::time::Duration;
+
+const CHUNK: usize = 64;
+const KEYS: usize = 200; // enough for leaves to split a few times
+
+fn value(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+async fn keys<C: RemoteMemory>(mut scan: KvIter<'_, C>) -> Vec<String> {
+    let mut keys = Vec::new();
+    while let Some(key) = scan.next_key().await.unwrap() {
+        keys.push(key);
+    }
+    keys
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn values_round_trip_across_chunk_boundaries() {
+    let node = Node::start(&[]);
+    let config = KvConfig {
+        chunk_size: CHUNK,
+        max_value_size: 4 * CHUNK,
+        ..KvConfig::default()
+    };
+    let mut store = KeyValueStore::with_config(connect(&node).await, config)
+        .await
+        .unwrap();
+
+    let lens = [
+        0,
+        1,
+        CHUNK - 1,
+        CHUNK,
+        CHUNK + 1,
+        2 * CHUNK,
+        4 * CHUNK - 1,
+        4 * CHUNK,
+    ];
+    for len in lens {
+        let key = format!("set:{}", len);
+        store.set(&key, &value(len)).await.unwrap();
+        assert_eq!(
+            store.get(&key).await.unwrap().unwrap(),
+            value(len),
+            "{} bytes",
+            len
+        );
+
+        let key = format!("stream:{}", len);
+        store.set_stream(&key, &mut &value(len)[..]).await.unwrap();
+        let mut read = Vec::new();
+        assert!(store.get_stream(&key, &mut read).await.unwrap());
+        assert_eq!(read, value(len), "{} bytes streamed", len);
+    }
+
+    // overwriting with fewer chunks leaves none of the old ones behind
+    let mut admin = connect(&node).await;
+    let regions = admin.usage().await.unwrap().regions.len();
+    store.set("shrinks", &value(3 * CHUNK)).await.unwrap();
+    store.set("shrinks", &value(CHUNK / 2)).await.unwrap();
+    assert_eq!(
+        store.get("shrinks").await.unwrap().unwrap(),
+        value(CHUNK / 2)
+    );
+    assert!(store.delete("shrinks").await.unwrap());
+    assert_eq!(admin.usage().await.unwrap().regions.len(), regions);
+
+    assert!(matches!(
+        store.set("too large", &value(4 * CHUNK + 1)).await,
+        Err(MemoryError::ValueTooLarge { size, limit }) if size == 4 * CHUNK + 1 && limit == 4 * CHUNK
+    ));
+    assert_eq!(store.get("too large").await.unwrap(), None);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn scans_stay_ordered_across_index_splits() {
+    let node = Node::start(&[]);
+    let mut store = KeyValueStore::new(connect(&node).await).await.unwrap();
+
+    // in an order far from sorted, so splits happen all over the tree, a
+    // batch at a time to keep it quick
+    let mut all: Vec<String> = (0..KEYS).map(|i| format!("key:{:04}", i)).collect();
+    let order: Vec<usize> = (0..KEYS).map(|i| i * 67 % KEYS).collect();
+    for batch in order.chunks(KEYS / 4) {
+        let mut tx = store.transaction();
+        for &i in batch {
+            tx.set(&all[i], all[i].as_bytes()).unwrap();
+        }
+        tx.commit().await.unwrap();
+    }
+    all.sort();
+
+    let mut scan = store.scan::<&str, _>(..).page_size(7);
+    for expected in &all {
+        let (key, value) = scan.next().await.unwrap().unwrap();
+        assert_eq!(&key, expected);
+        assert_eq!(value, expected.as_bytes());
+    }
+    assert_eq!(scan.next().await.unwrap(), None);
+
+    let reversed: Vec<String> = all.iter().rev().cloned().collect();
+    assert_eq!(keys(store.scan::<&str, _>(..).reverse()).await, reversed);
+    assert_eq!(
+        keys(store.scan("key:0100".."key:0150")).await,
+        all[100..150]
+    );
+    let bounds = (Bound::Excluded("key:0100"), Bound::Included("key:0150"));
+    assert_eq!(keys(store.scan::<&str, _>(bounds).reverse()).await, {
+        let mut keys = all[101..=150].to_vec();
+        keys.reverse();
+        keys
+    });
+    assert_eq!(keys(store.prefix("key:01")).await, all[100..200]);
+    assert_eq!(keys(store.prefix("nothing")).await, Vec::<String>::new());
+
+    // and after deleting every other key
+    let mut tx = store.transaction();
+    for key in all.iter().step_by(2) {
+        tx.delete(key);
+    }
+    tx.commit().await.unwrap();
+    let odd: Vec<String> = all.iter().skip(1).step_by(2).cloned().collect();
+    assert_eq!(keys(store.scan::<&str, _>(..)).await, odd);
+    assert_eq!(store.get("key:0000").await.unwrap(), None);
+    assert_eq!(store.get("key:0001").await.unwrap().unwrap(), b"key:0001");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn keys_expire_after_their_ttl() {
+    let node = Node::start(&[]);
+    let mut admin = connect(&node).await;
+    let mut store = KeyValueStore::new(connect(&node).await).await.unwrap();
+    let regions = admin.usage().await.unwrap().regions.len();
+
+    store.set("kept", b"stays").await.unwrap();
+    store
+        .expire("kept", Duration::from_millis(300))
+        .await
+        .unwrap();
+    assert!(store.persist("kept").await.unwrap());
+    // far enough off not to fit in milliseconds since the epoch
+    store
+        .set_with_ttl("forever", b"still here", Duration::MAX)
+        .await
+        .unwrap();
+    store
+        .set_with_ttl("short", b"gone soon", Duration::from_secs(1))
+        .await
+        .unwrap();
+
+    assert_eq!(store.get("short").await.unwrap().unwrap(), b"gone soon");
+    assert!(matches!(
+        store.ttl("short").await.unwrap(),
+        Some(Expiry::In(left)) if left <= Duration::from_secs(1)
+    ));
+    assert_eq!(store.ttl("kept").await.unwrap(), Some(Expiry::Never));
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert_eq!(store.get("short").await.unwrap(), None);
+    assert!(!store.exists("short").await.unwrap());
+    assert_eq!(store.ttl("short").await.unwrap(), None);
+    assert_eq!(store.get("kept").await.unwrap().unwrap(), b"stays");
+    assert_eq!(store.get("forever").await.unwrap().unwrap(), b"still here");
+
+    // expired keys nobody reads are reclaimed by sweeping
+    store
+        .set_with_ttl("unread", b"x", Duration::from_millis(100))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(store.sweep_expired().await.unwrap(), 1);
+    assert!(store.delete("kept").await.unwrap());
+    assert!(store.delete("forever").await.unwrap());
+    assert_eq!(admin.usage().await.unwrap().regions.len(), regions);
+}
@@ -0,0 +1,69 @@
+mod common;
+
+use cn::client::RemoteMemory;
+use cn::cluster::ClusterClient;
+use cn::credentials::Credentials;
+use cn::proto::memory::membership_client::MembershipClient;
+use cn::proto::memory::{MembershipUpdate, WatchRequest};
+use common::{config, Node};
+use std::collections::HashMap;
+use std::time::Duration;
+use tonic::Streaming;
+
+/// Reads membership updates until every node in `expected` has the
+/// liveness it maps to.
+async fn until(updates: &mut Streaming<MembershipUpdate>, expected: &[(&str, bool)]) {
+    let wait = async {
+        loop {
+            let update = updates.message().await.unwrap().unwrap();
+            let members: HashMap<String, bool> = update
+                .members
+                .into_iter()
+                .map(|member| (member.addr, member.alive))
+                .collect();
+            if expected
+                .iter()
+                .all(|(addr, alive)| members.get(*addr) == Some(alive))
+            {
+                return;
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), wait)
+        .await
+        .unwrap_or_else(|_| panic!("membership never became {:?}", expected));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn nodes_missing_heartbeats_are_marked_down_and_avoided() {
+    let coordinator = Node::start(&["--coordinate", "300"]);
+    let member = ["--coordinator", &coordinator.url, "--heartbeat", "50"];
+    let mut nodes = [Node::start(&member), Node::start(&member)];
+    let (up, down) = (nodes[0].url.clone(), nodes[1].url.clone());
+    let mut watcher = MembershipClient::connect(coordinator.url.clone())
+        .await
+        .unwrap();
+    let mut updates = watcher.watch(WatchRequest {}).await.unwrap().into_inner();
+    until(&mut updates, &[(&up, true), (&down, true)]).await;
+
+    let none = Credentials::default();
+    let mut client = ClusterClient::connect(vec![
+        config(&nodes[0], 1, &none),
+        config(&nodes[1], 1, &none),
+    ])
+    .await
+    .unwrap();
+    let follower = client
+        .follow_membership(coordinator.url.clone(), &none)
+        .unwrap();
+
+    nodes[1].kill();
+    until(&mut updates, &[(&up, true), (&down, false)]).await;
+    // the client hears of it from the same stream
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    for _ in 0..10 {
+        let id = client.allocate_memory(64).await.unwrap();
+        assert_eq!(ClusterClient::locate(id).0, 0, "placed on a node down");
+    }
+    follower.abort();
+}
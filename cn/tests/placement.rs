@@ -0,0 +1,48 @@
+mod common;
+
+use cn::client::RemoteMemory;
+use cn::cluster::ClusterClient;
+use cn::credentials::Credentials;
+use common::{config, Node};
+
+const REGIONS: usize = 120;
+const REGION: u64 = 1024;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn placement_follows_weights_and_skips_full_nodes() {
+    let capacity = (4 * REGION).to_string();
+    let nodes = [
+        Node::start(&[]),
+        Node::start(&[]),
+        Node::start(&[]),
+        Node::start(&["--capacity", &capacity]),
+    ];
+    let none = Credentials::default();
+    let mut client = ClusterClient::connect(vec![
+        config(&nodes[0], 1, &none),
+        config(&nodes[1], 3, &none),
+        config(&nodes[2], 0, &none),
+        config(&nodes[3], 1, &none),
+    ])
+    .await
+    .unwrap();
+
+    let mut placed = [0; 4];
+    for i in 0..REGIONS as u64 {
+        let id = client.allocate_memory(REGION).await.unwrap();
+        client.write(id, 0, i.to_le_bytes().to_vec()).await.unwrap();
+        let (node, region) = ClusterClient::locate(id);
+        assert_eq!(ClusterClient::handle(node, region), Some(id));
+        assert_eq!(client.read(id, 0, 8).await.unwrap(), i.to_le_bytes());
+        placed[node as usize] += 1;
+    }
+    // weights are only met on average, but the heaviest node should be the
+    // busiest, a node of weight 0 gets nothing, and a full node's share
+    // goes elsewhere
+    assert!(placed[1] > placed[0], "placed {:?}", placed);
+    assert_eq!(placed[2], 0, "placed {:?}", placed);
+    assert_eq!(placed[3], 4, "placed {:?}", placed);
+    assert_eq!(placed.iter().sum::<usize>(), REGIONS);
+
+    assert_eq!(ClusterClient::handle(1, 1 << 48), None);
+}
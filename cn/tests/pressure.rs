@@ -0,0 +1,83 @@
+mod common;
+
+use cn::client::{MemoryClient, RemoteMemory};
+use cn::credentials::Credentials;
+use cn::proto::memory::{Pressure, PressureUpdate};
+use common::{Node, TempDir};
+use std::time::Duration;
+use tonic::Streaming;
+
+const REGION: u64 = 1024;
+
+async fn connect(node: &Node, token: &str) -> MemoryClient {
+    let credentials = Credentials::new(None, None, Some(token)).unwrap();
+    MemoryClient::with_backups(node.url.clone(), Vec::new(), credentials)
+        .await
+        .unwrap()
+}
+
+async fn next(updates: &mut Streaming<PressureUpdate>) -> (Pressure, u64, u64) {
+    let update = updates.message().await.unwrap().unwrap();
+    (update.pressure(), update.used_bytes, update.capacity_bytes)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn pressure_reaches_everyone_and_usage_only_admins() {
+    let dir = TempDir::new("pressure-watch");
+    let tokens = dir.write("tokens", b"ops-token ops admin\napp-token app\n");
+    let capacity = (10 * REGION).to_string();
+    let node = Node::start(&["--tokens", &tokens, "--capacity", &capacity]);
+    let ops = connect(&node, "ops-token").await;
+    let mut app = connect(&node, "app-token").await;
+    let mut admin_updates = ops.watch_pressure().await.unwrap();
+    let mut app_updates = app.watch_pressure().await.unwrap();
+    let follower = app.follow_pressure();
+
+    // the current pressure first
+    assert_eq!(
+        next(&mut admin_updates).await,
+        (Pressure::None, 0, 10 * REGION)
+    );
+    assert_eq!(next(&mut app_updates).await, (Pressure::None, 0, 0));
+
+    // then every watermark crossed, 70, 85 and 95 percent by default
+    let mut regions = Vec::new();
+    for (count, pressure) in [
+        (7, Pressure::Low),
+        (9, Pressure::High),
+        (10, Pressure::Critical),
+    ] {
+        while regions.len() < count {
+            regions.push(app.allocate_memory(REGION).await.unwrap());
+        }
+        let used = count as u64 * REGION;
+        assert_eq!(
+            next(&mut admin_updates).await,
+            (pressure, used, 10 * REGION)
+        );
+        assert_eq!(next(&mut app_updates).await, (pressure, 0, 0));
+    }
+
+    // and back down
+    for id in regions {
+        app.free(id).await.unwrap();
+    }
+    let mut pressure = Pressure::Critical;
+    while pressure != Pressure::None {
+        let update = next(&mut app_updates).await;
+        assert!(update.0 < pressure, "{:?} after {:?}", update.0, pressure);
+        assert_eq!((update.1, update.2), (0, 0));
+        pressure = update.0;
+    }
+
+    // followers catch up on their own
+    app.allocate_memory(10 * REGION).await.unwrap();
+    for _ in 0..100 {
+        if app.pressure() == Pressure::Critical {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(app.pressure(), Pressure::Critical);
+    follower.abort();
+}
@@ -0,0 +1,358 @@
+mod common;
+
+use cn::kv::KeyValueStore;
+use cn::{memcached, resp};
+use common::{connect, Node};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Serves the store with `serve` on a free local port and connects to it.
+async fn serve<F>(
+    node: &Node,
+    serve: fn(String, KeyValueStore<cn::client::MemoryClient>) -> F,
+) -> BufReader<TcpStream>
+where
+    F: Future<Output = io::Result<()>> + Send + 'static,
+{
+    let store = KeyValueStore::new(connect(node).await).await.unwrap();
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    tokio::spawn(serve(addr.clone(), store));
+    for _ in 0..100 {
+        if let Ok(socket) = TcpStream::connect(&addr).await {
+            return BufReader::new(socket);
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("nothing listening on {}", addr);
+}
+
+#[derive(Debug, PartialEq)]
+enum Resp {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Resp>),
+}
+
+fn bulk(s: &str) -> Resp {
+    Resp::Bulk(Some(s.as_bytes().to_vec()))
+}
+
+async fn line(socket: &mut BufReader<TcpStream>) -> String {
+    let mut line = String::new();
+    socket.read_line(&mut line).await.unwrap();
+    assert!(line.ends_with("\r\n"), "truncated line {:?}", line);
+    line.truncate(line.len() - 2);
+    line
+}
+
+fn read(socket: &mut BufReader<TcpStream>) -> Pin<Box<dyn Future<Output = Resp> + Send + '_>> {
+    Box::pin(async move {
+        let line = line(socket).await;
+        let (kind, rest) = line.split_at(1);
+        match kind {
+            "+" => Resp::Simple(rest.to_string()),
+            "-" => Resp::Error(rest.to_string()),
+            ":" => Resp::Integer(rest.parse().unwrap()),
+            "$" => match rest.parse::<i64>().unwrap() {
+                -1 => Resp::Bulk(None),
+                len => {
+                    let mut data = vec![0; len as usize + 2];
+                    socket.read_exact(&mut data).await.unwrap();
+                    data.truncate(len as usize);
+                    Resp::Bulk(Some(data))
+                }
+            },
+            "*" => {
+                let mut items = Vec::new();
+                for _ in 0..rest.parse::<usize>().unwrap() {
+                    items.push(read(socket).await);
+                }
+                Resp::Array(items)
+            }
+            _ => panic!("unexpected reply {:?}", line),
+        }
+    })
+}
+
+async fn command(socket: &mut BufReader<TcpStream>, args: &[&str]) -> Resp {
+    let mut request = format!("*{}\r\n", args.len());
+    for arg in args {
+        request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    socket
+        .get_mut()
+        .write_all(request.as_bytes())
+        .await
+        .unwrap();
+    read(socket).await
+}
+
+/// Sends a memcached text command and reads lines up to the one `last`
+/// says ends the reply.
+async fn text(
+    socket: &mut BufReader<TcpStream>,
+    request: &str,
+    last: fn(&str) -> bool,
+) -> Vec<String> {
+    socket
+        .get_mut()
+        .write_all(request.as_bytes())
+        .await
+        .unwrap();
+    let mut reply = Vec::new();
+    loop {
+        let line = line(socket).await;
+        let done = last(&line);
+        reply.push(line);
+        if done {
+            return reply;
+        }
+    }
+}
+
+fn single(_: &str) -> bool {
+    true
+}
+
+fn end(line: &str) -> bool {
+    line == "END"
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn resp_round_trips() {
+    let node = Node::start(&[]);
+    let mut socket = serve(&node, |addr, store| async move {
+        resp::serve(&addr, store).await
+    })
+    .await;
+
+    assert_eq!(
+        command(&mut socket, &["PING"]).await,
+        Resp::Simple("PONG".into())
+    );
+    assert_eq!(
+        command(&mut socket, &["SET", "greeting", "hello"]).await,
+        Resp::Simple("OK".into())
+    );
+    assert_eq!(
+        command(&mut socket, &["GET", "greeting"]).await,
+        bulk("hello")
+    );
+    assert_eq!(
+        command(&mut socket, &["GET", "missing"]).await,
+        Resp::Bulk(None)
+    );
+    assert_eq!(
+        command(&mut socket, &["SET", "greeting", "again", "NX"]).await,
+        Resp::Bulk(None)
+    );
+    assert_eq!(
+        command(&mut socket, &["INCR", "counter"]).await,
+        Resp::Integer(1)
+    );
+    assert_eq!(
+        command(&mut socket, &["INCR", "counter"]).await,
+        Resp::Integer(2)
+    );
+
+    // expire times past what milliseconds since the epoch can hold
+    assert_eq!(
+        command(&mut socket, &["EXPIRE", "greeting", &i64::MAX.to_string()]).await,
+        Resp::Error("ERR invalid expire time in 'expire' command".into())
+    );
+    assert_eq!(
+        command(
+            &mut socket,
+            &["SET", "greeting", "x", "PX", &(i64::MAX - 1).to_string()]
+        )
+        .await,
+        Resp::Error("ERR invalid expire time in 'set' command".into())
+    );
+    assert_eq!(
+        command(&mut socket, &["TTL", "greeting"]).await,
+        Resp::Integer(-1)
+    );
+    assert_eq!(
+        command(&mut socket, &["EXPIRE", "greeting", "100"]).await,
+        Resp::Integer(1)
+    );
+    assert_eq!(
+        command(&mut socket, &["TTL", "greeting"]).await,
+        Resp::Integer(100)
+    );
+    assert_eq!(
+        command(&mut socket, &["TTL", "missing"]).await,
+        Resp::Integer(-2)
+    );
+
+    assert_eq!(
+        command(
+            &mut socket,
+            &["MSET", "user:1", "a", "user:2", "b", "user:10", "c"]
+        )
+        .await,
+        Resp::Simple("OK".into())
+    );
+    assert_eq!(
+        command(&mut socket, &["KEYS", "user:?"]).await,
+        Resp::Array(vec![bulk("user:1"), bulk("user:2")])
+    );
+    assert_eq!(
+        command(&mut socket, &["KEYS", "user:[^2]*"]).await,
+        Resp::Array(vec![bulk("user:1"), bulk("user:10")])
+    );
+    // would take exponential time backtracking to every star
+    let pathological = format!("{}b", "*a".repeat(30));
+    assert_eq!(
+        command(&mut socket, &["KEYS", &pathological]).await,
+        Resp::Array(Vec::new())
+    );
+
+    // a scan a key at a time still visits every match once
+    let mut cursor = "0".to_string();
+    let mut found = Vec::new();
+    loop {
+        match command(
+            &mut socket,
+            &["SCAN", &cursor, "MATCH", "user:*", "COUNT", "1"],
+        )
+        .await
+        {
+            Resp::Array(reply) => match &reply[..] {
+                [Resp::Bulk(Some(next)), Resp::Array(keys)] => {
+                    found.extend(keys.iter().map(|key| match key {
+                        Resp::Bulk(Some(key)) => String::from_utf8(key.clone()).unwrap(),
+                        other => panic!("unexpected key {:?}", other),
+                    }));
+                    cursor = String::from_utf8(next.clone()).unwrap();
+                }
+                other => panic!("unexpected scan reply {:?}", other),
+            },
+            other => panic!("unexpected scan reply {:?}", other),
+        }
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(found, ["user:1", "user:10", "user:2"]);
+
+    assert_eq!(
+        command(&mut socket, &["DEL", "user:1", "user:2", "nobody"]).await,
+        Resp::Integer(2)
+    );
+    assert_eq!(
+        command(&mut socket, &["EXISTS", "user:1", "user:10"]).await,
+        Resp::Integer(1)
+    );
+    assert!(matches!(
+        command(&mut socket, &["NOSUCHCOMMAND"]).await,
+        Resp::Error(_)
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn memcached_round_trips() {
+    let node = Node::start(&[]);
+    let mut socket = serve(&node, |addr, store| async move {
+        memcached::serve(&addr, store).await
+    })
+    .await;
+
+    assert_eq!(
+        text(&mut socket, "set greeting 5 0 5\r\nhello\r\n", single).await,
+        ["STORED"]
+    );
+    assert_eq!(
+        text(&mut socket, "get greeting missing\r\n", end).await,
+        ["VALUE greeting 5 5", "hello", "END"]
+    );
+    assert_eq!(
+        text(&mut socket, "add greeting 0 0 1\r\nx\r\n", single).await,
+        ["NOT_STORED"]
+    );
+    assert_eq!(
+        text(&mut socket, "replace missing 0 0 1\r\nx\r\n", single).await,
+        ["NOT_STORED"]
+    );
+
+    // cas succeeds only against the version last read
+    let reply = text(&mut socket, "gets greeting\r\n", end).await;
+    let version = reply[0].rsplit(' ').next().unwrap().to_string();
+    let stale: u64 = version.parse::<u64>().unwrap() + 1;
+    assert_eq!(
+        text(
+            &mut socket,
+            &format!("cas greeting 0 0 3 {}\r\nnew\r\n", stale),
+            single
+        )
+        .await,
+        ["EXISTS"]
+    );
+    assert_eq!(
+        text(
+            &mut socket,
+            &format!("cas greeting 0 0 3 {}\r\nnew\r\n", version),
+            single
+        )
+        .await,
+        ["STORED"]
+    );
+    assert_eq!(
+        text(&mut socket, "cas missing 0 0 1 1\r\nx\r\n", single).await,
+        ["NOT_FOUND"]
+    );
+    assert_eq!(
+        text(&mut socket, "get greeting\r\n", end).await,
+        ["VALUE greeting 0 3", "new", "END"]
+    );
+
+    assert_eq!(
+        text(&mut socket, "set counter 0 0 2\r\n41\r\n", single).await,
+        ["STORED"]
+    );
+    assert_eq!(
+        text(&mut socket, "incr counter 1\r\n", single).await,
+        ["42"]
+    );
+    assert_eq!(
+        text(&mut socket, "decr counter 50\r\n", single).await,
+        ["0"]
+    );
+
+    // an exptime too far off to count in milliseconds never expires, and a
+    // negative one already has
+    let far = format!("set far 0 {} 1\r\nx\r\n", i64::MAX);
+    assert_eq!(text(&mut socket, &far, single).await, ["STORED"]);
+    assert_eq!(
+        text(&mut socket, "get far\r\n", end).await,
+        ["VALUE far 0 1", "x", "END"]
+    );
+    assert_eq!(
+        text(&mut socket, "set past 0 -1 1\r\nx\r\n", single).await,
+        ["STORED"]
+    );
+    assert_eq!(text(&mut socket, "get past\r\n", end).await, ["END"]);
+
+    assert_eq!(
+        text(&mut socket, "delete greeting\r\n", single).await,
+        ["DELETED"]
+    );
+    assert_eq!(
+        text(&mut socket, "delete greeting\r\n", single).await,
+        ["NOT_FOUND"]
+    );
+    assert_eq!(
+        text(&mut socket, "nosuchcommand\r\n", single).await,
+        ["ERROR"]
+    );
+}
@@ -0,0 +1,197 @@
+mod common;
+
+use cn::client::RemoteMemory;
+use cn::cluster::ClusterClient;
+use cn::credentials::Credentials;
+use common::{config, Node, TempDir};
+use std::time::Duration;
+
+const REGIONS: u64 = 20;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn admins_move_regions_without_their_keys() {
+    let dir = TempDir::new("rebalance-keys");
+    let tokens = dir.write(
+        "tokens",
+        b"node-token dn admin\nops-token ops admin\napp-token app\n",
+    );
+    let args = [
+        "--keys",
+        "required",
+        "--tokens",
+        &tokens,
+        "--token",
+        "node-token",
+    ];
+    let nodes = [Node::start(&args), Node::start(&args)];
+    let app = Credentials::new(None, None, Some("app-token")).unwrap();
+    let ops = Credentials::new(None, None, Some("ops-token")).unwrap();
+
+    // everything on the first node, so there is something to rebalance
+    let mut owner =
+        ClusterClient::connect(vec![config(&nodes[0], 1, &app), config(&nodes[1], 0, &app)])
+            .await
+            .unwrap();
+    let mut regions = Vec::new();
+    for i in 0..REGIONS {
+        let id = owner.allocate_memory(4096).await.unwrap();
+        owner.write(id, 0, i.to_le_bytes().to_vec()).await.unwrap();
+        regions.push(id);
+    }
+
+    let both = || vec![config(&nodes[0], 1, &ops), config(&nodes[1], 1, &ops)];
+    let mut admin = ClusterClient::connect(both()).await.unwrap();
+    let moved = admin.rebalance().await.unwrap();
+    assert!(moved > 0, "nothing rebalanced");
+    for (i, &id) in (0..REGIONS).zip(&regions) {
+        assert_eq!(owner.read(id, 0, 8).await.unwrap(), i.to_le_bytes());
+    }
+
+    let mut progress = admin.drain(1).await.unwrap();
+    let mut done = false;
+    while let Some(update) = progress.message().await.unwrap() {
+        done = update.done;
+    }
+    assert!(done, "drain didn't finish");
+    for (i, &id) in (0..REGIONS).zip(&regions) {
+        assert_eq!(owner.read(id, 0, 8).await.unwrap(), i.to_le_bytes());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn rebalancing_keeps_shards_of_a_stripe_apart() {
+    let nodes = [Node::start(&[]), Node::start(&[]), Node::start(&[])];
+    let none = Credentials::default();
+
+    // every stripe has a shard on each of the first two nodes
+    let mut owner = ClusterClient::connect(vec![
+        config(&nodes[0], 1, &none),
+        config(&nodes[1], 1, &none),
+        config(&nodes[2], 0, &none),
+    ])
+    .await
+    .unwrap();
+    let mut regions = Vec::new();
+    for i in 0..REGIONS {
+        let (id, _) = owner.allocate_coded(4096, 1, 1).await.unwrap();
+        owner.write(id, 0, i.to_le_bytes().to_vec()).await.unwrap();
+        regions.push(id);
+    }
+
+    let all = || (0..3).map(|i| config(&nodes[i], 1, &none)).collect();
+    let mut admin = ClusterClient::connect(all()).await.unwrap();
+    let moved = admin.rebalance().await.unwrap();
+    assert!(moved > 0, "nothing rebalanced");
+    for (node, usage) in admin.usage().await.into_iter().enumerate() {
+        let stripes = usage.unwrap().stripes;
+        let mut held: Vec<u64> = stripes.values().copied().collect();
+        held.sort_unstable();
+        held.dedup();
+        assert_eq!(
+            held.len(),
+            stripes.len(),
+            "node {} holds two shards of a stripe",
+            node
+        );
+    }
+    for (i, &id) in (0..REGIONS).zip(&regions) {
+        assert_eq!(owner.read(id, 0, 8).await.unwrap(), i.to_le_bytes());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn drain_reports_progress_until_the_node_is_empty() {
+    let nodes = [Node::start(&[]), Node::start(&[])];
+    let none = Credentials::default();
+
+    let mut owner = ClusterClient::connect(vec![
+        config(&nodes[0], 1, &none),
+        config(&nodes[1], 0, &none),
+    ])
+    .await
+    .unwrap();
+    let mut regions = Vec::new();
+    for i in 0..REGIONS {
+        let id = owner.allocate_memory(4096).await.unwrap();
+        owner.write(id, 0, i.to_le_bytes().to_vec()).await.unwrap();
+        regions.push(id);
+    }
+
+    let both = || vec![config(&nodes[0], 1, &none), config(&nodes[1], 1, &none)];
+    let mut admin = ClusterClient::connect(both()).await.unwrap();
+    let mut progress = admin.drain(0).await.unwrap();
+    let mut updates = Vec::new();
+    while let Some(update) = progress.message().await.unwrap() {
+        updates.push(update);
+    }
+    assert_eq!((updates[0].moved, updates[0].regions_left), (0, REGIONS));
+    for pair in updates.windows(2) {
+        assert!(!pair[0].done, "progress after the drain was done");
+        assert!(pair[0].moved <= pair[1].moved);
+        assert!(pair[0].regions_left >= pair[1].regions_left);
+        assert!(pair[0].bytes_left >= pair[1].bytes_left);
+    }
+    let last = updates.last().expect("no progress reported");
+    assert!(last.done, "drain didn't finish");
+    assert_eq!(last.moved, REGIONS);
+    assert_eq!((last.regions_left, last.bytes_left), (0, 0));
+
+    // empty, and staying that way
+    let mut drained = common::connect(&nodes[0]).await;
+    let usage = drained.usage().await.unwrap();
+    assert!(usage.draining);
+    assert!(usage.regions.is_empty(), "{:?} left", usage.regions);
+    assert!(drained.allocate_memory(4096).await.is_err());
+    for (i, &id) in (0..REGIONS).zip(&regions) {
+        assert_eq!(owner.read(id, 0, 8).await.unwrap(), i.to_le_bytes());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn drain_fails_on_regions_it_cant_bring_back_into_ram() {
+    const REGION: u64 = 1024;
+    let dir = TempDir::new("rebalance-spilled");
+    let spill = dir.0.join("spill");
+    // room for four regions in the arena, but only three and a half in RAM
+    let arena_size = (4096 + 4 * (REGION + 128)).to_string();
+    let ram = (3 * REGION + REGION / 2).to_string();
+    let node = Node::start(&[
+        "--arena",
+        "memfd",
+        "--arena-size",
+        &arena_size,
+        "--spill",
+        spill.to_str().unwrap(),
+        "--ram",
+        &ram,
+    ]);
+    let target = Node::start(&[]);
+    let mut client = common::connect(&node).await;
+
+    // the fourth region pushes the first, the coldest, out to disk, and a
+    // smaller one then takes most of its block
+    let mut regions = Vec::new();
+    for _ in 0..3 {
+        regions.push(client.allocate_memory(REGION).await.unwrap());
+    }
+    for &id in &regions[1..] {
+        client.read(id, 0, 8).await.unwrap();
+    }
+    client.allocate_memory(REGION).await.unwrap();
+    client.allocate_memory(REGION / 2).await.unwrap();
+
+    let mut progress = client.drain(vec![target.url.clone()]).await.unwrap();
+    let failed = async {
+        loop {
+            match progress.message().await {
+                Ok(Some(update)) => assert!(!update.done, "drained a region it can't copy"),
+                Ok(None) => panic!("drain ended without failing"),
+                Err(status) => return status,
+            }
+        }
+    };
+    let failed = tokio::time::timeout(Duration::from_secs(30), failed)
+        .await
+        .expect("drain kept going");
+    assert_eq!(failed.code(), tonic::Code::ResourceExhausted);
+}
@@ -0,0 +1,198 @@
+mod common;
+
+use cn::client::{MemoryClient, RemoteMemory, Sharing};
+use cn::credentials::Credentials;
+use cn::directory::DirectoryClient;
+use cn::kv::{KeyValueStore, KvConfig};
+use cn::proto::memory::{AccessMode, MemoryAccessError};
+use common::{Node, TempDir};
+
+const KEYS: usize = 70; // enough to split the root leaf
+const CHUNK_SIZE: usize = 16; // so values span several chunks
+
+async fn connect(node: &Node, token: &str) -> MemoryClient {
+    let credentials = Credentials::new(None, None, Some(token)).unwrap();
+    MemoryClient::with_backups(node.url.clone(), Vec::new(), credentials)
+        .await
+        .unwrap()
+}
+
+async fn directory(node: &Node, token: &str) -> DirectoryClient {
+    let credentials = Credentials::new(None, None, Some(token)).unwrap();
+    DirectoryClient::connect(node.url.clone(), &credentials)
+        .await
+        .unwrap()
+}
+
+fn value(key: usize, round: usize) -> Vec<u8> {
+    format!("value {} of key {}, long enough to be chunked", round, key).into_bytes()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn compute_nodes_share_a_store_on_nodes_requiring_keys() {
+    let dir = TempDir::new("sharing-keys");
+    // two compute nodes acting as the same principal with tokens of their own
+    let tokens = dir.write("tokens", b"first-token app\nsecond-token app\n");
+    let node = Node::start(&["--keys", "required", "--tokens", &tokens]);
+    let config = KvConfig {
+        chunk_size: CHUNK_SIZE,
+        ..KvConfig::default()
+    };
+    let mut first = KeyValueStore::with_config(connect(&node, "first-token").await, config)
+        .await
+        .unwrap();
+    for key in 0..KEYS {
+        first
+            .set(&format!("key:{:03}", key), &value(key, 0))
+            .await
+            .unwrap();
+    }
+
+    let (header, header_key) = (first.header_id(), first.header_key());
+    let mut second = KeyValueStore::open(connect(&node, "second-token").await, header, header_key);
+    for key in 0..KEYS {
+        let found = second.get(&format!("key:{:03}", key)).await.unwrap();
+        assert_eq!(found, Some(value(key, 0)), "key {}", key);
+    }
+    // replacing and deleting frees regions the first compute node allocated
+    for key in (0..KEYS).step_by(7) {
+        second
+            .set(&format!("key:{:03}", key), &value(key, 1))
+            .await
+            .unwrap();
+    }
+    assert!(second.delete("key:001").await.unwrap());
+    let mut tx = second.transaction();
+    tx.set("key:002", &value(2, 1)).unwrap();
+    tx.commit().await.unwrap();
+
+    for key in 0..KEYS {
+        let expected = match key {
+            1 => None,
+            2 => Some(value(key, 1)),
+            key if key % 7 == 0 => Some(value(key, 1)),
+            key => Some(value(key, 0)),
+        };
+        let found = first.get(&format!("key:{:03}", key)).await.unwrap();
+        assert_eq!(found, expected, "key {}", key);
+    }
+    let page = first.scan("key:000".."key:010").next_page().await.unwrap();
+    assert_eq!(page.len(), 9);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn a_store_needs_its_header_key_on_nodes_requiring_keys() {
+    let node = Node::start(&["--keys", "required"]);
+    let mut store = KeyValueStore::new(common::connect(&node).await)
+        .await
+        .unwrap();
+    store.set("key", b"value").await.unwrap();
+
+    let mut keyless = KeyValueStore::open(common::connect(&node).await, store.header_id(), 0);
+    assert!(keyless.get("key").await.is_err());
+    let mut keyed = KeyValueStore::open(
+        common::connect(&node).await,
+        store.header_id(),
+        store.header_key(),
+    );
+    assert_eq!(keyed.get("key").await.unwrap(), Some(b"value".to_vec()));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn names_hand_read_write_keys_only_to_their_creator() {
+    let dir = TempDir::new("sharing-names");
+    let tokens = dir.write(
+        "tokens",
+        b"first-token app\nsecond-token app\nother-token other\nadmin-token ops admin\n",
+    );
+    let node = Node::start(&["--keys", "required", "--tokens", &tokens]);
+    let store = KeyValueStore::new(connect(&node, "first-token").await)
+        .await
+        .unwrap();
+    let (header, header_key) = (store.header_id(), store.header_key());
+    let mut first = directory(&node, "first-token").await;
+    first
+        .create("/stores/main", header, 0, header_key)
+        .await
+        .unwrap();
+    first
+        .create("/stores/old", header, 0, header_key)
+        .await
+        .unwrap();
+
+    // the same principal gets the read-write key, anyone else the read-only one
+    let mut second = directory(&node, "second-token").await;
+    let (_, _, key) = second.lookup("/stores/main").await.unwrap().unwrap();
+    assert_eq!(key, header_key);
+    let mut other = directory(&node, "other-token").await;
+    let (_, _, key) = other.lookup("/stores/main").await.unwrap().unwrap();
+    assert_ne!(key, header_key);
+    assert_ne!(key, 0);
+    for listing in other.list("/stores").await.unwrap() {
+        assert_ne!(listing.entry.unwrap().2, header_key);
+    }
+
+    // nor may anyone else remove or move the names
+    assert!(other.delete("/stores/main").await.is_err());
+    assert!(other.rename("/stores", "/taken").await.is_err());
+    assert!(second.lookup("/stores/main").await.unwrap().is_some());
+    assert!(second.lookup("/taken/main").await.unwrap().is_none());
+
+    second
+        .rename("/stores/main", "/stores/current")
+        .await
+        .unwrap();
+    let (_, _, key) = second.delete("/stores/current").await.unwrap().unwrap();
+    assert_eq!(key, header_key);
+    let mut admin = directory(&node, "admin-token").await;
+    assert!(admin.delete("/stores/old").await.unwrap().is_some());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn owners_grant_revoke_and_hand_over_access() {
+    let dir = TempDir::new("sharing-acl");
+    let tokens = dir.write("tokens", b"alice-token alice\nbob-token bob\n");
+    let node = Node::start(&["--tokens", &tokens]);
+    let mut alice = connect(&node, "alice-token").await;
+    let mut bob = connect(&node, "bob-token").await;
+    let denied = MemoryAccessError::AccessPermissionDenied;
+
+    let id = alice.allocate_memory(8).await.unwrap();
+    alice.write(id, 0, vec![1; 8]).await.unwrap();
+    assert_eq!(bob.read(id, 0, 8).await.unwrap_err(), denied);
+
+    alice
+        .share(id, "bob", Sharing::Grant(AccessMode::AccessRead))
+        .await
+        .unwrap();
+    assert_eq!(bob.read(id, 0, 8).await.unwrap(), [1; 8]);
+    assert_eq!(bob.write(id, 0, vec![2; 8]).await.unwrap_err(), denied);
+    // only owners share
+    assert_eq!(
+        bob.share(id, "bob", Sharing::Grant(AccessMode::AccessReadWrite))
+            .await
+            .unwrap_err(),
+        denied
+    );
+
+    alice
+        .share(id, "bob", Sharing::Grant(AccessMode::AccessReadWrite))
+        .await
+        .unwrap();
+    bob.write(id, 0, vec![2; 8]).await.unwrap();
+    alice.share(id, "bob", Sharing::Revoke).await.unwrap();
+    assert_eq!(bob.read(id, 0, 8).await.unwrap_err(), denied);
+
+    // handing it over leaves the old owner nothing
+    alice.share(id, "bob", Sharing::Transfer).await.unwrap();
+    assert_eq!(bob.read(id, 0, 8).await.unwrap(), [2; 8]);
+    assert_eq!(alice.read(id, 0, 8).await.unwrap_err(), denied);
+    assert_eq!(
+        alice
+            .share(id, "alice", Sharing::Transfer)
+            .await
+            .unwrap_err(),
+        denied
+    );
+    bob.free(id).await.unwrap();
+}
@@ -0,0 +1,73 @@
+mod common;
+
+use cn::client::{MemoryClient, RemoteMemory};
+use cn::credentials::Credentials;
+use cn::proto::memory::AllocationError;
+use common::{Node, TempDir};
+
+const REGION: u64 = 1024;
+
+async fn connect(node: &Node, token: &str) -> MemoryClient {
+    let credentials = Credentials::new(None, None, Some(token)).unwrap();
+    MemoryClient::with_backups(node.url.clone(), Vec::new(), credentials)
+        .await
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn tenants_allocate_within_their_own_quotas() {
+    let dir = TempDir::new("tenants-quotas");
+    let tokens = dir.write(
+        "tokens",
+        b"ops-token ops admin\nbytes-token bytes\ncount-token count\n",
+    );
+    let tenants = dir.write("tenants", b"bytes 2048 - bytes\ncount - 1 count\n");
+    let node = Node::start(&["--tokens", &tokens, "--tenants", &tenants]);
+    let mut bytes = connect(&node, "bytes-token").await;
+    let mut count = connect(&node, "count-token").await;
+
+    let first = bytes.allocate_memory(REGION).await.unwrap();
+    bytes.allocate_memory(REGION).await.unwrap();
+    assert_eq!(
+        bytes.allocate_memory(1).await,
+        Err(AllocationError::InsufficientMemory)
+    );
+    let only = count.allocate_memory(4 * REGION).await.unwrap();
+    assert_eq!(
+        count.allocate_memory(1).await,
+        Err(AllocationError::InsufficientMemory)
+    );
+    // each tenant has its own ids, and one being full leaves the other be
+    assert_ne!(first, only);
+    bytes.write(first, 0, vec![1; 8]).await.unwrap();
+    count.write(only, 0, vec![2; 8]).await.unwrap();
+    assert_eq!(bytes.read(first, 0, 8).await.unwrap(), [1; 8]);
+
+    // freeing gives the quota back
+    bytes.free(first).await.unwrap();
+    bytes.allocate_memory(REGION).await.unwrap();
+
+    let mut ops = connect(&node, "ops-token").await;
+    let mut usage = ops.usage().await.unwrap().tenants;
+    usage.sort_by(|a, b| a.name.cmp(&b.name));
+    let held: Vec<_> = usage
+        .iter()
+        .filter(|tenant| tenant.name == "bytes" || tenant.name == "count")
+        .map(|tenant| {
+            (
+                tenant.name.as_str(),
+                tenant.used_bytes,
+                tenant.regions,
+                tenant.max_bytes,
+                tenant.max_regions,
+            )
+        })
+        .collect();
+    assert_eq!(
+        held,
+        [
+            ("bytes", 2 * REGION, 2, 2048, 0),
+            ("count", 4 * REGION, 1, 0, 1),
+        ]
+    );
+}
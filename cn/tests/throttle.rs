@@ -0,0 +1,49 @@
+mod common;
+
+use cn::client::{MemoryClient, RemoteMemory};
+use cn::credentials::Credentials;
+use common::{Node, TempDir};
+use std::time::{Duration, Instant};
+
+const OPS: u64 = 15;
+const RATE: u64 = 5; // ops/sec for the slow client
+
+async fn connect(node: &Node, token: &str) -> MemoryClient {
+    let credentials = Credentials::new(None, None, Some(token)).unwrap();
+    MemoryClient::with_backups(node.url.clone(), Vec::new(), credentials)
+        .await
+        .unwrap()
+}
+
+/// Writes `OPS` times to a fresh region, checking each write reads back,
+/// and returns how long the writes took.
+async fn write_all(client: &mut MemoryClient) -> Duration {
+    let id = client.allocate_memory(8).await.unwrap();
+    let start = Instant::now();
+    for i in 0..OPS {
+        client.write(id, 0, i.to_le_bytes().to_vec()).await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    assert_eq!(
+        client.read(id, 0, 8).await.unwrap(),
+        (OPS - 1).to_le_bytes()
+    );
+    elapsed
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn throttled_clients_are_slowed_to_their_rate_without_failing() {
+    let dir = TempDir::new("throttle-rates");
+    let tokens = dir.write("tokens", b"slow-token slow\nfast-token fast\n");
+    let limits = dir.write("limits", format!("slow {} - 1\n", RATE).as_bytes());
+    let node = Node::start(&["--tokens", &tokens, "--limits", &limits]);
+    let mut slow = connect(&node, "slow-token").await;
+    let mut fast = connect(&node, "fast-token").await;
+
+    // a second's worth goes through at once, the rest at the rate, with
+    // the client waiting as long as it's told to rather than failing
+    let (slow, fast) = tokio::join!(write_all(&mut slow), write_all(&mut fast));
+    let at_rate = Duration::from_secs_f64((OPS - RATE - 1) as f64 / RATE as f64);
+    assert!(slow >= at_rate, "slow client took only {:?}", slow);
+    assert!(fast < at_rate, "fast client held up for {:?}", fast);
+}
@@ -0,0 +1,130 @@
+mod common;
+
+use cn::client::{MemoryClient, RemoteMemory};
+use cn::credentials::Credentials;
+use common::{Node, TempDir};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa, SanType};
+use std::net::IpAddr;
+
+/// A CA certificate that signs the others.
+fn ca(name: &str) -> Certificate {
+    let mut params = CertificateParams::default();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.distinguished_name.push(DnType::CommonName, name);
+    Certificate::from_params(params).unwrap()
+}
+
+/// A certificate for `names` signed by `ca`, and its key, both PEM.
+fn signed(names: Vec<SanType>, ca: &Certificate) -> (String, String) {
+    let mut params = CertificateParams::default();
+    params.subject_alt_names = names;
+    let cert = Certificate::from_params(params).unwrap();
+    (
+        cert.serialize_pem_with_signer(ca).unwrap(),
+        cert.serialize_private_key_pem(),
+    )
+}
+
+fn ip(addr: &str) -> SanType {
+    SanType::IpAddress(addr.parse::<IpAddr>().unwrap())
+}
+
+/// A CA, and a directory for the certificates it issues.
+struct Pki {
+    dir: TempDir,
+    ca: Certificate,
+}
+
+impl Pki {
+    fn new(name: &str) -> Self {
+        Self {
+            dir: TempDir::new(name),
+            ca: ca("test ca"),
+        }
+    }
+
+    fn ca_file(&self) -> String {
+        self.dir
+            .write("ca.pem", self.ca.serialize_pem().unwrap().as_bytes())
+    }
+
+    /// Writes a certificate for `names` signed by `ca`, which needn't be this
+    /// one, returning the paths of it and its key.
+    fn issue(&self, file: &str, names: Vec<SanType>, ca: &Certificate) -> (String, String) {
+        let (cert, key) = signed(names, ca);
+        (
+            self.dir.write(&format!("{}.pem", file), cert.as_bytes()),
+            self.dir.write(&format!("{}.key", file), key.as_bytes()),
+        )
+    }
+
+    /// Starts a node presenting a certificate for `names`, and only taking
+    /// clients with certificates from this CA.
+    fn node(&self, names: Vec<SanType>) -> Node {
+        let (cert, key) = self.issue("server", names, &self.ca);
+        let ca = self.ca_file();
+        Node::start(&[
+            "--tls-cert",
+            &cert,
+            "--tls-key",
+            &key,
+            "--tls-client-ca",
+            &ca,
+        ])
+    }
+}
+
+/// Whether a client with `credentials` gets to use the node.
+async fn accepted(node: &Node, credentials: Credentials) -> bool {
+    let mut client =
+        match MemoryClient::with_backups(node.url.clone(), Vec::new(), credentials).await {
+            Ok(client) => client,
+            Err(_) => return false,
+        };
+    // with TLS 1.3 a refused client certificate only shows on first use
+    let id = match client.allocate_memory(8).await {
+        Ok(id) => id,
+        Err(_) => return false,
+    };
+    client.write(id, 0, b"over tls".to_vec()).await.unwrap();
+    client.read(id, 0, 8).await.unwrap() == b"over tls"
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn client_with_a_certificate_from_the_ca_is_accepted() {
+    let pki = Pki::new("tls-valid");
+    let node = pki.node(vec![ip("::1")]);
+    let (cert, key) = pki.issue("client", vec![SanType::DnsName("client".into())], &pki.ca);
+    let credentials = Credentials::new(Some(&pki.ca_file()), Some((&cert, &key)), None).unwrap();
+    assert!(node.url.starts_with("https://[::1]:"));
+    assert!(accepted(&node, credentials).await);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn client_without_a_certificate_is_refused() {
+    let pki = Pki::new("tls-anonymous");
+    let node = pki.node(vec![ip("::1")]);
+    let credentials = Credentials::new(Some(&pki.ca_file()), None, None).unwrap();
+    assert!(!accepted(&node, credentials).await);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn client_with_a_certificate_from_another_ca_is_refused() {
+    let pki = Pki::new("tls-foreign");
+    let node = pki.node(vec![ip("::1")]);
+    let other = ca("other ca");
+    let (cert, key) = pki.issue("client", vec![SanType::DnsName("client".into())], &other);
+    let credentials = Credentials::new(Some(&pki.ca_file()), Some((&cert, &key)), None).unwrap();
+    assert!(!accepted(&node, credentials).await);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn ipv6_urls_are_verified_against_the_address_without_brackets() {
+    // a certificate for another address is refused, so the one above was
+    // accepted for [::1] itself
+    let pki = Pki::new("tls-other-host");
+    let node = pki.node(vec![ip("::2"), SanType::DnsName("localhost".into())]);
+    let (cert, key) = pki.issue("client", vec![SanType::DnsName("client".into())], &pki.ca);
+    let credentials = Credentials::new(Some(&pki.ca_file()), Some((&cert, &key)), None).unwrap();
+    assert!(!accepted(&node, credentials).await);
+}
@@ -0,0 +1,301 @@
+mod common;
+
+use cn::client::{MemoryClient, RemoteMemory};
+use cn::errors::MemoryError;
+use cn::kv::KeyValueStore;
+use cn::proto::memory::{AllocationError, DeallocationError, MemoryAccessError, Pressure};
+use common::{connect, Node};
+use rand::Rng;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+const CLIENTS: usize = 8;
+const INCREMENTS: usize = 10; // per client
+const COUNTERS: usize = 3;
+const KEYS: usize = 12; // each client's own, enough to split the leaves
+const SHARED: usize = 8;
+const STALLING_WRITERS: usize = 6;
+const STALLED_SETS: usize = 10; // per writer, enough to cycle through the root slots
+
+/// Adds one to the counter under `key`, in a transaction.
+async fn increment(store: &mut KeyValueStore<MemoryClient>, key: &str) -> Result<(), MemoryError> {
+    let mut tx = store.transaction();
+    let count = match tx.get(key).await? {
+        Some(value) => u64::from_le_bytes(value.try_into().unwrap()),
+        None => 0,
+    };
+    tx.set(key, &(count + 1).to_le_bytes())?;
+    tx.commit().await.map(|_| ())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn overlapping_transactions_lose_no_updates() {
+    let node = Node::start(&[]);
+    let store = KeyValueStore::new(connect(&node).await).await.unwrap();
+    let (header, header_key) = (store.header_id(), store.header_key());
+
+    let mut tasks = Vec::new();
+    for client in 0..CLIENTS {
+        let mut store = KeyValueStore::open(connect(&node).await, header, header_key);
+        tasks.push(tokio::spawn(async move {
+            let mut conflicts = 0;
+            for i in 0..INCREMENTS {
+                let key = format!("counter:{}", (client + i) % COUNTERS);
+                loop {
+                    match increment(&mut store, &key).await {
+                        Ok(()) => break,
+                        Err(MemoryError::TransactionConflict) => conflicts += 1,
+                        Err(e) if e.is_stale() => {}
+                        Err(e) => panic!("increment failed: {:?}", e),
+                    }
+                }
+            }
+            conflicts
+        }));
+    }
+    let mut conflicts = 0;
+    for task in tasks {
+        conflicts += task.await.unwrap();
+    }
+
+    let mut store = KeyValueStore::open(connect(&node).await, header, header_key);
+    let mut total = 0;
+    for counter in 0..COUNTERS {
+        let value = store
+            .get(&format!("counter:{}", counter))
+            .await
+            .unwrap()
+            .unwrap();
+        total += u64::from_le_bytes(value.try_into().unwrap());
+    }
+    assert_eq!(total, (CLIENTS * INCREMENTS) as u64);
+    println!("{} conflicts retried", conflicts);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn transactions_conflict_only_on_keys_they_read() {
+    let node = Node::start(&[]);
+    let mut store = KeyValueStore::new(connect(&node).await).await.unwrap();
+    let mut other =
+        KeyValueStore::open(connect(&node).await, store.header_id(), store.header_key());
+    store.set("read", b"1").await.unwrap();
+    store.set("unrelated", b"1").await.unwrap();
+
+    // a key read by the transaction changes before it commits
+    let mut tx = store.transaction();
+    assert_eq!(tx.get("read").await.unwrap().unwrap(), b"1");
+    tx.set("written", b"from tx").unwrap();
+    other.set("read", b"2").await.unwrap();
+    assert!(matches!(
+        tx.commit().await,
+        Err(MemoryError::TransactionConflict)
+    ));
+    assert_eq!(store.get("read").await.unwrap().unwrap(), b"2");
+    assert_eq!(store.get("written").await.unwrap(), None);
+
+    // so does one it found absent
+    let mut tx = store.transaction();
+    assert!(!tx.exists("absent").await.unwrap());
+    tx.set("written", b"from tx").unwrap();
+    other.set("absent", b"now present").await.unwrap();
+    assert!(matches!(
+        tx.commit().await,
+        Err(MemoryError::TransactionConflict)
+    ));
+
+    // but not one it never read
+    let mut tx = store.transaction();
+    assert_eq!(tx.get("read").await.unwrap().unwrap(), b"2");
+    tx.set("written", b"from tx").unwrap();
+    other.set("unrelated", b"2").await.unwrap();
+    tx.commit().await.unwrap();
+    assert_eq!(store.get("written").await.unwrap().unwrap(), b"from tx");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_sets_keep_the_index_ordered_and_complete() {
+    let node = Node::start(&[]);
+    let store = KeyValueStore::new(connect(&node).await).await.unwrap();
+    let (header, header_key) = (store.header_id(), store.header_key());
+
+    let mut tasks = Vec::new();
+    for client in 0..CLIENTS {
+        let mut store = KeyValueStore::open(connect(&node).await, header, header_key);
+        tasks.push(tokio::spawn(async move {
+            for i in 0..KEYS {
+                let own = format!("own:{}:{:03}", client, i);
+                store.set(&own, own.as_bytes()).await.unwrap();
+                if i < SHARED {
+                    let shared = format!("shared:{:02}", (client + i) % SHARED);
+                    store
+                        .set(&shared, client.to_string().as_bytes())
+                        .await
+                        .unwrap();
+                }
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    let mut store = KeyValueStore::open(connect(&node).await, header, header_key);
+    let mut scan = store.scan::<&str, _>(..);
+    let mut keys = Vec::new();
+    while let Some((key, value)) = scan.next().await.unwrap() {
+        if key.starts_with("own:") {
+            assert_eq!(value, key.as_bytes());
+        } else {
+            let writer: usize = String::from_utf8(value).unwrap().parse().unwrap();
+            assert!(writer < CLIENTS);
+        }
+        keys.push(key);
+    }
+    assert!(
+        keys.windows(2).all(|pair| pair[0] < pair[1]),
+        "out of order"
+    );
+
+    let mut expected = BTreeSet::new();
+    for client in 0..CLIENTS {
+        for i in 0..KEYS {
+            expected.insert(format!("own:{}:{:03}", client, i));
+        }
+    }
+    for i in 0..SHARED {
+        expected.insert(format!("shared:{:02}", i));
+    }
+    assert_eq!(keys, expected.into_iter().collect::<Vec<_>>());
+}
+
+/// A client that stalls for a while before each compare-and-swap, so that
+/// commits claiming root slots and publishing roots interleave.
+#[derive(Clone)]
+struct Stalling(MemoryClient);
+
+impl Stalling {
+    async fn stall() {
+        let wait = rand::thread_rng().gen_range(0..=3);
+        tokio::time::sleep(Duration::from_millis(wait)).await;
+    }
+}
+
+#[tonic::async_trait]
+impl RemoteMemory for Stalling {
+    async fn allocate_fenced(&mut self, size: u64) -> Result<(u64, u32), AllocationError> {
+        self.0.allocate_fenced(size).await
+    }
+
+    async fn allocate_evictable(
+        &mut self,
+        size: u64,
+        priority: u32,
+    ) -> Result<u64, AllocationError> {
+        RemoteMemory::allocate_evictable(&mut self.0, size, priority).await
+    }
+
+    async fn free(&mut self, id: u64) -> Result<(), DeallocationError> {
+        self.0.free(id).await
+    }
+
+    async fn read_fenced(
+        &mut self,
+        id: u64,
+        generation: u32,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, MemoryAccessError> {
+        self.0.read_fenced(id, generation, offset, length).await
+    }
+
+    async fn write_fenced(
+        &mut self,
+        id: u64,
+        generation: u32,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<(), MemoryAccessError> {
+        self.0.write_fenced(id, generation, offset, data).await
+    }
+
+    async fn get_memory_size(&mut self, id: u64) -> Result<u64, MemoryAccessError> {
+        self.0.get_memory_size(id).await
+    }
+
+    async fn compare_and_swap_fenced(
+        &mut self,
+        id: u64,
+        generation: u32,
+        offset: u64,
+        expected: u64,
+        desired: u64,
+    ) -> Result<Result<u64, u64>, MemoryAccessError> {
+        Self::stall().await;
+        self.0
+            .compare_and_swap_fenced(id, generation, offset, expected, desired)
+            .await
+    }
+
+    async fn compare_and_swap_with_tail(
+        &mut self,
+        id: u64,
+        offset: u64,
+        expected: u64,
+        desired: u64,
+        tail: Vec<u8>,
+    ) -> Result<Result<u64, u64>, MemoryAccessError> {
+        Self::stall().await;
+        self.0
+            .compare_and_swap_with_tail(id, offset, expected, desired, tail)
+            .await
+    }
+
+    fn region_key(&self, id: u64) -> u64 {
+        self.0.region_key(id)
+    }
+
+    fn learn_key(&self, id: u64, key: u64) {
+        self.0.learn_key(id, key)
+    }
+
+    fn pressure(&self) -> Pressure {
+        self.0.pressure()
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn stalled_commits_never_overwrite_reused_root_slots() {
+    let node = Node::start(&[]);
+    let store = KeyValueStore::new(connect(&node).await).await.unwrap();
+    let (header, header_key) = (store.header_id(), store.header_key());
+
+    let mut tasks = Vec::new();
+    for writer in 0..STALLING_WRITERS {
+        let client = Stalling(connect(&node).await);
+        let mut store = KeyValueStore::open(client, header, header_key);
+        tasks.push(tokio::spawn(async move {
+            for i in 0..STALLED_SETS {
+                let key = format!("stalled:{}:{:02}", writer, i);
+                store.set(&key, key.as_bytes()).await.unwrap();
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    // a root written over a reused slot would point readers at freed nodes
+    let mut store = KeyValueStore::open(connect(&node).await, header, header_key);
+    let read = async {
+        for writer in 0..STALLING_WRITERS {
+            for i in 0..STALLED_SETS {
+                let key = format!("stalled:{}:{:02}", writer, i);
+                let value = store.get(&key).await.unwrap();
+                assert_eq!(value, Some(key.clone().into_bytes()), "{}", key);
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(60), read)
+        .await
+        .expect("reads kept finding stale roots");
+}
@@ -0,0 +1,531 @@
+use crate::storage::{Full, Label, Storage};
+use memmap2::MmapMut;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::ops::Range;
+use std::os::fd::FromRawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const MEMFD: &str = "memfd";
+const MAGIC: &[u8; 8] = b"dnarena1";
+const HEADER: usize = 4096; // magic, generation, then nothing until the first block
+const BLOCK_HEADER: usize = 128;
+const ALIGN: usize = 64; // blocks start on cache lines, and so does their data
+const MIN_SPLIT: usize = BLOCK_HEADER + ALIGN; // smallest remainder split off a block
+const MAX_OWNER: usize = BLOCK_HEADER - 48;
+const USED: u32 = 0x5553_4544;
+const FREE: u32 = 0x4652_4545;
+const UNKNOWN: u32 = u32::MAX; // owner_len of regions whose owner didn't fit
+const CLASSES: usize = usize::BITS as usize; // free lists, by the power of two below the span
+const OFFSET_BITS: u32 = 40; // of a list head; the rest counts pushes and pops
+const OFFSET_MASK: u64 = (1 << OFFSET_BITS) - 1;
+
+// Block header fields, by offset from the start of the block.
+const TAG: usize = 0;
+const OWNER_LEN: usize = 4;
+const SPAN: usize = 8; // bytes in the block, header included
+const ID: usize = 16;
+const NEXT: usize = ID; // of free blocks: the next on its list, in ALIGN units, or 0
+const SIZE: usize = 24;
+const KEY: usize = 32;
+const EVICTABLE: usize = 40;
+const PRIORITY: usize = 44;
+const OWNER: usize = 48;
+
+/// Regions kept in one mapping of a file, or of an anonymous memfd, instead
+/// of on the heap. Each is a block of a header followed by its bytes.
+///
+/// Blocks are allocated without locking: free blocks sit on lock-free
+/// stacks, one for each power of two their span is at least, and
+/// allocating pops one that fits, splitting off what it doesn't need, while
+/// freeing pushes the block back, each with a compare-and-swap on the
+/// stack's head. A count kept next to the head keeps a pop that lost a race
+/// from swapping in a stale next block. Free neighbours are only merged
+/// once nothing fits otherwise, which takes the arena to itself. The map
+/// from ids to blocks is the node's, changed under its lock like the rest.
+///
+/// The header records the region's id, owner, key and eviction priority,
+/// and the file's header the node's generation, so a node restarted on the
+/// same file takes its regions back under the same ids. Owners with names
+/// too long to record lose their regions on a restart, and grants, which
+/// stripes regions are shards of, regions spilled to disk at the time, and
+/// the forwarding addresses and tombstones of regions migrated away or
+/// evicted don't survive one either.
+pub struct Arena {
+    _map: MmapMut, // accessed through `base`, so blocks can be written when shared
+    base: *mut u8,
+    len: usize,
+    heads: [AtomicU64; CLASSES], // of the free lists: pushes and pops, then the offset
+    blocks: HashMap<usize, usize>, // region id to the offset of its block
+    reattached: Option<u32>,     // the generation of regions taken back
+}
+
+// SAFETY: `base` points into the mapping the arena owns. Blocks are only
+// written by whoever took them off a free list, until they are pushed back,
+// or with the arena to itself, and free lists only change atomically.
+unsafe impl Send for Arena {}
+unsafe impl Sync for Arena {}
+
+/// A region an arena kept from an earlier run.
+pub struct Reattached {
+    pub id: usize,
+    pub size: usize,
+    pub label: Label,
+}
+
+impl Arena {
+    /// Maps the file at `path`, or a new memfd for `memfd`. Files written by
+    /// an earlier run are taken back as they are, and new ones are made
+    /// `size` bytes long.
+    pub fn open(path: &str, size: usize) -> io::Result<Self> {
+        let file = if path == MEMFD {
+            memfd()?
+        } else {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?
+        };
+        let existing = file.metadata()?.len() as usize;
+        if existing == 0 {
+            if size < HEADER + BLOCK_HEADER + ALIGN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "arena too small",
+                ));
+            }
+            file.set_len(size as u64)?;
+        }
+        // SAFETY: the node is the only one meant to use the file while it
+        // runs, so nothing else changes it under the mapping.
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        let mut arena = Self {
+            base: map.as_mut_ptr(),
+            len: map.len(),
+            _map: map,
+            heads: std::array::from_fn(|_| AtomicU64::new(0)),
+            blocks: HashMap::new(),
+            reattached: None,
+        };
+        if existing == 0 {
+            arena.format();
+        } else {
+            arena.walk()?;
+        }
+        Ok(arena)
+    }
+
+    /// The generation the regions taken back from an earlier run are from.
+    pub fn generation(&self) -> Option<u32> {
+        self.reattached
+    }
+
+    /// The regions taken back from an earlier run.
+    pub fn reattached(&self) -> Vec<Reattached> {
+        self.blocks
+            .iter()
+            .map(|(&id, &block)| {
+                let priority =
+                    (self.u32_at(block + EVICTABLE) != 0).then(|| self.u32_at(block + PRIORITY));
+                Reattached {
+                    id,
+                    size: self.u64_at(block + SIZE) as usize,
+                    label: Label {
+                        owner: self.owner(block),
+                        key: self.u64_at(block + KEY),
+                        priority,
+                    },
+                }
+            })
+            .collect()
+    }
+
+    fn format(&mut self) {
+        self.slice_mut(0..8).copy_from_slice(MAGIC);
+        self.set_u32(8, 0);
+        let span = (self.len - HEADER) / ALIGN * ALIGN;
+        self.push(HEADER, span);
+    }
+
+    /// Takes back the blocks an earlier run left, dropping regions whose
+    /// owner wasn't recorded.
+    fn walk(&mut self) -> io::Result<()> {
+        let corrupt = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+        if self.len < HEADER || self.slice(0..8) != MAGIC {
+            return Err(corrupt("not an arena"));
+        }
+        let generation = self.u32_at(8);
+        let mut block = HEADER;
+        let mut free = Vec::new();
+        let mut unowned = Vec::new();
+        while block + BLOCK_HEADER <= self.len {
+            let span = self.u64_at(block + SPAN) as usize;
+            if span < BLOCK_HEADER || !span.is_multiple_of(ALIGN) || block + span > self.len {
+                return Err(corrupt("arena block out of bounds"));
+            }
+            match self.u32_at(block + TAG) {
+                USED => {
+                    let owner_len = self.u32_at(block + OWNER_LEN);
+                    if BLOCK_HEADER + self.u64_at(block + SIZE) as usize > span
+                        || (owner_len != UNKNOWN && owner_len as usize > MAX_OWNER)
+                    {
+                        return Err(corrupt("arena region out of bounds"));
+                    }
+                    let id = self.u64_at(block + ID) as usize;
+                    if self.owner(block).is_none() {
+                        unowned.push(id);
+                    }
+                    self.blocks.insert(id, block);
+                }
+                FREE => free.push((block, span)),
+                _ => return Err(corrupt("arena block without a tag")),
+            }
+            block += span;
+        }
+        for (block, span) in merge(free) {
+            self.push(block, span);
+        }
+        for id in unowned {
+            self.remove(id);
+        }
+        if !self.blocks.is_empty() {
+            self.reattached = Some(generation);
+        }
+        Ok(())
+    }
+
+    /// The span of a block holding `size` bytes.
+    fn span_for(size: usize) -> usize {
+        (BLOCK_HEADER + size).div_ceil(ALIGN) * ALIGN
+    }
+
+    /// Carves a block for region `id` out of a free one, without locking.
+    /// It still has to be put in `blocks`.
+    fn allocate(&self, id: usize, size: usize) -> Option<usize> {
+        let needed = Self::span_for(size);
+        // the list below the span may hold blocks that fit, the ones above
+        // only hold blocks that do
+        let below = class(needed);
+        let block = match self.pop(below) {
+            Some(block) if self.span_at(block) >= needed => Some(block),
+            Some(block) => {
+                self.push(block, self.span_at(block));
+                None
+            }
+            None => None,
+        };
+        let block = block.or_else(|| (below + 1..CLASSES).find_map(|class| self.pop(class)))?;
+        Some(self.carve(block, self.span_at(block), id, size))
+    }
+
+    /// Like `allocate`, but merges free neighbours first and takes the
+    /// smallest block that fits, which needs the arena to itself.
+    fn allocate_merged(&mut self, id: usize, size: usize) -> Option<usize> {
+        let needed = Self::span_for(size);
+        let mut free = Vec::new();
+        for class in 0..CLASSES {
+            while let Some(block) = self.pop(class) {
+                free.push((block, self.span_at(block)));
+            }
+        }
+        let mut free = merge(free);
+        let best = (0..free.len())
+            .filter(|&i| free[i].1 >= needed)
+            .min_by_key(|&i| free[i].1);
+        let taken = best.map(|i| free.swap_remove(i));
+        for (block, span) in free {
+            self.push(block, span);
+        }
+        let (block, span) = taken?;
+        Some(self.carve(block, span, id, size))
+    }
+
+    /// Makes the free block at `block` region `id`'s, pushing back what it
+    /// doesn't need.
+    fn carve(&self, block: usize, mut span: usize, id: usize, size: usize) -> usize {
+        let needed = Self::span_for(size);
+        if span - needed >= MIN_SPLIT {
+            self.push(block + needed, span - needed);
+            span = needed;
+        }
+        // SAFETY: the block was taken off the free lists, so it is ours.
+        // Its id goes where its next block was, which a pop that lost the
+        // race for it may still read, so it is written atomically.
+        unsafe {
+            self.zero(block..block + ID);
+            self.zero(block + SIZE..block + BLOCK_HEADER + size);
+            self.put_u32(block + OWNER_LEN, UNKNOWN);
+            self.put_u64(block + SPAN, span as u64);
+            self.put_u64(block + SIZE, size as u64);
+        }
+        self.word(block + ID).store(id as u64, Ordering::Relaxed);
+        // Tagged last, so a block is only taken back once it is whole.
+        unsafe { self.put_u32(block + TAG, USED) };
+        block
+    }
+
+    /// Puts a block the caller has to itself on the free list for its span.
+    fn push(&self, block: usize, span: usize) {
+        // SAFETY: the block is the caller's until it is on the list
+        unsafe {
+            self.put_u32(block + TAG, FREE);
+            self.put_u64(block + SPAN, span as u64);
+        }
+        let head = &self.heads[class(span)];
+        let mut current = head.load(Ordering::Relaxed);
+        loop {
+            self.word(block + NEXT)
+                .store(current & OFFSET_MASK, Ordering::Relaxed);
+            let new = retag(current, (block / ALIGN) as u64);
+            match head.compare_exchange_weak(current, new, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Takes the block at the head of a free list, if there is one.
+    fn pop(&self, class: usize) -> Option<usize> {
+        let head = &self.heads[class];
+        let mut current = head.load(Ordering::Acquire);
+        loop {
+            let block = (current & OFFSET_MASK) as usize * ALIGN;
+            if block == 0 {
+                return None;
+            }
+            // stale if another pop took the block meanwhile, but then the
+            // count has moved on and the swap fails
+            let next = self.word(block + NEXT).load(Ordering::Relaxed);
+            match head.compare_exchange_weak(
+                current,
+                retag(current, next),
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Some(block),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// The free blocks on the lists, by following them. Only exact while
+    /// nothing else allocates or frees.
+    fn free_blocks(&self) -> Vec<(usize, usize)> {
+        let mut free = Vec::new();
+        for head in &self.heads {
+            let mut next = head.load(Ordering::Acquire) & OFFSET_MASK;
+            while next != 0 && free.len() < self.len / ALIGN {
+                let block = next as usize * ALIGN;
+                if block + BLOCK_HEADER > self.len {
+                    break;
+                }
+                free.push((block, self.span_at(block)));
+                next = self.word(block + NEXT).load(Ordering::Relaxed);
+            }
+        }
+        free
+    }
+
+    fn span_at(&self, block: usize) -> usize {
+        self.u64_at(block + SPAN) as usize
+    }
+
+    fn owner(&self, block: usize) -> Option<String> {
+        let len = self.u32_at(block + OWNER_LEN);
+        if len == UNKNOWN {
+            return None;
+        }
+        let start = block + OWNER;
+        Some(String::from_utf8_lossy(self.slice(start..start + len as usize)).into_owned())
+    }
+
+    fn data(&self, block: usize) -> Range<usize> {
+        let start = block + BLOCK_HEADER;
+        start..start + self.u64_at(block + SIZE) as usize
+    }
+
+    fn slice(&self, range: Range<usize>) -> &[u8] {
+        assert!(range.start <= range.end && range.end <= self.len);
+        // SAFETY: within the mapping, and only written by whoever has the
+        // block to itself
+        unsafe { std::slice::from_raw_parts(self.base.add(range.start), range.len()) }
+    }
+
+    fn slice_mut(&mut self, range: Range<usize>) -> &mut [u8] {
+        assert!(range.start <= range.end && range.end <= self.len);
+        // SAFETY: within the mapping, which the arena has to itself
+        unsafe { std::slice::from_raw_parts_mut(self.base.add(range.start), range.len()) }
+    }
+
+    /// A header word that pops may read while it is being written.
+    fn word(&self, at: usize) -> &AtomicU64 {
+        assert!(at + 8 <= self.len && at.is_multiple_of(8));
+        // SAFETY: within the mapping, and aligned since the mapping starts
+        // on a page
+        unsafe { &*self.base.add(at).cast::<AtomicU64>() }
+    }
+
+    /// # Safety
+    ///
+    /// The bytes must be in a block the caller took off the free lists and
+    /// hasn't handed out again, or the caller must have the arena to itself.
+    unsafe fn put_u32(&self, at: usize, value: u32) {
+        assert!(at + 4 <= self.len);
+        self.base
+            .add(at)
+            .cast::<[u8; 4]>()
+            .write(value.to_le_bytes());
+    }
+
+    /// # Safety
+    ///
+    /// Like `put_u32`.
+    unsafe fn put_u64(&self, at: usize, value: u64) {
+        assert!(at + 8 <= self.len);
+        self.base
+            .add(at)
+            .cast::<[u8; 8]>()
+            .write(value.to_le_bytes());
+    }
+
+    /// # Safety
+    ///
+    /// Like `put_u32`.
+    unsafe fn zero(&self, range: Range<usize>) {
+        assert!(range.start <= range.end && range.end <= self.len);
+        self.base.add(range.start).write_bytes(0, range.len());
+    }
+
+    fn u32_at(&self, at: usize) -> u32 {
+        u32::from_le_bytes(self.slice(at..at + 4).try_into().unwrap())
+    }
+
+    fn u64_at(&self, at: usize) -> u64 {
+        u64::from_le_bytes(self.slice(at..at + 8).try_into().unwrap())
+    }
+
+    fn set_u32(&mut self, at: usize, value: u32) {
+        self.slice_mut(at..at + 4)
+            .copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_u64(&mut self, at: usize, value: u64) {
+        self.slice_mut(at..at + 8)
+            .copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// The free list for blocks of `span` bytes: the power of two below it.
+fn class(span: usize) -> usize {
+    (usize::BITS - 1 - span.leading_zeros()) as usize
+}
+
+/// A list head pointing at `offset`, counting one more push or pop than
+/// `head`.
+fn retag(head: u64, offset: u64) -> u64 {
+    ((head >> OFFSET_BITS).wrapping_add(1) << OFFSET_BITS) | offset
+}
+
+/// Free blocks with their free neighbours merged in, in order.
+fn merge(mut free: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    free.sort_unstable();
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(free.len());
+    for (block, span) in free {
+        match merged.last_mut() {
+            Some((start, run)) if *start + *run == block => *run += span,
+            _ => merged.push((block, span)),
+        }
+    }
+    merged
+}
+
+impl Storage for Arena {
+    fn insert(&mut self, id: usize, size: usize) -> Result<(), Full> {
+        // a region replaced keeps its block until the new one has one
+        let block = match self.allocate(id, size) {
+            Some(block) => block,
+            None => self.allocate_merged(id, size).ok_or(Full)?,
+        };
+        if let Some(old) = self.blocks.insert(id, block) {
+            self.push(old, self.span_at(old));
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, id: usize) -> Option<usize> {
+        let block = self.blocks.remove(&id)?;
+        let size = self.u64_at(block + SIZE) as usize;
+        self.push(block, self.span_at(block));
+        Some(size)
+    }
+
+    fn get(&self, id: usize) -> Option<&[u8]> {
+        let block = *self.blocks.get(&id)?;
+        Some(self.slice(self.data(block)))
+    }
+
+    fn get_mut(&mut self, id: usize) -> Option<&mut [u8]> {
+        let block = *self.blocks.get(&id)?;
+        let data = self.data(block);
+        Some(self.slice_mut(data))
+    }
+
+    fn regions(&self) -> Vec<(usize, usize)> {
+        self.blocks
+            .iter()
+            .map(|(&id, &block)| (id, self.u64_at(block + SIZE) as usize))
+            .collect()
+    }
+
+    fn count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn fits(&self, size: usize, freeing: &[usize]) -> bool {
+        let needed = Self::span_for(size);
+        let mut free = self.free_blocks();
+        if free.iter().any(|&(_, span)| span >= needed) {
+            return true;
+        }
+        for &block in freeing.iter().filter_map(|id| self.blocks.get(id)) {
+            free.push((block, self.span_at(block)));
+        }
+        merge(free).iter().any(|&(_, span)| span >= needed)
+    }
+
+    fn label(&mut self, id: usize, label: &Label) {
+        let Some(&block) = self.blocks.get(&id) else {
+            return;
+        };
+        self.set_u64(block + KEY, label.key);
+        self.set_u32(block + EVICTABLE, label.priority.is_some() as u32);
+        self.set_u32(block + PRIORITY, label.priority.unwrap_or(0));
+        match label.owner.as_deref() {
+            Some(owner) if owner.len() <= MAX_OWNER => {
+                let start = block + OWNER;
+                self.slice_mut(start..start + owner.len())
+                    .copy_from_slice(owner.as_bytes());
+                self.set_u32(block + OWNER_LEN, owner.len() as u32);
+            }
+            _ => self.set_u32(block + OWNER_LEN, UNKNOWN),
+        }
+    }
+
+    fn record_generation(&mut self, generation: u32) {
+        self.set_u32(8, generation);
+    }
+}
+
+/// An anonymous file only this process maps, unless it passes it on.
+fn memfd() -> io::Result<File> {
+    // SAFETY: the name is a valid C string, and the descriptor returned is
+    // new and ours alone, so the file can own it.
+    let fd = unsafe { libc::memfd_create(c"dn-arena".as_ptr(), 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
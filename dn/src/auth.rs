@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tonic::service::Interceptor;
+use tonic::{Code, Request, Status};
+
+/// Who a request came from, as established by the `Authenticator`.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub name: String,
+    // other data nodes and operators: may act on any region and manage the
+    // node
+    pub admin: bool,
+    // compute nodes failing over: may promote backups and reconfigure chains
+    pub replicator: bool,
+}
+
+impl Principal {
+    pub fn check_admin(&self) -> Result<(), Forbidden> {
+        if self.admin {
+            Ok(())
+        } else {
+            Err(Forbidden)
+        }
+    }
+
+    /// Whether the caller may change which replica takes requests.
+    pub fn check_replicator(&self) -> Result<(), Forbidden> {
+        if self.admin || self.replicator {
+            Ok(())
+        } else {
+            Err(Forbidden)
+        }
+    }
+}
+
+/// The caller of a request that went through the `Authenticator`.
+pub fn caller<T>(request: &Request<T>) -> Principal {
+    request
+        .extensions()
+        .get::<Principal>()
+        .cloned()
+        .expect("requests go through the authenticator")
+}
+
+/// Not allowed for the caller.
+#[derive(Debug)]
+pub struct Forbidden;
+
+impl From<Forbidden> for Status {
+    fn from(_: Forbidden) -> Self {
+        Status::new(Code::PermissionDenied, "Permission denied")
+    }
+}
+
+/// Checks the bearer token on every request against the known tokens, and
+/// records who made it. Without any tokens everyone is let in as an admin,
+/// the way the node worked before it had authentication.
+#[derive(Clone, Default)]
+pub struct Authenticator {
+    tokens: Option<Arc<HashMap<String, Principal>>>,
+}
+
+impl Authenticator {
+    /// Reads tokens from a file with one `<token> <principal>
+    /// [admin|replicator]` per line. Blank lines and lines starting with `#`
+    /// are skipped.
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut tokens = HashMap::new();
+        for (number, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (admin, replicator) = match fields[..] {
+                [_, _] => (false, false),
+                [_, _, "admin"] => (true, false),
+                [_, _, "replicator"] => (false, true),
+                _ => return Err(format!("{}:{}: bad token line", path, number + 1).into()),
+            };
+            let principal = Principal {
+                name: fields[1].to_string(),
+                admin,
+                replicator,
+            };
+            tokens.insert(fields[0].to_string(), principal);
+        }
+        Ok(Self {
+            tokens: Some(Arc::new(tokens)),
+        })
+    }
+}
+
+impl Interceptor for Authenticator {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let principal = match &self.tokens {
+            None => Principal {
+                name: String::new(),
+                admin: true,
+                replicator: false,
+            },
+            Some(tokens) => request
+                .metadata()
+                .get("authorization")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .and_then(|token| tokens.get(token))
+                .cloned()
+                .ok_or_else(|| Status::new(Code::Unauthenticated, "Invalid or missing token"))?,
+        };
+        request.extensions_mut().insert(principal);
+        Ok(request)
+    }
+}
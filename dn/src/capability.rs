@@ -0,0 +1,22 @@
+use sha2::{Digest, Sha256};
+
+/// A fresh read-write key for a region. Never 0, which requests use for
+/// presenting no key.
+pub fn new_key() -> u64 {
+    loop {
+        let key = rand::random::<u64>();
+        if key != 0 {
+            return key;
+        }
+    }
+}
+
+/// The read-only key that goes with a read-write key. Anyone holding the
+/// read-write key can derive it, but not the other way around.
+pub fn read_only(key: u64) -> u64 {
+    let digest = Sha256::new()
+        .chain_update(b"read-only")
+        .chain_update(key.to_le_bytes())
+        .finalize();
+    u64::from_le_bytes(digest[..8].try_into().unwrap())
+}
@@ -0,0 +1,97 @@
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::{
+    Certificate, Channel, ClientTlsConfig, Endpoint, Identity, ServerTlsConfig,
+};
+use tonic::{Request, Status};
+
+/// A channel that presents this node's token on every call.
+pub type Authed = InterceptedService<Channel, Token>;
+
+/// How this node connects to other nodes: its backups, migration targets
+/// and the coordinator. TLS is used for `https` URLs only.
+#[derive(Clone, Default)]
+pub struct Credentials {
+    client: ClientTlsConfig,
+    token: Token,
+}
+
+impl Credentials {
+    /// Trusts servers whose certificates are signed by the CA in `ca`,
+    /// presents `identity` to servers that require client certificates, and
+    /// authenticates with `token` to servers that require one.
+    pub fn client(
+        ca: Option<&str>,
+        identity: Option<&Identity>,
+        token: Option<&str>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut config = ClientTlsConfig::new();
+        if let Some(ca) = ca {
+            config = config.ca_certificate(Certificate::from_pem(std::fs::read(ca)?));
+        }
+        if let Some(identity) = identity {
+            config = config.identity(identity.clone());
+        }
+        let token = match token {
+            Some(token) => Token(Some(format!("Bearer {}", token).parse()?)),
+            None => Token(None),
+        };
+        Ok(Self {
+            client: config,
+            token,
+        })
+    }
+
+    pub fn endpoint(&self, url: String) -> Result<Endpoint, tonic::transport::Error> {
+        let endpoint = Endpoint::from_shared(url)?;
+        if endpoint.uri().scheme_str() != Some("https") {
+            return Ok(endpoint);
+        }
+        // verified against the host in the URL, which for IPv6 addresses
+        // has to lose its brackets
+        let host = endpoint.uri().host().unwrap_or_default();
+        let host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        endpoint.tls_config(self.client.clone().domain_name(host))
+    }
+
+    pub fn authed(&self, channel: Channel) -> Authed {
+        InterceptedService::new(channel, self.token.clone())
+    }
+}
+
+/// Adds the bearer token, if there is one, to outgoing requests.
+#[derive(Clone, Default)]
+pub struct Token(Option<MetadataValue<Ascii>>);
+
+impl Interceptor for Token {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(token) = &self.0 {
+            request
+                .metadata_mut()
+                .insert("authorization", token.clone());
+        }
+        Ok(request)
+    }
+}
+
+/// Reads a certificate chain and its private key, both PEM.
+pub fn identity(cert: &str, key: &str) -> std::io::Result<Identity> {
+    Ok(Identity::from_pem(
+        std::fs::read(cert)?,
+        std::fs::read(key)?,
+    ))
+}
+
+/// Serves TLS as `identity`, and if `client_ca` is given only accepts
+/// clients presenting a certificate signed by that CA.
+pub fn server(identity: Identity, client_ca: Option<&str>) -> std::io::Result<ServerTlsConfig> {
+    let mut config = ServerTlsConfig::new().identity(identity);
+    if let Some(ca) = client_ca {
+        config = config.client_ca_root(Certificate::from_pem(std::fs::read(ca)?));
+    }
+    Ok(config)
+}
@@ -0,0 +1,218 @@
+use crate::auth::{self, Forbidden, Principal};
+use crate::capability;
+use crate::proto::memory::{self, ListedName, NameEntry};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use tonic::{Code, Request, Response, Status};
+
+/// Names for regions, as slash-separated paths like `/stores/sessions`.
+/// Directories are implicit: a name is a directory while there are names
+/// under it, and can have an entry of its own as well. Entries are kept in
+/// memory like everything else on the node, so they are lost on restart,
+/// together with the regions of this node they name.
+#[derive(Default)]
+pub struct Directory {
+    entries: Mutex<BTreeMap<String, Named>>,
+}
+
+/// An entry together with the principal that created it, who alone (with
+/// admins) may remove or move it and learn its read-write key.
+struct Named {
+    entry: NameEntry,
+    owner: String,
+}
+
+impl Named {
+    fn check_owner(&self, caller: &Principal) -> Result<(), Forbidden> {
+        if caller.admin || caller.name == self.owner {
+            Ok(())
+        } else {
+            Err(Forbidden)
+        }
+    }
+
+    /// The entry as `caller` gets to see it: everyone but the owner and
+    /// admins only gets the read-only key.
+    fn entry_for(&self, caller: &Principal) -> NameEntry {
+        let mut entry = self.entry.clone();
+        if entry.key != 0 && self.check_owner(caller).is_err() {
+            entry.key = capability::read_only(entry.key);
+        }
+        entry
+    }
+}
+
+struct InvalidName(String);
+
+impl From<InvalidName> for Status {
+    fn from(invalid: InvalidName) -> Self {
+        Status::new(
+            Code::InvalidArgument,
+            format!("Invalid name {:?}", invalid.0),
+        )
+    }
+}
+
+/// Rejects anything but `/` followed by non-empty components separated by
+/// single slashes.
+fn check_name(name: &str) -> Result<(), InvalidName> {
+    let valid = name
+        .strip_prefix('/')
+        .is_some_and(|rest| rest.split('/').all(|component| !component.is_empty()));
+    if !valid {
+        return Err(InvalidName(name.to_string()));
+    }
+    Ok(())
+}
+
+/// The prefix shared by every name under `directory`.
+fn children_prefix(directory: &str) -> String {
+    match directory {
+        "/" => "/".to_string(),
+        _ => format!("{}/", directory),
+    }
+}
+
+fn not_found(name: &str) -> Status {
+    Status::new(Code::NotFound, format!("No such name {}", name))
+}
+
+fn already_exists(name: &str) -> Status {
+    Status::new(Code::AlreadyExists, format!("{} already exists", name))
+}
+
+/// `name` and every name under it.
+fn subtree<'a>(
+    entries: &'a BTreeMap<String, Named>,
+    name: &'a str,
+) -> impl Iterator<Item = (&'a String, &'a Named)> + 'a {
+    let prefix = children_prefix(name);
+    entries
+        .range(name.to_string()..)
+        .take_while(move |(key, _)| key.starts_with(name))
+        .filter(move |(key, _)| key.as_str() == name || key.starts_with(&prefix))
+}
+
+#[tonic::async_trait]
+impl memory::directory_server::Directory for Directory {
+    async fn create_name(
+        &self,
+        request: Request<memory::CreateNameRequest>,
+    ) -> Result<Response<memory::CreateNameResponse>, Status> {
+        let owner = auth::caller(&request).name;
+        let input = request.into_inner();
+        check_name(&input.name)?;
+        let entry = input
+            .entry
+            .ok_or(Status::new(Code::InvalidArgument, "Missing entry"))?;
+        let mut entries = self.entries.lock().unwrap();
+        if entries.contains_key(&input.name) {
+            return Err(already_exists(&input.name));
+        }
+        entries.insert(input.name, Named { entry, owner });
+        Ok(Response::new(memory::CreateNameResponse {}))
+    }
+
+    async fn lookup_name(
+        &self,
+        request: Request<memory::LookupNameRequest>,
+    ) -> Result<Response<memory::LookupNameResponse>, Status> {
+        let caller = auth::caller(&request);
+        let input = request.into_inner();
+        let entries = self.entries.lock().unwrap();
+        match entries.get(&input.name) {
+            Some(named) => Ok(Response::new(memory::LookupNameResponse {
+                entry: Some(named.entry_for(&caller)),
+            })),
+            None => Err(not_found(&input.name)),
+        }
+    }
+
+    async fn list_names(
+        &self,
+        request: Request<memory::ListNamesRequest>,
+    ) -> Result<Response<memory::ListNamesResponse>, Status> {
+        let caller = auth::caller(&request);
+        let input = request.into_inner();
+        if input.directory != "/" {
+            check_name(&input.directory)?;
+        }
+        let prefix = children_prefix(&input.directory);
+        let entries = self.entries.lock().unwrap();
+        let mut names: BTreeMap<&str, ListedName> = BTreeMap::new();
+        for (key, named) in entries
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+        {
+            let rest = &key[prefix.len()..];
+            let (component, below) = match rest.split_once('/') {
+                Some((component, _)) => (component, true),
+                None => (rest, false),
+            };
+            let listed = names.entry(component).or_insert_with(|| ListedName {
+                name: component.to_string(),
+                entry: None,
+                has_children: false,
+            });
+            if below {
+                listed.has_children = true;
+            } else {
+                listed.entry = Some(named.entry_for(&caller));
+            }
+        }
+        let names = names.into_values().collect();
+        Ok(Response::new(memory::ListNamesResponse { names }))
+    }
+
+    async fn delete_name(
+        &self,
+        request: Request<memory::DeleteNameRequest>,
+    ) -> Result<Response<memory::DeleteNameResponse>, Status> {
+        let caller = auth::caller(&request);
+        let input = request.into_inner();
+        let mut entries = self.entries.lock().unwrap();
+        let named = entries
+            .get(&input.name)
+            .ok_or_else(|| not_found(&input.name))?;
+        named.check_owner(&caller)?;
+        let named = entries.remove(&input.name).unwrap();
+        Ok(Response::new(memory::DeleteNameResponse {
+            entry: Some(named.entry),
+        }))
+    }
+
+    async fn rename_name(
+        &self,
+        request: Request<memory::RenameNameRequest>,
+    ) -> Result<Response<memory::RenameNameResponse>, Status> {
+        let caller = auth::caller(&request);
+        let input = request.into_inner();
+        check_name(&input.from)?;
+        check_name(&input.to)?;
+        if input.to == input.from || input.to.starts_with(&children_prefix(&input.from)) {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!("Can't move {} into itself", input.from),
+            ));
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let mut moving = Vec::new();
+        for (key, named) in subtree(&entries, &input.from) {
+            named.check_owner(&caller)?;
+            moving.push(key.clone());
+        }
+        if moving.is_empty() {
+            return Err(not_found(&input.from));
+        }
+        if let Some((existing, _)) = subtree(&entries, &input.to).next() {
+            return Err(already_exists(existing));
+        }
+        for from in moving {
+            let entry = entries.remove(&from).unwrap();
+            let to = format!("{}{}", input.to, &from[input.from.len()..]);
+            entries.insert(to, entry);
+        }
+        Ok(Response::new(memory::RenameNameResponse {}))
+    }
+}
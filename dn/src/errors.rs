@@ -1,6 +1,9 @@
 #[derive(Debug)]
 pub enum AllocationError {
     AllocationTooLarge,
+    Draining,
+    InsufficientMemory,
+    QuotaExceeded,
 }
 
 impl std::fmt::Display for AllocationError {
@@ -9,14 +12,23 @@ impl std::fmt::Display for AllocationError {
             AllocationError::AllocationTooLarge => {
                 write!(f, "Requested too much memory in allocation")
             }
+            AllocationError::Draining => write!(f, "Node is draining"),
+            AllocationError::InsufficientMemory => write!(f, "Not enough memory on the node"),
+            AllocationError::QuotaExceeded => write!(f, "Tenant is over its quota"),
         }
     }
 }
 impl std::error::Error for AllocationError {}
 
+use crate::auth::Forbidden;
+use crate::memory::Forward;
+
 #[derive(Debug)]
 pub enum DeallocationError {
     InvalidMemoryAddress,
+    Moved(Forward),
+    PermissionDenied,
+    Evicted,
 }
 
 impl std::fmt::Display for DeallocationError {
@@ -25,15 +37,40 @@ impl std::fmt::Display for DeallocationError {
             DeallocationError::InvalidMemoryAddress => {
                 write!(f, "Couldn't locate memory address to deallocate")
             }
+            DeallocationError::Moved(forward) => write!(f, "Region moved to {}", forward.addr),
+            DeallocationError::PermissionDenied => write!(f, "Region belongs to someone else"),
+            DeallocationError::Evicted => write!(f, "Region was evicted"),
         }
     }
 }
 impl std::error::Error for DeallocationError {}
 
+impl From<MemoryAccessError> for DeallocationError {
+    fn from(error: MemoryAccessError) -> Self {
+        match error {
+            MemoryAccessError::Moved(forward) => DeallocationError::Moved(forward),
+            MemoryAccessError::PermissionDenied => DeallocationError::PermissionDenied,
+            MemoryAccessError::Evicted => DeallocationError::Evicted,
+            _ => DeallocationError::InvalidMemoryAddress,
+        }
+    }
+}
+
+impl From<Forbidden> for DeallocationError {
+    fn from(_: Forbidden) -> Self {
+        DeallocationError::PermissionDenied
+    }
+}
+
 #[derive(Debug)]
 pub enum MemoryAccessError {
     InvalidMemoryAddress,
     OutOfBoundsAccess,
+    StaleGeneration,
+    Moved(Forward),
+    PermissionDenied,
+    Evicted,
+    Disk(String), // a spilled region's file failed
 }
 
 impl std::fmt::Display for MemoryAccessError {
@@ -41,6 +78,22 @@ impl std::fmt::Display for MemoryAccessError {
         match self {
             MemoryAccessError::InvalidMemoryAddress => write!(f, "Couldn't locate memory address"),
             MemoryAccessError::OutOfBoundsAccess => write!(f, "Memory access out of bounds"),
+            MemoryAccessError::StaleGeneration => {
+                write!(
+                    f,
+                    "Memory address is from an earlier generation of this node"
+                )
+            }
+            MemoryAccessError::Moved(forward) => write!(f, "Region moved to {}", forward.addr),
+            MemoryAccessError::PermissionDenied => write!(f, "Region belongs to someone else"),
+            MemoryAccessError::Evicted => write!(f, "Region was evicted"),
+            MemoryAccessError::Disk(e) => write!(f, "Spilled region failed: {}", e),
         }
     }
 }
+
+impl From<Forbidden> for MemoryAccessError {
+    fn from(_: Forbidden) -> Self {
+        MemoryAccessError::PermissionDenied
+    }
+}
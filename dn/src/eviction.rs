@@ -0,0 +1,52 @@
+/// Which regions go first, whether evicted once the node is full, among
+/// the evictable ones of the lowest priority, or demoted to disk: the least
+/// recently or the least frequently used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Eviction {
+    #[default]
+    Lru,
+    Lfu,
+}
+
+impl std::str::FromStr for Eviction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(Eviction::Lru),
+            "lfu" => Ok(Eviction::Lfu),
+            _ => Err(format!("Unknown eviction policy {}", s)),
+        }
+    }
+}
+
+/// How a region has been used. Uses are counted on the node's clock,
+/// which ticks with every access.
+#[derive(Debug, Clone, Copy)]
+pub struct Uses {
+    pub last_used: u64,
+    pub uses: u64,
+}
+
+impl Uses {
+    pub fn new(now: u64) -> Self {
+        Self {
+            last_used: now,
+            uses: 0,
+        }
+    }
+
+    pub fn touch(&mut self, now: u64) {
+        self.last_used = now;
+        self.uses += 1;
+    }
+
+    /// Orders regions by when they should go, earliest first. Ties in
+    /// frequency go to the least recently used.
+    pub fn rank(&self, policy: Eviction) -> (u64, u64) {
+        match policy {
+            Eviction::Lru => (self.last_used, 0),
+            Eviction::Lfu => (self.uses, self.last_used),
+        }
+    }
+}
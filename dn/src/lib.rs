@@ -0,0 +1,204 @@
+mod arena;
+mod auth;
+mod capability;
+mod credentials;
+mod directory;
+mod errors;
+mod eviction;
+mod membership;
+mod memory;
+mod migration;
+mod pressure;
+mod proto;
+mod replication;
+mod rpc;
+mod scheduler;
+mod spill;
+mod storage;
+mod tenant;
+mod throttle;
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::Server;
+
+use crate::arena::Arena;
+use crate::auth::Authenticator;
+use crate::credentials::Credentials;
+use crate::directory::Directory;
+use crate::eviction::Eviction;
+use crate::membership::Coordinator;
+use crate::memory::DataNode;
+use crate::pressure::Watermarks;
+use crate::proto::memory::directory_server::DirectoryServer;
+use crate::proto::memory::membership_server::MembershipServer;
+use crate::proto::memory::memory_server::MemoryServer;
+use crate::replication::{Mode, Replication, Role};
+use crate::rpc::MemoryService;
+use crate::spill::{Spill, Tiering};
+use crate::throttle::Limits;
+
+const DEFAULT_ADDR: &str = "[::1]:50051";
+const DEFAULT_HEARTBEAT_MS: u64 = 250;
+const DEFAULT_ARENA_SIZE: usize = 64 * 1024 * 1024;
+
+/// Starts a data node set up by `args`, the options `dn` takes, returning
+/// the address it listens on, which for port 0 is the one it was given,
+/// and the future that serves it until it fails.
+pub async fn start<I: IntoIterator<Item = String>>(
+    args: I,
+) -> Result<
+    (
+        SocketAddr,
+        impl Future<Output = Result<(), tonic::transport::Error>> + Send,
+    ),
+    Box<dyn std::error::Error>,
+> {
+    let mut args = args.into_iter();
+    let mut addr = DEFAULT_ADDR.to_string();
+    let mut mode = Mode::PrimaryBackup;
+    let mut role = Role::Primary;
+    let mut backups = Vec::new();
+    let mut coordinator = None;
+    let mut advertise = None;
+    let mut heartbeat = Duration::from_millis(DEFAULT_HEARTBEAT_MS);
+    let mut timeout = None;
+    let mut tls_cert = None;
+    let mut tls_key = None;
+    let mut tls_ca = None;
+    let mut tls_client_ca = None;
+    let mut tokens = None;
+    let mut token = None;
+    let mut require_keys = false;
+    let mut tenants = None;
+    let mut limits = None;
+    let mut capacity = None;
+    let mut watermarks = Watermarks::default();
+    let mut eviction = Eviction::default();
+    let mut spill = None;
+    let mut ram = None;
+    let mut demotion = Eviction::default();
+    let mut promote_after = 1;
+    let mut arena = None;
+    let mut arena_size = DEFAULT_ARENA_SIZE;
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or(format!("{} needs a value", arg))?;
+        match arg.as_str() {
+            "--listen" => addr = value,
+            "--replication" => mode = value.parse()?,
+            "--role" => role = value.parse()?,
+            "--backup" => backups.push(value),
+            "--coordinator" => coordinator = Some(value),
+            "--advertise" => advertise = Some(value),
+            "--heartbeat" => heartbeat = Duration::from_millis(value.parse()?),
+            "--coordinate" => timeout = Some(Duration::from_millis(value.parse()?)),
+            "--tls-cert" => tls_cert = Some(value),
+            "--tls-key" => tls_key = Some(value),
+            "--tls-ca" => tls_ca = Some(value),
+            "--tls-client-ca" => tls_client_ca = Some(value),
+            "--tokens" => tokens = Some(value),
+            "--token" => token = Some(value),
+            "--keys" => {
+                require_keys = match value.as_str() {
+                    "required" => true,
+                    "optional" => false,
+                    _ => return Err(format!("Unknown key mode {}", value).into()),
+                }
+            }
+            "--tenants" => tenants = Some(value),
+            "--limits" => limits = Some(value),
+            "--capacity" => capacity = Some(value.parse()?),
+            "--watermarks" => watermarks = value.parse()?,
+            "--eviction" => eviction = value.parse()?,
+            "--spill" => spill = Some(value),
+            "--ram" => ram = Some(value.parse()?),
+            "--demotion" => demotion = value.parse()?,
+            "--promote-after" => promote_after = value.parse::<u32>()?.max(1),
+            "--arena" => arena = Some(value),
+            "--arena-size" => arena_size = value.parse()?,
+            _ => return Err(format!("Unknown option {}", arg).into()),
+        }
+    }
+    let identity = match (tls_cert, tls_key) {
+        (Some(cert), Some(key)) => Some(credentials::identity(&cert, &key)?),
+        (None, None) => None,
+        _ => return Err("--tls-cert and --tls-key go together".into()),
+    };
+    if tls_client_ca.is_some() && identity.is_none() {
+        return Err("--tls-client-ca needs --tls-cert and --tls-key".into());
+    }
+    let credentials = Credentials::client(tls_ca.as_deref(), identity.as_ref(), token.as_deref())?;
+    let authenticator = match tokens {
+        Some(path) => Authenticator::from_file(&path)?,
+        None => Authenticator::default(),
+    };
+    let listener = TcpListener::bind(addr.parse::<SocketAddr>()?).await?;
+    let addr = listener.local_addr()?;
+    let scheme = if identity.is_some() { "https" } else { "http" };
+    let advertise = advertise.unwrap_or_else(|| format!("{}://{}", scheme, addr));
+
+    let mut node = DataNode::new();
+    if require_keys {
+        node.require_keys();
+    }
+    if let Some(capacity) = capacity {
+        node.set_capacity(capacity, watermarks);
+    }
+    node.set_eviction(eviction);
+    if let Some(path) = tenants {
+        node.add_tenants(tenant::from_file(&path)?);
+    }
+    if let Some(path) = arena {
+        let reattached = node.set_arena(Arena::open(&path, arena_size)?);
+        if reattached > 0 {
+            log::info!("Took back {} regions from {}", reattached, path);
+        }
+    }
+    match (spill, ram) {
+        (Some(dir), Some(ram)) => {
+            let tiering = Tiering {
+                ram,
+                demotion,
+                promote_after,
+            };
+            node.set_spill(Spill::open(&dir, tiering)?);
+        }
+        (None, None) => {}
+        _ => return Err("--spill and --ram go together".into()),
+    }
+    if let Some(coordinator) = coordinator {
+        membership::spawn_heartbeats(
+            coordinator,
+            advertise,
+            node.generation(),
+            heartbeat,
+            &credentials,
+        )?;
+    }
+    let replication = Replication::new(mode, role, backups, credentials.clone())?;
+    let limits = match limits {
+        Some(path) => Limits::from_file(&path)?,
+        None => Limits::default(),
+    };
+    let mmry = MemoryService::new(node, replication, credentials, limits);
+
+    let mut server = Server::builder();
+    if let Some(identity) = identity {
+        server = server.tls_config(credentials::server(identity, tls_client_ca.as_deref())?)?;
+    }
+    let serving = server
+        .add_service(MemoryServer::with_interceptor(mmry, authenticator.clone()))
+        .add_service(DirectoryServer::with_interceptor(
+            Directory::default(),
+            authenticator.clone(),
+        ))
+        .add_optional_service(
+            timeout
+                .map(|t| MembershipServer::with_interceptor(Coordinator::start(t), authenticator)),
+        )
+        .serve_with_incoming(TcpListenerStream::new(listener));
+    Ok((addr, serving))
+}
@@ -1,25 +1,116 @@
-mod errors;
-mod memory;
-mod proto;
-mod rpc;
+use std::error::Error;
 
-use tonic::transport::Server;
+/// Usage: dn [--listen <addr>] [--replication primary-backup|chain]
+///           [--role primary|backup] [--backup <url>]...
+///           [--coordinator <url> [--advertise <url>] [--heartbeat <ms>]]
+///           [--coordinate <timeout ms>]
+///           [--tls-cert <pem> --tls-key <pem>] [--tls-client-ca <pem>]
+///           [--tls-ca <pem>] [--tokens <file>] [--token <token>]
+///           [--keys optional|required] [--tenants <file>] [--limits <file>]
+///           [--capacity <bytes> [--watermarks <low>,<high>,<critical>]
+///           [--eviction lru|lfu]]
+///           [--spill <dir> --ram <bytes> [--demotion lru|lfu]
+///           [--promote-after <accesses>]]
+///           [--arena <file>|memfd [--arena-size <bytes>]]
+///
+/// A primary replicates every change to the backups given with `--backup`
+/// before acknowledging it. A backup only accepts changes from its primary
+/// until a client promotes it. In a chain, every node lists the nodes after
+/// it with `--backup`, and the first one is the head.
+///
+/// With `--coordinator` the node heartbeats to the coordinator, as the URL
+/// given with `--advertise`, which has to match the one clients use for it.
+/// `--coordinate` makes this node the coordinator too, marking nodes down
+/// after the given time without a heartbeat; it should be a few heartbeat
+/// intervals.
+///
+/// With `--tls-cert` and `--tls-key` the node only accepts TLS, and with
+/// `--tls-client-ca` only from clients with a certificate signed by that
+/// CA. The same certificate is presented when connecting to other nodes at
+/// `https` URLs, which are trusted if signed by the CA in `--tls-ca`.
+///
+/// With `--tokens` every call needs one of the tokens in the file, which
+/// has a `<token> <principal> [admin|replicator]` line for each, and
+/// regions can only be used by the principal that allocated them, or by
+/// admins. Only admins and replicators may promote a backup or make a node
+/// the tail of its chain, as clients do to fail over. The node calls other
+/// nodes with the token given with `--token`, which they should know as an
+/// admin's.
+///
+/// With `--keys required` every access also has to present the region's
+/// key, returned when it was allocated, or for reads the read-only key
+/// derived from it.
+///
+/// With `--tenants` the node is shared between the tenants in the file, one
+/// `<tenant> <max bytes> <max regions> <principal>...` line each, with `-`
+/// for no limit. Each principal allocates in its tenant, which has its own
+/// range of ids, and allocations that would take a tenant over a limit fail
+/// with INSUFFICIENT_MEMORY. Everyone else shares a default tenant without
+/// limits. Every replica needs the same file. GetUsage reports what each
+/// tenant holds.
+///
+/// Clients are served in turn, weighted fairly between them. With
+/// `--limits` they are also held to the rates in the file, one
+/// `<client> <ops/sec> <bytes/sec> <weight>` line each, with `-` for no
+/// limit and `*` for everyone not listed. Clients are principals, or
+/// without `--tokens` the addresses they connect from. Requests over a rate
+/// fail with RESOURCE_EXHAUSTED and a `retry-after-ms` header.
+///
+/// With `--capacity` the node holds at most that many bytes, and reports
+/// pressure to clients watching it once the bytes in use cross the
+/// `--watermarks`, percentages of the capacity, 70,85,95 by default. Once
+/// it is full, evictable regions are evicted to make room, the least
+/// recently used first, or with `--eviction lfu` the least frequently used.
+///
+/// With `--spill` regions that don't fit in the `--ram` budget are demoted
+/// to files in that directory, the least recently used first, or with
+/// `--demotion lfu` the least frequently used, and promoted back once they
+/// have been accessed `--promote-after` times there, 1 by default. Regions
+/// on disk still count towards the capacity.
+///
+/// With `--arena` regions are kept in one mapping of that file, or of an
+/// anonymous memfd, rather than on the heap, which tools outside the node
+/// can read too. New files are made `--arena-size` bytes long, 64mb by
+/// default, and allocations fail with INSUFFICIENT_MEMORY once it is full.
+/// A node restarted on the same file, which a memfd can't be, takes back
+/// the regions in it, under the same generation, so clients' ids stay
+/// valid. Grants and regions spilled to disk are lost, and so are the
+/// forwarding addresses of regions migrated away and the tombstones of
+/// evicted ones: their old ids then fail with OUT_OF_RANGE, like any other
+/// unknown id, instead of saying where the region went or that it was
+/// evicted. Allocating in the arena takes O(log n) in the number of free
+/// blocks, under the same lock as every other change to the node.
+///
+/// Every node also serves a directory of region names, for the clients that
+/// pick it as theirs.
+/// Prints what the node logs, warnings and errors to stderr and the rest
+/// to stdout.
+struct Printer;
 
-use crate::memory::DataNode;
-use crate::proto::memory::memory_server::MemoryServer;
-use crate::rpc::MemoryService;
+impl log::Log for Printer {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info
+    }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "[::1]:50051".parse()?;
-
-    let node = DataNode::new();
-    let mmry = MemoryService::new(node);
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() <= log::Level::Warn {
+            eprintln!("{}", record.args());
+        } else {
+            println!("{}", record.args());
+        }
+    }
 
-    Server::builder()
-        .add_service(MemoryServer::new(mmry))
-        .serve(addr)
-        .await?;
+    fn flush(&self) {}
+}
 
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    log::set_logger(&Printer).map_err(|e| e.to_string())?;
+    log::set_max_level(log::LevelFilter::Info);
+    let (_, serving) = dn::start(std::env::args().skip(1)).await?;
+    serving.await?;
     Ok(())
 }
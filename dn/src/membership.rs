@@ -0,0 +1,183 @@
+use crate::auth;
+use crate::credentials::Credentials;
+use crate::proto::memory::{
+    self, membership_client::MembershipClient, HeartbeatRequest, Member, MembershipUpdate,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+struct MemberState {
+    generation: u32,
+    last_seen: Instant,
+    alive: bool,
+}
+
+/// Keeps track of which data nodes are alive from their heartbeats, and
+/// pushes the membership to watchers whenever it changes. A node is marked
+/// down once it has gone `timeout` without a heartbeat, and back up with
+/// the next one. Nodes are never forgotten, so a node that is down for good
+/// stays in the membership as down.
+pub struct Coordinator {
+    members: Arc<Mutex<HashMap<String, MemberState>>>,
+    updates: Arc<watch::Sender<Vec<Member>>>,
+}
+
+impl Coordinator {
+    /// Starts checking for missed heartbeats in the background.
+    pub fn start(timeout: Duration) -> Self {
+        let coordinator = Coordinator {
+            members: Arc::new(Mutex::new(HashMap::new())),
+            updates: Arc::new(watch::channel(Vec::new()).0),
+        };
+
+        let members = coordinator.members.clone();
+        let updates = coordinator.updates.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(timeout / 4);
+            loop {
+                ticker.tick().await;
+                let mut members = members.lock().unwrap();
+                let mut changed = false;
+                for (addr, member) in members.iter_mut() {
+                    if member.alive && member.last_seen.elapsed() > timeout {
+                        log::info!("Node {} is down", addr);
+                        member.alive = false;
+                        changed = true;
+                    }
+                }
+                if changed {
+                    publish(&members, &updates);
+                }
+            }
+        });
+        coordinator
+    }
+}
+
+#[tonic::async_trait]
+impl memory::membership_server::Membership for Coordinator {
+    async fn heartbeat(
+        &self,
+        request: Request<HeartbeatRequest>,
+    ) -> Result<Response<memory::HeartbeatResponse>, Status> {
+        // only nodes may speak for nodes
+        auth::caller(&request).check_admin()?;
+        let input = request.into_inner();
+        let mut members = self.members.lock().unwrap();
+        let now = Instant::now();
+        let changed = match members.get_mut(&input.addr) {
+            Some(member) => {
+                let changed = if member.generation != input.generation {
+                    log::info!("Node {} restarted", input.addr);
+                    true
+                } else if !member.alive {
+                    log::info!("Node {} is back up", input.addr);
+                    true
+                } else {
+                    false
+                };
+                member.generation = input.generation;
+                member.last_seen = now;
+                member.alive = true;
+                changed
+            }
+            None => {
+                log::info!("Node {} joined", input.addr);
+                members.insert(
+                    input.addr,
+                    MemberState {
+                        generation: input.generation,
+                        last_seen: now,
+                        alive: true,
+                    },
+                );
+                true
+            }
+        };
+        if changed {
+            publish(&members, &self.updates);
+        }
+        Ok(Response::new(memory::HeartbeatResponse {}))
+    }
+
+    type WatchStream = ReceiverStream<Result<MembershipUpdate, Status>>;
+
+    async fn watch(
+        &self,
+        _request: Request<memory::WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let mut updates = self.updates.subscribe();
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            loop {
+                let members = updates.borrow_and_update().clone();
+                if tx.send(Ok(MembershipUpdate { members })).await.is_err() {
+                    break; // the watcher went away
+                }
+                if updates.changed().await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+fn publish(members: &HashMap<String, MemberState>, updates: &watch::Sender<Vec<Member>>) {
+    let mut snapshot: Vec<Member> = members
+        .iter()
+        .map(|(addr, member)| Member {
+            addr: addr.clone(),
+            generation: member.generation,
+            alive: member.alive,
+        })
+        .collect();
+    snapshot.sort_by(|a, b| a.addr.cmp(&b.addr));
+    updates.send_replace(snapshot);
+}
+
+/// Heartbeats to the coordinator every `interval` for as long as the node
+/// runs, announcing it as reachable at `addr`. The coordinator doesn't have
+/// to be up yet; the node is registered by the first heartbeat that gets
+/// through.
+pub fn spawn_heartbeats(
+    coordinator: String,
+    addr: String,
+    generation: u32,
+    interval: Duration,
+    credentials: &Credentials,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let channel = credentials
+        .endpoint(coordinator)?
+        .timeout(interval)
+        .connect_lazy();
+    let mut client = MembershipClient::new(credentials.authed(channel));
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        let mut reachable = true;
+        loop {
+            ticker.tick().await;
+            let request = HeartbeatRequest {
+                addr: addr.clone(),
+                generation,
+            };
+            match client.heartbeat(request).await {
+                Ok(_) if !reachable => {
+                    log::info!("Reached the coordinator again");
+                    reachable = true;
+                }
+                Ok(_) => {}
+                Err(status) if reachable => {
+                    log::warn!("Couldn't reach the coordinator: {}", status.message());
+                    reachable = false;
+                }
+                Err(_) => {}
+            }
+        }
+    });
+    Ok(())
+}
@@ -1,57 +1,359 @@
+use crate::arena::Arena;
+use crate::auth::{Forbidden, Principal};
+use crate::capability;
 use crate::errors::{AllocationError, DeallocationError, MemoryAccessError};
+use crate::eviction::{Eviction, Uses};
+use crate::pressure::{Pressure, Reading, Watermarks};
+use crate::spill::{Spill, Tier, Tiers};
+use crate::storage::{Heap, Label, Storage};
+use crate::tenant::{Tenant, TENANT_SHIFT};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
 
 pub struct DataNode {
-    mem: Vec<Option<Vec<u8>>>,
+    mem: Box<dyn Storage>,                           // regions in RAM
+    tenants: Vec<Tenant>, // the default one first, for everyone not in another
+    members: HashMap<String, usize>, // principal to the index of its tenant
+    generation: u32,      // ids restart from 0 with every process, this tells them apart
+    owners: HashMap<usize, String>, // kept for moved regions, so forwards aren't leaked
+    grants: HashMap<usize, HashMap<String, Access>>, // likewise
+    keys: HashMap<usize, u64>, // read-write keys, also kept for moved regions
+    require_keys: bool,
+    moved: HashMap<usize, Forward>, // ids are never reused, so these can stay
+    dirty: HashMap<usize, BTreeSet<usize>>, // pages written in regions being migrated
+    uses: HashMap<usize, Uses>,     // of every live region
+    evictable: HashMap<usize, u32>, // priorities of regions that may go when the node is full
+    stripes: HashMap<usize, u64>,   // of regions that are shards of coded ones
+    evicted: HashSet<usize>,        // likewise kept, to tell them from freed ones
+    swaps: [HashSet<u64>; 2],       // request ids of recent swaps, then of the ones before
+    eviction: Eviction,
+    clock: u64,              // ticks with every access
+    spill: Option<Spill>,    // the tier below RAM, if any
+    ram: usize,              // bytes in regions in RAM
+    draining: bool,          // refusing new regions
+    used: usize,             // bytes in live regions
+    capacity: Option<usize>, // bytes the node may hold, if limited
+    watermarks: Watermarks,
+    pressure: watch::Sender<Reading>, // changes of pressure, for watchers
+}
+
+/// What a principal may do with a region, each including the ones before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    Read,
+    Write,
+    Own, // free it and change who may use it
+}
+
+/// Where a region migrated to.
+#[derive(Debug, Clone)]
+pub struct Forward {
+    pub addr: String,
+    pub id: u64,
+    pub generation: u32,
+    pub key: u64,
 }
 
 const MAX_ALLOCATION: usize = 1024 * 1024; // 1mb
+const MAX_SWAPS: usize = 1 << 16; // remembered before the oldest are forgotten
+pub const PAGE_SIZE: usize = 4096; // granularity of dirty tracking
+const LOCAL_MASK: usize = (1 << TENANT_SHIFT) - 1; // id within the tenant's range
 
 impl DataNode {
     pub fn new() -> Self {
-        return DataNode { mem: Vec::new() };
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        let mixed = (nanos ^ (nanos >> 32) ^ ((std::process::id() as u64) << 16)) as u32;
+        DataNode {
+            mem: Box::new(Heap::default()),
+            tenants: vec![Tenant::new("default")],
+            members: HashMap::new(),
+            generation: mixed.max(1), // 0 means unchecked in requests
+            owners: HashMap::new(),
+            grants: HashMap::new(),
+            keys: HashMap::new(),
+            require_keys: false,
+            moved: HashMap::new(),
+            dirty: HashMap::new(),
+            uses: HashMap::new(),
+            evictable: HashMap::new(),
+            stripes: HashMap::new(),
+            evicted: HashSet::new(),
+            swaps: Default::default(),
+            eviction: Eviction::default(),
+            clock: 0,
+            spill: None,
+            ram: 0,
+            draining: false,
+            used: 0,
+            capacity: None,
+            watermarks: Watermarks::default(),
+            pressure: watch::channel(Reading {
+                pressure: Pressure::None,
+                used: 0,
+            })
+            .0,
+        }
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Backups take on their primary's generation, so ids the primary fenced
+    /// stay valid if a backup takes over.
+    pub fn adopt_generation(&mut self, generation: u32) {
+        self.generation = generation;
+        self.mem.record_generation(generation);
+    }
+
+    /// Rejects accesses made with ids from an earlier generation. Generation
+    /// 0 skips the check.
+    pub fn check_generation(&self, generation: u32) -> Result<(), MemoryAccessError> {
+        if generation != 0 && generation != self.generation {
+            return Err(MemoryAccessError::StaleGeneration);
+        }
+        Ok(())
+    }
+
+    /// Limits the node to `capacity` bytes, reporting pressure as the bytes
+    /// in use cross `watermarks`.
+    pub fn set_capacity(&mut self, capacity: usize, watermarks: Watermarks) {
+        self.capacity = Some(capacity);
+        self.watermarks = watermarks;
+        self.update_pressure();
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    pub fn set_eviction(&mut self, eviction: Eviction) {
+        self.eviction = eviction;
+    }
+
+    /// Keeps regions in `arena` instead of on the heap, taking back the ones
+    /// it kept from an earlier run, under that run's generation so their
+    /// ids stay valid. Returns how many were taken back.
+    pub fn set_arena(&mut self, arena: Arena) -> usize {
+        if let Some(generation) = arena.generation() {
+            self.generation = generation;
+        }
+        let reattached = arena.reattached();
+        self.mem = Box::new(arena);
+        self.mem.record_generation(self.generation);
+        for region in &reattached {
+            self.account(region.id, region.size);
+            if let Some(owner) = &region.label.owner {
+                self.owners.insert(region.id, owner.clone());
+            }
+            self.keys.insert(region.id, region.label.key);
+            if let Some(priority) = region.label.priority {
+                self.evictable.insert(region.id, priority);
+            }
+        }
+        reattached.len()
+    }
+
+    /// Spills regions that don't fit in RAM to disk.
+    pub fn set_spill(&mut self, spill: Spill) {
+        self.spill = Some(spill);
+        self.demote_cold(None);
     }
 
-    pub fn allocate_memory(&mut self, size: usize) -> Result<usize, AllocationError> {
+    /// What is in RAM and on disk, if the node spills to disk.
+    pub fn tiers(&self) -> Option<Tiers> {
+        let spill = self.spill.as_ref()?;
+        Some(Tiers {
+            ram_bytes: self.ram,
+            ram_regions: self.mem.count(),
+            disk_bytes: spill.bytes(),
+            disk_regions: spill.regions().count(),
+            stats: spill.stats(),
+        })
+    }
+
+    /// Follows the node's pressure, starting from the current one.
+    pub fn watch_pressure(&self) -> watch::Receiver<Reading> {
+        self.pressure.subscribe()
+    }
+
+    /// Adds tenants after the default one.
+    pub fn add_tenants(&mut self, tenants: Vec<Tenant>) {
+        for tenant in tenants {
+            for member in &tenant.members {
+                self.members.insert(member.clone(), self.tenants.len());
+            }
+            self.tenants.push(tenant);
+        }
+    }
+
+    pub fn tenants(&self) -> &[Tenant] {
+        &self.tenants
+    }
+
+    /// Allocates a region in its owner's tenant, returning its id and
+    /// read-write key. Fails with `QuotaExceeded` if the tenant would go
+    /// over its limits, and `InsufficientMemory` if the node has no room.
+    /// Regions with an eviction priority are evicted to make room for
+    /// others once every other check has passed, and their ids pushed onto
+    /// `evicted` for the caller to replicate, even if storage then turns
+    /// out too fragmented to fit it.
+    pub fn allocate_memory(
+        &mut self,
+        size: usize,
+        owner: &str,
+        priority: Option<u32>,
+        evicted: &mut Vec<usize>,
+    ) -> Result<(usize, u64), AllocationError> {
+        if size > MAX_ALLOCATION {
+            return Err(AllocationError::AllocationTooLarge);
+        }
+        if self.draining {
+            return Err(AllocationError::Draining);
+        }
+        let index = self.members.get(owner).copied().unwrap_or(0);
+        let tenant = &self.tenants[index];
+        if !tenant.has_room(size) || tenant.next > LOCAL_MASK {
+            return Err(AllocationError::QuotaExceeded);
+        }
+        // nothing is evicted unless the region is then sure to fit
+        let victims = self
+            .plan_room(size)
+            .ok_or(AllocationError::InsufficientMemory)?;
+        let resident: Vec<usize> = victims
+            .iter()
+            .copied()
+            .filter(|&id| self.mem.get(id).is_some())
+            .collect();
+        if !self.mem.fits(size, &resident) {
+            return Err(AllocationError::InsufficientMemory);
+        }
+        for &id in &victims {
+            self.evict(id);
+        }
+        evicted.extend(victims);
+
+        let id = (index << TENANT_SHIFT) | self.tenants[index].next;
+        let key = capability::new_key();
+        self.insert(id, size)?;
+        self.owners.insert(id, owner.to_string());
+        self.keys.insert(id, key);
+        if let Some(priority) = priority {
+            self.evictable.insert(id, priority);
+        }
+        self.label(id);
+        Ok((id, key))
+    }
+
+    /// Allocates a region under the id and key the primary gave it.
+    pub fn allocate_at(
+        &mut self,
+        id: usize,
+        size: usize,
+        owner: &str,
+        key: u64,
+        priority: Option<u32>,
+    ) -> Result<(), AllocationError> {
         if size > MAX_ALLOCATION {
             return Err(AllocationError::AllocationTooLarge);
         }
 
-        let id = self.mem.len();
-        self.mem.push(Some(vec![0u8; size]));
-        Ok(id)
+        self.insert(id, size)?;
+        self.owners.insert(id, owner.to_string());
+        self.keys.insert(id, key);
+        if let Some(priority) = priority {
+            self.evictable.insert(id, priority);
+        }
+        self.label(id);
+        Ok(())
+    }
+
+    /// The evictable regions to evict for `size` more bytes to fit, if the
+    /// node would otherwise go over its capacity, or `None` if evicting
+    /// every one that may go wouldn't make enough room. Regions being
+    /// migrated are left alone.
+    fn plan_room(&self, size: usize) -> Option<Vec<usize>> {
+        let Some(capacity) = self.capacity else {
+            return Some(Vec::new());
+        };
+        let Some(needed) = (self.used + size).checked_sub(capacity) else {
+            return Some(Vec::new());
+        };
+        let mut candidates: Vec<_> = self
+            .evictable
+            .iter()
+            .filter(|(id, _)| !self.dirty.contains_key(id))
+            .map(|(&id, &priority)| (id, (priority, self.rank(id, self.eviction))))
+            .collect();
+        candidates.sort_unstable_by_key(|&(_, rank)| rank);
+
+        let mut victims = Vec::new();
+        let mut freed = 0;
+        for (id, _) in candidates {
+            if freed >= needed {
+                break;
+            }
+            freed += self.size(id).unwrap_or(0);
+            victims.push(id);
+        }
+        (freed >= needed).then_some(victims)
+    }
+
+    /// Drops an evictable region, leaving a tombstone so later accesses
+    /// can tell it was evicted.
+    pub fn evict(&mut self, id: usize) {
+        if self.remove(id).is_none() {
+            return;
+        }
+        self.owners.remove(&id);
+        self.grants.remove(&id);
+        self.keys.remove(&id);
+        self.evicted.insert(id);
+    }
+
+    /// The priority the region is evictable with, if it is.
+    pub fn eviction_priority(&self, id: usize) -> Option<u32> {
+        self.evictable.get(&id).copied()
+    }
+
+    /// The stripe region `id` is a shard of, 0 if it isn't one.
+    pub fn stripe(&self, id: usize) -> u64 {
+        self.stripes.get(&id).copied().unwrap_or(0)
+    }
+
+    /// Records region `id` as a shard of `stripe`, unless that is 0.
+    pub fn set_stripe(&mut self, id: usize, stripe: u64) {
+        if stripe != 0 {
+            self.stripes.insert(id, stripe);
+        }
     }
 
     pub fn free_memory(&mut self, id: usize) -> Result<(), DeallocationError> {
-        self.mem
-            .get_mut(id)
-            .ok_or(DeallocationError::InvalidMemoryAddress)
-            .and_then(|slot| {
-                if slot.is_some() {
-                    *slot = None;
-                    Ok(())
-                } else {
-                    Err(DeallocationError::InvalidMemoryAddress)
-                }
-            })
+        self.check_live(id)?;
+        self.dirty.remove(&id);
+        self.remove(id)
+            .ok_or(DeallocationError::InvalidMemoryAddress)?;
+        self.owners.remove(&id);
+        self.grants.remove(&id);
+        self.keys.remove(&id);
+        Ok(())
     }
 
     pub fn read_memory(
-        &self,
+        &mut self,
         id: usize,
         offset: usize,
         length: usize,
-    ) -> Result<&[u8], MemoryAccessError> {
-        println!("{:?}\n\n\n", self.mem);
-        println!("-----New access----");
-        self.mem
-            .get(id)
-            .ok_or(MemoryAccessError::InvalidMemoryAddress)
-            .and_then(|slot| slot.as_ref().ok_or(MemoryAccessError::InvalidMemoryAddress))
-            .and_then(|memory| {
-                memory
-                    .get(offset..offset + length)
-                    .ok_or(MemoryAccessError::OutOfBoundsAccess)
-            })
+    ) -> Result<Vec<u8>, MemoryAccessError> {
+        self.check_bounds(id, offset, length)?;
+        match self.locate(id)? {
+            Tier::Ram => Ok(self.in_ram(id)[offset..offset + length].to_vec()),
+            Tier::Disk => self.spilled().read(id, offset, length).map_err(disk_error),
+        }
     }
 
     pub fn write_memory(
@@ -60,25 +362,500 @@ impl DataNode {
         offset: usize,
         data: &[u8],
     ) -> Result<(), MemoryAccessError> {
-        self.mem
-            .get_mut(id)
-            .ok_or(MemoryAccessError::InvalidMemoryAddress)
-            .and_then(|slot| slot.as_mut().ok_or(MemoryAccessError::InvalidMemoryAddress))
-            .and_then(|memory| {
-                if offset + data.len() <= memory.len() {
-                    memory[offset..offset + data.len()].copy_from_slice(data);
-                    Ok(())
-                } else {
-                    Err(MemoryAccessError::OutOfBoundsAccess)
+        self.update(id, offset, data.len(), |target| {
+            target.copy_from_slice(data)
+        })?;
+        self.mark_dirty(id, offset, data.len());
+        Ok(())
+    }
+
+    /// XORs `data` into the region, returning the bytes it leaves there.
+    /// XORs commute, so clients can apply changes to the same bytes without
+    /// coordinating.
+    pub fn xor_memory(
+        &mut self,
+        id: usize,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<Vec<u8>, MemoryAccessError> {
+        let result = self.update(id, offset, data.len(), |target| {
+            for (byte, x) in target.iter_mut().zip(data) {
+                *byte ^= x;
+            }
+            target.to_vec()
+        })?;
+        self.mark_dirty(id, offset, data.len());
+        Ok(result)
+    }
+
+    pub fn get_memory_size(&self, id: usize) -> Result<usize, MemoryAccessError> {
+        self.check_live(id)?;
+        self.size(id).ok_or(MemoryAccessError::InvalidMemoryAddress)
+    }
+
+    /// Swaps the word at `offset` for `desired` if it holds `expected`,
+    /// writing `tail` right after it in the same step when it does.
+    pub fn compare_and_swap(
+        &mut self,
+        id: usize,
+        offset: usize,
+        expected: u64,
+        desired: u64,
+        tail: &[u8],
+    ) -> Result<u64, MemoryAccessError> {
+        let length = 8 + tail.len();
+        let previous = self.update(id, offset, length, |target| {
+            let (word, rest) = target.split_at_mut(8);
+            let previous = u64::from_le_bytes((&*word).try_into().unwrap());
+            if previous == expected {
+                word.copy_from_slice(&desired.to_le_bytes());
+                rest.copy_from_slice(tail);
+            }
+            previous
+        })?;
+        if previous == expected {
+            self.mark_dirty(id, offset, length);
+        }
+        Ok(previous)
+    }
+
+    /// Whether a swap was done under `request_id`, here or by the primary
+    /// that forwarded it before failing, as long as it was recent enough to
+    /// be remembered. 0 is never remembered.
+    pub fn swapped(&self, request_id: u64) -> bool {
+        request_id != 0 && self.swaps.iter().any(|swaps| swaps.contains(&request_id))
+    }
+
+    /// Remembers that a swap was done under `request_id`, so that a retry
+    /// of it isn't done again, until `MAX_SWAPS` more have been.
+    pub fn record_swap(&mut self, request_id: u64) {
+        if request_id == 0 {
+            return;
+        }
+        if self.swaps[0].len() >= MAX_SWAPS {
+            self.swaps[1] = std::mem::take(&mut self.swaps[0]);
+        }
+        self.swaps[0].insert(request_id);
+    }
+
+    /// Whether `caller` may use the region as `needed`: admins may do
+    /// anything with any region, owners anything with theirs, and everyone
+    /// else what they were granted. Ids that aren't in use are left for the
+    /// access itself to reject.
+    pub fn check_access(
+        &self,
+        id: usize,
+        caller: &Principal,
+        needed: Access,
+    ) -> Result<(), Forbidden> {
+        let Some(owner) = self.owners.get(&id) else {
+            return Ok(());
+        };
+        let granted = if caller.admin || *owner == caller.name {
+            Some(Access::Own)
+        } else {
+            self.grants
+                .get(&id)
+                .and_then(|grants| grants.get(&caller.name))
+                .copied()
+        };
+        match granted {
+            Some(granted) if granted >= needed => Ok(()),
+            _ => Err(Forbidden),
+        }
+    }
+
+    /// Lets `principal` access the region as `access`, or not at all for
+    /// `None`. Owners always have full access, so can't be granted any.
+    pub fn grant(
+        &mut self,
+        id: usize,
+        principal: &str,
+        access: Option<Access>,
+    ) -> Result<(), MemoryAccessError> {
+        self.get_memory_size(id)?;
+        let grants = self.grants.entry(id).or_default();
+        match access {
+            Some(access) => grants.insert(principal.to_string(), access.min(Access::Write)),
+            None => grants.remove(principal),
+        };
+        Ok(())
+    }
+
+    /// Makes `owner` the region's owner, leaving its grants as they are.
+    pub fn transfer(&mut self, id: usize, owner: &str) -> Result<(), MemoryAccessError> {
+        self.get_memory_size(id)?;
+        self.owners.insert(id, owner.to_string());
+        self.label(id);
+        Ok(())
+    }
+
+    /// Who else may use the region, and how.
+    pub fn grants(&self, id: usize) -> Vec<(String, Access)> {
+        self.grants.get(&id).map_or_else(Vec::new, |grants| {
+            grants
+                .iter()
+                .map(|(principal, &access)| (principal.clone(), access))
+                .collect()
+        })
+    }
+
+    /// From now on, accesses have to present the region's key.
+    pub fn require_keys(&mut self) {
+        self.require_keys = true;
+    }
+
+    /// Checks the key presented for an access: the region's read-write key,
+    /// or for reads its read-only key. A region that has moved is reported
+    /// as such once the key checks out, with the key for the same access to
+    /// it where it went. Ids that aren't in use are left for the access
+    /// itself to reject, and nothing is checked unless keys are required.
+    pub fn check_key(&self, id: usize, key: u64, write: bool) -> Result<(), MemoryAccessError> {
+        if !self.require_keys {
+            return Ok(());
+        }
+        let Some(&read_write) = self.keys.get(&id) else {
+            return Ok(());
+        };
+        let read_only = if key == read_write {
+            false
+        } else if !write && key == capability::read_only(read_write) {
+            true
+        } else {
+            return Err(MemoryAccessError::PermissionDenied);
+        };
+        match self.moved.get(&id) {
+            Some(forward) => {
+                let mut forward = forward.clone();
+                if read_only {
+                    forward.key = capability::read_only(forward.key);
                 }
+                Err(MemoryAccessError::Moved(forward))
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn owner(&self, id: usize) -> Option<&str> {
+        self.owners.get(&id).map(String::as_str)
+    }
+
+    /// Bytes held by live regions, and each region's id and size, by id.
+    pub fn usage(&self) -> (usize, Vec<(usize, usize)>) {
+        let mut regions: Vec<(usize, usize)> = self
+            .mem
+            .regions()
+            .into_iter()
+            .chain(self.spill.iter().flat_map(Spill::regions))
+            .collect();
+        regions.sort_unstable();
+        (regions.iter().map(|&(_, size)| size).sum(), regions)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    /// Stops taking new regions, so the node can be emptied.
+    pub fn start_draining(&mut self) {
+        self.draining = true;
+    }
+
+    /// Starts recording which pages of the region get written, for
+    /// migrating it. Returns the region's size. The region is kept in RAM
+    /// until it is untracked.
+    pub fn track_dirty(&mut self, id: usize) -> Result<usize, MemoryAccessError> {
+        let size = self.get_memory_size(id)?;
+        if self.mem.get(id).is_none() && !self.promote(id)? {
+            return Err(MemoryAccessError::Disk("No room in RAM".to_string()));
+        }
+        self.dirty.insert(id, BTreeSet::new());
+        Ok(size)
+    }
+
+    pub fn untrack_dirty(&mut self, id: usize) {
+        self.dirty.remove(&id);
+    }
+
+    pub fn dirty_pages(&self, id: usize) -> usize {
+        self.dirty.get(&id).map_or(0, |pages| pages.len())
+    }
+
+    /// The pages written since the last call, with their current contents.
+    /// Fails if the region has been freed.
+    pub fn take_dirty(&mut self, id: usize) -> Result<Vec<(usize, Vec<u8>)>, MemoryAccessError> {
+        let pages = self
+            .dirty
+            .get_mut(&id)
+            .map(std::mem::take)
+            .unwrap_or_default();
+        let size = self.get_memory_size(id)?;
+        pages
+            .into_iter()
+            .map(|page| {
+                let offset = page * PAGE_SIZE;
+                let length = PAGE_SIZE.min(size - offset);
+                Ok((offset, self.in_ram(id)[offset..offset + length].to_vec()))
             })
+            .collect()
     }
 
-    pub fn get_memory_size(&self, id: usize) -> Result<usize, MemoryAccessError> {
+    /// Where the region went, if it has moved.
+    pub fn forwarded(&self, id: usize) -> Option<Forward> {
+        self.moved.get(&id).cloned()
+    }
+
+    /// Drops a region that now lives elsewhere, leaving a forwarding
+    /// address in its place.
+    pub fn mark_moved(&mut self, id: usize, forward: Forward) {
+        self.remove(id);
+        self.dirty.remove(&id);
+        self.moved.insert(id, forward);
+    }
+
+    /// Puts a zeroed region in place under `id`, in RAM, unless there is no
+    /// room for it there.
+    fn insert(&mut self, id: usize, size: usize) -> Result<(), AllocationError> {
+        // a region being replaced is only dropped once the new one has room
+        let replaced: Vec<usize> = self.mem.get(id).map(|_| id).into_iter().collect();
+        if !self.mem.fits(size, &replaced) {
+            return Err(AllocationError::InsufficientMemory);
+        }
+        self.remove(id);
         self.mem
-            .get(id)
-            .ok_or(MemoryAccessError::InvalidMemoryAddress)
-            .and_then(|slot| slot.as_ref().ok_or(MemoryAccessError::InvalidMemoryAddress))
-            .map(|memory| memory.len())
+            .insert(id, size)
+            .map_err(|_| AllocationError::InsufficientMemory)?;
+        self.account(id, size);
+        self.demote_cold(Some(id));
+        Ok(())
+    }
+
+    /// Counts a region that was put in RAM against the tenant whose range
+    /// it is in.
+    fn account(&mut self, id: usize, size: usize) {
+        self.ram += size;
+        self.uses.insert(id, Uses::new(self.clock));
+        let tenant = self.tenant_mut(id);
+        tenant.next = tenant.next.max((id & LOCAL_MASK) + 1);
+        tenant.used_bytes += size;
+        tenant.regions += 1;
+        self.used += size;
+        self.update_pressure();
+    }
+
+    /// Records the region's owner, key and priority with it, for storage
+    /// that outlives the process.
+    fn label(&mut self, id: usize) {
+        let label = Label {
+            owner: self.owners.get(&id).cloned(),
+            key: self.keys.get(&id).copied().unwrap_or(0),
+            priority: self.evictable.get(&id).copied(),
+        };
+        self.mem.label(id, &label);
+    }
+
+    /// Drops a region from whichever tier it is in, returning its size.
+    fn remove(&mut self, id: usize) -> Option<usize> {
+        let size = match self.mem.remove(id) {
+            Some(size) => {
+                self.ram -= size;
+                size
+            }
+            None => self.spill.as_mut()?.remove(id)?,
+        };
+        self.uses.remove(&id);
+        self.evictable.remove(&id);
+        self.stripes.remove(&id);
+        let tenant = self.tenant_mut(id);
+        tenant.used_bytes -= size;
+        tenant.regions -= 1;
+        self.used -= size;
+        self.update_pressure();
+        Some(size)
+    }
+
+    fn size(&self, id: usize) -> Option<usize> {
+        match self.mem.get(id) {
+            Some(memory) => Some(memory.len()),
+            None => self.spill.as_ref()?.size(id),
+        }
     }
+
+    fn check_bounds(
+        &self,
+        id: usize,
+        offset: usize,
+        length: usize,
+    ) -> Result<(), MemoryAccessError> {
+        let size = self.get_memory_size(id)?;
+        match offset.checked_add(length) {
+            Some(end) if end <= size => Ok(()),
+            _ => Err(MemoryAccessError::OutOfBoundsAccess),
+        }
+    }
+
+    /// Counts an access to a live region and says which tier serves it,
+    /// promoting it first if it is on disk and due.
+    fn locate(&mut self, id: usize) -> Result<Tier, MemoryAccessError> {
+        if let Some(uses) = self.uses.get_mut(&id) {
+            self.clock += 1;
+            uses.touch(self.clock);
+        }
+        let in_ram = self.mem.get(id).is_some();
+        let Some(spill) = self.spill.as_mut() else {
+            return Ok(Tier::Ram);
+        };
+        if in_ram {
+            spill.hit_ram();
+            return Ok(Tier::Ram);
+        }
+        if !spill.hit_disk(id) || !self.promote(id)? {
+            return Ok(Tier::Disk);
+        }
+        Ok(Tier::Ram)
+    }
+
+    /// Applies `change` to `length` bytes of the region at `offset`, in
+    /// whichever tier serves it.
+    fn update<T>(
+        &mut self,
+        id: usize,
+        offset: usize,
+        length: usize,
+        change: impl FnOnce(&mut [u8]) -> T,
+    ) -> Result<T, MemoryAccessError> {
+        self.check_bounds(id, offset, length)?;
+        match self.locate(id)? {
+            Tier::Ram => {
+                let memory = self.mem.get_mut(id).unwrap();
+                Ok(change(&mut memory[offset..offset + length]))
+            }
+            Tier::Disk => {
+                let spill = self.spilled();
+                let mut bytes = spill.read(id, offset, length).map_err(disk_error)?;
+                let result = change(&mut bytes);
+                spill.write(id, offset, &bytes).map_err(disk_error)?;
+                Ok(result)
+            }
+        }
+    }
+
+    /// Brings a spilled region back into RAM, making room for it by
+    /// demoting others. Leaves it on disk, returning false, if the storage
+    /// has no room for it.
+    fn promote(&mut self, id: usize) -> Result<bool, MemoryAccessError> {
+        let size = self.spilled().size(id).unwrap_or(0);
+        if self.mem.insert(id, size).is_err() {
+            return Ok(false);
+        }
+        let memory = match self.spilled().promote(id) {
+            Ok(memory) => memory,
+            Err(e) => {
+                self.mem.remove(id);
+                return Err(disk_error(e));
+            }
+        };
+        self.mem.get_mut(id).unwrap().copy_from_slice(&memory);
+        self.ram += size;
+        self.label(id);
+        self.demote_cold(Some(id));
+        Ok(true)
+    }
+
+    /// Demotes the regions the spill's policy picks, coldest first, until
+    /// RAM is back within its budget. Leaves `keep` and regions being
+    /// migrated in RAM.
+    fn demote_cold(&mut self, keep: Option<usize>) {
+        let Some(tiering) = self.spill.as_ref().map(Spill::tiering) else {
+            return;
+        };
+        if self.ram <= tiering.ram {
+            return;
+        }
+        let mut candidates: Vec<_> = self
+            .mem
+            .regions()
+            .into_iter()
+            .map(|(id, _)| id)
+            .filter(|&id| Some(id) != keep && !self.dirty.contains_key(&id))
+            .map(|id| (id, self.rank(id, tiering.demotion)))
+            .collect();
+        candidates.sort_unstable_by_key(|&(_, rank)| rank);
+
+        for (id, _) in candidates {
+            if self.ram <= tiering.ram {
+                break;
+            }
+            let memory = self.in_ram(id).to_vec();
+            if let Err(e) = self.spilled().demote(id, &memory) {
+                log::warn!("Couldn't spill region {}: {}", id, e);
+                return;
+            }
+            self.mem.remove(id);
+            self.ram -= memory.len();
+        }
+    }
+
+    /// Where the region comes among others for going first under `policy`.
+    fn rank(&self, id: usize, policy: Eviction) -> (u64, u64) {
+        self.uses.get(&id).map_or((0, 0), |uses| uses.rank(policy))
+    }
+
+    fn in_ram(&self, id: usize) -> &[u8] {
+        self.mem.get(id).expect("region in RAM")
+    }
+
+    fn spilled(&mut self) -> &mut Spill {
+        self.spill
+            .as_mut()
+            .expect("only nodes that spill have regions on disk")
+    }
+
+    /// Tells watchers if the pressure changed.
+    fn update_pressure(&mut self) {
+        let pressure = self.watermarks.pressure(self.used, self.capacity);
+        let used = self.used;
+        self.pressure.send_if_modified(|reading| {
+            if reading.pressure == pressure {
+                return false;
+            }
+            *reading = Reading { pressure, used };
+            true
+        });
+    }
+
+    /// The tenant whose range `id` is in. Ids from tenants this node wasn't
+    /// told about, replicated from a primary that was, get one without
+    /// limits.
+    fn tenant_mut(&mut self, id: usize) -> &mut Tenant {
+        let index = id >> TENANT_SHIFT;
+        while self.tenants.len() <= index {
+            let name = format!("#{}", self.tenants.len());
+            self.tenants.push(Tenant::new(&name));
+        }
+        &mut self.tenants[index]
+    }
+
+    /// Fails for regions that have moved away or been evicted.
+    fn check_live(&self, id: usize) -> Result<(), MemoryAccessError> {
+        if self.evicted.contains(&id) {
+            return Err(MemoryAccessError::Evicted);
+        }
+        match self.moved.get(&id) {
+            Some(forward) => Err(MemoryAccessError::Moved(forward.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn mark_dirty(&mut self, id: usize, offset: usize, length: usize) {
+        if let Some(pages) = self.dirty.get_mut(&id) {
+            if length > 0 {
+                pages.extend(offset / PAGE_SIZE..=(offset + length - 1) / PAGE_SIZE);
+            }
+        }
+    }
+}
+
+fn disk_error(error: std::io::Error) -> MemoryAccessError {
+    MemoryAccessError::Disk(error.to_string())
 }
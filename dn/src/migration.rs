@@ -0,0 +1,334 @@
+use crate::credentials::{Authed, Credentials};
+use crate::errors::MemoryAccessError;
+use crate::memory::{Access, DataNode, Forward, PAGE_SIZE};
+use crate::proto::memory::{
+    self, allocate_response, memory_client::MemoryClient, replicate_request::Op, AccessMode,
+    AllocateRequest, FreeRequest, GrantRequest, RegionMoved, TransferRequest, WriteRequest,
+};
+use crate::replication::Replication;
+use prost::Message;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tonic::{Code, Status};
+
+const COPY_CHUNK: usize = 16 * PAGE_SIZE; // per write while copying the whole region
+const MAX_ROUNDS: usize = 8; // of recopying dirty pages before cutting over regardless
+const CUTOVER_PAGES: usize = 16; // few enough dirty pages to copy with the region locked
+
+/// The status for accesses to a region that has moved, with where it went
+/// in the details.
+pub fn moved_status(forward: Forward) -> Status {
+    let details = RegionMoved {
+        addr: forward.addr,
+        id: forward.id,
+        generation: forward.generation,
+        key: forward.key,
+    };
+    Status::with_details(
+        Code::NotFound,
+        "Region moved",
+        details.encode_to_vec().into(),
+    )
+}
+
+/// Migrates every region off a node that has started draining, to each of
+/// `targets` in turn, sending progress before the first region and after
+/// each one. Regions are moved one at a time, so clients only ever wait on
+/// one cutover. Keeps going if the receiver goes away; fails once a region
+/// can't be moved to any of the targets.
+pub async fn drain(
+    data_node: &Arc<Mutex<DataNode>>,
+    replication: &Arc<Mutex<Replication>>,
+    credentials: &Credentials,
+    targets: Vec<String>,
+    progress: mpsc::Sender<Result<memory::DrainProgress, Status>>,
+) {
+    let mut moved = 0;
+    let mut next = 0;
+    loop {
+        let (used, regions) = data_node.lock().await.usage();
+        let done = regions.is_empty();
+        let update = memory::DrainProgress {
+            moved,
+            regions_left: regions.len() as u64,
+            bytes_left: used as u64,
+            done,
+        };
+        let _ = progress.send(Ok(update)).await;
+        if done {
+            log::info!("Drained, safe to stop");
+            return;
+        }
+
+        let (id, _) = regions[0];
+        let mut placed = false;
+        let mut failure = None;
+        for attempt in 0..targets.len() {
+            let target = &targets[(next + attempt) % targets.len()];
+            match migrate(data_node, replication, credentials, id, target.clone()).await {
+                Ok(_) => {
+                    moved += 1;
+                    next = (next + attempt + 1) % targets.len();
+                    placed = true;
+                    break;
+                }
+                // freed while being moved
+                Err(status) if status.code() == Code::NotFound => {
+                    placed = true;
+                    break;
+                }
+                Err(status) => {
+                    log::warn!("Couldn't move region {}: {}", id, status.message());
+                    failure = Some(status);
+                }
+            }
+        }
+        if !placed {
+            // with the code of the last failure, telling whether it's worth
+            // draining again
+            let last = failure.unwrap_or_else(|| Status::new(Code::Aborted, "No targets"));
+            let status = Status::new(
+                last.code(),
+                format!(
+                    "Couldn't move region {} to any target: {}",
+                    id,
+                    last.message()
+                ),
+            );
+            let _ = progress.send(Err(status)).await;
+            return;
+        }
+    }
+}
+
+/// Copies region `id` to the data node at `target` while clients keep
+/// using it, then cuts over to the copy, returning its id and generation
+/// there, and read-write key.
+///
+/// Pages written while the region is copied are recorded and copied again,
+/// in rounds, until few enough are left to copy with the node locked. Only
+/// that last round holds up clients. The region is then replaced by a
+/// forwarding address, which is replicated to backups like any other
+/// change. If anything fails before then the copy is freed and the region
+/// stays where it was.
+///
+/// The copy is allocated on behalf of the region's owner, evictable if the
+/// region is, and given the same grants at cutover, so with authentication
+/// this node's token has to be an admin's on the target.
+pub async fn migrate(
+    data_node: &Arc<Mutex<DataNode>>,
+    replication: &Arc<Mutex<Replication>>,
+    credentials: &Credentials,
+    id: usize,
+    target: String,
+) -> Result<(u64, u32, u64), Status> {
+    let (size, owner, priority, stripe) = {
+        let mut mem = data_node.lock().await;
+        replication.lock().await.check_writable()?;
+        let size = mem.track_dirty(id).map_err(|e| match e {
+            // spilled, and couldn't be brought back for copying
+            MemoryAccessError::Disk(message) => Status::new(
+                Code::ResourceExhausted,
+                format!("Can't bring region {} into RAM: {}", id, message),
+            ),
+            _ => not_found(),
+        })?;
+        let owner = mem.owner(id).unwrap_or_default().to_string();
+        (size, owner, mem.eviction_priority(id), mem.stripe(id))
+    };
+
+    let request = AllocateRequest {
+        size: size as u64,
+        owner,
+        evictable: priority.is_some(),
+        priority: priority.unwrap_or(0),
+        stripe,
+    };
+    let mut copy = match Copy::allocate(credentials, target, request).await {
+        Ok(copy) => copy,
+        Err(status) => {
+            data_node.lock().await.untrack_dirty(id);
+            return Err(status);
+        }
+    };
+    match copy_and_cut_over(data_node, replication, id, size, &mut copy).await {
+        Ok(()) => Ok((copy.id, copy.generation, copy.key)),
+        Err(status) => {
+            data_node.lock().await.untrack_dirty(id);
+            let request = FreeRequest {
+                id: copy.id,
+                key: copy.key,
+            };
+            let _ = copy.client.free_memory(request).await;
+            Err(status)
+        }
+    }
+}
+
+/// The region being copied to on the target.
+struct Copy {
+    client: MemoryClient<Authed>,
+    addr: String,
+    id: u64,
+    generation: u32,
+    key: u64,
+    owner: String,
+}
+
+impl Copy {
+    async fn allocate(
+        credentials: &Credentials,
+        addr: String,
+        request: AllocateRequest,
+    ) -> Result<Self, Status> {
+        let channel = credentials
+            .endpoint(addr.clone())
+            .map_err(|_| Status::new(Code::InvalidArgument, "Invalid target"))?
+            .connect()
+            .await
+            .map_err(|e| target_failed(&e.to_string()))?;
+        let mut client = MemoryClient::new(credentials.authed(channel));
+        let owner = request.owner.clone();
+        let response = client
+            .allocate_memory(request)
+            .await
+            .map_err(|status| target_failed(status.message()))?
+            .into_inner();
+        match response.result {
+            Some(allocate_response::Result::Size(id)) => Ok(Copy {
+                client,
+                addr,
+                id,
+                generation: response.generation,
+                key: response.key,
+                owner,
+            }),
+            _ => Err(target_failed("no room")),
+        }
+    }
+
+    async fn write(&mut self, offset: usize, data: Vec<u8>) -> Result<(), Status> {
+        let request = WriteRequest {
+            id: self.id,
+            offset: offset as u64,
+            data,
+            generation: self.generation,
+            key: self.key,
+        };
+        self.client
+            .write_memory(request)
+            .await
+            .map_err(|status| target_failed(status.message()))?;
+        Ok(())
+    }
+
+    /// Gives the copy the region's owner, if it changed while copying, and
+    /// grants.
+    async fn share(&mut self, owner: &str, grants: Vec<(String, Access)>) -> Result<(), Status> {
+        if owner != self.owner {
+            let request = TransferRequest {
+                id: self.id,
+                principal: owner.to_string(),
+            };
+            self.client
+                .transfer_ownership(request)
+                .await
+                .map_err(|status| target_failed(status.message()))?;
+        }
+        for (principal, access) in grants {
+            let mode = match access {
+                Access::Read => AccessMode::AccessRead,
+                _ => AccessMode::AccessReadWrite,
+            };
+            let request = GrantRequest {
+                id: self.id,
+                principal,
+                mode: mode as i32,
+            };
+            self.client
+                .grant_access(request)
+                .await
+                .map_err(|status| target_failed(status.message()))?;
+        }
+        Ok(())
+    }
+}
+
+async fn copy_and_cut_over(
+    data_node: &Arc<Mutex<DataNode>>,
+    replication: &Arc<Mutex<Replication>>,
+    id: usize,
+    size: usize,
+    copy: &mut Copy,
+) -> Result<(), Status> {
+    for offset in (0..size).step_by(COPY_CHUNK) {
+        let length = COPY_CHUNK.min(size - offset);
+        let data = data_node
+            .lock()
+            .await
+            .read_memory(id, offset, length)
+            .map_err(|_| not_found())?;
+        copy.write(offset, data).await?;
+    }
+
+    for _ in 0..MAX_ROUNDS {
+        let pages = {
+            let mut mem = data_node.lock().await;
+            if mem.dirty_pages(id) <= CUTOVER_PAGES {
+                break;
+            }
+            mem.take_dirty(id).map_err(|_| not_found())?
+        };
+        for (offset, data) in pages {
+            copy.write(offset, data).await?;
+        }
+    }
+
+    // locked until the forwarding address is in place, so nothing is
+    // written here after its last page is copied
+    let mut mem = data_node.lock().await;
+    let mut replication = replication.lock().await;
+    replication.check_writable()?;
+    for (offset, data) in mem.take_dirty(id).map_err(|_| not_found())? {
+        copy.write(offset, data).await?;
+    }
+    let owner = mem.owner(id).unwrap_or_default().to_string();
+    copy.share(&owner, mem.grants(id)).await?;
+    let forward = Forward {
+        addr: copy.addr.clone(),
+        id: copy.id,
+        generation: copy.generation,
+        key: copy.key,
+    };
+    mem.mark_moved(id, forward);
+    let op = Op::Move(memory::ReplicatedMove {
+        id: id as u64,
+        to: Some(RegionMoved {
+            addr: copy.addr.clone(),
+            id: copy.id,
+            generation: copy.generation,
+            key: copy.key,
+        }),
+    });
+    log::info!("Migrated region {} to {}", id, copy.addr);
+    // past the point of no return, since writes may already be reaching the
+    // copy, so a failure here only leaves the backups behind
+    if let Err(status) = replication.forward(mem.generation(), Some(op)).await {
+        log::warn!("Couldn't replicate the move: {}", status.message());
+    }
+    Ok(())
+}
+
+/// Failures on the target are reported as aborting the migration, rather
+/// than with the target's own code, which clients would take as being about
+/// this node.
+fn target_failed(message: &str) -> Status {
+    Status::new(
+        Code::Aborted,
+        format!("Migration target failed: {}", message),
+    )
+}
+
+fn not_found() -> Status {
+    Status::new(Code::NotFound, "Invalid memory access")
+}
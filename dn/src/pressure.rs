@@ -0,0 +1,76 @@
+/// How close the node is to its capacity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Pressure {
+    None,
+    Low,
+    High,
+    Critical,
+}
+
+/// What the node last told watchers. Only changes of pressure are sent, so
+/// `used` is as of the last change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reading {
+    pub pressure: Pressure,
+    pub used: usize,
+}
+
+/// The shares of the node's capacity in use at which pressure goes up to
+/// each level.
+#[derive(Debug, Clone, Copy)]
+pub struct Watermarks {
+    pub low: f64,
+    pub high: f64,
+    pub critical: f64,
+}
+
+impl Default for Watermarks {
+    fn default() -> Self {
+        Self {
+            low: 0.7,
+            high: 0.85,
+            critical: 0.95,
+        }
+    }
+}
+
+impl std::str::FromStr for Watermarks {
+    type Err = String;
+
+    /// Parses `<low>,<high>,<critical>`, percentages of the capacity in
+    /// increasing order.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("Bad watermarks {}", s);
+        let levels: Vec<f64> = s
+            .split(',')
+            .map(|level| level.parse::<f64>().map(|percent| percent / 100.0))
+            .collect::<Result<_, _>>()
+            .map_err(|_| bad())?;
+        match levels[..] {
+            [low, high, critical] if 0.0 < low && low <= high && high <= critical => Ok(Self {
+                low,
+                high,
+                critical,
+            }),
+            _ => Err(bad()),
+        }
+    }
+}
+
+impl Watermarks {
+    pub fn pressure(&self, used: usize, capacity: Option<usize>) -> Pressure {
+        let Some(capacity) = capacity else {
+            return Pressure::None;
+        };
+        let share = used as f64 / capacity.max(1) as f64;
+        if share >= self.critical {
+            Pressure::Critical
+        } else if share >= self.high {
+            Pressure::High
+        } else if share >= self.low {
+            Pressure::Low
+        } else {
+            Pressure::None
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use crate::credentials::{Authed, Credentials};
+use crate::proto::memory::{memory_client::MemoryClient, replicate_request::Op, ReplicateRequest};
+use std::time::Duration;
+use tonic::metadata::MetadataValue;
+use tonic::{Code, Status};
+
+// Changes are forwarded with the node locked, so a backup that stops
+// answering holds up every request until it is given up on.
+const BACKUP_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const BACKUP_TIMEOUT: Duration = Duration::from_secs(5);
+// marks the refusal of a node promoted past the sender
+const PROMOTED: &str = "promoted";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Primary, // or the head of a chain
+    Backup,
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "primary" => Ok(Role::Primary),
+            "backup" => Ok(Role::Backup),
+            _ => Err(format!("Unknown role {}", s)),
+        }
+    }
+}
+
+/// How a replica set passes changes along.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// The primary sends every change to all backups, and serves reads.
+    PrimaryBackup,
+    /// Each node sends changes on to its successor only, and reads are
+    /// served by the tail, which has seen every acknowledged change and
+    /// nothing else, so they are linearizable without asking the others.
+    Chain,
+}
+
+impl std::str::FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "primary-backup" => Ok(Mode::PrimaryBackup),
+            "chain" => Ok(Mode::Chain),
+            _ => Err(format!("Unknown replication mode {}", s)),
+        }
+    }
+}
+
+/// Returned for client requests sent to the wrong node of a replica set,
+/// with a code that tells clients to fail over.
+pub enum Misrouted {
+    NotPrimary,
+    NotTail,
+}
+
+impl From<Misrouted> for Status {
+    fn from(misrouted: Misrouted) -> Self {
+        match misrouted {
+            Misrouted::NotPrimary => Status::new(Code::Unavailable, "Not the primary"),
+            Misrouted::NotTail => Status::new(Code::Unavailable, "Not the tail"),
+        }
+    }
+}
+
+/// What a promoted node refuses forwarded changes with, telling a former
+/// primary that it has been replaced.
+pub fn promoted_status() -> Status {
+    let mut status = Status::new(Code::FailedPrecondition, "Not a backup");
+    status
+        .metadata_mut()
+        .insert(PROMOTED, MetadataValue::from_static("true"));
+    status
+}
+
+struct Backup {
+    addr: String,
+    client: MemoryClient<Authed>,
+}
+
+/// This node's place in its replica set. A primary serves clients and
+/// forwards every change to its backups before acknowledging it; a backup
+/// only applies what is forwarded to it until it is promoted.
+///
+/// In a chain, `backups` are the rest of the chain in order, and changes go
+/// to the first of them that is still alive, which passes them on in turn.
+/// Skipping a failed node that way is what reconfigures the chain.
+///
+/// A backup that fails to apply a change is dropped from the set rather than
+/// holding up the primary, and is not brought back: it has to be restarted
+/// empty alongside a fresh primary to rejoin.
+pub struct Replication {
+    mode: Mode,
+    role: Role,
+    backups: Vec<Backup>,
+    credentials: Credentials,
+}
+
+impl Replication {
+    pub fn new(
+        mode: Mode,
+        role: Role,
+        backups: Vec<String>,
+        credentials: Credentials,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut replication = Replication {
+            mode,
+            role,
+            backups: Vec::new(),
+            credentials,
+        };
+        replication.set_backups(backups)?;
+        Ok(replication)
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Whether this node takes changes from clients.
+    pub fn check_writable(&self) -> Result<(), Misrouted> {
+        match self.role {
+            Role::Primary => Ok(()),
+            Role::Backup => Err(Misrouted::NotPrimary),
+        }
+    }
+
+    /// Whether this node answers reads from clients.
+    pub fn check_readable(&self) -> Result<(), Misrouted> {
+        match self.mode {
+            Mode::PrimaryBackup => self.check_writable(),
+            Mode::Chain if self.backups.is_empty() => Ok(()),
+            Mode::Chain => Err(Misrouted::NotTail),
+        }
+    }
+
+    pub fn promote(&mut self, backups: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+        if self.role == Role::Primary {
+            return Ok(());
+        }
+        self.set_backups(backups)?;
+        self.role = Role::Primary;
+        log::info!("Promoted to primary, replicating to {:?}", self.addrs());
+        Ok(())
+    }
+
+    /// Makes this node the tail of its chain once none of its successors
+    /// answer. Fails while one does, since it would keep serving reads
+    /// without seeing further changes.
+    pub async fn make_tail(&mut self, generation: u32) -> Result<(), Status> {
+        if self.mode != Mode::Chain {
+            return Err(Status::new(Code::FailedPrecondition, "Not in a chain"));
+        }
+        self.forward(generation, None).await?;
+        if let Some(successor) = self.backups.first() {
+            return Err(Status::new(
+                Code::FailedPrecondition,
+                format!("Successor {} is still alive", successor.addr),
+            ));
+        }
+        log::info!("Now the tail of the chain");
+        Ok(())
+    }
+
+    /// Sends `op` on to the backups, or just checks they are alive if there
+    /// is none. If a backup has been promoted in the meantime this node
+    /// steps down, since clients have moved on without it, and the caller
+    /// should fail the request.
+    pub async fn forward(&mut self, generation: u32, op: Option<Op>) -> Result<(), Status> {
+        let mut stepped_down = false;
+        match self.mode {
+            Mode::PrimaryBackup => {
+                let mut live = Vec::with_capacity(self.backups.len());
+                for mut backup in self.backups.drain(..) {
+                    match send(&mut backup, generation, &op).await {
+                        Ok(()) => live.push(backup),
+                        Err(promoted) => stepped_down |= promoted,
+                    }
+                }
+                self.backups = live;
+            }
+            Mode::Chain => {
+                while let Some(successor) = self.backups.first_mut() {
+                    match send(successor, generation, &op).await {
+                        Ok(()) => break,
+                        Err(promoted) => {
+                            stepped_down |= promoted;
+                            self.backups.remove(0);
+                        }
+                    }
+                }
+            }
+        }
+
+        if stepped_down {
+            self.role = Role::Backup;
+            self.backups.clear();
+            return Err(Misrouted::NotPrimary.into());
+        }
+        Ok(())
+    }
+
+    fn set_backups(&mut self, addrs: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut backups = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            // connected on first use, so backups can start after the primary
+            let channel = self
+                .credentials
+                .endpoint(addr.clone())?
+                .connect_timeout(BACKUP_CONNECT_TIMEOUT)
+                .timeout(BACKUP_TIMEOUT)
+                .connect_lazy();
+            backups.push(Backup {
+                addr,
+                client: MemoryClient::new(self.credentials.authed(channel)),
+            });
+        }
+        self.backups = backups;
+        Ok(())
+    }
+
+    fn addrs(&self) -> Vec<&str> {
+        self.backups.iter().map(|b| b.addr.as_str()).collect()
+    }
+}
+
+/// Replicates `op` to one backup. On failure the backup should be dropped,
+/// and `Err(true)` means it has been promoted past this node.
+async fn send(backup: &mut Backup, generation: u32, op: &Option<Op>) -> Result<(), bool> {
+    let request = ReplicateRequest {
+        generation,
+        op: op.clone(),
+    };
+    match backup.client.replicate(request).await {
+        Ok(_) => Ok(()),
+        Err(status) if status.metadata().contains_key(PROMOTED) => {
+            log::warn!("Backup {} has been promoted, stepping down", backup.addr);
+            Err(true)
+        }
+        Err(status) => {
+            log::warn!("Dropping backup {}: {}", backup.addr, status.message());
+            Err(false)
+        }
+    }
+}
@@ -1,20 +1,98 @@
+use crate::auth::{self, Principal};
 use crate::errors::{AllocationError, DeallocationError, MemoryAccessError};
 use crate::proto::memory;
+use crate::proto::memory::replicate_request::Op;
 
-use crate::memory::DataNode;
+use crate::credentials::Credentials;
+use crate::memory::{Access, DataNode, Forward, PAGE_SIZE};
+use crate::migration::{self, moved_status};
+use crate::pressure::Pressure;
+use crate::replication::{promoted_status, Replication, Role};
+use crate::scheduler::{Scheduler, Turn};
+use crate::throttle::{self, Limits, Throttle};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::wrappers::ReceiverStream;
 
 use tonic::{Code, Status};
 
+/// Locks are always taken data node first, then replication. Changes are
+/// forwarded to backups with both held, so backups apply them in the order
+/// the primary did and nothing is read before it has been replicated.
+///
+/// Clients' requests for regions are first held to their rates, and then
+/// wait for their turn at the node, so that one busy client can't starve
+/// the others. Requests between nodes and for managing the node skip both.
 pub struct MemoryService {
     data_node: Arc<Mutex<DataNode>>,
+    replication: Arc<Mutex<Replication>>,
+    credentials: Credentials, // for connecting to migration targets
+    throttle: std::sync::Mutex<Throttle>,
+    scheduler: Scheduler,
 }
 
 impl MemoryService {
-    pub fn new(data_node: DataNode) -> Self {
+    /// Holds a request moving `bytes` to its client's rates, then waits for
+    /// the client's turn at the node. Each page moved takes as much of the
+    /// node's time as the request itself. Requests hand the turn on once the
+    /// node has done its part, before forwarding to backups, so slow backups
+    /// don't count against the client.
+    async fn admit<T>(&self, request: &tonic::Request<T>, bytes: usize) -> Result<Turn, Status> {
+        let client = throttle::client(request);
+        let weight = {
+            let mut throttle = self.throttle.lock().unwrap();
+            throttle.admit(&client, bytes)?;
+            throttle.limit(&client).weight
+        };
+        let cost = 1.0 + bytes as f64 / PAGE_SIZE as f64;
+        Ok(self.scheduler.turn(&client, weight, cost).await)
+    }
+
+    /// Applies `change` to who may use region `id`, if `caller` owns it,
+    /// and replicates it as `op`, handing on `turn` in between.
+    async fn share<F>(
+        &self,
+        turn: Turn,
+        caller: &Principal,
+        id: u64,
+        change: F,
+        op: Op,
+    ) -> Result<(), Status>
+    where
+        F: FnOnce(&mut DataNode) -> Result<(), MemoryAccessError>,
+    {
+        let mut mem = self.data_node.lock().await;
+        let mut replication = self.replication.lock().await;
+        replication.check_writable()?;
+        let response = mem
+            .check_access(id as usize, caller, Access::Own)
+            .map_err(MemoryAccessError::from)
+            .and_then(|_| change(&mut mem));
+        drop(turn);
+
+        match response {
+            Ok(()) => replication.forward(mem.generation(), Some(op)).await,
+            Err(MemoryAccessError::Moved(forward)) => Err(moved_status(forward)),
+            Err(MemoryAccessError::PermissionDenied) => {
+                Err(Status::new(Code::PermissionDenied, "Permission denied"))
+            }
+            Err(MemoryAccessError::Evicted) => Err(Status::new(Code::DataLoss, "Region evicted")),
+            Err(_) => Err(Status::new(Code::NotFound, "Invalid memory access")),
+        }
+    }
+
+    pub fn new(
+        data_node: DataNode,
+        replication: Replication,
+        credentials: Credentials,
+        limits: Limits,
+    ) -> Self {
         MemoryService {
             data_node: Arc::new(Mutex::new(data_node)),
+            replication: Arc::new(Mutex::new(replication)),
+            credentials,
+            throttle: std::sync::Mutex::new(Throttle::new(limits)),
+            scheduler: Scheduler::default(),
         }
     }
 }
@@ -25,21 +103,66 @@ impl memory::memory_server::Memory for MemoryService {
         &self,
         request: tonic::Request<memory::AllocateRequest>,
     ) -> Result<tonic::Response<memory::AllocateResponse>, tonic::Status> {
+        let turn = self
+            .admit(&request, request.get_ref().size as usize)
+            .await?;
+        let caller = auth::caller(&request);
         let input = request.into_inner();
+        // only admins may allocate on someone else's behalf, as migrations do
+        let owner = if input.owner.is_empty() {
+            caller.name
+        } else {
+            caller.check_admin()?;
+            input.owner
+        };
         let mut mem = self.data_node.lock().await;
-        let response = mem.allocate_memory(input.size as usize);
+        let mut replication = self.replication.lock().await;
+        replication.check_writable()?;
+        let priority = input.evictable.then_some(input.priority);
+        let mut evicted = Vec::new();
+        let response = mem.allocate_memory(input.size as usize, &owner, priority, &mut evicted);
+        drop(turn);
+        if !evicted.is_empty() {
+            let ids = evicted.into_iter().map(|id| id as u64).collect();
+            let op = Op::Evict(memory::ReplicatedEvict { ids });
+            replication.forward(mem.generation(), Some(op)).await?;
+        }
 
         match response {
-            Ok(id) => Ok(tonic::Response::new(memory::AllocateResponse {
-                result: Some(memory::allocate_response::Result::Size(id as u64)),
-            })),
-            Err(err) => {
-                let status = match err {
-                    AllocationError::AllocationTooLarge => {
-                        Status::new(Code::InvalidArgument, "Invalid size requested")
-                    }
-                };
-                Err(status)
+            Ok((id, key)) => {
+                mem.set_stripe(id, input.stripe);
+                let op = Op::Allocate(memory::ReplicatedAllocate {
+                    id: id as u64,
+                    size: input.size,
+                    owner,
+                    key,
+                    evictable: input.evictable,
+                    priority: input.priority,
+                    stripe: input.stripe,
+                });
+                replication.forward(mem.generation(), Some(op)).await?;
+                Ok(tonic::Response::new(memory::AllocateResponse {
+                    result: Some(memory::allocate_response::Result::Size(id as u64)),
+                    generation: mem.generation(),
+                    key,
+                }))
+            }
+            // which clients don't tell apart
+            Err(AllocationError::InsufficientMemory | AllocationError::QuotaExceeded) => {
+                let error = memory::AllocationError::InsufficientMemory as i32;
+                Ok(tonic::Response::new(memory::AllocateResponse {
+                    result: Some(memory::allocate_response::Result::Error(error)),
+                    generation: mem.generation(),
+                    key: 0,
+                }))
+            }
+            Err(AllocationError::AllocationTooLarge) => {
+                Err(Status::new(Code::InvalidArgument, "Invalid size requested"))
+            }
+            // not exhausted, and not gone either, so that clients neither
+            // take it for a full node nor fail over
+            Err(AllocationError::Draining) => {
+                Err(Status::new(Code::FailedPrecondition, "Node is draining"))
             }
         }
     }
@@ -48,19 +171,41 @@ impl memory::memory_server::Memory for MemoryService {
         &self,
         request: tonic::Request<memory::FreeRequest>,
     ) -> Result<tonic::Response<memory::FreeResponse>, tonic::Status> {
+        let turn = self.admit(&request, 0).await?;
+        let caller = auth::caller(&request);
         let input = request.into_inner();
         let mut mem = self.data_node.lock().await;
-        let response = mem.free_memory(input.id as usize);
+        let mut replication = self.replication.lock().await;
+        replication.check_writable()?;
+        let response = mem
+            .check_access(input.id as usize, &caller, Access::Own)
+            .map_err(DeallocationError::from)
+            .and_then(|_| {
+                mem.check_key(input.id as usize, input.key, true)
+                    .map_err(DeallocationError::from)
+            })
+            .and_then(|_| mem.free_memory(input.id as usize));
+        drop(turn);
 
         match response {
-            Ok(_) => Ok(tonic::Response::new(memory::FreeResponse {
-                result: Some(memory::free_response::Result::Ok(true)),
-            })),
+            Ok(_) => {
+                replication
+                    .forward(mem.generation(), Some(Op::Free(input.id)))
+                    .await?;
+                Ok(tonic::Response::new(memory::FreeResponse {
+                    result: Some(memory::free_response::Result::Ok(true)),
+                }))
+            }
             Err(err) => {
                 let status = match err {
                     DeallocationError::InvalidMemoryAddress => {
                         Status::new(Code::OutOfRange, "Invalid memory access")
                     }
+                    DeallocationError::Moved(forward) => moved_status(forward),
+                    DeallocationError::PermissionDenied => {
+                        Status::new(Code::PermissionDenied, "Permission denied")
+                    }
+                    DeallocationError::Evicted => Status::new(Code::DataLoss, "Region evicted"),
                 };
                 Err(status)
             }
@@ -71,17 +216,26 @@ impl memory::memory_server::Memory for MemoryService {
         &self,
         request: tonic::Request<memory::ReadRequest>,
     ) -> Result<tonic::Response<memory::ReadResponse>, tonic::Status> {
+        let _turn = self
+            .admit(&request, request.get_ref().length as usize)
+            .await?;
+        let caller = auth::caller(&request);
         let input = request.into_inner();
-        let mem = self.data_node.lock().await;
-        let response = mem.read_memory(
-            input.id as usize,
-            input.offset as usize,
-            input.length as usize,
-        );
+        let mut mem = self.data_node.lock().await;
+        self.replication.lock().await.check_readable()?;
+        let response = mem.check_generation(input.generation).and_then(|_| {
+            mem.check_access(input.id as usize, &caller, Access::Read)?;
+            mem.check_key(input.id as usize, input.key, false)?;
+            mem.read_memory(
+                input.id as usize,
+                input.offset as usize,
+                input.length as usize,
+            )
+        });
 
         match response {
             Ok(bytes) => Ok(tonic::Response::new(memory::ReadResponse {
-                result: Some(memory::read_response::Result::Memory(bytes.to_vec())),
+                result: Some(memory::read_response::Result::Memory(bytes)),
             })),
             Err(err) => {
                 let status = match err {
@@ -91,6 +245,15 @@ impl memory::memory_server::Memory for MemoryService {
                     MemoryAccessError::OutOfBoundsAccess => {
                         Status::new(Code::OutOfRange, "Out of bounds access")
                     }
+                    MemoryAccessError::StaleGeneration => {
+                        Status::new(Code::FailedPrecondition, "Stale generation")
+                    }
+                    MemoryAccessError::Moved(forward) => moved_status(forward),
+                    MemoryAccessError::PermissionDenied => {
+                        Status::new(Code::PermissionDenied, "Permission denied")
+                    }
+                    MemoryAccessError::Evicted => Status::new(Code::DataLoss, "Region evicted"),
+                    MemoryAccessError::Disk(_) => Status::new(Code::Internal, "Spill file failed"),
                 };
                 Err(status)
             }
@@ -101,14 +264,32 @@ impl memory::memory_server::Memory for MemoryService {
         &self,
         request: tonic::Request<memory::WriteRequest>,
     ) -> Result<tonic::Response<memory::WriteResponse>, Status> {
+        let turn = self.admit(&request, request.get_ref().data.len()).await?;
+        let caller = auth::caller(&request);
         let input = request.into_inner();
         let mut mem = self.data_node.lock().await;
-        let response = mem.write_memory(input.id as usize, input.offset as usize, &input.data);
+        let mut replication = self.replication.lock().await;
+        replication.check_writable()?;
+        let response = mem.check_generation(input.generation).and_then(|_| {
+            mem.check_access(input.id as usize, &caller, Access::Write)?;
+            mem.check_key(input.id as usize, input.key, true)?;
+            mem.write_memory(input.id as usize, input.offset as usize, &input.data)
+        });
+        drop(turn);
 
         match response {
-            Ok(_) => Ok(tonic::Response::new(memory::WriteResponse {
-                result: Some(memory::write_response::Result::Ok(true)),
-            })),
+            Ok(_) => {
+                let op = Op::Write(memory::ReplicatedWrite {
+                    id: input.id,
+                    offset: input.offset,
+                    data: input.data,
+                    request_id: 0,
+                });
+                replication.forward(mem.generation(), Some(op)).await?;
+                Ok(tonic::Response::new(memory::WriteResponse {
+                    result: Some(memory::write_response::Result::Ok(true)),
+                }))
+            }
             Err(err) => {
                 let status = match err {
                     MemoryAccessError::InvalidMemoryAddress => {
@@ -117,6 +298,15 @@ impl memory::memory_server::Memory for MemoryService {
                     MemoryAccessError::OutOfBoundsAccess => {
                         Status::new(Code::OutOfRange, "Out of bounds access")
                     }
+                    MemoryAccessError::StaleGeneration => {
+                        Status::new(Code::FailedPrecondition, "Stale generation")
+                    }
+                    MemoryAccessError::Moved(forward) => moved_status(forward),
+                    MemoryAccessError::PermissionDenied => {
+                        Status::new(Code::PermissionDenied, "Permission denied")
+                    }
+                    MemoryAccessError::Evicted => Status::new(Code::DataLoss, "Region evicted"),
+                    MemoryAccessError::Disk(_) => Status::new(Code::Internal, "Spill file failed"),
                 };
                 Err(status)
             }
@@ -127,15 +317,469 @@ impl memory::memory_server::Memory for MemoryService {
         &self,
         request: tonic::Request<memory::GetMemorySizeRequest>,
     ) -> Result<tonic::Response<memory::GetMemorySizeResponse>, Status> {
+        let _turn = self.admit(&request, 0).await?;
+        let caller = auth::caller(&request);
         let input = request.into_inner();
         let mem = self.data_node.lock().await;
-        let response = mem.get_memory_size(input.id as usize);
+        self.replication.lock().await.check_readable()?;
+        mem.check_access(input.id as usize, &caller, Access::Read)?;
+        let response = mem
+            .check_key(input.id as usize, input.key, false)
+            .and_then(|_| mem.get_memory_size(input.id as usize));
 
         match response {
             Ok(size) => Ok(tonic::Response::new(memory::GetMemorySizeResponse {
                 result: Some(memory::get_memory_size_response::Result::Size(size as u64)),
             })),
+            Err(MemoryAccessError::Moved(forward)) => Err(moved_status(forward)),
+            Err(MemoryAccessError::PermissionDenied) => {
+                Err(Status::new(Code::PermissionDenied, "Permission denied"))
+            }
+            Err(MemoryAccessError::Evicted) => Err(Status::new(Code::DataLoss, "Region evicted")),
             Err(_) => Err(Status::new(Code::NotFound, "Invalid memory access")),
         }
     }
+
+    async fn compare_and_swap(
+        &self,
+        request: tonic::Request<memory::CompareAndSwapRequest>,
+    ) -> Result<tonic::Response<memory::CompareAndSwapResponse>, Status> {
+        let turn = self
+            .admit(&request, 8 + request.get_ref().tail.len())
+            .await?;
+        let caller = auth::caller(&request);
+        let input = request.into_inner();
+        let mut mem = self.data_node.lock().await;
+        let mut replication = self.replication.lock().await;
+        replication.check_writable()?;
+        let response = mem.check_generation(input.generation).and_then(|_| {
+            mem.check_access(input.id as usize, &caller, Access::Write)?;
+            mem.check_key(input.id as usize, input.key, true)?;
+            if mem.swapped(input.request_id) {
+                // a retry of a swap that went through
+                return Ok(None);
+            }
+            mem.compare_and_swap(
+                input.id as usize,
+                input.offset as usize,
+                input.expected,
+                input.desired,
+                &input.tail,
+            )
+            .map(Some)
+        });
+        drop(turn);
+
+        match response {
+            Ok(previous) => {
+                let retried = previous.is_none();
+                let previous = previous.unwrap_or(input.expected);
+                if previous == input.expected && !retried {
+                    mem.record_swap(input.request_id);
+                    // backups just see the winning swap as a write, but
+                    // remember it too for retries after a failover
+                    let mut data = input.desired.to_le_bytes().to_vec();
+                    data.extend_from_slice(&input.tail);
+                    let op = Op::Write(memory::ReplicatedWrite {
+                        id: input.id,
+                        offset: input.offset,
+                        data,
+                        request_id: input.request_id,
+                    });
+                    replication.forward(mem.generation(), Some(op)).await?;
+                }
+                Ok(tonic::Response::new(memory::CompareAndSwapResponse {
+                    result: Some(memory::compare_and_swap_response::Result::Previous(
+                        previous,
+                    )),
+                }))
+            }
+            Err(err) => {
+                let status = match err {
+                    MemoryAccessError::InvalidMemoryAddress => {
+                        Status::new(Code::NotFound, "Invalid memory access")
+                    }
+                    MemoryAccessError::OutOfBoundsAccess => {
+                        Status::new(Code::OutOfRange, "Out of bounds access")
+                    }
+                    MemoryAccessError::StaleGeneration => {
+                        Status::new(Code::FailedPrecondition, "Stale generation")
+                    }
+                    MemoryAccessError::Moved(forward) => moved_status(forward),
+                    MemoryAccessError::PermissionDenied => {
+                        Status::new(Code::PermissionDenied, "Permission denied")
+                    }
+                    MemoryAccessError::Evicted => Status::new(Code::DataLoss, "Region evicted"),
+                    MemoryAccessError::Disk(_) => Status::new(Code::Internal, "Spill file failed"),
+                };
+                Err(status)
+            }
+        }
+    }
+
+    async fn xor_memory(
+        &self,
+        request: tonic::Request<memory::XorRequest>,
+    ) -> Result<tonic::Response<memory::XorResponse>, Status> {
+        let turn = self.admit(&request, request.get_ref().data.len()).await?;
+        let caller = auth::caller(&request);
+        let input = request.into_inner();
+        let mut mem = self.data_node.lock().await;
+        let mut replication = self.replication.lock().await;
+        replication.check_writable()?;
+        let response = mem.check_generation(input.generation).and_then(|_| {
+            mem.check_access(input.id as usize, &caller, Access::Write)?;
+            mem.check_key(input.id as usize, input.key, true)?;
+            mem.xor_memory(input.id as usize, input.offset as usize, &input.data)
+        });
+        drop(turn);
+
+        match response {
+            Ok(result) => {
+                // backups just see the result as a write
+                let op = Op::Write(memory::ReplicatedWrite {
+                    id: input.id,
+                    offset: input.offset,
+                    data: result,
+                    request_id: 0,
+                });
+                replication.forward(mem.generation(), Some(op)).await?;
+                Ok(tonic::Response::new(memory::XorResponse {
+                    result: Some(memory::xor_response::Result::Ok(true)),
+                }))
+            }
+            Err(err) => {
+                let status = match err {
+                    MemoryAccessError::InvalidMemoryAddress => {
+                        Status::new(Code::NotFound, "Invalid memory access")
+                    }
+                    MemoryAccessError::OutOfBoundsAccess => {
+                        Status::new(Code::OutOfRange, "Out of bounds access")
+                    }
+                    MemoryAccessError::StaleGeneration => {
+                        Status::new(Code::FailedPrecondition, "Stale generation")
+                    }
+                    MemoryAccessError::Moved(forward) => moved_status(forward),
+                    MemoryAccessError::PermissionDenied => {
+                        Status::new(Code::PermissionDenied, "Permission denied")
+                    }
+                    MemoryAccessError::Evicted => Status::new(Code::DataLoss, "Region evicted"),
+                    MemoryAccessError::Disk(_) => Status::new(Code::Internal, "Spill file failed"),
+                };
+                Err(status)
+            }
+        }
+    }
+
+    async fn replicate(
+        &self,
+        request: tonic::Request<memory::ReplicateRequest>,
+    ) -> Result<tonic::Response<memory::ReplicateResponse>, Status> {
+        auth::caller(&request).check_admin()?;
+        let input = request.into_inner();
+        let mut mem = self.data_node.lock().await;
+        let mut replication = self.replication.lock().await;
+        if replication.role() == Role::Primary {
+            return Err(promoted_status());
+        }
+        mem.adopt_generation(input.generation);
+
+        let response = match input.op.clone() {
+            Some(Op::Allocate(op)) => mem
+                .allocate_at(
+                    op.id as usize,
+                    op.size as usize,
+                    &op.owner,
+                    op.key,
+                    op.evictable.then_some(op.priority),
+                )
+                .map(|_| mem.set_stripe(op.id as usize, op.stripe))
+                .map_err(|e| e.to_string()),
+            Some(Op::Write(op)) => mem
+                .write_memory(op.id as usize, op.offset as usize, &op.data)
+                .map(|_| mem.record_swap(op.request_id))
+                .map_err(|e| e.to_string()),
+            Some(Op::Free(id)) => {
+                // may be resent after a chain skips a failed node, so it is
+                // fine to find the region already freed
+                let _ = mem.free_memory(id as usize);
+                Ok(())
+            }
+            Some(Op::Grant(op)) => mem
+                .grant(op.id as usize, &op.principal, granted(op.mode))
+                .map_err(|e| e.to_string()),
+            Some(Op::Transfer(op)) => mem
+                .transfer(op.id as usize, &op.owner)
+                .map_err(|e| e.to_string()),
+            Some(Op::Evict(op)) => {
+                for id in op.ids {
+                    mem.evict(id as usize);
+                }
+                Ok(())
+            }
+            Some(Op::Move(op)) => {
+                let to = op.to.unwrap_or_default();
+                let forward = Forward {
+                    addr: to.addr,
+                    id: to.id,
+                    generation: to.generation,
+                    key: to.key,
+                };
+                mem.mark_moved(op.id as usize, forward);
+                Ok(())
+            }
+            None => Ok(()), // a liveness check
+        };
+
+        match response {
+            Ok(_) => {
+                // in a chain, pass it on before acknowledging
+                replication.forward(input.generation, input.op).await?;
+                Ok(tonic::Response::new(memory::ReplicateResponse {}))
+            }
+            // the backup has diverged from its primary
+            Err(message) => Err(Status::new(Code::Internal, message)),
+        }
+    }
+
+    async fn promote(
+        &self,
+        request: tonic::Request<memory::PromoteRequest>,
+    ) -> Result<tonic::Response<memory::PromoteResponse>, Status> {
+        auth::caller(&request).check_replicator()?;
+        let input = request.into_inner();
+        let mem = self.data_node.lock().await;
+        let mut replication = self.replication.lock().await;
+        replication
+            .promote(input.backups)
+            .map_err(|e| Status::new(Code::InvalidArgument, e.to_string()))?;
+
+        Ok(tonic::Response::new(memory::PromoteResponse {
+            generation: mem.generation(),
+        }))
+    }
+
+    async fn make_tail(
+        &self,
+        request: tonic::Request<memory::MakeTailRequest>,
+    ) -> Result<tonic::Response<memory::MakeTailResponse>, Status> {
+        auth::caller(&request).check_replicator()?;
+        let mem = self.data_node.lock().await;
+        let mut replication = self.replication.lock().await;
+        replication.make_tail(mem.generation()).await?;
+        Ok(tonic::Response::new(memory::MakeTailResponse {}))
+    }
+
+    async fn migrate_region(
+        &self,
+        request: tonic::Request<memory::MigrateRequest>,
+    ) -> Result<tonic::Response<memory::MigrateResponse>, Status> {
+        auth::caller(&request).check_admin()?;
+        let input = request.into_inner();
+        // no key asked for, since admins rebalancing or draining a node
+        // don't hold the keys of the regions they move
+        if let Some(forward) = self.data_node.lock().await.forwarded(input.id as usize) {
+            return Err(moved_status(forward));
+        }
+        let (id, generation, key) = migration::migrate(
+            &self.data_node,
+            &self.replication,
+            &self.credentials,
+            input.id as usize,
+            input.target,
+        )
+        .await?;
+        Ok(tonic::Response::new(memory::MigrateResponse {
+            id,
+            generation,
+            key,
+        }))
+    }
+
+    async fn get_usage(
+        &self,
+        request: tonic::Request<memory::UsageRequest>,
+    ) -> Result<tonic::Response<memory::UsageResponse>, Status> {
+        auth::caller(&request).check_admin()?;
+        let mem = self.data_node.lock().await;
+        self.replication.lock().await.check_readable()?;
+        let (used, regions) = mem.usage();
+        Ok(tonic::Response::new(memory::UsageResponse {
+            draining: mem.is_draining(),
+            used_bytes: used as u64,
+            regions: regions
+                .into_iter()
+                .map(|(id, size)| memory::RegionUsage {
+                    id: id as u64,
+                    size: size as u64,
+                    stripe: mem.stripe(id),
+                })
+                .collect(),
+            tenants: mem
+                .tenants()
+                .iter()
+                .map(|tenant| memory::TenantUsage {
+                    name: tenant.name.clone(),
+                    used_bytes: tenant.used_bytes as u64,
+                    regions: tenant.regions as u64,
+                    max_bytes: tenant.max_bytes.unwrap_or(0) as u64,
+                    max_regions: tenant.max_regions.unwrap_or(0) as u64,
+                })
+                .collect(),
+            tiers: mem
+                .tiers()
+                .map(|tiers| {
+                    vec![
+                        memory::TierUsage {
+                            name: "ram".to_string(),
+                            used_bytes: tiers.ram_bytes as u64,
+                            regions: tiers.ram_regions as u64,
+                            hits: tiers.stats.ram_hits,
+                            moved_in: tiers.stats.promotions,
+                        },
+                        memory::TierUsage {
+                            name: "disk".to_string(),
+                            used_bytes: tiers.disk_bytes as u64,
+                            regions: tiers.disk_regions as u64,
+                            hits: tiers.stats.disk_hits,
+                            moved_in: tiers.stats.demotions,
+                        },
+                    ]
+                })
+                .unwrap_or_default(),
+        }))
+    }
+
+    type DrainStream = ReceiverStream<Result<memory::DrainProgress, Status>>;
+
+    async fn drain(
+        &self,
+        request: tonic::Request<memory::DrainRequest>,
+    ) -> Result<tonic::Response<Self::DrainStream>, Status> {
+        auth::caller(&request).check_admin()?;
+        let input = request.into_inner();
+        if input.targets.is_empty() {
+            return Err(Status::new(Code::InvalidArgument, "No targets to drain to"));
+        }
+        {
+            let mut mem = self.data_node.lock().await;
+            self.replication.lock().await.check_writable()?;
+            mem.start_draining();
+        }
+
+        let (tx, rx) = mpsc::channel(16);
+        let data_node = self.data_node.clone();
+        let replication = self.replication.clone();
+        let credentials = self.credentials.clone();
+        tokio::spawn(async move {
+            migration::drain(&data_node, &replication, &credentials, input.targets, tx).await;
+        });
+        Ok(tonic::Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn grant_access(
+        &self,
+        request: tonic::Request<memory::GrantRequest>,
+    ) -> Result<tonic::Response<memory::GrantResponse>, Status> {
+        let turn = self.admit(&request, 0).await?;
+        let caller = auth::caller(&request);
+        let input = request.into_inner();
+        let Some(access) = granted(input.mode) else {
+            return Err(Status::new(Code::InvalidArgument, "Invalid access mode"));
+        };
+        let op = Op::Grant(memory::ReplicatedGrant {
+            id: input.id,
+            principal: input.principal.clone(),
+            mode: input.mode,
+        });
+        let change =
+            |mem: &mut DataNode| mem.grant(input.id as usize, &input.principal, Some(access));
+        self.share(turn, &caller, input.id, change, op).await?;
+        Ok(tonic::Response::new(memory::GrantResponse {}))
+    }
+
+    async fn revoke_access(
+        &self,
+        request: tonic::Request<memory::RevokeRequest>,
+    ) -> Result<tonic::Response<memory::RevokeResponse>, Status> {
+        let turn = self.admit(&request, 0).await?;
+        let caller = auth::caller(&request);
+        let input = request.into_inner();
+        let op = Op::Grant(memory::ReplicatedGrant {
+            id: input.id,
+            principal: input.principal.clone(),
+            mode: memory::AccessMode::None as i32,
+        });
+        let change = |mem: &mut DataNode| mem.grant(input.id as usize, &input.principal, None);
+        self.share(turn, &caller, input.id, change, op).await?;
+        Ok(tonic::Response::new(memory::RevokeResponse {}))
+    }
+
+    async fn transfer_ownership(
+        &self,
+        request: tonic::Request<memory::TransferRequest>,
+    ) -> Result<tonic::Response<memory::TransferResponse>, Status> {
+        let turn = self.admit(&request, 0).await?;
+        let caller = auth::caller(&request);
+        let input = request.into_inner();
+        let op = Op::Transfer(memory::ReplicatedTransfer {
+            id: input.id,
+            owner: input.principal.clone(),
+        });
+        let change = |mem: &mut DataNode| mem.transfer(input.id as usize, &input.principal);
+        self.share(turn, &caller, input.id, change, op).await?;
+        Ok(tonic::Response::new(memory::TransferResponse {}))
+    }
+
+    type WatchPressureStream = ReceiverStream<Result<memory::PressureUpdate, Status>>;
+
+    async fn watch_pressure(
+        &self,
+        request: tonic::Request<memory::PressureRequest>,
+    ) -> Result<tonic::Response<Self::WatchPressureStream>, Status> {
+        // the turn only covers starting to watch, not the stream
+        drop(self.admit(&request, 0).await?);
+        // how much memory the node uses says how much its other tenants
+        // use, so only admins are told
+        let admin = auth::caller(&request).admin;
+        let (mut readings, capacity) = {
+            let mem = self.data_node.lock().await;
+            (mem.watch_pressure(), mem.capacity())
+        };
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            loop {
+                let reading = *readings.borrow_and_update();
+                let pressure = match reading.pressure {
+                    Pressure::None => memory::Pressure::None,
+                    Pressure::Low => memory::Pressure::Low,
+                    Pressure::High => memory::Pressure::High,
+                    Pressure::Critical => memory::Pressure::Critical,
+                };
+                let update = memory::PressureUpdate {
+                    pressure: pressure as i32,
+                    used_bytes: if admin { reading.used as u64 } else { 0 },
+                    capacity_bytes: if admin {
+                        capacity.unwrap_or(0) as u64
+                    } else {
+                        0
+                    },
+                };
+                // until the watcher goes away
+                if tx.send(Ok(update)).await.is_err() || readings.changed().await.is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(tonic::Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// The access an `AccessMode` grants, `None` for revoking it.
+pub fn granted(mode: i32) -> Option<Access> {
+    match memory::AccessMode::from_i32(mode) {
+        Some(memory::AccessMode::AccessRead) => Some(Access::Read),
+        Some(memory::AccessMode::AccessReadWrite) => Some(Access::Write),
+        _ => None,
+    }
 }
@@ -0,0 +1,124 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+/// Weighted fair queuing of requests for the node, which serves one at a
+/// time. While the node is busy, clients get turns in proportion to their
+/// weights, however many requests each has waiting, rather than in the
+/// order requests arrived, so one busy client can't hold up the others.
+///
+/// Each request is tagged with the virtual time it would finish at if its
+/// client got its weight's share of the node from when it last had a
+/// request served, and the request with the earliest tag goes next.
+#[derive(Default)]
+pub struct Scheduler {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    busy: bool,
+    virtual_time: f64,
+    finish: HashMap<String, f64>, // tag of each client's last request
+    waiting: BinaryHeap<Waiter>,
+    arrived: u64, // breaks ties between equal tags in arrival order
+}
+
+struct Waiter {
+    start: f64,
+    finish: f64,
+    arrival: u64,
+    turn: oneshot::Sender<Turn>,
+}
+
+// a min-heap on (finish, arrival)
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .finish
+            .total_cmp(&self.finish)
+            .then(other.arrival.cmp(&self.arrival))
+    }
+}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+impl Scheduler {
+    /// Waits for the client's turn at the node, which lasts until the
+    /// returned `Turn` is dropped. `cost` is how much of the node the
+    /// request takes, relative to others.
+    pub async fn turn(&self, client: &str, weight: f64, cost: f64) -> Turn {
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            let start = state
+                .finish
+                .get(client)
+                .copied()
+                .unwrap_or(0.0)
+                .max(state.virtual_time);
+            let finish = start + cost / weight;
+            state.finish.insert(client.to_string(), finish);
+            if !state.busy {
+                state.busy = true;
+                state.virtual_time = start;
+                return Turn {
+                    state: self.state.clone(),
+                };
+            }
+            let (sender, receiver) = oneshot::channel();
+            let arrival = state.arrived;
+            state.arrived += 1;
+            state.waiting.push(Waiter {
+                start,
+                finish,
+                arrival,
+                turn: sender,
+            });
+            receiver
+        };
+        receiver.await.expect("turns are handed on until taken")
+    }
+}
+
+/// A request's turn at the node. Dropping it hands the node on to the next
+/// request.
+pub struct Turn {
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        let next = {
+            let mut state = self.state.lock().unwrap();
+            match state.waiting.pop() {
+                Some(next) => {
+                    state.virtual_time = next.start;
+                    next
+                }
+                None => {
+                    state.busy = false;
+                    return;
+                }
+            }
+        };
+        let turn = Turn {
+            state: self.state.clone(),
+        };
+        // a request that stopped waiting gives the turn straight back,
+        // dropping it on to the one after
+        let _ = next.turn.send(turn);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::eviction::Eviction;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+
+const FILE_PREFIX: &str = "region-";
+
+/// Where a region's bytes are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tier {
+    Ram,
+    Disk,
+}
+
+/// How regions move between RAM and disk: once RAM holds more than `ram`
+/// bytes, the regions `demotion` picks go to disk, and a region on disk
+/// comes back after `promote_after` accesses there. Until then accesses are
+/// served from its file.
+#[derive(Debug, Clone, Copy)]
+pub struct Tiering {
+    pub ram: usize,
+    pub demotion: Eviction,
+    pub promote_after: u32,
+}
+
+/// Accesses served from each tier, and regions moved into each.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TierStats {
+    pub ram_hits: u64,
+    pub disk_hits: u64,
+    pub promotions: u64,
+    pub demotions: u64,
+}
+
+/// What a node that spills holds in each tier.
+#[derive(Debug, Clone, Copy)]
+pub struct Tiers {
+    pub ram_bytes: usize,
+    pub ram_regions: usize,
+    pub disk_bytes: usize,
+    pub disk_regions: usize,
+    pub stats: TierStats,
+}
+
+/// The tier below RAM: regions demoted to a file each in a local directory,
+/// meant to be on fast local storage. Files are read and written in place
+/// with blocking calls, with the node locked like for any other access, so
+/// every request to the node waits on the disk while a region is accessed
+/// there, demoted or promoted; moving a region is up to a whole region's
+/// worth of I/O. Keeping the working set within `ram` keeps that rare.
+///
+/// Spilled regions are only this node's business, so backups tier their
+/// copies by their own accesses, and files left by an earlier run, whose
+/// ids are no longer valid, are removed on startup.
+pub struct Spill {
+    dir: PathBuf,
+    tiering: Tiering,
+    sizes: HashMap<usize, usize>,
+    accesses: HashMap<usize, u32>, // since being demoted
+    bytes: usize,
+    stats: TierStats,
+}
+
+impl Spill {
+    pub fn open(dir: &str, tiering: Tiering) -> io::Result<Self> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir)?;
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with(FILE_PREFIX) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(Self {
+            dir,
+            tiering,
+            sizes: HashMap::new(),
+            accesses: HashMap::new(),
+            bytes: 0,
+            stats: TierStats::default(),
+        })
+    }
+
+    pub fn tiering(&self) -> Tiering {
+        self.tiering
+    }
+
+    pub fn stats(&self) -> TierStats {
+        self.stats
+    }
+
+    pub fn size(&self, id: usize) -> Option<usize> {
+        self.sizes.get(&id).copied()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.sizes.iter().map(|(&id, &size)| (id, size))
+    }
+
+    /// Counts an access served from RAM.
+    pub fn hit_ram(&mut self) {
+        self.stats.ram_hits += 1;
+    }
+
+    /// Counts an access to a spilled region, returning whether it is due
+    /// to be promoted.
+    pub fn hit_disk(&mut self, id: usize) -> bool {
+        self.stats.disk_hits += 1;
+        let accesses = self.accesses.entry(id).or_default();
+        *accesses += 1;
+        *accesses >= self.tiering.promote_after
+    }
+
+    pub fn demote(&mut self, id: usize, data: &[u8]) -> io::Result<()> {
+        fs::write(self.path(id), data)?;
+        self.sizes.insert(id, data.len());
+        self.accesses.insert(id, 0);
+        self.bytes += data.len();
+        self.stats.demotions += 1;
+        Ok(())
+    }
+
+    /// Takes the region's bytes back off disk.
+    pub fn promote(&mut self, id: usize) -> io::Result<Vec<u8>> {
+        let data = fs::read(self.path(id))?;
+        self.remove(id);
+        self.stats.promotions += 1;
+        Ok(data)
+    }
+
+    pub fn read(&self, id: usize, offset: usize, length: usize) -> io::Result<Vec<u8>> {
+        let mut data = vec![0u8; length];
+        File::open(self.path(id))?.read_exact_at(&mut data, offset as u64)?;
+        Ok(data)
+    }
+
+    pub fn write(&self, id: usize, offset: usize, data: &[u8]) -> io::Result<()> {
+        OpenOptions::new()
+            .write(true)
+            .open(self.path(id))?
+            .write_all_at(data, offset as u64)
+    }
+
+    /// Drops a spilled region, returning its size.
+    pub fn remove(&mut self, id: usize) -> Option<usize> {
+        let size = self.sizes.remove(&id)?;
+        self.accesses.remove(&id);
+        self.bytes -= size;
+        if let Err(e) = fs::remove_file(self.path(id)) {
+            log::warn!("Couldn't remove spilled region {}: {}", id, e);
+        }
+        Some(size)
+    }
+
+    fn path(&self, id: usize) -> PathBuf {
+        self.dir.join(format!("{}{}", FILE_PREFIX, id))
+    }
+}
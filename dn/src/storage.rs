@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+
+/// Where a node keeps the bytes of its regions in RAM.
+pub trait Storage: Send {
+    /// Puts a zeroed region of `size` bytes under `id`, unless there is no
+    /// room for it.
+    fn insert(&mut self, id: usize, size: usize) -> Result<(), Full>;
+
+    /// Drops the region, returning its size.
+    fn remove(&mut self, id: usize) -> Option<usize>;
+
+    fn get(&self, id: usize) -> Option<&[u8]>;
+
+    fn get_mut(&mut self, id: usize) -> Option<&mut [u8]>;
+
+    /// The id and size of each region.
+    fn regions(&self) -> Vec<(usize, usize)>;
+
+    fn count(&self) -> usize;
+
+    /// Whether a region of `size` bytes would fit once the regions in
+    /// `freeing` are dropped. Storage without a fixed size always has room.
+    fn fits(&self, _size: usize, _freeing: &[usize]) -> bool {
+        true
+    }
+
+    /// Records what a restarted node needs to know about the region besides
+    /// its bytes. Only storage that outlives the process keeps it.
+    fn label(&mut self, _id: usize, _label: &Label) {}
+
+    /// Records the generation the node's ids are from, likewise.
+    fn record_generation(&mut self, _generation: u32) {}
+}
+
+/// What a node knows about a region besides its bytes and size, as kept by
+/// storage that outlives the process.
+#[derive(Debug, Clone, Default)]
+pub struct Label {
+    pub owner: Option<String>,
+    pub key: u64,
+    pub priority: Option<u32>, // for evictable regions
+}
+
+/// The storage had no room for a region.
+#[derive(Debug)]
+pub struct Full;
+
+/// Regions on the process's heap, each allocated on its own.
+#[derive(Default)]
+pub struct Heap {
+    regions: HashMap<usize, Vec<u8>>,
+}
+
+impl Storage for Heap {
+    fn insert(&mut self, id: usize, size: usize) -> Result<(), Full> {
+        self.regions.insert(id, vec![0u8; size]);
+        Ok(())
+    }
+
+    fn remove(&mut self, id: usize) -> Option<usize> {
+        self.regions.remove(&id).map(|memory| memory.len())
+    }
+
+    fn get(&self, id: usize) -> Option<&[u8]> {
+        self.regions.get(&id).map(Vec::as_slice)
+    }
+
+    fn get_mut(&mut self, id: usize) -> Option<&mut [u8]> {
+        self.regions.get_mut(&id).map(Vec::as_mut_slice)
+    }
+
+    fn regions(&self) -> Vec<(usize, usize)> {
+        self.regions
+            .iter()
+            .map(|(&id, memory)| (id, memory.len()))
+            .collect()
+    }
+
+    fn count(&self) -> usize {
+        self.regions.len()
+    }
+}
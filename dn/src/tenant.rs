@@ -0,0 +1,70 @@
+/// Ids hold the index of the region's tenant above this many bits, so every
+/// tenant allocates from its own range. Clients keep 48 bits of an id, which
+/// leaves room for 65535 tenants besides the default one.
+pub const TENANT_SHIFT: u32 = 32;
+pub const MAX_TENANTS: usize = 1 << 16;
+
+/// A share of the node, with optional limits on the bytes and regions it
+/// may hold. Regions count against the tenant they were allocated in, even
+/// after being shared with or transferred to someone in another one.
+#[derive(Debug, Clone, Default)]
+pub struct Tenant {
+    pub name: String,
+    pub members: Vec<String>, // principals that allocate in it
+    pub max_bytes: Option<usize>,
+    pub max_regions: Option<usize>,
+    pub used_bytes: usize,
+    pub regions: usize,
+    pub next: usize, // id within the tenant's range for its next region
+}
+
+impl Tenant {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ..Self::default()
+        }
+    }
+
+    /// Whether one more region of `size` bytes stays within the limits.
+    pub fn has_room(&self, size: usize) -> bool {
+        self.max_bytes
+            .is_none_or(|max| self.used_bytes + size <= max)
+            && self.max_regions.is_none_or(|max| self.regions < max)
+    }
+}
+
+/// Reads tenants from a file with one
+/// `<tenant> <max bytes> <max regions> <principal>...` line for each, in
+/// order. `-` leaves a limit off. Blank lines and lines starting with `#`
+/// are skipped. Every replica of a node has to be given the same file,
+/// since tenants are told apart by their position in it.
+pub fn from_file(path: &str) -> Result<Vec<Tenant>, Box<dyn std::error::Error>> {
+    let mut tenants = Vec::new();
+    for (number, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let bad = || format!("{}:{}: bad tenant line", path, number + 1);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [name, max_bytes, max_regions, members @ ..] = &fields[..] else {
+            return Err(bad().into());
+        };
+        let limit = |value: &str| match value {
+            "-" => Ok(None),
+            value => value.parse().map(Some).map_err(|_| bad()),
+        };
+        let tenant = Tenant {
+            members: members.iter().map(|m| m.to_string()).collect(),
+            max_bytes: limit(max_bytes)?,
+            max_regions: limit(max_regions)?,
+            ..Tenant::new(name)
+        };
+        tenants.push(tenant);
+    }
+    if tenants.len() >= MAX_TENANTS {
+        return Err(format!("{}: at most {} tenants", path, MAX_TENANTS - 1).into());
+    }
+    Ok(tenants)
+}
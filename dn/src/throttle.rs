@@ -0,0 +1,175 @@
+use crate::auth;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tonic::metadata::MetadataValue;
+use tonic::{Code, Request, Status};
+
+/// How fast a client may go, and its share of the node when it's busy.
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub ops_per_sec: Option<f64>,
+    pub bytes_per_sec: Option<f64>,
+    pub weight: f64,
+}
+
+impl Default for Limit {
+    fn default() -> Self {
+        Self {
+            ops_per_sec: None,
+            bytes_per_sec: None,
+            weight: 1.0,
+        }
+    }
+}
+
+/// The limit of each client, and of everyone not listed.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    clients: HashMap<String, Limit>,
+    default: Limit,
+}
+
+impl Limits {
+    /// Reads limits from a file with one
+    /// `<client> <ops/sec> <bytes/sec> <weight>` line for each client, where
+    /// `-` leaves a rate unlimited and a client `*` sets the default. Blank
+    /// lines and lines starting with `#` are skipped.
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut limits = Limits::default();
+        for (number, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad = || format!("{}:{}: bad limit line", path, number + 1);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [client, ops, bytes, weight] = fields[..] else {
+                return Err(bad().into());
+            };
+            let rate = |value: &str| match value {
+                "-" => Ok(None),
+                value => match value.parse::<f64>() {
+                    Ok(rate) if rate > 0.0 => Ok(Some(rate)),
+                    _ => Err(bad()),
+                },
+            };
+            let limit = Limit {
+                ops_per_sec: rate(ops)?,
+                bytes_per_sec: rate(bytes)?,
+                weight: rate(weight)?.ok_or_else(bad)?,
+            };
+            match client {
+                "*" => limits.default = limit,
+                client => {
+                    limits.clients.insert(client.to_string(), limit);
+                }
+            }
+        }
+        Ok(limits)
+    }
+
+    pub fn get(&self, client: &str) -> Limit {
+        self.clients.get(client).copied().unwrap_or(self.default)
+    }
+}
+
+/// Who a request counts against: its principal, or on nodes without
+/// authentication, where everyone is the same anonymous admin, the address
+/// it came from.
+pub fn client<T>(request: &Request<T>) -> String {
+    let caller = auth::caller(request);
+    if !caller.name.is_empty() {
+        return caller.name;
+    }
+    request
+        .remote_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default()
+}
+
+/// Holds clients to their rates with a token bucket each for operations
+/// and bytes, holding up to a second's worth. A request is let through
+/// while both buckets have something left, and may take them below empty,
+/// so requests bigger than a second's worth still get through, followed by
+/// a wait.
+#[derive(Default)]
+pub struct Throttle {
+    limits: Limits,
+    buckets: HashMap<String, (Bucket, Bucket)>, // (ops, bytes)
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Tops the bucket up for the time since it was last, returning how
+    /// long until it has something in it, if it's empty.
+    fn refill(&mut self, rate: Option<f64>, now: Instant) -> Option<Duration> {
+        let rate = rate?;
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.updated = now;
+        (self.tokens <= 0.0).then(|| Duration::from_secs_f64(-self.tokens / rate))
+    }
+}
+
+impl Throttle {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn limit(&self, client: &str) -> Limit {
+        self.limits.get(client)
+    }
+
+    /// Takes an operation moving `bytes` from the client's buckets, or
+    /// says how long until it may try again.
+    pub fn admit(&mut self, client: &str, bytes: usize) -> Result<(), Throttled> {
+        let limit = self.limits.get(client);
+        if limit.ops_per_sec.is_none() && limit.bytes_per_sec.is_none() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let (ops, data) = self.buckets.entry(client.to_string()).or_insert_with(|| {
+            let full = |rate: Option<f64>| Bucket {
+                tokens: rate.unwrap_or(0.0),
+                updated: now,
+            };
+            (full(limit.ops_per_sec), full(limit.bytes_per_sec))
+        });
+        let wait = ops
+            .refill(limit.ops_per_sec, now)
+            .max(data.refill(limit.bytes_per_sec, now));
+        if let Some(wait) = wait {
+            return Err(Throttled(wait));
+        }
+        ops.tokens -= 1.0;
+        data.tokens -= bytes as f64;
+        Ok(())
+    }
+}
+
+/// A client went over its rate, and may try again after the wait.
+#[derive(Debug)]
+pub struct Throttled(pub Duration);
+
+impl From<Throttled> for Status {
+    fn from(throttled: Throttled) -> Self {
+        // at least a millisecond, so clients don't retry straight away
+        let millis = throttled.0.as_millis().max(1);
+        let mut status = Status::new(
+            Code::ResourceExhausted,
+            format!("Rate limit exceeded, retry in {} ms", millis),
+        );
+        status
+            .metadata_mut()
+            .insert("retry-after-ms", MetadataValue::from(millis as u64));
+        status
+    }
+}